@@ -0,0 +1,221 @@
+use std::{error::Error, sync::mpsc, thread};
+
+use automerge::ActorId;
+use futures_channel::oneshot;
+
+use crate::{Persister, StoredSizes};
+
+/// The async counterpart of [`Persister`], for storage that is accessed through a runtime.
+///
+/// The methods are the same as those required by [`Persister`], with the returned futures being
+/// `Send` so they can be awaited from tasks on a multithreaded runtime.
+#[async_trait::async_trait]
+pub trait AsyncPersister {
+    /// The error type that the operations can produce
+    type Error: Error + Send + 'static;
+
+    /// Returns all of the changes that have been persisted through this persister.
+    async fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Inserts the given changes at the unique addresses specified by their `actor_id` and
+    /// `sequence_number`.
+    async fn insert_changes(
+        &mut self,
+        changes: Vec<(ActorId, u64, Vec<u8>)>,
+    ) -> Result<(), Self::Error>;
+
+    /// Removes the changes at the unique addresses specified by their `actor_id` and
+    /// `sequence_number`, ignoring any that do not exist.
+    async fn remove_changes(&mut self, changes: Vec<(ActorId, u64)>) -> Result<(), Self::Error>;
+
+    /// Returns the document, if one has been persisted previously.
+    async fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Sets the document to the given data.
+    async fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Returns the sync state for the given peer if one exists.
+    async fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Sets the sync state for the given peer.
+    async fn set_sync_state(
+        &mut self,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error>;
+
+    /// Removes the sync states associated with the given `peer_ids`.
+    async fn remove_sync_states(&mut self, peer_ids: Vec<Vec<u8>>) -> Result<(), Self::Error>;
+
+    /// Returns the list of peer ids with stored sync states.
+    async fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Returns the metadata stored under the key, if any.
+    async fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Sets the metadata stored under the key.
+    async fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Removes the metadata stored under the key, if any.
+    async fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error>;
+
+    /// Returns the keys of all stored metadata.
+    async fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Returns the sizes components being stored consume.
+    async fn sizes(&self) -> StoredSizes;
+
+    /// Flush the data out to disk.
+    async fn flush(&mut self) -> Result<usize, Self::Error>;
+}
+
+type Job<P> = Box<dyn FnOnce(&mut P) + Send>;
+
+/// Use a synchronous [`Persister`] as an [`AsyncPersister`], running it on a thread of its own.
+///
+/// Each operation is sent to the thread and awaited without blocking the runtime, so existing
+/// persisters such as those for sled or sqlite can be used from async code as they are. The
+/// operations run one at a time in the order they were called. The thread exits once the adapter
+/// is dropped and the operations already sent have finished.
+///
+/// ```rust
+/// # use automerge_persistent_core::{AsyncPersister, BlockingPersisterAdapter, MemoryPersister};
+/// let mut persister = BlockingPersisterAdapter::new(MemoryPersister::default());
+/// futures::executor::block_on(async {
+///     persister.set_document(vec![1, 2, 3]).await.unwrap();
+///     assert_eq!(persister.get_document().await.unwrap(), Some(vec![1, 2, 3]));
+/// });
+/// ```
+#[derive(Debug)]
+pub struct BlockingPersisterAdapter<P> {
+    jobs: mpsc::Sender<Job<P>>,
+}
+
+impl<P> BlockingPersisterAdapter<P>
+where
+    P: Persister + Send + 'static,
+{
+    /// Move the persister on to a new thread to run its operations.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the thread could not be spawned.
+    pub fn new(mut persister: P) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job<P>>();
+        thread::Builder::new()
+            .name("automerge-persister".to_owned())
+            .spawn(move || {
+                for job in receiver {
+                    job(&mut persister);
+                }
+            })
+            .expect("failed to spawn persister thread");
+        Self { jobs }
+    }
+
+    /// Run the operation on the persister's thread, waiting for its result.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the persister panicked on an earlier or this operation, stopping its thread.
+    pub async fn call<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut P) -> T + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let sent = self.jobs.send(Box::new(move |persister| {
+            // the caller may have stopped waiting
+            let _ = sender.send(f(persister));
+        }));
+        match (sent, receiver.await) {
+            (Ok(()), Ok(result)) => result,
+            _ => panic!("the persister's thread stopped after it panicked"),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<P> AsyncPersister for BlockingPersisterAdapter<P>
+where
+    P: Persister + Send + 'static,
+    P::Error: Send,
+{
+    type Error = P::Error;
+
+    async fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.call(|p| p.get_changes()).await
+    }
+
+    async fn insert_changes(
+        &mut self,
+        changes: Vec<(ActorId, u64, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        self.call(move |p| p.insert_changes(changes)).await
+    }
+
+    async fn remove_changes(&mut self, changes: Vec<(ActorId, u64)>) -> Result<(), Self::Error> {
+        self.call(move |p| p.remove_changes(changes.iter().map(|(a, s)| (a, *s)).collect()))
+            .await
+    }
+
+    async fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.call(|p| p.get_document()).await
+    }
+
+    async fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.call(move |p| p.set_document(data)).await
+    }
+
+    async fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let peer_id = peer_id.to_vec();
+        self.call(move |p| p.get_sync_state(&peer_id)).await
+    }
+
+    async fn set_sync_state(
+        &mut self,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        self.call(move |p| p.set_sync_state(peer_id, sync_state))
+            .await
+    }
+
+    async fn remove_sync_states(&mut self, peer_ids: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        self.call(move |p| {
+            let peer_ids = peer_ids.iter().map(Vec::as_slice).collect::<Vec<_>>();
+            p.remove_sync_states(&peer_ids)
+        })
+        .await
+    }
+
+    async fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.call(|p| p.get_peer_ids()).await
+    }
+
+    async fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let key = key.to_vec();
+        self.call(move |p| p.get_metadata(&key)).await
+    }
+
+    async fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.call(move |p| p.set_metadata(key, value)).await
+    }
+
+    async fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        let key = key.to_vec();
+        self.call(move |p| p.remove_metadata(&key)).await
+    }
+
+    async fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.call(|p| p.get_metadata_keys()).await
+    }
+
+    async fn sizes(&self) -> StoredSizes {
+        self.call(|p| p.sizes()).await
+    }
+
+    async fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.call(|p| p.flush()).await
+    }
+}
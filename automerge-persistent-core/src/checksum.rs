@@ -0,0 +1,554 @@
+use std::{
+    collections::HashSet,
+    convert::{TryFrom, TryInto},
+    error::Error,
+    fmt,
+};
+
+use automerge::{ActorId, Change, ChangeHash};
+
+use crate::{
+    forward_persister, persister::decode_actors, DocumentVersion, Forward, MappedDocument,
+    Persister, StorageReport, VersionConflict, VersionedDocument,
+};
+
+/// The start of a checksummed record, which no automerge document, change or codec record starts
+/// with.
+const MAGIC: &[u8] = b"\0crc";
+
+const CHANGE: u8 = 0;
+const CHANGE_BY_HASH: u8 = 1;
+const DOCUMENT: u8 = 2;
+
+/// The CRC-32 lookup table, for the reversed IEEE polynomial.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, b| {
+        (crc >> 8) ^ CRC_TABLE[((crc ^ u32::from(*b)) & 0xff) as usize]
+    })
+}
+
+/// The record a checksum failed for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RecordKey {
+    /// The change stored for the actor and sequence number.
+    Change(ActorId, u64),
+    /// The change stored by its hash.
+    ChangeHash(ChangeHash),
+    /// The document.
+    Document,
+}
+
+impl fmt::Display for RecordKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Change(actor, seq) => write!(f, "change {actor}/{seq}"),
+            Self::ChangeHash(hash) => write!(f, "change {hash}"),
+            Self::Document => write!(f, "document"),
+        }
+    }
+}
+
+/// Errors from a [`ChecksummedPersister`].
+#[derive(Debug)]
+pub enum ChecksumError<E> {
+    /// The inner persister failed.
+    PersisterError(E),
+    /// A stored record doesn't match its checksum, such as from bit rot in the storage.
+    ChecksumMismatch(RecordKey),
+    /// A stored record starts as a checksummed one but is too short to be one.
+    Truncated,
+    /// A stored record has no checksum, such as from corruption of its start or a record planted
+    /// in the storage.
+    ///
+    /// Records stored before checksumming was enabled can be read with
+    /// [`ChecksummedPersister::allow_unchecksummed_reads`].
+    MissingChecksum,
+    /// A stored record was checksummed for another key than the one it was read as, such as a
+    /// record copied over another in the storage.
+    WrongKey(RecordKey),
+}
+
+impl<E> fmt::Display for ChecksumError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PersisterError(e) => e.fmt(f),
+            Self::ChecksumMismatch(key) => write!(f, "the {key} doesn't match its checksum"),
+            Self::Truncated => write!(f, "a checksummed record is truncated"),
+            Self::MissingChecksum => write!(f, "a stored record has no checksum"),
+            Self::WrongKey(key) => write!(f, "the {key} is stored under another key"),
+        }
+    }
+}
+
+impl<E> Error for ChecksumError<E>
+where
+    E: Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::PersisterError(e) => e.source(),
+            Self::ChecksumMismatch(_)
+            | Self::Truncated
+            | Self::MissingChecksum
+            | Self::WrongKey(_) => None,
+        }
+    }
+}
+
+/// Frame the data with the checksum of its key and itself.
+fn encode(key: &RecordKey, data: &[u8]) -> Vec<u8> {
+    let mut framed = MAGIC.to_vec();
+    // the checksum is filled in once the key is written
+    framed.extend([0; 4]);
+    match key {
+        RecordKey::Change(actor, seq) => {
+            let actor = actor.to_bytes();
+            framed.push(CHANGE);
+            framed.extend((actor.len() as u32).to_be_bytes());
+            framed.extend(actor);
+            framed.extend(seq.to_be_bytes());
+        }
+        RecordKey::ChangeHash(hash) => {
+            framed.push(CHANGE_BY_HASH);
+            framed.extend(hash.0);
+        }
+        RecordKey::Document => framed.push(DOCUMENT),
+    }
+    framed.extend(data);
+    let crc = crc32(&framed[MAGIC.len() + 4..]);
+    framed[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&crc.to_be_bytes());
+    framed
+}
+
+/// The key in the frame and the offset of the data after it.
+fn decode_key(body: &[u8]) -> Option<(RecordKey, usize)> {
+    match *body.first()? {
+        CHANGE => {
+            let len = usize::try_from(u32::from_be_bytes(body.get(1..5)?.try_into().ok()?)).ok()?;
+            let actor = body.get(5..5 + len)?;
+            let seq = u64::from_be_bytes(body.get(5 + len..13 + len)?.try_into().ok()?);
+            Some((RecordKey::Change(ActorId::from(actor), seq), 13 + len))
+        }
+        CHANGE_BY_HASH => {
+            let hash = ChangeHash::try_from(body.get(1..33)?).ok()?;
+            Some((RecordKey::ChangeHash(hash), 33))
+        }
+        DOCUMENT => Some((RecordKey::Document, 1)),
+        _ => None,
+    }
+}
+
+/// Check the checksum of a framed record and take its key and data.
+fn decode<E>(mut framed: Vec<u8>) -> Result<(RecordKey, Vec<u8>), ChecksumError<E>> {
+    if !framed.starts_with(MAGIC) {
+        return Err(ChecksumError::MissingChecksum);
+    }
+    let header = MAGIC.len() + 4;
+    let crc = framed
+        .get(MAGIC.len()..header)
+        .and_then(|crc| crc.try_into().ok())
+        .map(u32::from_be_bytes)
+        .ok_or(ChecksumError::Truncated)?;
+    let body = &framed[header..];
+    let (key, offset) = decode_key(body).ok_or(ChecksumError::Truncated)?;
+    if crc32(body) != crc {
+        return Err(ChecksumError::ChecksumMismatch(key));
+    }
+    let data = framed.split_off(header + offset);
+    Ok((key, data))
+}
+
+/// The data of a record and the key it was checksummed for, if it had a checksum.
+type KeyedRecord = (Option<RecordKey>, Vec<u8>);
+
+/// Whether the change is the one the key is for, if the data is a change rather than encoded by
+/// an outer layer.
+fn change_matches(key: &RecordKey, data: &[u8]) -> bool {
+    let Ok(change) = Change::from_bytes(data.to_vec()) else {
+        return true;
+    };
+    match key {
+        RecordKey::Change(actor, seq) => change.actor_id() == actor && change.seq == *seq,
+        RecordKey::ChangeHash(hash) => change.hash == *hash,
+        RecordKey::Document => false,
+    }
+}
+
+/// A persister storing a CRC-32 checksum with every change and the document, checked when they
+/// are read back.
+///
+/// A record that was changed in storage, such as by bit rot, is reported as a
+/// [`ChecksumError::ChecksumMismatch`] with the key of the record rather than failing to decode
+/// later on. Each record is checksummed along with the key it was written for, so a record
+/// copied over another is reported as a [`ChecksumError::WrongKey`]: the document must have been
+/// written as the document, and changes as the changes they contain, with each key appearing
+/// once. Sync states and metadata are passed through unchecked.
+///
+/// Records without a checksum fail with [`ChecksumError::MissingChecksum`], unless reading those
+/// stored before wrapping the persister is allowed with [`Self::allow_unchecksummed_reads`].
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{
+/// #     ChecksumError, ChecksummedPersister, MemoryPersister, PersistentAutomerge, Persister,
+/// # };
+/// let mut doc = PersistentAutomerge::load(ChecksummedPersister::new(MemoryPersister::default()))
+///     .unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+/// doc.compact(&[]).unwrap();
+///
+/// // flip a bit of the stored document
+/// let mut inner = doc.close().unwrap().into_inner();
+/// let mut document = inner.get_document().unwrap().unwrap();
+/// *document.last_mut().unwrap() ^= 1;
+/// inner.set_document(document).unwrap();
+///
+/// let persister = ChecksummedPersister::new(inner);
+/// assert!(matches!(
+///     persister.get_document(),
+///     Err(ChecksumError::ChecksumMismatch(_))
+/// ));
+/// ```
+#[derive(Debug)]
+pub struct ChecksummedPersister<P> {
+    inner: P,
+    allow_unchecksummed: bool,
+}
+
+impl<P> ChecksummedPersister<P>
+where
+    P: Persister,
+{
+    /// Wrap the persister, checksumming the records stored through it.
+    pub const fn new(inner: P) -> Self {
+        Self {
+            inner,
+            allow_unchecksummed: false,
+        }
+    }
+
+    /// Read records without a checksum as they are, rather than failing with
+    /// [`ChecksumError::MissingChecksum`].
+    ///
+    /// This is for stores written before checksumming was enabled, until they have been
+    /// compacted or rewritten through this persister. Corruption of the start of a record then
+    /// goes unnoticed, so it should be turned off again afterwards.
+    #[must_use]
+    pub const fn allow_unchecksummed_reads(mut self) -> Self {
+        self.allow_unchecksummed = true;
+        self
+    }
+
+    /// Get a reference to the inner persister.
+    pub const fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Take the inner persister back out.
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    /// Check a record, taking its key and data, or passing it through without a key if it has no
+    /// checksum and that is allowed.
+    fn read(&self, record: Vec<u8>) -> Result<KeyedRecord, ChecksumError<P::Error>> {
+        if self.allow_unchecksummed && !record.starts_with(MAGIC) {
+            return Ok((None, record));
+        }
+        let (key, data) = decode(record)?;
+        Ok((Some(key), data))
+    }
+
+    fn read_document(
+        &self,
+        document: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>, ChecksumError<P::Error>> {
+        document
+            .map(|document| match self.read(document)? {
+                (Some(RecordKey::Document) | None, data) => Ok(data),
+                (Some(key), _) => Err(ChecksumError::WrongKey(key)),
+            })
+            .transpose()
+    }
+}
+
+impl<P> Forward for ChecksummedPersister<P>
+where
+    P: Persister,
+{
+    type Inner = P;
+    type Error = ChecksumError<P::Error>;
+
+    fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn map_error(error: P::Error) -> Self::Error {
+        ChecksumError::PersisterError(error)
+    }
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let mut seen = HashSet::new();
+        self.inner
+            .get_changes()
+            .map_err(ChecksumError::PersisterError)?
+            .into_iter()
+            .map(|change| match self.read(change)? {
+                (Some(key), data) => {
+                    if change_matches(&key, &data) && seen.insert(key.clone()) {
+                        Ok(data)
+                    } else {
+                        Err(ChecksumError::WrongKey(key))
+                    }
+                }
+                (None, data) => Ok(data),
+            })
+            .collect()
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let changes = changes
+            .into_iter()
+            .map(|(actor, seq, change)| {
+                let framed = encode(&RecordKey::Change(actor.clone(), seq), &change);
+                (actor, seq, framed)
+            })
+            .collect();
+        self.inner
+            .insert_changes(changes)
+            .map_err(ChecksumError::PersisterError)
+    }
+
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        let changes = changes
+            .into_iter()
+            .map(|(hash, change)| (hash, encode(&RecordKey::ChangeHash(hash), &change)))
+            .collect();
+        self.inner
+            .insert_changes_by_hash(changes)
+            .map_err(ChecksumError::PersisterError)
+    }
+
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        // the inner persister only sees framed changes
+        decode_actors(Forward::get_changes(self)?)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        let document = self
+            .inner
+            .get_document()
+            .map_err(ChecksumError::PersisterError)?;
+        self.read_document(document)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.inner
+            .set_document(encode(&RecordKey::Document, &data))
+            .map_err(ChecksumError::PersisterError)
+    }
+
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        let (document, version) = self
+            .inner
+            .get_document_versioned()
+            .map_err(ChecksumError::PersisterError)?;
+        Ok((self.read_document(document)?, version))
+    }
+
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        let (document, version) = self
+            .inner
+            .get_document_mapped()
+            .map_err(ChecksumError::PersisterError)?;
+        let document = self
+            .read_document(document.map(|d| (*d).as_ref().to_vec()))?
+            .map(|d| Box::new(d) as Box<dyn AsRef<[u8]>>);
+        Ok((document, version))
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.inner
+            .set_document_if(encode(&RecordKey::Document, &data), expected)
+            .map_err(ChecksumError::PersisterError)
+    }
+
+    /// The document is written with its checksum and the compaction passed through whole, the
+    /// changes are removed by their keys which checksumming doesn't change.
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.inner
+            .compact_if(encode(&RecordKey::Document, &data), expected, changes)
+            .map_err(ChecksumError::PersisterError)
+    }
+
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        // read back unframed, so the checksums are counted as overhead
+        StorageReport::read(self)
+    }
+}
+
+forward_persister!(impl<P> for ChecksummedPersister<P> where P: Persister);
+
+#[cfg(test)]
+mod tests {
+    use automerge::{transaction::Transactable, AutoCommit, Change, ROOT};
+
+    use super::{encode, ChecksumError, ChecksummedPersister, RecordKey};
+    use crate::{test_support, MemoryPersister, Persister};
+
+    fn changes() -> Vec<Change> {
+        let mut doc = AutoCommit::new();
+        for key in ["a", "b"] {
+            doc.put(ROOT, key, 1).unwrap();
+            doc.commit();
+        }
+        doc.get_changes(&[]).unwrap().into_iter().cloned().collect()
+    }
+
+    fn key(change: &Change) -> RecordKey {
+        RecordKey::Change(change.actor_id().clone(), change.seq)
+    }
+
+    #[test]
+    fn conforms_as_a_layer() {
+        test_support::check_layer(ChecksummedPersister::new, |p| p.inner());
+    }
+
+    #[test]
+    fn corrupted_magic_is_a_checksum_error() {
+        let mut persister = ChecksummedPersister::new(MemoryPersister::default());
+        persister.set_document(vec![1, 2, 3]).unwrap();
+
+        let mut inner = persister.into_inner();
+        let mut document = inner.get_document().unwrap().unwrap();
+        document[1] ^= 1;
+        inner.set_document(document).unwrap();
+
+        let persister = ChecksummedPersister::new(inner);
+        assert!(matches!(
+            persister.get_document(),
+            Err(ChecksumError::MissingChecksum)
+        ));
+    }
+
+    #[test]
+    fn unchecksummed_records_are_only_read_when_allowed() {
+        let mut inner = MemoryPersister::default();
+        inner.set_document(vec![1, 2, 3]).unwrap();
+
+        let persister = ChecksummedPersister::new(inner);
+        assert!(matches!(
+            persister.get_document(),
+            Err(ChecksumError::MissingChecksum)
+        ));
+        let persister =
+            ChecksummedPersister::new(persister.into_inner()).allow_unchecksummed_reads();
+        assert_eq!(persister.get_document().unwrap(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn record_swapped_between_keys_is_detected() {
+        let changes = changes();
+        let mut persister = ChecksummedPersister::new(MemoryPersister::default());
+        for change in &changes {
+            persister
+                .insert_changes(vec![(
+                    change.actor_id().clone(),
+                    change.seq,
+                    change.raw_bytes().to_vec(),
+                )])
+                .unwrap();
+        }
+        assert_eq!(persister.get_changes().unwrap().len(), 2);
+
+        // the first change copied over the second
+        let mut inner = persister.into_inner();
+        let (first, second) = (&changes[0], &changes[1]);
+        inner
+            .insert_changes(vec![(
+                second.actor_id().clone(),
+                second.seq,
+                encode(&key(first), first.raw_bytes()),
+            )])
+            .unwrap();
+        let persister = ChecksummedPersister::new(inner);
+        assert!(matches!(
+            persister.get_changes(),
+            Err(ChecksumError::WrongKey(k)) if k == key(first)
+        ));
+
+        // and over the document
+        let mut inner = persister.into_inner();
+        inner
+            .set_document(encode(&key(first), first.raw_bytes()))
+            .unwrap();
+        let persister = ChecksummedPersister::new(inner);
+        assert!(matches!(
+            persister.get_document(),
+            Err(ChecksumError::WrongKey(k)) if k == key(first)
+        ));
+    }
+
+    #[test]
+    fn change_checksummed_for_another_key_is_detected() {
+        let changes = changes();
+        let (first, second) = (&changes[0], &changes[1]);
+        let mut inner = MemoryPersister::default();
+        // the frame claims the second change's key but holds the first
+        inner
+            .insert_changes(vec![(
+                second.actor_id().clone(),
+                second.seq,
+                encode(&key(second), first.raw_bytes()),
+            )])
+            .unwrap();
+        let persister = ChecksummedPersister::new(inner);
+        assert!(matches!(
+            persister.get_changes(),
+            Err(ChecksumError::WrongKey(k)) if k == key(second)
+        ));
+    }
+}
@@ -0,0 +1,519 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    convert::{TryFrom, TryInto},
+    error::Error,
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+use automerge::ActorId;
+
+use crate::{
+    compact_in_steps, forward_persister, DocumentVersion, Forward, MappedDocument, Persister,
+    QuarantinedRecord, StorageReport, VersionConflict, VersionedDocument,
+};
+
+/// Metadata key prefix for the chunks of a document, followed by the id of the document and the
+/// index of the chunk.
+const CHUNK_PREFIX: &[u8] = b"document_chunk/";
+
+/// Metadata key prefix for the chunks of quarantined records, kept apart from those of the
+/// document so that replacing the document doesn't remove them.
+const QUARANTINE_CHUNK_PREFIX: &[u8] = b"quarantine_chunk/";
+
+/// The start of a stored document that is a manifest of chunks, which no automerge document or
+/// codec record starts with.
+const MANIFEST_MAGIC: &[u8] = b"\0chunks";
+
+/// Stored in place of a document that was split into chunks.
+struct Manifest {
+    /// Derived from the contents of the document, so that concurrent writers of different
+    /// documents don't overwrite each other's chunks.
+    id: u64,
+    chunks: u32,
+    len: u64,
+}
+
+impl Manifest {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = MANIFEST_MAGIC.to_vec();
+        data.extend(self.id.to_be_bytes());
+        data.extend(self.chunks.to_be_bytes());
+        data.extend(self.len.to_be_bytes());
+        data
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let rest = data.strip_prefix(MANIFEST_MAGIC)?;
+        if rest.len() != 20 {
+            return None;
+        }
+        Some(Self {
+            id: u64::from_be_bytes(rest[..8].try_into().ok()?),
+            chunks: u32::from_be_bytes(rest[8..12].try_into().ok()?),
+            len: u64::from_be_bytes(rest[12..].try_into().ok()?),
+        })
+    }
+}
+
+fn chunk_key(prefix: &[u8], id: u64, index: u32) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.extend(format!("{id:016x}/{index}").as_bytes());
+    key
+}
+
+/// Errors from a [`ChunkedPersister`].
+#[derive(Debug)]
+pub enum ChunkedError<E> {
+    /// The inner persister failed.
+    PersisterError(E),
+    /// A chunk of the stored document is missing.
+    MissingChunk(u32),
+    /// The chunks of the stored document don't add up to its length.
+    LengthMismatch {
+        /// The length of the document when it was stored.
+        expected: u64,
+        /// The length of the chunks that were read back.
+        actual: u64,
+    },
+}
+
+impl<E> fmt::Display for ChunkedError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PersisterError(e) => e.fmt(f),
+            Self::MissingChunk(index) => write!(f, "chunk {index} of the document is missing"),
+            Self::LengthMismatch { expected, actual } => write!(
+                f,
+                "the chunks of the document have {actual} bytes, expected {expected}"
+            ),
+        }
+    }
+}
+
+impl<E> Error for ChunkedError<E>
+where
+    E: Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::PersisterError(e) => e.source(),
+            Self::MissingChunk(_) | Self::LengthMismatch { .. } => None,
+        }
+    }
+}
+
+/// A persister splitting documents too large to store in one value into chunks, for backends
+/// that limit the size of values.
+///
+/// Chunks are stored as metadata of the inner persister, with a small manifest of them stored as
+/// the document. This should wrap the backend directly so that the documents it chunks have
+/// already been through any encryption or compression. The chunks count towards the metadata in
+/// [`Persister::sizes`], and as documents of any size can be stored this has no
+/// [`Persister::max_value_size`].
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{ChunkedPersister, MemoryPersister, PersistentAutomerge, Persister};
+/// let persister = ChunkedPersister::new(MemoryPersister::default()).with_chunk_size(64);
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     for i in 0..100 {
+///         tx.put(ROOT, format!("key{i}"), i).unwrap();
+///     }
+///     Ok(())
+/// })
+/// .unwrap();
+/// doc.compact(&[]).unwrap();
+///
+/// // the backend only holds small values
+/// let inner = doc.persister().inner();
+/// assert!(inner.get_document().unwrap().unwrap().len() <= 64);
+/// assert!(inner.get_metadata_keys().unwrap().len() > 1);
+///
+/// let persister = doc.close().unwrap();
+/// let doc = PersistentAutomerge::load(persister).unwrap();
+/// assert_eq!(doc.document().keys(ROOT).count(), 100);
+/// ```
+#[derive(Debug)]
+pub struct ChunkedPersister<P> {
+    inner: P,
+    chunk_size: Option<usize>,
+}
+
+impl<P> ChunkedPersister<P>
+where
+    P: Persister,
+{
+    /// Wrap the persister, splitting documents larger than its [`Persister::max_value_size`].
+    pub fn new(inner: P) -> Self {
+        let chunk_size = inner.max_value_size();
+        Self { inner, chunk_size }
+    }
+
+    /// Split documents larger than the given number of bytes, in place of the inner persister's
+    /// [`Persister::max_value_size`].
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size.max(1));
+        self
+    }
+
+    /// Get a reference to the inner persister.
+    pub const fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Take the inner persister back out.
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    /// Reassemble the document if what is stored is a manifest of chunks.
+    fn read(&self, document: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, ChunkedError<P::Error>> {
+        self.read_chunked(CHUNK_PREFIX, document)
+    }
+
+    /// Reassemble the record if it is a manifest of chunks stored under the prefix.
+    fn read_chunked(
+        &self,
+        prefix: &[u8],
+        document: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>, ChunkedError<P::Error>> {
+        let manifest = match document.as_deref().and_then(Manifest::decode) {
+            Some(manifest) => manifest,
+            None => return Ok(document),
+        };
+        let mut data = Vec::with_capacity(usize::try_from(manifest.len).unwrap_or_default());
+        for index in 0..manifest.chunks {
+            let chunk = self
+                .inner
+                .get_metadata(&chunk_key(prefix, manifest.id, index))
+                .map_err(ChunkedError::PersisterError)?
+                .ok_or(ChunkedError::MissingChunk(index))?;
+            data.extend(chunk);
+        }
+        let actual = data.len() as u64;
+        if actual != manifest.len {
+            return Err(ChunkedError::LengthMismatch {
+                expected: manifest.len,
+                actual,
+            });
+        }
+        Ok(Some(data))
+    }
+
+    /// Store the chunks of the data under the prefix if it is too large for one value, returning
+    /// the manifest to store in its place, with the id of the chunks derived from `id`.
+    fn write_chunks(
+        &mut self,
+        prefix: &[u8],
+        id: &[u8],
+        data: &[u8],
+    ) -> Result<Option<Manifest>, ChunkedError<P::Error>> {
+        let chunk_size = match self.chunk_size {
+            Some(chunk_size) if data.len() > chunk_size => chunk_size,
+            _ => return Ok(None),
+        };
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        let id = hasher.finish();
+
+        let mut chunks = 0;
+        for (chunk, index) in data.chunks(chunk_size).zip(0..) {
+            self.inner
+                .set_metadata(chunk_key(prefix, id, index), chunk.to_vec())
+                .map_err(ChunkedError::PersisterError)?;
+            chunks = index + 1;
+        }
+        let manifest = Manifest {
+            id,
+            chunks,
+            len: data.len() as u64,
+        };
+        Ok(Some(manifest))
+    }
+
+    fn chunk_keys(&self) -> Result<Vec<Vec<u8>>, ChunkedError<P::Error>> {
+        Ok(self
+            .inner
+            .get_metadata_keys()
+            .map_err(ChunkedError::PersisterError)?
+            .into_iter()
+            .filter(|key| key.starts_with(CHUNK_PREFIX))
+            .collect())
+    }
+
+    /// Remove the chunks that were stored before the document was replaced, other than those of
+    /// the new document.
+    fn remove_chunks(
+        &mut self,
+        keys: Vec<Vec<u8>>,
+        keep: Option<u64>,
+    ) -> Result<(), ChunkedError<P::Error>> {
+        let keep = keep.map(|id| {
+            let mut prefix = chunk_key(CHUNK_PREFIX, id, 0);
+            prefix.pop();
+            prefix
+        });
+        for key in keys {
+            if keep.as_ref().is_none_or(|keep| !key.starts_with(keep)) {
+                self.inner
+                    .remove_metadata(&key)
+                    .map_err(ChunkedError::PersisterError)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the chunks of the record quarantined under the key, if it was split.
+    fn remove_quarantined_chunks(&mut self, key: &[u8]) -> Result<(), ChunkedError<P::Error>> {
+        let record = self
+            .inner
+            .get_quarantined()
+            .map_err(ChunkedError::PersisterError)?
+            .into_iter()
+            .find(|(k, _)| k == key);
+        if let Some(manifest) = record.and_then(|(_, record)| Manifest::decode(&record)) {
+            for index in 0..manifest.chunks {
+                self.inner
+                    .remove_metadata(&chunk_key(QUARANTINE_CHUNK_PREFIX, manifest.id, index))
+                    .map_err(ChunkedError::PersisterError)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<P> Forward for ChunkedPersister<P>
+where
+    P: Persister,
+{
+    type Inner = P;
+    type Error = ChunkedError<P::Error>;
+
+    fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn map_error(error: P::Error) -> Self::Error {
+        ChunkedError::PersisterError(error)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        let document = self
+            .inner
+            .get_document()
+            .map_err(ChunkedError::PersisterError)?;
+        self.read(document)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let stale = self.chunk_keys()?;
+        let manifest = self.write_chunks(CHUNK_PREFIX, &data, &data)?;
+        let id = manifest.as_ref().map(|manifest| manifest.id);
+        self.inner
+            .set_document(manifest.map_or(data, |manifest| manifest.encode()))
+            .map_err(ChunkedError::PersisterError)?;
+        self.remove_chunks(stale, id)
+    }
+
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        let (document, version) = self
+            .inner
+            .get_document_versioned()
+            .map_err(ChunkedError::PersisterError)?;
+        Ok((self.read(document)?, version))
+    }
+
+    /// A document that wasn't split is passed through as the inner persister mapped it.
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        let (document, version) = self
+            .inner
+            .get_document_mapped()
+            .map_err(ChunkedError::PersisterError)?;
+        let document = match document {
+            Some(d) if Manifest::decode((*d).as_ref()).is_some() => self
+                .read(Some((*d).as_ref().to_vec()))?
+                .map(|d| Box::new(d) as Box<dyn AsRef<[u8]>>),
+            document => document,
+        };
+        Ok((document, version))
+    }
+
+    /// On a conflict the chunks just written are left for the next successful write to remove,
+    /// as the winning writer may have stored the same document under the same chunks.
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let stale = self.chunk_keys()?;
+        let manifest = self.write_chunks(CHUNK_PREFIX, &data, &data)?;
+        let id = manifest.as_ref().map(|manifest| manifest.id);
+        let result = self
+            .inner
+            .set_document_if(
+                manifest.map_or(data, |manifest| manifest.encode()),
+                expected,
+            )
+            .map_err(ChunkedError::PersisterError)?;
+        if result.is_ok() {
+            self.remove_chunks(stale, id)?;
+        }
+        Ok(result)
+    }
+
+    /// The document may be split over several records, so it is set and then the changes removed.
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        compact_in_steps(self, data, expected, changes)
+    }
+
+    /// The chunks of the document and quarantined records are left out.
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .inner
+            .get_metadata_keys()
+            .map_err(ChunkedError::PersisterError)?
+            .into_iter()
+            .filter(|key| {
+                !key.starts_with(CHUNK_PREFIX) && !key.starts_with(QUARANTINE_CHUNK_PREFIX)
+            })
+            .collect())
+    }
+
+    fn max_value_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Records too large to store in one value, such as a corrupt document, are split like
+    /// documents are.
+    fn quarantine(&mut self, key: Vec<u8>, record: Vec<u8>) -> Result<(), Self::Error> {
+        self.remove_quarantined_chunks(&key)?;
+        let manifest = self.write_chunks(QUARANTINE_CHUNK_PREFIX, &key, &record)?;
+        self.inner
+            .quarantine(key, manifest.map_or(record, |manifest| manifest.encode()))
+            .map_err(ChunkedError::PersisterError)
+    }
+
+    fn get_quarantined(&self) -> Result<Vec<QuarantinedRecord>, Self::Error> {
+        self.inner
+            .get_quarantined()
+            .map_err(ChunkedError::PersisterError)?
+            .into_iter()
+            .map(|(key, record)| {
+                let record = self
+                    .read_chunked(QUARANTINE_CHUNK_PREFIX, Some(record))?
+                    .unwrap_or_default();
+                Ok((key, record))
+            })
+            .collect()
+    }
+
+    fn remove_quarantined(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.remove_quarantined_chunks(key)?;
+        self.inner
+            .remove_quarantined(key)
+            .map_err(ChunkedError::PersisterError)
+    }
+
+    /// The chunks of the document are reported as part of its size rather than as metadata, and
+    /// the manifests stored in place of split records as overhead.
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        let mut report = StorageReport::read(self)?;
+        for key in self
+            .inner
+            .get_metadata_keys()
+            .map_err(ChunkedError::PersisterError)?
+        {
+            let size = self
+                .inner
+                .get_metadata(&key)
+                .map_err(ChunkedError::PersisterError)?
+                .map_or(0, |chunk| chunk.len() as u64);
+            if key.starts_with(CHUNK_PREFIX) {
+                report.sizes.metadata = report.sizes.metadata.saturating_sub(size);
+                report.sizes.document += size;
+            } else if key.starts_with(QUARANTINE_CHUNK_PREFIX) {
+                // quarantined records are read back as their manifests
+                report.overhead = report.overhead.saturating_sub(size);
+            }
+        }
+        Ok(report)
+    }
+}
+
+forward_persister!(impl<P> for ChunkedPersister<P> where P: Persister);
+
+#[cfg(test)]
+mod tests {
+    use super::ChunkedPersister;
+    use crate::{test_support::Probe, Persister};
+
+    #[test]
+    fn mapped_document_is_read_through() {
+        let mut persister = ChunkedPersister::new(Probe::default()).with_chunk_size(2);
+        persister.set_document(vec![1, 2, 3]).unwrap();
+        let (document, _) = persister.get_document_mapped().unwrap();
+        assert_eq!((*document.unwrap()).as_ref(), &[1, 2, 3]);
+
+        persister.set_document(vec![4]).unwrap();
+        let (document, _) = persister.get_document_mapped().unwrap();
+        assert_eq!((*document.unwrap()).as_ref(), &[4]);
+        assert_eq!(persister.inner().mapped_reads(), 2);
+    }
+
+    #[test]
+    fn large_quarantined_records_are_chunked() {
+        let mut persister = ChunkedPersister::new(Probe::default()).with_chunk_size(2);
+        persister.quarantine(b"a".to_vec(), vec![1, 2, 3]).unwrap();
+        persister.quarantine(b"b".to_vec(), vec![4]).unwrap();
+        // replacing the document doesn't remove the quarantined chunks
+        persister.set_document(vec![5, 6, 7]).unwrap();
+
+        let mut quarantined = persister.get_quarantined().unwrap();
+        quarantined.sort();
+        assert_eq!(
+            quarantined,
+            vec![(b"a".to_vec(), vec![1, 2, 3]), (b"b".to_vec(), vec![4])]
+        );
+        assert!(persister
+            .get_metadata_keys()
+            .unwrap()
+            .iter()
+            .all(|key| key.starts_with(b"quarantine/")));
+
+        persister.remove_quarantined(b"a").unwrap();
+        assert_eq!(persister.get_quarantined().unwrap().len(), 1);
+        assert!(!persister
+            .inner()
+            .get_metadata_keys()
+            .unwrap()
+            .iter()
+            .any(|key| key.starts_with(b"quarantine_chunk/")));
+    }
+
+    #[test]
+    fn report_counts_the_manifest_as_overhead() {
+        let mut persister = ChunkedPersister::new(Probe::default()).with_chunk_size(2);
+        persister.set_document(vec![1, 2, 3]).unwrap();
+        persister.quarantine(b"a".to_vec(), vec![4, 5, 6]).unwrap();
+
+        let report = persister.report().unwrap();
+        assert_eq!(report.document_size, 3);
+        // the manifest and both chunks
+        assert_eq!(report.sizes.document, 30);
+        assert_eq!(report.overhead, 27);
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+/// When a persister makes its writes durable, such as by syncing its files to disk.
+///
+/// This bounds how much can be lost on a crash or power failure, trading it against the cost of
+/// syncing. Whatever the policy, [`crate::Persister::flush`] makes all writes so far durable.
+///
+/// ```rust
+/// # use std::time::Duration;
+/// # use automerge_persistent_core::{DurabilityPolicy, DurabilityTracker};
+/// let mut tracker = DurabilityTracker::new(DurabilityPolicy::EveryNWrites(2));
+/// assert!(!tracker.record_write());
+/// assert!(tracker.record_write());
+/// tracker.synced();
+/// assert!(!tracker.record_write());
+///
+/// let mut tracker = DurabilityTracker::new(DurabilityPolicy::Interval(Duration::ZERO));
+/// assert!(tracker.record_write());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurabilityPolicy {
+    /// Make every write durable before it returns, losing nothing.
+    EveryWrite,
+    /// Make writes durable once this many have been made since they last were, losing at most
+    /// that many.
+    EveryNWrites(u32),
+    /// Make writes durable on the first write once this long has passed since they last were.
+    ///
+    /// There is no background timer so writes followed by a quiet period stay unsynced until the
+    /// next write or flush.
+    Interval(Duration),
+    /// Only make writes durable when flushed.
+    Manual,
+}
+
+/// Tracks writes against a [`DurabilityPolicy`] to tell a persister when to sync them.
+#[derive(Debug, Clone)]
+pub struct DurabilityTracker {
+    policy: DurabilityPolicy,
+    unsynced: u32,
+    last_sync: Instant,
+}
+
+impl DurabilityTracker {
+    /// Start tracking writes, as if everything had just been synced.
+    pub fn new(policy: DurabilityPolicy) -> Self {
+        Self {
+            policy,
+            unsynced: 0,
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// The policy being followed.
+    pub const fn policy(&self) -> DurabilityPolicy {
+        self.policy
+    }
+
+    /// Record a write, returning whether the persister should now make its writes durable.
+    ///
+    /// The persister should call [`Self::synced`] once it has.
+    pub fn record_write(&mut self) -> bool {
+        self.unsynced = self.unsynced.saturating_add(1);
+        match self.policy {
+            DurabilityPolicy::EveryWrite => true,
+            DurabilityPolicy::EveryNWrites(n) => self.unsynced >= n.max(1),
+            DurabilityPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            DurabilityPolicy::Manual => false,
+        }
+    }
+
+    /// Record that all writes so far are durable, such as after a flush.
+    pub fn synced(&mut self) {
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+    }
+
+    /// The number of writes made since they were last made durable.
+    pub const fn unsynced(&self) -> u32 {
+        self.unsynced
+    }
+}
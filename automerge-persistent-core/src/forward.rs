@@ -0,0 +1,457 @@
+use std::error::Error;
+
+use automerge::{ActorId, ChangeHash};
+
+use crate::{
+    DocumentVersion, MappedDocument, Persister, QuarantinedRecord, StorageReport, StoredSizes,
+    VersionConflict, VersionedDocument,
+};
+
+/// A persister that wraps another, passing every operation it doesn't change through to it.
+///
+/// Every [`Persister`] method has a default here that calls the inner persister, so a wrapper
+/// only writes the ones it changes and new methods are forwarded without touching it. The
+/// [`Persister`] implementation is then generated with [`forward_persister!`], which calls the
+/// methods here.
+///
+/// A wrapper changing how records are stored, such as encrypting them, needs to override every
+/// method reading or writing those records, including the ones with defaults like
+/// [`Persister::get_document_mapped`] and [`Persister::quarantine`]. It can't pass
+/// [`Persister::compact_if`] through either, and should override it with [`compact_in_steps`].
+/// Other wrappers keeping state about the document or changes override it to call the inner
+/// persister's, so compaction stays atomic.
+///
+/// Both traits have the same methods, so calling them is ambiguous where both are imported. It is
+/// easiest to implement this by its path rather than importing it, as below.
+///
+/// ```rust
+/// # use automerge_persistent_core::{forward_persister, MemoryPersister, Persister};
+/// /// Counts the documents written.
+/// #[derive(Debug)]
+/// struct Counting<P> {
+///     inner: P,
+///     writes: usize,
+/// }
+///
+/// impl<P: Persister> automerge_persistent_core::Forward for Counting<P> {
+///     type Inner = P;
+///     type Error = P::Error;
+///
+///     fn inner(&self) -> &P {
+///         &self.inner
+///     }
+///
+///     fn inner_mut(&mut self) -> &mut P {
+///         &mut self.inner
+///     }
+///
+///     fn map_error(error: P::Error) -> P::Error {
+///         error
+///     }
+///
+///     fn set_document(&mut self, data: Vec<u8>) -> Result<(), P::Error> {
+///         self.writes += 1;
+///         self.inner.set_document(data)
+///     }
+/// }
+///
+/// forward_persister!(impl<P> for Counting<P> where P: Persister);
+///
+/// let mut persister = Counting { inner: MemoryPersister::default(), writes: 0 };
+/// persister.set_document(vec![1]).unwrap();
+/// // passed through
+/// persister.set_metadata(b"key".to_vec(), vec![2]).unwrap();
+/// assert_eq!(persister.writes, 1);
+/// assert_eq!(persister.get_metadata(b"key").unwrap(), Some(vec![2]));
+/// ```
+pub trait Forward {
+    /// The persister wrapped.
+    type Inner: Persister;
+
+    /// The error type that the operations can produce
+    type Error: Error + 'static;
+
+    /// Get a reference to the inner persister.
+    fn inner(&self) -> &Self::Inner;
+
+    /// Get a mutable reference to the inner persister.
+    fn inner_mut(&mut self) -> &mut Self::Inner;
+
+    /// Convert an error from the inner persister.
+    fn map_error(error: <Self::Inner as Persister>::Error) -> Self::Error;
+
+    /// See [`Persister::get_changes`].
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.inner().get_changes().map_err(Self::map_error)
+    }
+
+    /// See [`Persister::insert_changes`].
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        self.inner_mut()
+            .insert_changes(changes)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::remove_changes`].
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.inner_mut()
+            .remove_changes(changes)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::content_addressed`].
+    fn content_addressed(&self) -> bool {
+        self.inner().content_addressed()
+    }
+
+    /// See [`Persister::insert_changes_by_hash`].
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        self.inner_mut()
+            .insert_changes_by_hash(changes)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::remove_changes_by_hash`].
+    fn remove_changes_by_hash(&mut self, hashes: &[ChangeHash]) -> Result<(), Self::Error> {
+        self.inner_mut()
+            .remove_changes_by_hash(hashes)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::list_actors`].
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        self.inner().list_actors().map_err(Self::map_error)
+    }
+
+    /// See [`Persister::get_document`].
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner().get_document().map_err(Self::map_error)
+    }
+
+    /// See [`Persister::set_document`].
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.inner_mut().set_document(data).map_err(Self::map_error)
+    }
+
+    /// See [`Persister::get_document_versioned`].
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        self.inner()
+            .get_document_versioned()
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::get_document_mapped`].
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        self.inner().get_document_mapped().map_err(Self::map_error)
+    }
+
+    /// See [`Persister::set_document_if`].
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.inner_mut()
+            .set_document_if(data, expected)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::compact_if`].
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.inner_mut()
+            .compact_if(data, expected, changes)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::get_sync_state`].
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner()
+            .get_sync_state(peer_id)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::set_sync_state`].
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.inner_mut()
+            .set_sync_state(peer_id, sync_state)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::remove_sync_states`].
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.inner_mut()
+            .remove_sync_states(peer_ids)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::get_peer_ids`].
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.inner().get_peer_ids().map_err(Self::map_error)
+    }
+
+    /// See [`Persister::get_metadata`].
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner().get_metadata(key).map_err(Self::map_error)
+    }
+
+    /// See [`Persister::set_metadata`].
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.inner_mut()
+            .set_metadata(key, value)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::remove_metadata`].
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.inner_mut()
+            .remove_metadata(key)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::get_metadata_keys`].
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.inner().get_metadata_keys().map_err(Self::map_error)
+    }
+
+    /// See [`Persister::sizes`].
+    fn sizes(&self) -> StoredSizes {
+        self.inner().sizes()
+    }
+
+    /// See [`Persister::flush`].
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.inner_mut().flush().map_err(Self::map_error)
+    }
+
+    /// See [`Persister::vacuum`].
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        self.inner_mut().vacuum().map_err(Self::map_error)
+    }
+
+    /// See [`Persister::max_value_size`].
+    fn max_value_size(&self) -> Option<usize> {
+        self.inner().max_value_size()
+    }
+
+    /// See [`Persister::quarantine`].
+    fn quarantine(&mut self, key: Vec<u8>, record: Vec<u8>) -> Result<(), Self::Error> {
+        self.inner_mut()
+            .quarantine(key, record)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::get_quarantined`].
+    fn get_quarantined(&self) -> Result<Vec<QuarantinedRecord>, Self::Error> {
+        self.inner().get_quarantined().map_err(Self::map_error)
+    }
+
+    /// See [`Persister::remove_quarantined`].
+    fn remove_quarantined(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.inner_mut()
+            .remove_quarantined(key)
+            .map_err(Self::map_error)
+    }
+
+    /// See [`Persister::report`].
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        self.inner().report().map_err(Self::map_error)
+    }
+}
+
+/// Compact through the wrapper's own [`Forward::set_document_if`] and [`Forward::remove_changes`],
+/// setting the document and then removing the changes like the [`Persister::compact_if`] default.
+///
+/// This is for wrappers changing how the document or changes are stored, which can't pass the
+/// compaction through to the inner persister whole.
+///
+/// # Errors
+///
+/// Returns the error from setting the document or removing the changes.
+pub fn compact_in_steps<F>(
+    forward: &mut F,
+    data: Vec<u8>,
+    expected: Option<&DocumentVersion>,
+    changes: Vec<(&ActorId, u64)>,
+) -> Result<Result<Option<DocumentVersion>, VersionConflict>, F::Error>
+where
+    F: Forward + ?Sized,
+{
+    let version = Forward::set_document_if(forward, data, expected)?;
+    if version.is_ok() {
+        Forward::remove_changes(forward, changes)?;
+    }
+    Ok(version)
+}
+
+/// Implement [`Persister`] for a type implementing [`Forward`], calling its method for every
+/// operation.
+///
+/// Generic parameters are listed after `impl` with their bounds in the `where` clause, see
+/// [`Forward`] for an example.
+#[macro_export]
+macro_rules! forward_persister {
+    (impl<$($generic:ident),*> for $ty:ty $(where $($bound:tt)+)?) => {
+        impl<$($generic),*> $crate::Persister for $ty
+        where
+            $ty: $crate::Forward,
+            $($($bound)+)?
+        {
+            type Error = <$ty as $crate::Forward>::Error;
+
+            fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+                $crate::Forward::get_changes(self)
+            }
+
+            fn insert_changes(
+                &mut self,
+                changes: Vec<($crate::__private::ActorId, u64, Vec<u8>)>,
+            ) -> Result<(), Self::Error> {
+                $crate::Forward::insert_changes(self, changes)
+            }
+
+            fn remove_changes(
+                &mut self,
+                changes: Vec<(&$crate::__private::ActorId, u64)>,
+            ) -> Result<(), Self::Error> {
+                $crate::Forward::remove_changes(self, changes)
+            }
+
+            fn content_addressed(&self) -> bool {
+                $crate::Forward::content_addressed(self)
+            }
+
+            fn insert_changes_by_hash(
+                &mut self,
+                changes: Vec<($crate::__private::ChangeHash, Vec<u8>)>,
+            ) -> Result<(), Self::Error> {
+                $crate::Forward::insert_changes_by_hash(self, changes)
+            }
+
+            fn remove_changes_by_hash(
+                &mut self,
+                hashes: &[$crate::__private::ChangeHash],
+            ) -> Result<(), Self::Error> {
+                $crate::Forward::remove_changes_by_hash(self, hashes)
+            }
+
+            fn list_actors(&self) -> Result<Vec<$crate::__private::ActorId>, Self::Error> {
+                $crate::Forward::list_actors(self)
+            }
+
+            fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+                $crate::Forward::get_document(self)
+            }
+
+            fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+                $crate::Forward::set_document(self, data)
+            }
+
+            fn get_document_versioned(&self) -> Result<$crate::VersionedDocument, Self::Error> {
+                $crate::Forward::get_document_versioned(self)
+            }
+
+            fn get_document_mapped(&self) -> Result<$crate::MappedDocument, Self::Error> {
+                $crate::Forward::get_document_mapped(self)
+            }
+
+            fn set_document_if(
+                &mut self,
+                data: Vec<u8>,
+                expected: Option<&$crate::DocumentVersion>,
+            ) -> Result<
+                Result<Option<$crate::DocumentVersion>, $crate::VersionConflict>,
+                Self::Error,
+            > {
+                $crate::Forward::set_document_if(self, data, expected)
+            }
+
+            fn compact_if(
+                &mut self,
+                data: Vec<u8>,
+                expected: Option<&$crate::DocumentVersion>,
+                changes: Vec<(&$crate::__private::ActorId, u64)>,
+            ) -> Result<
+                Result<Option<$crate::DocumentVersion>, $crate::VersionConflict>,
+                Self::Error,
+            > {
+                $crate::Forward::compact_if(self, data, expected, changes)
+            }
+
+            fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+                $crate::Forward::get_sync_state(self, peer_id)
+            }
+
+            fn set_sync_state(
+                &mut self,
+                peer_id: Vec<u8>,
+                sync_state: Vec<u8>,
+            ) -> Result<(), Self::Error> {
+                $crate::Forward::set_sync_state(self, peer_id, sync_state)
+            }
+
+            fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+                $crate::Forward::remove_sync_states(self, peer_ids)
+            }
+
+            fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+                $crate::Forward::get_peer_ids(self)
+            }
+
+            fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+                $crate::Forward::get_metadata(self, key)
+            }
+
+            fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+                $crate::Forward::set_metadata(self, key, value)
+            }
+
+            fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+                $crate::Forward::remove_metadata(self, key)
+            }
+
+            fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+                $crate::Forward::get_metadata_keys(self)
+            }
+
+            fn sizes(&self) -> $crate::StoredSizes {
+                $crate::Forward::sizes(self)
+            }
+
+            fn flush(&mut self) -> Result<usize, Self::Error> {
+                $crate::Forward::flush(self)
+            }
+
+            fn vacuum(&mut self) -> Result<(), Self::Error> {
+                $crate::Forward::vacuum(self)
+            }
+
+            fn max_value_size(&self) -> Option<usize> {
+                $crate::Forward::max_value_size(self)
+            }
+
+            fn quarantine(&mut self, key: Vec<u8>, record: Vec<u8>) -> Result<(), Self::Error> {
+                $crate::Forward::quarantine(self, key, record)
+            }
+
+            fn get_quarantined(&self) -> Result<Vec<$crate::QuarantinedRecord>, Self::Error> {
+                $crate::Forward::get_quarantined(self)
+            }
+
+            fn remove_quarantined(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+                $crate::Forward::remove_quarantined(self, key)
+            }
+
+            fn report(&self) -> Result<$crate::StorageReport, Self::Error> {
+                $crate::Forward::report(self)
+            }
+        }
+    };
+}
@@ -0,0 +1,67 @@
+// #![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+// #![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! The [`Persister`] trait for storage backends of persistent automerge documents.
+//!
+//! This has minimal dependencies so that persister implementations don't need the rest of
+//! `automerge-persistent`, which re-exports everything here. An in-memory [`MemoryPersister`]
+//! is included for testing.
+//!
+//! ```rust
+//! # use automerge_persistent_core::{MemoryPersister, Persister};
+//! let mut persister = MemoryPersister::default();
+//! persister.set_document(vec![1, 2, 3]).unwrap();
+//! assert_eq!(persister.get_document().unwrap(), Some(vec![1, 2, 3]));
+//! ```
+//!
+//! With the `async` feature it also has the [`AsyncPersister`] trait for storage accessed through
+//! a runtime, and the [`BlockingPersisterAdapter`] for using a [`Persister`] as one.
+
+#[cfg(feature = "async")]
+mod asynchronous;
+mod checksum;
+mod chunked;
+mod durability;
+mod forward;
+mod mem;
+mod multi;
+mod persister;
+mod report;
+#[doc(hidden)]
+pub mod test_support;
+
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncPersister, BlockingPersisterAdapter};
+pub use checksum::{ChecksumError, ChecksummedPersister, RecordKey};
+pub use chunked::{ChunkedError, ChunkedPersister};
+pub use durability::{DurabilityPolicy, DurabilityTracker};
+pub use forward::{compact_in_steps, Forward};
+pub use mem::MemoryPersister;
+pub use multi::{DocumentId, DocumentPersister, MultiDocPersister};
+pub use persister::{
+    DocumentVersion, MappedDocument, Persister, QuarantinedRecord, SharedPersister,
+    VersionConflict, VersionedDocument, QUARANTINE_PREFIX,
+};
+pub use report::{ChangeKey, StorageReport};
+
+/// Bytes stored for each of the stored types.
+#[derive(Debug, Default, Clone)]
+pub struct StoredSizes {
+    /// Total bytes stored for all changes.
+    pub changes: u64,
+    /// Total bytes stored in the document.
+    pub document: u64,
+    /// Total bytes stored for all sync states.
+    pub sync_states: u64,
+    /// Total bytes stored for all metadata.
+    pub metadata: u64,
+}
+
+/// Types used by [`forward_persister!`], not part of the public API.
+#[doc(hidden)]
+pub mod __private {
+    pub use automerge::{ActorId, ChangeHash};
+}
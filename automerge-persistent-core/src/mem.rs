@@ -1,8 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use automerge::ActorId;
 
-use crate::{Persister, StoredSizes};
+use crate::{DocumentVersion, Persister, StoredSizes, VersionConflict, VersionedDocument};
 
 /// **For Testing** An in-memory persister.
 ///
@@ -13,7 +13,27 @@ pub struct MemoryPersister {
     changes: HashMap<(ActorId, u64), Vec<u8>>,
     document: Option<Vec<u8>>,
     sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    metadata: HashMap<Vec<u8>, Vec<u8>>,
     sizes: StoredSizes,
+    content_addressed: bool,
+    /// Bumped each time the document is set, used as its version.
+    document_generation: u64,
+}
+
+impl MemoryPersister {
+    /// Construct a persister that addresses changes by their hash.
+    pub fn new_content_addressed() -> Self {
+        Self {
+            content_addressed: true,
+            ..Self::default()
+        }
+    }
+
+    fn document_version(&self) -> Option<DocumentVersion> {
+        self.document
+            .as_ref()
+            .map(|_| DocumentVersion(self.document_generation.to_be_bytes().to_vec()))
+    }
 }
 
 impl Persister for MemoryPersister {
@@ -45,6 +65,24 @@ impl Persister for MemoryPersister {
         Ok(())
     }
 
+    fn content_addressed(&self) -> bool {
+        self.content_addressed
+    }
+
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        if self.content_addressed {
+            let changes = self.changes.values().cloned().collect();
+            return crate::persister::decode_actors(changes);
+        }
+        Ok(self
+            .changes
+            .keys()
+            .map(|(a, _)| a.clone())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect())
+    }
+
     /// Get the document.
     fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
         Ok(self.document.clone())
@@ -54,9 +92,27 @@ impl Persister for MemoryPersister {
     fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
         self.sizes.document = data.len() as u64;
         self.document = Some(data);
+        self.document_generation += 1;
         Ok(())
     }
 
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        Ok((self.document.clone(), self.document_version()))
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let current = self.document_version();
+        if current.as_ref() != expected {
+            return Ok(Err(VersionConflict { current }));
+        }
+        self.set_document(data)?;
+        Ok(Ok(self.document_version()))
+    }
+
     fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
         Ok(self.sync_states.get(peer_id).cloned())
     }
@@ -82,6 +138,29 @@ impl Persister for MemoryPersister {
         Ok(self.sync_states.keys().cloned().collect())
     }
 
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.get(key).cloned())
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.sizes.metadata += value.len() as u64;
+        if let Some(old) = self.metadata.insert(key, value) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        if let Some(old) = self.metadata.remove(key) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.keys().cloned().collect())
+    }
+
     fn sizes(&self) -> StoredSizes {
         self.sizes.clone()
     }
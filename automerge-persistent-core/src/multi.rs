@@ -0,0 +1,563 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use automerge::ActorId;
+
+use crate::{DocumentVersion, Persister, StoredSizes, VersionConflict, VersionedDocument};
+
+/// Identifies a document in a [`MultiDocPersister`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DocumentId(pub Vec<u8>);
+
+impl From<Vec<u8>> for DocumentId {
+    fn from(id: Vec<u8>) -> Self {
+        Self(id)
+    }
+}
+
+impl From<&[u8]> for DocumentId {
+    fn from(id: &[u8]) -> Self {
+        Self(id.to_vec())
+    }
+}
+
+impl From<&str> for DocumentId {
+    fn from(id: &str) -> Self {
+        Self(id.as_bytes().to_vec())
+    }
+}
+
+impl AsRef<[u8]> for DocumentId {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for DocumentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+/// Storage for many documents, with every operation of a [`Persister`] taking the document it
+/// applies to.
+///
+/// This makes hosting many documents in one backend first-class, rather than each backend
+/// namespacing them under its own prefixes. A [`DocumentPersister`] scopes it to a single document
+/// for use with a persistent document, sharing the store through an `Arc<Mutex<_>>`.
+///
+/// ```rust
+/// # use std::{collections::BTreeMap, sync::{Arc, Mutex}};
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{
+/// #     DocumentId, DocumentPersister, MemoryPersister, MultiDocPersister, PersistentAutomerge,
+/// # };
+/// let store = Arc::new(Mutex::new(BTreeMap::<DocumentId, MemoryPersister>::new()));
+///
+/// for name in ["a", "b"] {
+///     let persister = DocumentPersister::new(Arc::clone(&store), name.into());
+///     let mut doc = PersistentAutomerge::load(persister).unwrap();
+///     doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///         tx.put(ROOT, "name", name).unwrap();
+///         Ok(())
+///     })
+///     .unwrap();
+/// }
+///
+/// assert_eq!(
+///     store.list_documents().unwrap(),
+///     vec![DocumentId::from("a"), DocumentId::from("b")]
+/// );
+/// let mut store = store;
+/// store.delete_document(&"a".into()).unwrap();
+/// assert_eq!(store.list_documents().unwrap(), vec![DocumentId::from("b")]);
+/// ```
+pub trait MultiDocPersister {
+    /// The error type that the operations can produce
+    type Error: Error + 'static;
+
+    /// Returns the documents that have anything stored, in no particular order.
+    fn list_documents(&self) -> Result<Vec<DocumentId>, Self::Error>;
+
+    /// Removes everything stored for the document.
+    ///
+    /// If the document does not exist this should not return an error.
+    fn delete_document(&mut self, doc: &DocumentId) -> Result<(), Self::Error>;
+
+    /// See [`Persister::get_changes`].
+    fn get_changes(&self, doc: &DocumentId) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::insert_changes`].
+    fn insert_changes(
+        &mut self,
+        doc: &DocumentId,
+        changes: Vec<(ActorId, u64, Vec<u8>)>,
+    ) -> Result<(), Self::Error>;
+
+    /// See [`Persister::remove_changes`].
+    fn remove_changes(
+        &mut self,
+        doc: &DocumentId,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error>;
+
+    /// See [`Persister::get_document`].
+    fn get_document(&self, doc: &DocumentId) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::set_document`].
+    fn set_document(&mut self, doc: &DocumentId, data: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// See [`Persister::get_document_versioned`].
+    fn get_document_versioned(&self, doc: &DocumentId) -> Result<VersionedDocument, Self::Error> {
+        Ok((self.get_document(doc)?, None))
+    }
+
+    /// See [`Persister::set_document_if`].
+    fn set_document_if(
+        &mut self,
+        doc: &DocumentId,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let _ = expected;
+        self.set_document(doc, data)?;
+        Ok(Ok(None))
+    }
+
+    /// See [`Persister::get_sync_state`].
+    fn get_sync_state(
+        &self,
+        doc: &DocumentId,
+        peer_id: &[u8],
+    ) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::set_sync_state`].
+    fn set_sync_state(
+        &mut self,
+        doc: &DocumentId,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error>;
+
+    /// See [`Persister::remove_sync_states`].
+    fn remove_sync_states(
+        &mut self,
+        doc: &DocumentId,
+        peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error>;
+
+    /// See [`Persister::get_peer_ids`].
+    fn get_peer_ids(&self, doc: &DocumentId) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::get_metadata`].
+    fn get_metadata(&self, doc: &DocumentId, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::set_metadata`].
+    fn set_metadata(
+        &mut self,
+        doc: &DocumentId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), Self::Error>;
+
+    /// See [`Persister::remove_metadata`].
+    fn remove_metadata(&mut self, doc: &DocumentId, key: &[u8]) -> Result<(), Self::Error>;
+
+    /// See [`Persister::get_metadata_keys`].
+    fn get_metadata_keys(&self, doc: &DocumentId) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::sizes`].
+    fn sizes(&self, doc: &DocumentId) -> StoredSizes;
+
+    /// Flush the data of all documents out to disk, see [`Persister::flush`].
+    fn flush(&mut self) -> Result<usize, Self::Error>;
+
+    /// See [`Persister::vacuum`].
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Keeps a separate persister for each document, creating them as documents are written to.
+///
+/// Mostly useful with [`crate::MemoryPersister`] for testing.
+impl<P> MultiDocPersister for BTreeMap<DocumentId, P>
+where
+    P: Persister + Default,
+{
+    type Error = P::Error;
+
+    fn list_documents(&self) -> Result<Vec<DocumentId>, Self::Error> {
+        Ok(self.keys().cloned().collect())
+    }
+
+    fn delete_document(&mut self, doc: &DocumentId) -> Result<(), Self::Error> {
+        self.remove(doc);
+        Ok(())
+    }
+
+    fn get_changes(&self, doc: &DocumentId) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.get(doc).map_or(Ok(Vec::new()), P::get_changes)
+    }
+
+    fn insert_changes(
+        &mut self,
+        doc: &DocumentId,
+        changes: Vec<(ActorId, u64, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        self.entry(doc.clone()).or_default().insert_changes(changes)
+    }
+
+    fn remove_changes(
+        &mut self,
+        doc: &DocumentId,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        self.get_mut(doc)
+            .map_or(Ok(()), |p| p.remove_changes(changes))
+    }
+
+    fn get_document(&self, doc: &DocumentId) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(doc).map_or(Ok(None), P::get_document)
+    }
+
+    fn set_document(&mut self, doc: &DocumentId, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.entry(doc.clone()).or_default().set_document(data)
+    }
+
+    fn get_document_versioned(&self, doc: &DocumentId) -> Result<VersionedDocument, Self::Error> {
+        self.get(doc)
+            .map_or(Ok((None, None)), P::get_document_versioned)
+    }
+
+    fn set_document_if(
+        &mut self,
+        doc: &DocumentId,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.entry(doc.clone())
+            .or_default()
+            .set_document_if(data, expected)
+    }
+
+    fn get_sync_state(
+        &self,
+        doc: &DocumentId,
+        peer_id: &[u8],
+    ) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(doc)
+            .map_or(Ok(None), |p| p.get_sync_state(peer_id))
+    }
+
+    fn set_sync_state(
+        &mut self,
+        doc: &DocumentId,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        self.entry(doc.clone())
+            .or_default()
+            .set_sync_state(peer_id, sync_state)
+    }
+
+    fn remove_sync_states(
+        &mut self,
+        doc: &DocumentId,
+        peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        self.get_mut(doc)
+            .map_or(Ok(()), |p| p.remove_sync_states(peer_ids))
+    }
+
+    fn get_peer_ids(&self, doc: &DocumentId) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.get(doc).map_or(Ok(Vec::new()), P::get_peer_ids)
+    }
+
+    fn get_metadata(&self, doc: &DocumentId, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(doc).map_or(Ok(None), |p| p.get_metadata(key))
+    }
+
+    fn set_metadata(
+        &mut self,
+        doc: &DocumentId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        self.entry(doc.clone())
+            .or_default()
+            .set_metadata(key, value)
+    }
+
+    fn remove_metadata(&mut self, doc: &DocumentId, key: &[u8]) -> Result<(), Self::Error> {
+        self.get_mut(doc).map_or(Ok(()), |p| p.remove_metadata(key))
+    }
+
+    fn get_metadata_keys(&self, doc: &DocumentId) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.get(doc).map_or(Ok(Vec::new()), P::get_metadata_keys)
+    }
+
+    fn sizes(&self, doc: &DocumentId) -> StoredSizes {
+        self.get(doc).map(P::sizes).unwrap_or_default()
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        let mut flushed = 0;
+        for persister in self.values_mut() {
+            flushed += persister.flush()?;
+        }
+        Ok(flushed)
+    }
+
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        for persister in self.values_mut() {
+            persister.vacuum()?;
+        }
+        Ok(())
+    }
+}
+
+/// Shares a store between the documents in it, each through their own [`DocumentPersister`].
+impl<M> MultiDocPersister for Arc<Mutex<M>>
+where
+    M: MultiDocPersister,
+{
+    type Error = M::Error;
+
+    fn list_documents(&self) -> Result<Vec<DocumentId>, Self::Error> {
+        lock(self).list_documents()
+    }
+
+    fn delete_document(&mut self, doc: &DocumentId) -> Result<(), Self::Error> {
+        lock(self).delete_document(doc)
+    }
+
+    fn get_changes(&self, doc: &DocumentId) -> Result<Vec<Vec<u8>>, Self::Error> {
+        lock(self).get_changes(doc)
+    }
+
+    fn insert_changes(
+        &mut self,
+        doc: &DocumentId,
+        changes: Vec<(ActorId, u64, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        lock(self).insert_changes(doc, changes)
+    }
+
+    fn remove_changes(
+        &mut self,
+        doc: &DocumentId,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        lock(self).remove_changes(doc, changes)
+    }
+
+    fn get_document(&self, doc: &DocumentId) -> Result<Option<Vec<u8>>, Self::Error> {
+        lock(self).get_document(doc)
+    }
+
+    fn set_document(&mut self, doc: &DocumentId, data: Vec<u8>) -> Result<(), Self::Error> {
+        lock(self).set_document(doc, data)
+    }
+
+    fn get_document_versioned(&self, doc: &DocumentId) -> Result<VersionedDocument, Self::Error> {
+        lock(self).get_document_versioned(doc)
+    }
+
+    fn set_document_if(
+        &mut self,
+        doc: &DocumentId,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        lock(self).set_document_if(doc, data, expected)
+    }
+
+    fn get_sync_state(
+        &self,
+        doc: &DocumentId,
+        peer_id: &[u8],
+    ) -> Result<Option<Vec<u8>>, Self::Error> {
+        lock(self).get_sync_state(doc, peer_id)
+    }
+
+    fn set_sync_state(
+        &mut self,
+        doc: &DocumentId,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        lock(self).set_sync_state(doc, peer_id, sync_state)
+    }
+
+    fn remove_sync_states(
+        &mut self,
+        doc: &DocumentId,
+        peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        lock(self).remove_sync_states(doc, peer_ids)
+    }
+
+    fn get_peer_ids(&self, doc: &DocumentId) -> Result<Vec<Vec<u8>>, Self::Error> {
+        lock(self).get_peer_ids(doc)
+    }
+
+    fn get_metadata(&self, doc: &DocumentId, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        lock(self).get_metadata(doc, key)
+    }
+
+    fn set_metadata(
+        &mut self,
+        doc: &DocumentId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        lock(self).set_metadata(doc, key, value)
+    }
+
+    fn remove_metadata(&mut self, doc: &DocumentId, key: &[u8]) -> Result<(), Self::Error> {
+        lock(self).remove_metadata(doc, key)
+    }
+
+    fn get_metadata_keys(&self, doc: &DocumentId) -> Result<Vec<Vec<u8>>, Self::Error> {
+        lock(self).get_metadata_keys(doc)
+    }
+
+    fn sizes(&self, doc: &DocumentId) -> StoredSizes {
+        lock(self).sizes(doc)
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        lock(self).flush()
+    }
+
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        lock(self).vacuum()
+    }
+}
+
+fn lock<M>(store: &Mutex<M>) -> std::sync::MutexGuard<'_, M> {
+    store.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A [`Persister`] for one document of a [`MultiDocPersister`].
+///
+/// See [`MultiDocPersister`] for an example.
+#[derive(Debug)]
+pub struct DocumentPersister<M> {
+    store: M,
+    id: DocumentId,
+}
+
+impl<M> DocumentPersister<M>
+where
+    M: MultiDocPersister,
+{
+    /// Scope the store to the document with the given id.
+    pub const fn new(store: M, id: DocumentId) -> Self {
+        Self { store, id }
+    }
+
+    /// The id of the document.
+    pub const fn id(&self) -> &DocumentId {
+        &self.id
+    }
+
+    /// Get a reference to the store.
+    pub const fn store(&self) -> &M {
+        &self.store
+    }
+
+    /// Take the store back out.
+    pub fn into_inner(self) -> M {
+        self.store
+    }
+}
+
+impl<M> Persister for DocumentPersister<M>
+where
+    M: MultiDocPersister,
+{
+    type Error = M::Error;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.store.get_changes(&self.id)
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        self.store.insert_changes(&self.id, changes)
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.store.remove_changes(&self.id, changes)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.store.get_document(&self.id)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.store.set_document(&self.id, data)
+    }
+
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        self.store.get_document_versioned(&self.id)
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.store.set_document_if(&self.id, data, expected)
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.store.get_sync_state(&self.id, peer_id)
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.store.set_sync_state(&self.id, peer_id, sync_state)
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.store.remove_sync_states(&self.id, peer_ids)
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.store.get_peer_ids(&self.id)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.store.get_metadata(&self.id, key)
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.store.set_metadata(&self.id, key, value)
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.store.remove_metadata(&self.id, key)
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.store.get_metadata_keys(&self.id)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.store.sizes(&self.id)
+    }
+
+    /// Flushes every document in the store.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.store.flush()
+    }
+
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        self.store.vacuum()
+    }
+}
@@ -0,0 +1,854 @@
+use std::{collections::HashSet, error::Error, fmt, sync::Arc};
+
+use automerge::{ActorId, Change, ChangeHash};
+
+use crate::{report, StorageReport, StoredSizes};
+
+/// An opaque token for a version of the stored document, such as an etag or a generation number.
+///
+/// Tokens are only meaningful to the persister that returned them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DocumentVersion(pub Vec<u8>);
+
+/// The stored document was not at the version expected by [`Persister::set_document_if`].
+///
+/// Another writer has set the document since it was last read, so it should be read again
+/// before retrying.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionConflict {
+    /// The version of the document that is stored.
+    pub current: Option<DocumentVersion>,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the stored document is not at the expected version")
+    }
+}
+
+impl Error for VersionConflict {}
+
+/// Metadata key prefix under which [`Persister::quarantine`] keeps records by default, followed
+/// by their key.
+pub const QUARANTINE_PREFIX: &[u8] = b"quarantine/";
+
+/// A record kept by [`Persister::quarantine`], with its key.
+pub type QuarantinedRecord = (Vec<u8>, Vec<u8>);
+
+fn quarantine_key(key: &[u8]) -> Vec<u8> {
+    let mut metadata_key = QUARANTINE_PREFIX.to_vec();
+    metadata_key.extend_from_slice(key);
+    metadata_key
+}
+
+/// A document as returned by [`Persister::get_document_versioned`], along with its version.
+pub type VersionedDocument = (Option<Vec<u8>>, Option<DocumentVersion>);
+
+/// A document as returned by [`Persister::get_document_mapped`], its bytes possibly borrowed
+/// from storage, along with its version.
+pub type MappedDocument = (Option<Box<dyn AsRef<[u8]>>>, Option<DocumentVersion>);
+
+/// A Persister persists both changes and documents to durable storage.
+///
+/// In the event of a power loss changes should still be around for loading after. It is up to the
+/// implementation to decide on trade-offs regarding how often to fsync for example.
+///
+/// Changes are identified by a pair of `actor_id` and `sequence_number`. This uniquely identifies a
+/// change and so is suitable for use as a key in the implementation.
+///
+/// Documents are saved automerge Backends so are more compact than the raw changes they represent.
+pub trait Persister {
+    /// The error type that the operations can produce
+    type Error: Error + 'static;
+
+    /// Returns all of the changes that have been persisted through this persister.
+    /// Ordering is not specified as the automerge Backend should handle that.
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Inserts the given change at the unique address specified by the `actor_id` and `sequence_number`.
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error>;
+
+    /// Removes the change at the unique address specified by the `actor_id` and `sequence_number`.
+    ///
+    /// If the change does not exist this should not return an error.
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error>;
+
+    /// Whether changes should be addressed by their hash rather than their `actor_id` and
+    /// `sequence_number`.
+    ///
+    /// When this returns true [`Self::insert_changes_by_hash`] and
+    /// [`Self::remove_changes_by_hash`] are used in place of the `actor_id` based methods. This
+    /// maps better onto content-addressed stores and deduplicates changes shared between forks.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::new_content_addressed()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// assert_eq!(doc.persister().get_changes().unwrap().len(), 1);
+    ///
+    /// doc.compact(&[]).unwrap();
+    /// assert!(doc.persister().get_changes().unwrap().is_empty());
+    /// ```
+    fn content_addressed(&self) -> bool {
+        false
+    }
+
+    /// Inserts the given changes at the address given by their hash.
+    ///
+    /// By default each change is stored through [`Self::insert_changes`] with the hash in place
+    /// of the `actor_id` and a `sequence_number` of zero.
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        self.insert_changes(
+            changes
+                .into_iter()
+                .map(|(h, c)| (hash_address(&h), 0, c))
+                .collect(),
+        )
+    }
+
+    /// Removes the changes with the given hashes.
+    ///
+    /// If a change does not exist this should not return an error.
+    fn remove_changes_by_hash(&mut self, hashes: &[ChangeHash]) -> Result<(), Self::Error> {
+        let addresses = hashes.iter().map(hash_address).collect::<Vec<_>>();
+        self.remove_changes(addresses.iter().map(|a| (a, 0)).collect())
+    }
+
+    /// Returns the actors that have changes stored, in no particular order.
+    ///
+    /// By default this decodes every stored change, implementations that key changes by
+    /// `actor_id` should derive it from their keys instead. Changes that can't be decoded are
+    /// skipped.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// assert_eq!(doc.persister().list_actors().unwrap(), vec![doc.actor_id().clone()]);
+    /// ```
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        decode_actors(self.get_changes()?)
+    }
+
+    /// Returns the document, if one has been persisted previously.
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Sets the document to the given data.
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Returns the document along with its version, read together.
+    ///
+    /// The version is `None` when there is no document, or when the persister doesn't track
+    /// versions, which is the default.
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        Ok((self.get_document()?, None))
+    }
+
+    /// Returns the document along with its version like [`Self::get_document_versioned`], but
+    /// with its bytes borrowed from storage where the persister can, such as from a memory map,
+    /// to avoid copying large documents on to the heap when loading.
+    ///
+    /// The default copies them out of [`Self::get_document_versioned`].
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        let (document, version) = self.get_document_versioned()?;
+        Ok((
+            document.map(|d| Box::new(d) as Box<dyn AsRef<[u8]>>),
+            version,
+        ))
+    }
+
+    /// Sets the document only if the stored one is still at the `expected` version, with `None`
+    /// expecting there to be no document, returning the new version.
+    ///
+    /// This lets multiple writers detect when another has replaced the document since they read
+    /// it, rather than losing that update. Persisters that don't track versions ignore `expected`
+    /// and set the document unconditionally, which is the default.
+    ///
+    /// ```rust
+    /// # use automerge_persistent::{MemoryPersister, Persister};
+    /// let mut persister = MemoryPersister::default();
+    /// let (_, version) = persister.get_document_versioned().unwrap();
+    /// let new_version = persister.set_document_if(vec![1], version.as_ref()).unwrap().unwrap();
+    ///
+    /// // a stale version is rejected
+    /// assert!(persister.set_document_if(vec![2], version.as_ref()).unwrap().is_err());
+    /// assert!(persister.set_document_if(vec![2], new_version.as_ref()).unwrap().is_ok());
+    /// ```
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let _ = expected;
+        self.set_document(data)?;
+        Ok(Ok(None))
+    }
+
+    /// Sets the document like [`Self::set_document_if`] and, if it was set, removes the changes it
+    /// includes, as compacting does.
+    ///
+    /// By default the document is set and then the changes are removed, so a crash in between
+    /// leaves changes stored that are already in the document. That is harmless as the next
+    /// compaction removes them, but persisters with transactions should do both in one so that
+    /// storage is never seen part way through a compaction.
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let version = self.set_document_if(data, expected)?;
+        if version.is_ok() {
+            self.remove_changes(changes)?;
+        }
+        Ok(version)
+    }
+
+    /// Returns the sync state for the given peer if one exists.
+    ///
+    /// A peer id corresponds to an instance of a backend and may be serving multiple frontends so
+    /// we cannot have it work on `ActorIds`.
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Sets the sync state for the given peer.
+    ///
+    /// A peer id corresponds to an instance of a backend and may be serving multiple frontends so
+    /// we cannot have it work on `ActorIds`.
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Removes the sync states associated with the given `peer_ids`.
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error>;
+
+    /// Returns the list of peer ids with stored `SyncStates`.
+    ///
+    /// This is intended for use by users to see what `peer_ids` are taking space so that they can be
+    /// removed during a compaction.
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Returns the metadata stored under the given key, if any.
+    ///
+    /// Metadata is small state that lives alongside the document, such as the local actor id.
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Sets the metadata stored under the given key.
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Removes the metadata stored under the given key.
+    ///
+    /// If the key does not exist this should not return an error.
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error>;
+
+    /// Returns the list of keys with stored metadata.
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Returns the sizes components being stored consume.
+    ///
+    /// This can be used as an indicator of when to compact the storage.
+    fn sizes(&self) -> StoredSizes;
+
+    /// Flush the data out to disk.
+    fn flush(&mut self) -> Result<usize, Self::Error>;
+
+    /// Reclaim the space used by removed data.
+    ///
+    /// Removing changes during a compaction doesn't return disk space on many backends, this is
+    /// for them to do so, such as by merging segments. It may be slow so is intended to be called
+    /// occasionally from maintenance jobs. By default this does nothing.
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// The largest value this persister can store in one item, for backends that limit it such
+    /// as `DynamoDB` or `localStorage`.
+    ///
+    /// Documents larger than this can be split into chunks by wrapping the persister in a
+    /// [`crate::ChunkedPersister`]. By default there is no limit.
+    fn max_value_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Keep a record that could not be decoded apart from the rest of the data under the key, so
+    /// that it is neither used nor lost and can be looked at later.
+    ///
+    /// By default it is kept in the metadata under [`QUARANTINE_PREFIX`] followed by the key,
+    /// implementations with somewhere better to keep it should do so.
+    fn quarantine(&mut self, key: Vec<u8>, record: Vec<u8>) -> Result<(), Self::Error> {
+        self.set_metadata(quarantine_key(&key), record)
+    }
+
+    /// Returns the keys and records kept by [`Self::quarantine`].
+    fn get_quarantined(&self) -> Result<Vec<QuarantinedRecord>, Self::Error> {
+        let mut quarantined = Vec::new();
+        for key in self.get_metadata_keys()? {
+            if let Some(stripped) = key.strip_prefix(QUARANTINE_PREFIX) {
+                if let Some(record) = self.get_metadata(&key)? {
+                    quarantined.push((stripped.to_vec(), record));
+                }
+            }
+        }
+        Ok(quarantined)
+    }
+
+    /// Removes the record kept under the key by [`Self::quarantine`], once it has been dealt with.
+    ///
+    /// If the key does not exist this should not return an error.
+    fn remove_quarantined(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.remove_metadata(&quarantine_key(key))
+    }
+
+    /// Summarise what is stored, for tooling and monitoring that shouldn't need each backend's
+    /// own tools.
+    ///
+    /// By default this reads back everything stored, implementations that can report it more
+    /// cheaply or know their real overhead should do so.
+    ///
+    /// ```rust
+    /// # use automerge_persistent_core::{MemoryPersister, Persister};
+    /// let mut persister = MemoryPersister::default();
+    /// persister.set_document(vec![1, 2, 3]).unwrap();
+    ///
+    /// let report = persister.report().unwrap();
+    /// assert_eq!(report.change_records, 0);
+    /// assert_eq!(report.document_size, 3);
+    /// assert!(report.oldest_change.is_none());
+    /// println!("{report}");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when reading from it.
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        report::read(self)
+    }
+}
+
+/// A [`Persister`] whose operations only need shared access, for storage that is already
+/// internally synchronised.
+///
+/// Every `SharedPersister` is also a [`Persister`], and an [`Arc`] of one is too, so a single
+/// backend can be shared between documents and threads without any external locking.
+///
+/// ```rust
+/// # use std::sync::{Arc, Mutex};
+/// # use automerge::{transaction::Transactable, ActorId, ROOT};
+/// # use automerge_persistent::{MemoryPersister, Persister, PersistentAutomerge, SharedPersister, StoredSizes};
+/// #[derive(Debug, Default)]
+/// struct LockedMemory(Mutex<MemoryPersister>);
+///
+/// impl SharedPersister for LockedMemory {
+///     type Error = std::convert::Infallible;
+///
+///     fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+///         self.0.lock().unwrap().get_changes()
+///     }
+///     fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+///         self.0.lock().unwrap().insert_changes(changes)
+///     }
+///     fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+///         self.0.lock().unwrap().remove_changes(changes)
+///     }
+///     fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+///         self.0.lock().unwrap().get_document()
+///     }
+///     fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+///         self.0.lock().unwrap().set_document(data)
+///     }
+///     fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+///         self.0.lock().unwrap().get_sync_state(peer_id)
+///     }
+///     fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+///         self.0.lock().unwrap().set_sync_state(peer_id, sync_state)
+///     }
+///     fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+///         self.0.lock().unwrap().remove_sync_states(peer_ids)
+///     }
+///     fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+///         self.0.lock().unwrap().get_peer_ids()
+///     }
+///     fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+///         self.0.lock().unwrap().get_metadata(key)
+///     }
+///     fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+///         self.0.lock().unwrap().set_metadata(key, value)
+///     }
+///     fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+///         self.0.lock().unwrap().remove_metadata(key)
+///     }
+///     fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+///         self.0.lock().unwrap().get_metadata_keys()
+///     }
+///     fn sizes(&self) -> StoredSizes {
+///         self.0.lock().unwrap().sizes()
+///     }
+///     fn flush(&self) -> Result<usize, Self::Error> {
+///         self.0.lock().unwrap().flush()
+///     }
+/// }
+///
+/// let storage = Arc::new(LockedMemory::default());
+/// let mut doc = PersistentAutomerge::load(Arc::clone(&storage)).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+/// assert!(SharedPersister::get_metadata_keys(&*storage).unwrap().len() > 0);
+/// ```
+pub trait SharedPersister {
+    /// The error type that the operations can produce
+    type Error: Error + 'static;
+
+    /// See [`Persister::get_changes`].
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::insert_changes`].
+    fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error>;
+
+    /// See [`Persister::remove_changes`].
+    fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error>;
+
+    /// See [`Persister::content_addressed`].
+    fn content_addressed(&self) -> bool {
+        false
+    }
+
+    /// See [`Persister::insert_changes_by_hash`].
+    fn insert_changes_by_hash(
+        &self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        self.insert_changes(
+            changes
+                .into_iter()
+                .map(|(h, c)| (hash_address(&h), 0, c))
+                .collect(),
+        )
+    }
+
+    /// See [`Persister::remove_changes_by_hash`].
+    fn remove_changes_by_hash(&self, hashes: &[ChangeHash]) -> Result<(), Self::Error> {
+        let addresses = hashes.iter().map(hash_address).collect::<Vec<_>>();
+        self.remove_changes(addresses.iter().map(|a| (a, 0)).collect())
+    }
+
+    /// See [`Persister::list_actors`].
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        decode_actors(self.get_changes()?)
+    }
+
+    /// See [`Persister::get_document`].
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::set_document`].
+    fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// See [`Persister::get_document_versioned`].
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        Ok((self.get_document()?, None))
+    }
+
+    /// Returns the document along with its version like [`Self::get_document_versioned`], but
+    /// with its bytes borrowed from storage where the persister can, such as from a memory map,
+    /// to avoid copying large documents on to the heap when loading.
+    ///
+    /// The default copies them out of [`Self::get_document_versioned`].
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        let (document, version) = self.get_document_versioned()?;
+        Ok((
+            document.map(|d| Box::new(d) as Box<dyn AsRef<[u8]>>),
+            version,
+        ))
+    }
+
+    /// See [`Persister::set_document_if`].
+    fn set_document_if(
+        &self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let _ = expected;
+        self.set_document(data)?;
+        Ok(Ok(None))
+    }
+
+    /// See [`Persister::compact_if`].
+    fn compact_if(
+        &self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let version = self.set_document_if(data, expected)?;
+        if version.is_ok() {
+            self.remove_changes(changes)?;
+        }
+        Ok(version)
+    }
+
+    /// See [`Persister::get_sync_state`].
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::set_sync_state`].
+    fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// See [`Persister::remove_sync_states`].
+    fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error>;
+
+    /// See [`Persister::get_peer_ids`].
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::get_metadata`].
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::set_metadata`].
+    fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// See [`Persister::remove_metadata`].
+    fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error>;
+
+    /// See [`Persister::get_metadata_keys`].
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// See [`Persister::sizes`].
+    fn sizes(&self) -> StoredSizes;
+
+    /// See [`Persister::flush`].
+    fn flush(&self) -> Result<usize, Self::Error>;
+
+    /// See [`Persister::vacuum`].
+    fn vacuum(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// See [`Persister::max_value_size`].
+    fn max_value_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// See [`Persister::quarantine`].
+    fn quarantine(&self, key: Vec<u8>, record: Vec<u8>) -> Result<(), Self::Error> {
+        self.set_metadata(quarantine_key(&key), record)
+    }
+
+    /// See [`Persister::get_quarantined`].
+    fn get_quarantined(&self) -> Result<Vec<QuarantinedRecord>, Self::Error> {
+        let mut quarantined = Vec::new();
+        for key in self.get_metadata_keys()? {
+            if let Some(stripped) = key.strip_prefix(QUARANTINE_PREFIX) {
+                if let Some(record) = self.get_metadata(&key)? {
+                    quarantined.push((stripped.to_vec(), record));
+                }
+            }
+        }
+        Ok(quarantined)
+    }
+
+    /// See [`Persister::remove_quarantined`].
+    fn remove_quarantined(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.remove_metadata(&quarantine_key(key))
+    }
+
+    /// See [`Persister::report`].
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        report::read(&report::Shared(self))
+    }
+}
+
+impl<T> Persister for T
+where
+    T: SharedPersister,
+{
+    type Error = T::Error;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        SharedPersister::get_changes(self)
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        SharedPersister::insert_changes(self, changes)
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        SharedPersister::remove_changes(self, changes)
+    }
+
+    fn content_addressed(&self) -> bool {
+        SharedPersister::content_addressed(self)
+    }
+
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        SharedPersister::insert_changes_by_hash(self, changes)
+    }
+
+    fn remove_changes_by_hash(&mut self, hashes: &[ChangeHash]) -> Result<(), Self::Error> {
+        SharedPersister::remove_changes_by_hash(self, hashes)
+    }
+
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        SharedPersister::list_actors(self)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        SharedPersister::get_document(self)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        SharedPersister::set_document(self, data)
+    }
+
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        SharedPersister::get_document_versioned(self)
+    }
+
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        SharedPersister::get_document_mapped(self)
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        SharedPersister::set_document_if(self, data, expected)
+    }
+
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        SharedPersister::compact_if(self, data, expected, changes)
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        SharedPersister::get_sync_state(self, peer_id)
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        SharedPersister::set_sync_state(self, peer_id, sync_state)
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        SharedPersister::remove_sync_states(self, peer_ids)
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        SharedPersister::get_peer_ids(self)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        SharedPersister::get_metadata(self, key)
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        SharedPersister::set_metadata(self, key, value)
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        SharedPersister::remove_metadata(self, key)
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        SharedPersister::get_metadata_keys(self)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        SharedPersister::sizes(self)
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        SharedPersister::flush(self)
+    }
+
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        SharedPersister::vacuum(self)
+    }
+
+    fn max_value_size(&self) -> Option<usize> {
+        SharedPersister::max_value_size(self)
+    }
+
+    fn quarantine(&mut self, key: Vec<u8>, record: Vec<u8>) -> Result<(), Self::Error> {
+        SharedPersister::quarantine(self, key, record)
+    }
+
+    fn get_quarantined(&self) -> Result<Vec<QuarantinedRecord>, Self::Error> {
+        SharedPersister::get_quarantined(self)
+    }
+
+    fn remove_quarantined(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        SharedPersister::remove_quarantined(self, key)
+    }
+
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        SharedPersister::report(self)
+    }
+}
+
+impl<T> SharedPersister for Arc<T>
+where
+    T: SharedPersister + ?Sized,
+{
+    type Error = T::Error;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        SharedPersister::get_changes(&**self)
+    }
+
+    fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        SharedPersister::insert_changes(&**self, changes)
+    }
+
+    fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        SharedPersister::remove_changes(&**self, changes)
+    }
+
+    fn content_addressed(&self) -> bool {
+        SharedPersister::content_addressed(&**self)
+    }
+
+    fn insert_changes_by_hash(
+        &self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        SharedPersister::insert_changes_by_hash(&**self, changes)
+    }
+
+    fn remove_changes_by_hash(&self, hashes: &[ChangeHash]) -> Result<(), Self::Error> {
+        SharedPersister::remove_changes_by_hash(&**self, hashes)
+    }
+
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        SharedPersister::list_actors(&**self)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        SharedPersister::get_document(&**self)
+    }
+
+    fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+        SharedPersister::set_document(&**self, data)
+    }
+
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        SharedPersister::get_document_versioned(&**self)
+    }
+
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        SharedPersister::get_document_mapped(&**self)
+    }
+
+    fn set_document_if(
+        &self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        SharedPersister::set_document_if(&**self, data, expected)
+    }
+
+    fn compact_if(
+        &self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        SharedPersister::compact_if(&**self, data, expected, changes)
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        SharedPersister::get_sync_state(&**self, peer_id)
+    }
+
+    fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        SharedPersister::set_sync_state(&**self, peer_id, sync_state)
+    }
+
+    fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        SharedPersister::remove_sync_states(&**self, peer_ids)
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        SharedPersister::get_peer_ids(&**self)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        SharedPersister::get_metadata(&**self, key)
+    }
+
+    fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        SharedPersister::set_metadata(&**self, key, value)
+    }
+
+    fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+        SharedPersister::remove_metadata(&**self, key)
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        SharedPersister::get_metadata_keys(&**self)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        SharedPersister::sizes(&**self)
+    }
+
+    fn flush(&self) -> Result<usize, Self::Error> {
+        SharedPersister::flush(&**self)
+    }
+
+    fn vacuum(&self) -> Result<(), Self::Error> {
+        SharedPersister::vacuum(&**self)
+    }
+
+    fn max_value_size(&self) -> Option<usize> {
+        SharedPersister::max_value_size(&**self)
+    }
+
+    fn quarantine(&self, key: Vec<u8>, record: Vec<u8>) -> Result<(), Self::Error> {
+        SharedPersister::quarantine(&**self, key, record)
+    }
+
+    fn get_quarantined(&self) -> Result<Vec<QuarantinedRecord>, Self::Error> {
+        SharedPersister::get_quarantined(&**self)
+    }
+
+    fn remove_quarantined(&self, key: &[u8]) -> Result<(), Self::Error> {
+        SharedPersister::remove_quarantined(&**self, key)
+    }
+
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        SharedPersister::report(&**self)
+    }
+}
+
+/// The distinct actors of the changes that can be decoded.
+pub fn decode_actors<E>(changes: Vec<Vec<u8>>) -> Result<Vec<ActorId>, E> {
+    Ok(changes
+        .into_iter()
+        .filter_map(|bytes| Change::from_bytes(bytes).ok())
+        .map(|change| change.actor_id().clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect())
+}
+
+/// The address a change is stored at by the default content-addressed methods.
+fn hash_address(hash: &ChangeHash) -> ActorId {
+    ActorId::from(&hash.0[..])
+}
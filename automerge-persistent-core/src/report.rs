@@ -0,0 +1,226 @@
+use std::fmt;
+
+use automerge::{ActorId, Change};
+
+use crate::{Persister, SharedPersister, StoredSizes};
+
+/// A summary of what a persister stores, from [`crate::Persister::report`], for tooling and
+/// monitoring to render.
+#[derive(Debug, Default, Clone)]
+pub struct StorageReport {
+    /// The number of individually stored change records.
+    pub change_records: usize,
+    /// The bytes of the stored document, `0` if there isn't one.
+    pub document_size: u64,
+    /// The stored change committed earliest, of those that could be decoded.
+    pub oldest_change: Option<ChangeKey>,
+    /// The stored change committed latest, of those that could be decoded.
+    pub newest_change: Option<ChangeKey>,
+    /// The sizes reported by [`crate::Persister::sizes`].
+    pub sizes: StoredSizes,
+    /// An estimate of the bytes used beyond the stored values themselves, such as by keys.
+    ///
+    /// This is the difference between [`Self::sizes`] and the values read back, so it is only as
+    /// accurate as the sizes the persister reports.
+    pub overhead: u64,
+}
+
+/// The key of a stored change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeKey {
+    /// The actor that made the change.
+    pub actor: ActorId,
+    /// The sequence number of the change for its actor.
+    pub seq: u64,
+    /// When the change was committed, in milliseconds since the unix epoch.
+    pub time: i64,
+}
+
+impl StorageReport {
+    /// Build the report by reading back everything the persister stores, as
+    /// [`Persister::report`] does by default.
+    ///
+    /// Wrappers that transform what they store can use this to report the records as they read
+    /// them, with what they add to each counted in [`Self::overhead`].
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when reading from it.
+    pub fn read<P>(persister: &P) -> Result<Self, P::Error>
+    where
+        P: Persister + ?Sized,
+    {
+        read(persister)
+    }
+}
+
+/// The reads a report is built from, shared by both kinds of persister so that
+/// [`Persister::report`] and [`SharedPersister::report`] build it the same way.
+pub trait Stored {
+    type Error;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn sizes(&self) -> StoredSizes;
+}
+
+impl<P> Stored for P
+where
+    P: Persister + ?Sized,
+{
+    type Error = P::Error;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Persister::get_changes(self)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Persister::get_document(self)
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Persister::get_peer_ids(self)
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Persister::get_sync_state(self, peer_id)
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Persister::get_metadata_keys(self)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Persister::get_metadata(self, key)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        Persister::sizes(self)
+    }
+}
+
+/// A [`SharedPersister`] to read a report from, including unsized ones that don't get the
+/// blanket [`Persister`] impl.
+pub struct Shared<'a, S: ?Sized>(pub &'a S);
+
+impl<S> Stored for Shared<'_, S>
+where
+    S: SharedPersister + ?Sized,
+{
+    type Error = S::Error;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.0.get_changes()
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.0.get_document()
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.0.get_peer_ids()
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.0.get_sync_state(peer_id)
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.0.get_metadata_keys()
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.0.get_metadata(key)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.0.sizes()
+    }
+}
+
+/// Build the report by reading back everything stored.
+pub fn read<S>(stored: &S) -> Result<StorageReport, S::Error>
+where
+    S: Stored + ?Sized,
+{
+    let changes = stored.get_changes()?;
+    let document = stored.get_document()?;
+    let mut values = 0;
+    for peer_id in stored.get_peer_ids()? {
+        values += stored
+            .get_sync_state(&peer_id)?
+            .map_or(0, |s| s.len() as u64);
+    }
+    for key in stored.get_metadata_keys()? {
+        values += stored.get_metadata(&key)?.map_or(0, |v| v.len() as u64);
+    }
+    Ok(build(&changes, document.as_deref(), values, stored.sizes()))
+}
+
+impl fmt::Display for ChangeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} at {}ms", self.actor, self.seq, self.time)
+    }
+}
+
+impl fmt::Display for StorageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "change records: {}", self.change_records)?;
+        writeln!(f, "document size: {} bytes", self.document_size)?;
+        if let Some(oldest) = &self.oldest_change {
+            writeln!(f, "oldest change: {oldest}")?;
+        }
+        if let Some(newest) = &self.newest_change {
+            writeln!(f, "newest change: {newest}")?;
+        }
+        writeln!(
+            f,
+            "stored: {} bytes of changes, {} of document, {} of sync states, {} of metadata",
+            self.sizes.changes, self.sizes.document, self.sizes.sync_states, self.sizes.metadata
+        )?;
+        write!(f, "estimated overhead: {} bytes", self.overhead)
+    }
+}
+
+/// Build the report from what was read out of a persister, with `values` the total length of the
+/// sync states and metadata values.
+fn build(
+    changes: &[Vec<u8>],
+    document: Option<&[u8]>,
+    values: u64,
+    sizes: StoredSizes,
+) -> StorageReport {
+    let mut oldest: Option<ChangeKey> = None;
+    let mut newest: Option<ChangeKey> = None;
+    for change in changes
+        .iter()
+        .filter_map(|bytes| Change::from_bytes(bytes.clone()).ok())
+    {
+        let key = ChangeKey {
+            actor: change.actor_id().clone(),
+            seq: change.seq,
+            time: change.time,
+        };
+        if oldest.as_ref().is_none_or(|oldest| key.time < oldest.time) {
+            oldest = Some(key.clone());
+        }
+        if newest.as_ref().is_none_or(|newest| key.time >= newest.time) {
+            newest = Some(key);
+        }
+    }
+    let document_size = document.map_or(0, |d| d.len() as u64);
+    let read = changes.iter().map(|c| c.len() as u64).sum::<u64>() + document_size + values;
+    let total = sizes.changes + sizes.document + sizes.sync_states + sizes.metadata;
+    StorageReport {
+        change_records: changes.len(),
+        document_size,
+        oldest_change: oldest,
+        newest_change: newest,
+        sizes,
+        overhead: total.saturating_sub(read),
+    }
+}
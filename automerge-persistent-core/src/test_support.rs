@@ -0,0 +1,97 @@
+//! Persisters for the tests of this crate and `automerge-persistent`, not part of the public
+//! API.
+
+use std::cell::Cell;
+
+use automerge::ActorId;
+
+use crate::{
+    forward_persister, DocumentVersion, Forward, MappedDocument, MemoryPersister, Persister,
+    VersionConflict,
+};
+
+/// A [`MemoryPersister`] counting the reads of [`Persister::get_document_mapped`] and the calls
+/// to [`Persister::compact_if`], to check that wrappers pass them through.
+#[derive(Debug, Default)]
+pub struct Probe {
+    inner: MemoryPersister,
+    mapped_reads: Cell<usize>,
+    compactions: usize,
+}
+
+impl Probe {
+    /// The number of times the document has been read mapped.
+    pub const fn mapped_reads(&self) -> usize {
+        self.mapped_reads.get()
+    }
+
+    /// The number of times compaction has been called.
+    pub const fn compactions(&self) -> usize {
+        self.compactions
+    }
+}
+
+impl Forward for Probe {
+    type Inner = MemoryPersister;
+    type Error = std::convert::Infallible;
+
+    fn inner(&self) -> &MemoryPersister {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut MemoryPersister {
+        &mut self.inner
+    }
+
+    fn map_error(error: std::convert::Infallible) -> Self::Error {
+        error
+    }
+
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        self.mapped_reads.set(self.mapped_reads.get() + 1);
+        self.inner.get_document_mapped()
+    }
+
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.compactions += 1;
+        self.inner.compact_if(data, expected, changes)
+    }
+}
+
+forward_persister!(impl<> for Probe);
+
+/// Check what every layer over a persister should do.
+///
+/// The layer `wrap` builds over a [`Probe`] should read the document mapped from it, pass
+/// compaction through to it whole and round trip quarantined records. `probe` gets the probe back out of the layer. The layer is returned for checks specific to it.
+pub fn check_layer<L>(wrap: impl FnOnce(Probe) -> L, probe: impl Fn(&L) -> &Probe) -> L
+where
+    L: Persister,
+{
+    let mut persister = wrap(Probe::default());
+    persister.set_document(vec![1, 2, 3]).unwrap();
+    let (document, version) = persister.get_document_mapped().unwrap();
+    assert_eq!((*document.unwrap()).as_ref(), &[1, 2, 3]);
+    assert_eq!(probe(&persister).mapped_reads(), 1);
+
+    persister
+        .compact_if(vec![4], version.as_ref(), Vec::new())
+        .unwrap()
+        .unwrap();
+    assert_eq!(probe(&persister).compactions(), 1);
+    assert_eq!(persister.get_document().unwrap(), Some(vec![4]));
+
+    persister.quarantine(b"a".to_vec(), vec![5, 6]).unwrap();
+    assert_eq!(
+        persister.get_quarantined().unwrap(),
+        vec![(b"a".to_vec(), vec![5, 6])]
+    );
+    persister.remove_quarantined(b"a").unwrap();
+    assert!(persister.get_quarantined().unwrap().is_empty());
+    persister
+}
@@ -0,0 +1,356 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [fjall](https://github.com/fjall-rs/fjall).
+//!
+//! # Single persister
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_fjall::FjallPersister;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let dir = tempfile::tempdir()?;
+//! let keyspace = fjall::Config::new(dir.path()).open()?;
+//!
+//! let persister = FjallPersister::new(keyspace, "")?;
+//! let doc = PersistentAutomerge::load(persister)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Multiple persisters sharing the same keyspace
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_fjall::FjallPersister;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let dir = tempfile::tempdir()?;
+//! let keyspace = fjall::Config::new(dir.path()).open()?;
+//!
+//! let persister1 = FjallPersister::new(keyspace.clone(), "1")?;
+//! let doc1 = PersistentAutomerge::load(persister1)?;
+//!
+//! let persister2 = FjallPersister::new(keyspace, "2")?;
+//! let doc2 = PersistentAutomerge::load(persister2)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use automerge::ActorId;
+use automerge_persistent_core::{SharedPersister, StoredSizes};
+use fjall::{Keyspace, PartitionCreateOptions, PartitionHandle, PersistMode};
+
+/// The name of the partition changes are stored in.
+pub const CHANGES_PARTITION: &str = "changes";
+/// The name of the partition documents are stored in.
+pub const DOCUMENTS_PARTITION: &str = "documents";
+/// The name of the partition sync states are stored in.
+pub const SYNC_STATES_PARTITION: &str = "sync_states";
+/// The name of the partition metadata is stored in.
+pub const METADATA_PARTITION: &str = "metadata";
+
+/// The persister that stores changes and documents in fjall partitions.
+///
+/// Changes, documents, sync states and metadata are kept in separate partitions of the keyspace.
+///
+/// An optional prefix can be used in case multiple persisters may share the same keyspace.
+///
+/// Fjall partitions are internally synchronised so this is a [`SharedPersister`] and can be
+/// shared behind an [`std::sync::Arc`] without extra locking.
+pub struct FjallPersister {
+    keyspace: Keyspace,
+    changes_partition: PartitionHandle,
+    document_partition: PartitionHandle,
+    sync_states_partition: PartitionHandle,
+    metadata_partition: PartitionHandle,
+    prefix: String,
+    sizes: AtomicSizes,
+}
+
+impl std::fmt::Debug for FjallPersister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FjallPersister")
+            .field("prefix", &self.prefix)
+            .field("sizes", &self.sizes)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Default)]
+struct AtomicSizes {
+    changes: AtomicU64,
+    document: AtomicU64,
+    sync_states: AtomicU64,
+    metadata: AtomicU64,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum FjallPersisterError {
+    /// Internal errors from fjall.
+    #[error(transparent)]
+    FjallError(#[from] fjall::Error),
+}
+
+impl FjallPersister {
+    /// Construct a new persister, opening the partitions in the keyspace if they do not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the partitions could not be opened or their existing contents could
+    /// not be read to calculate the stored sizes.
+    pub fn new<S>(keyspace: Keyspace, prefix: S) -> Result<Self, FjallPersisterError>
+    where
+        S: Into<String>,
+    {
+        let open = |name| keyspace.open_partition(name, PartitionCreateOptions::default());
+        let changes_partition = open(CHANGES_PARTITION)?;
+        let document_partition = open(DOCUMENTS_PARTITION)?;
+        let sync_states_partition = open(SYNC_STATES_PARTITION)?;
+        let metadata_partition = open(METADATA_PARTITION)?;
+
+        let s = Self {
+            keyspace,
+            changes_partition,
+            document_partition,
+            sync_states_partition,
+            metadata_partition,
+            prefix: prefix.into(),
+            sizes: AtomicSizes::default(),
+        };
+        let prefix_size = |partition: &PartitionHandle| {
+            partition
+                .prefix(&s.prefix)
+                .map(|kv| kv.map(|(_, v)| v.len() as u64))
+                .sum::<Result<u64, _>>()
+        };
+        let changes = prefix_size(&s.changes_partition)?;
+        let document = s.get_document()?.unwrap_or_default().len() as u64;
+        let sync_states = prefix_size(&s.sync_states_partition)?;
+        let metadata = prefix_size(&s.metadata_partition)?;
+        s.sizes.changes.store(changes, Ordering::Relaxed);
+        s.sizes.document.store(document, Ordering::Relaxed);
+        s.sizes.sync_states.store(sync_states, Ordering::Relaxed);
+        s.sizes.metadata.store(metadata, Ordering::Relaxed);
+        Ok(s)
+    }
+
+    /// Make a key from the prefix, `actor_id` and `sequence_number`.
+    ///
+    /// Converts the `actor_id` to bytes and appends the `sequence_number` in big endian form.
+    fn make_key(&self, actor_id: &ActorId, seq: u64) -> Vec<u8> {
+        let mut key = self.prefix.as_bytes().to_vec();
+        key.extend(actor_id.to_bytes());
+        key.extend(&seq.to_be_bytes());
+        key
+    }
+
+    /// Make a key just from the prefix.
+    /// Since each document only has one thing to store in this partition we can just use the
+    /// prefix.
+    fn make_document_key(&self) -> Vec<u8> {
+        self.prefix.as_bytes().to_vec()
+    }
+
+    fn make_prefixed_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut prefixed_key = self.prefix.as_bytes().to_vec();
+        prefixed_key.extend(key);
+        prefixed_key
+    }
+
+    fn prefixed_keys(
+        &self,
+        partition: &PartitionHandle,
+    ) -> Result<Vec<Vec<u8>>, FjallPersisterError> {
+        partition
+            .prefix(&self.prefix)
+            .map(|kv| {
+                kv.map(|(k, _)| k[self.prefix.len()..].to_vec())
+                    .map_err(FjallPersisterError::FjallError)
+            })
+            .collect()
+    }
+
+    /// Insert a value, keeping the stored size up to date.
+    fn insert(
+        partition: &PartitionHandle,
+        size: &AtomicU64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), FjallPersisterError> {
+        let old = partition.size_of(&key)?;
+        size.fetch_add(value.len() as u64, Ordering::Relaxed);
+        partition.insert(key, value)?;
+        if let Some(old) = old {
+            size.fetch_sub(u64::from(old), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Remove a value, keeping the stored size up to date.
+    fn remove(
+        partition: &PartitionHandle,
+        size: &AtomicU64,
+        key: Vec<u8>,
+    ) -> Result<(), FjallPersisterError> {
+        if let Some(old) = partition.size_of(&key)? {
+            partition.remove(key)?;
+            size.fetch_sub(u64::from(old), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+impl SharedPersister for FjallPersister {
+    type Error = FjallPersisterError;
+
+    /// Get all of the current changes.
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.changes_partition
+            .prefix(&self.prefix)
+            .map(|kv| kv.map(|(_, v)| v.to_vec()).map_err(Self::Error::FjallError))
+            .collect()
+    }
+
+    /// Insert all of the given changes atomically in a single batch.
+    fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let mut batch = self.keyspace.batch();
+        let mut added = 0;
+        let mut removed = 0;
+        for (a, s, c) in changes {
+            let key = self.make_key(&a, s);
+            if let Some(old) = self.changes_partition.size_of(&key)? {
+                removed += u64::from(old);
+            }
+            added += c.len() as u64;
+            batch.insert(&self.changes_partition, key, c);
+        }
+        batch.commit()?;
+        self.sizes.changes.fetch_add(added, Ordering::Relaxed);
+        self.sizes.changes.fetch_sub(removed, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Remove all of the given changes atomically in a single batch.
+    fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let mut batch = self.keyspace.batch();
+        let mut removed = 0;
+        for (a, s) in changes {
+            let key = self.make_key(a, s);
+            if let Some(old) = self.changes_partition.size_of(&key)? {
+                removed += u64::from(old);
+                batch.remove(&self.changes_partition, key);
+            }
+        }
+        batch.commit()?;
+        self.sizes.changes.fetch_sub(removed, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Retrieve the document from the partition.
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .document_partition
+            .get(self.make_document_key())?
+            .map(|v| v.to_vec()))
+    }
+
+    /// Set the document in the partition.
+    fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.sizes
+            .document
+            .store(data.len() as u64, Ordering::Relaxed);
+        self.document_partition
+            .insert(self.make_document_key(), data)?;
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .sync_states_partition
+            .get(self.make_prefixed_key(peer_id))?
+            .map(|v| v.to_vec()))
+    }
+
+    fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        Self::insert(
+            &self.sync_states_partition,
+            &self.sizes.sync_states,
+            self.make_prefixed_key(&peer_id),
+            sync_state,
+        )
+    }
+
+    fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for id in peer_ids {
+            Self::remove(
+                &self.sync_states_partition,
+                &self.sizes.sync_states,
+                self.make_prefixed_key(id),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.prefixed_keys(&self.sync_states_partition)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .metadata_partition
+            .get(self.make_prefixed_key(key))?
+            .map(|v| v.to_vec()))
+    }
+
+    fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        Self::insert(
+            &self.metadata_partition,
+            &self.sizes.metadata,
+            self.make_prefixed_key(&key),
+            value,
+        )
+    }
+
+    fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+        Self::remove(
+            &self.metadata_partition,
+            &self.sizes.metadata,
+            self.make_prefixed_key(key),
+        )
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.prefixed_keys(&self.metadata_partition)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        StoredSizes {
+            changes: self.sizes.changes.load(Ordering::Relaxed),
+            document: self.sizes.document.load(Ordering::Relaxed),
+            sync_states: self.sizes.sync_states.load(Ordering::Relaxed),
+            metadata: self.sizes.metadata.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sync the journal to disk.
+    ///
+    /// The journal is shared by the whole keyspace so this does not know how many bytes were
+    /// flushed and always returns 0.
+    fn flush(&self) -> Result<usize, Self::Error> {
+        self.keyspace.persist(PersistMode::SyncAll)?;
+        Ok(0)
+    }
+
+    /// Compact the partitions that compactions remove from, dropping their deleted entries.
+    fn vacuum(&self) -> Result<(), Self::Error> {
+        self.changes_partition.major_compact()?;
+        self.sync_states_partition.major_compact()?;
+        Ok(())
+    }
+}
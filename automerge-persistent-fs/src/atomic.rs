@@ -0,0 +1,274 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    fs::{self, File},
+    io::{self, Read, Write},
+    ops::Range,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::single_file::fnv1a;
+
+/// Appended to the name of a record while it is being written.
+pub(crate) const TEMP_SUFFIX: &str = ".tmp";
+/// Starts every record written with the integrity marker.
+const MARKER: &[u8; 4] = b"AMPR";
+/// The marker followed by the length and checksum of the data.
+const HEADER_LEN: usize = 20;
+
+/// The steps of writing a record that a crash could stop it after.
+///
+/// A record is written by:
+///
+/// 1. writing an integrity marker with the length and checksum of the data, followed by the data,
+///    to a temporary file next to it,
+/// 2. syncing the temporary file,
+/// 3. renaming it over the record,
+/// 4. and, once the batch of records has been written, syncing the directory so the rename is
+///    durable.
+///
+/// So the record is always either the old or the new one, and the integrity marker catches a
+/// record torn by anything else, such as a file system that doesn't order the rename after the
+/// data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CrashPoint {
+    /// Part way through writing the temporary file.
+    TornTemp,
+    /// After writing the temporary file but before syncing it.
+    BeforeSync,
+    /// After syncing the temporary file but before renaming it over the record.
+    BeforeRename,
+    /// After renaming the temporary file but before syncing the directory.
+    BeforeDirSync,
+    /// After the record was renamed in to place but with its tail lost, as a file system not
+    /// honouring the ordering could leave it.
+    TornRecord,
+}
+
+impl CrashPoint {
+    /// Every crash point, in the order they are reached.
+    pub const ALL: [Self; 5] = [
+        Self::TornTemp,
+        Self::BeforeSync,
+        Self::BeforeRename,
+        Self::BeforeDirSync,
+        Self::TornRecord,
+    ];
+}
+
+/// What was read back from a record after a crash while writing a new value over an old one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashOutcome {
+    /// The old value.
+    Old,
+    /// The new value.
+    New,
+    /// Neither, and reading it failed with [`crate::FsPersisterError::Corrupt`].
+    Detected,
+    /// Something other than the old or new value was read without error.
+    Torn,
+}
+
+/// The outcome of crashing at each [`CrashPoint`], from
+/// [`FsPersister::durability_report`](crate::FsPersister::durability_report).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DurabilityReport {
+    /// What the record read back as after crashing at each point.
+    pub outcomes: Vec<(CrashPoint, CrashOutcome)>,
+}
+
+impl DurabilityReport {
+    /// Whether no crash left a torn record that could be read without error.
+    #[must_use]
+    pub fn is_torn_write_safe(&self) -> bool {
+        self.outcomes
+            .iter()
+            .all(|(_, outcome)| *outcome != CrashOutcome::Torn)
+    }
+}
+
+/// How change records are made durable as they are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// Write through the page cache and then sync each record, the default.
+    #[default]
+    Synced,
+    /// Open records with `O_DSYNC` so each write returns once the data is on storage, padding
+    /// records with zeroes to a multiple of `alignment` bytes so writes cover whole blocks.
+    ///
+    /// This spreads the cost of durability over the writes rather than in spikes as the page
+    /// cache is flushed, which suits storage with a battery backed or otherwise fast write
+    /// cache.
+    Dsync {
+        /// The size of the storage's blocks, usually 4096.
+        alignment: usize,
+    },
+}
+
+impl WriteMode {
+    fn open_options(self) -> fs::OpenOptions {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        if let Self::Dsync { .. } = self {
+            options.custom_flags(libc::O_DSYNC);
+        }
+        options
+    }
+
+    /// Pad the encoded record to the alignment.
+    fn pad(self, bytes: &mut Vec<u8>) {
+        if let Self::Dsync { alignment } = self {
+            let alignment = alignment.max(1);
+            bytes.resize(bytes.len().div_ceil(alignment) * alignment, 0);
+        }
+    }
+}
+
+/// The data with its integrity header.
+fn encode(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + data.len());
+    bytes.extend(MARKER);
+    bytes.extend((data.len() as u64).to_be_bytes());
+    bytes.extend(fnv1a(data).to_be_bytes());
+    bytes.extend(data);
+    bytes
+}
+
+/// The length and checksum of the data from the integrity header, if the bytes start with one.
+fn decode_header(bytes: &[u8]) -> Option<(u64, u64)> {
+    if bytes.len() < HEADER_LEN || !bytes.starts_with(MARKER) {
+        return None;
+    }
+    Some((
+        u64::from_be_bytes(bytes[4..12].try_into().unwrap()),
+        u64::from_be_bytes(bytes[12..20].try_into().unwrap()),
+    ))
+}
+
+/// Where the data is in the record, or `None` if it fails its integrity check.
+///
+/// Records from before the integrity marker are read whole, and any padding after the data is
+/// ignored.
+pub(crate) fn data_range(bytes: &[u8]) -> Option<Range<usize>> {
+    let (len, checksum) = match decode_header(bytes) {
+        Some(header) => header,
+        None => return Some(0..bytes.len()),
+    };
+    let end = HEADER_LEN.checked_add(usize::try_from(len).ok()?)?;
+    let data = bytes.get(HEADER_LEN..end)?;
+    (fnv1a(data) == checksum).then_some(HEADER_LEN..end)
+}
+
+/// The data of the record, or `None` if it fails its integrity check.
+pub(crate) fn decode(mut bytes: Vec<u8>) -> Option<Vec<u8>> {
+    let range = data_range(&bytes)?;
+    bytes.truncate(range.end);
+    Some(if range.start == 0 {
+        bytes
+    } else {
+        bytes.split_off(range.start)
+    })
+}
+
+/// The length of the data in the record.
+pub(crate) fn data_len(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    (&mut file)
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+    match decode_header(&header) {
+        Some((len, _)) => Ok(len),
+        None => Ok(file.metadata()?.len()),
+    }
+}
+
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(TEMP_SUFFIX);
+    temp.into()
+}
+
+pub(crate) fn is_temp(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(TEMP_SUFFIX)
+}
+
+/// Write the record atomically, leaving the directory to be synced by the caller.
+pub(crate) fn write(path: &Path, data: &[u8], mode: WriteMode) -> io::Result<()> {
+    write_until(path, data, mode, None)
+}
+
+/// Write the record, stopping as if the process had crashed at the given point.
+pub(crate) fn write_until(
+    path: &Path,
+    data: &[u8],
+    mode: WriteMode,
+    crash: Option<CrashPoint>,
+) -> io::Result<()> {
+    let mut bytes = encode(data);
+    mode.pad(&mut bytes);
+    let temp = temp_path(path);
+    let mut file = mode.open_options().open(&temp)?;
+    if crash == Some(CrashPoint::TornTemp) {
+        return file.write_all(&bytes[..bytes.len() / 2]);
+    }
+    file.write_all(&bytes)?;
+    if crash == Some(CrashPoint::BeforeSync) {
+        return Ok(());
+    }
+    if mode == WriteMode::Synced {
+        file.sync_data()?;
+    }
+    if crash == Some(CrashPoint::BeforeRename) {
+        return Ok(());
+    }
+    fs::rename(&temp, path)?;
+    if crash == Some(CrashPoint::TornRecord) {
+        let file = fs::OpenOptions::new().write(true).open(path)?;
+        return file.set_len(bytes.len() as u64 - 1);
+    }
+    Ok(())
+}
+
+/// Sync the directory, making the renames and removals in it durable.
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Remove the temporary files left in the directory by writes that were interrupted.
+pub(crate) fn remove_temps(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_temp(&path) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn write_async(
+    path: PathBuf,
+    data: Vec<u8>,
+    mode: WriteMode,
+) -> io::Result<usize> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut bytes = encode(&data);
+    mode.pad(&mut bytes);
+    let temp = temp_path(&path);
+    let mut file = tokio::fs::OpenOptions::from(mode.open_options())
+        .open(&temp)
+        .await?;
+    file.write_all(&bytes).await?;
+    if mode == WriteMode::Synced {
+        file.sync_data().await?;
+    }
+    tokio::fs::rename(&temp, &path).await?;
+    Ok(data.len())
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn sync_dir_async(dir: PathBuf) -> io::Result<()> {
+    tokio::fs::File::open(dir).await?.sync_all().await
+}
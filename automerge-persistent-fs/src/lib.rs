@@ -1,23 +1,41 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
     os::unix::prelude::OsStrExt,
     path::{Path, PathBuf},
 };
 
 use automerge::ActorId;
-use automerge_persistent::{Persister, StoredSizes};
+use automerge_persistent_core::{
+    DurabilityPolicy, DurabilityTracker, MappedDocument, Persister, StoredSizes,
+};
 #[cfg(feature = "async")]
-use futures::{Future, FutureExt, TryStreamExt};
+use futures::{Future, TryStreamExt};
 use hex::FromHexError;
 
+mod atomic;
+mod lock;
+mod mmap;
+mod single_file;
+
+pub use atomic::{CrashOutcome, CrashPoint, DurabilityReport, WriteMode};
+use lock::{LockError, LockFile, LockMode};
+use mmap::{MappedRecord, Mmap};
+pub use single_file::{SingleFileError, SingleFilePersister};
+
 #[derive(Debug)]
 pub struct FsPersister {
     changes_path: PathBuf,
     doc_path: PathBuf,
     sync_states_path: PathBuf,
+    metadata_path: PathBuf,
     cache: FsPersisterCache,
     sizes: StoredSizes,
+    lock: Option<LockFile>,
+    durability: DurabilityTracker,
+    /// Directories records have been removed from since they were last synced.
+    unsynced_dirs: HashSet<PathBuf>,
+    map_document: bool,
 }
 
 #[derive(Debug)]
@@ -25,13 +43,19 @@ pub struct FsPersisterCache {
     changes: HashMap<(ActorId, u64), Vec<u8>>,
     document: Option<Vec<u8>>,
     sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    metadata: HashMap<Vec<u8>, Vec<u8>>,
+    change_write_mode: WriteMode,
 }
 
 impl FsPersisterCache {
     fn flush_changes(&mut self, changes_path: PathBuf) -> Result<usize, std::io::Error> {
         let mut flushed = 0;
         for ((a, s), c) in self.changes.drain() {
-            fs::write(make_changes_path(&changes_path, &a, s), &c)?;
+            atomic::write(
+                &make_changes_path(&changes_path, &a, s),
+                &c,
+                self.change_write_mode,
+            )?;
             flushed += c.len();
         }
         Ok(flushed)
@@ -40,7 +64,7 @@ impl FsPersisterCache {
     fn flush_document(&mut self, doc_path: PathBuf) -> Result<usize, std::io::Error> {
         let mut flushed = 0;
         if let Some(data) = self.document.take() {
-            fs::write(&doc_path, &data)?;
+            atomic::write(&doc_path, &data, WriteMode::Synced)?;
             flushed = data.len();
         }
         Ok(flushed)
@@ -49,12 +73,29 @@ impl FsPersisterCache {
     fn flush_sync_states(&mut self, sync_states_path: PathBuf) -> Result<usize, std::io::Error> {
         let mut flushed = 0;
         for (peer_id, sync_state) in self.sync_states.drain() {
-            fs::write(make_peer_path(&sync_states_path, &peer_id), &sync_state)?;
+            atomic::write(
+                &make_peer_path(&sync_states_path, &peer_id),
+                &sync_state,
+                WriteMode::Synced,
+            )?;
             flushed += sync_state.len();
         }
         Ok(flushed)
     }
 
+    fn flush_metadata(&mut self, metadata_path: PathBuf) -> Result<usize, std::io::Error> {
+        let mut flushed = 0;
+        for (key, value) in self.metadata.drain() {
+            atomic::write(
+                &make_metadata_path(&metadata_path, &key),
+                &value,
+                WriteMode::Synced,
+            )?;
+            flushed += value.len();
+        }
+        Ok(flushed)
+    }
+
     #[cfg(feature = "async")]
     async fn flush_changes_async(
         &mut self,
@@ -62,10 +103,11 @@ impl FsPersisterCache {
     ) -> Result<usize, std::io::Error> {
         let futs = futures::stream::FuturesUnordered::new();
         for ((a, s), c) in self.changes.drain() {
-            let len = c.len();
-            futs.push(
-                tokio::fs::write(make_changes_path(&changes_path, &a, s), c).map(move |_| Ok(len)),
-            );
+            futs.push(atomic::write_async(
+                make_changes_path(&changes_path, &a, s),
+                c,
+                self.change_write_mode,
+            ));
         }
         let res: Result<Vec<usize>, std::io::Error> = futs.try_collect().await;
         Ok(res?.iter().sum())
@@ -75,8 +117,7 @@ impl FsPersisterCache {
     async fn flush_document_async(&mut self, doc_path: PathBuf) -> Result<usize, std::io::Error> {
         let mut flushed = 0;
         if let Some(data) = self.document.take() {
-            tokio::fs::write(&doc_path, &data).await?;
-            flushed = data.len();
+            flushed = atomic::write_async(doc_path, data, WriteMode::Synced).await?;
         }
         Ok(flushed)
     }
@@ -88,11 +129,28 @@ impl FsPersisterCache {
     ) -> Result<usize, std::io::Error> {
         let futs = futures::stream::FuturesUnordered::new();
         for (peer_id, sync_state) in self.sync_states.drain() {
-            let len = sync_state.len();
-            futs.push(
-                tokio::fs::write(make_peer_path(&sync_states_path, &peer_id), sync_state)
-                    .map(move |_| Ok(len)),
-            );
+            futs.push(atomic::write_async(
+                make_peer_path(&sync_states_path, &peer_id),
+                sync_state,
+                WriteMode::Synced,
+            ));
+        }
+        let res: Result<Vec<usize>, std::io::Error> = futs.try_collect().await;
+        Ok(res?.iter().sum())
+    }
+
+    #[cfg(feature = "async")]
+    async fn flush_metadata_async(
+        &mut self,
+        metadata_path: PathBuf,
+    ) -> Result<usize, std::io::Error> {
+        let futs = futures::stream::FuturesUnordered::new();
+        for (key, value) in self.metadata.drain() {
+            futs.push(atomic::write_async(
+                make_metadata_path(&metadata_path, &key),
+                value,
+                WriteMode::Synced,
+            ));
         }
         let res: Result<Vec<usize>, std::io::Error> = futs.try_collect().await;
         Ok(res?.iter().sum())
@@ -104,11 +162,13 @@ impl FsPersisterCache {
         doc_path: PathBuf,
         changes_path: PathBuf,
         sync_states_path: PathBuf,
+        metadata_path: PathBuf,
     ) -> Result<usize, std::io::Error> {
         let mut flushed = 0;
         flushed += self.flush_document_async(doc_path).await?;
         flushed += self.flush_changes_async(changes_path).await?;
         flushed += self.flush_sync_states_async(sync_states_path).await?;
+        flushed += self.flush_metadata_async(metadata_path).await?;
         Ok(flushed)
     }
 
@@ -117,19 +177,47 @@ impl FsPersisterCache {
         doc_path: PathBuf,
         changes_path: PathBuf,
         sync_states_path: PathBuf,
+        metadata_path: PathBuf,
     ) -> Result<usize, std::io::Error> {
         let mut flushed = 0;
         flushed += self.flush_document(doc_path)?;
         flushed += self.flush_changes(changes_path)?;
         flushed += self.flush_sync_states(sync_states_path)?;
+        flushed += self.flush_metadata(metadata_path)?;
         Ok(flushed)
     }
 
+    /// The directories flushing will write records in to.
+    fn dirs(
+        &self,
+        doc_path: &Path,
+        changes_path: &Path,
+        sync_states_path: &Path,
+        metadata_path: &Path,
+    ) -> HashSet<PathBuf> {
+        let mut dirs = HashSet::new();
+        if let (Some(_), Some(root)) = (&self.document, doc_path.parent()) {
+            dirs.insert(root.to_owned());
+        }
+        for (written, dir) in [
+            (self.changes.is_empty(), changes_path),
+            (self.sync_states.is_empty(), sync_states_path),
+            (self.metadata.is_empty(), metadata_path),
+        ] {
+            if !written {
+                dirs.insert(dir.to_owned());
+            }
+        }
+        dirs
+    }
+
     fn drain_clone(&mut self) -> Self {
         Self {
             changes: self.changes.drain().collect(),
             document: self.document.take(),
             sync_states: self.sync_states.drain().collect(),
+            metadata: self.metadata.drain().collect(),
+            change_write_mode: self.change_write_mode,
         }
     }
 }
@@ -141,11 +229,32 @@ pub enum FsPersisterError {
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Hex(#[from] FromHexError),
+    /// Another instance holds a conflicting lock on the directory, along with its process id if
+    /// it holds it exclusively.
+    #[error("the directory is locked by another instance (pid {0:?})")]
+    Locked(Option<u32>),
+    /// The persister was opened for reading only.
+    #[error("the persister is read only")]
+    ReadOnly,
+    /// The record at the path failed its integrity check.
+    #[error("the record at {0:?} is corrupt")]
+    Corrupt(PathBuf),
+}
+
+impl From<LockError> for FsPersisterError {
+    fn from(error: LockError) -> Self {
+        match error {
+            LockError::Io(e) => Self::Io(e),
+            LockError::Locked(pid) => Self::Locked(pid),
+        }
+    }
 }
 
 const CHANGES_DIR: &str = "changes";
 const DOC_FILE: &str = "doc";
 const SYNC_DIR: &str = "sync";
+const METADATA_DIR: &str = "metadata";
+const LOCK_FILE: &str = "lock";
 
 impl FsPersister {
     pub fn new<R: AsRef<Path>, P: AsRef<Path>>(
@@ -167,16 +276,28 @@ impl FsPersister {
             fs::create_dir(&sync_states_path)?;
         }
 
+        let metadata_path = root_path.join(METADATA_DIR);
+        if fs::metadata(&metadata_path).is_err() {
+            fs::create_dir(&metadata_path)?;
+        }
+
         let mut s = Self {
             changes_path,
             doc_path,
             sync_states_path,
+            metadata_path,
             cache: FsPersisterCache {
                 changes: HashMap::new(),
                 document: None,
                 sync_states: HashMap::new(),
+                metadata: HashMap::new(),
+                change_write_mode: WriteMode::default(),
             },
             sizes: StoredSizes::default(),
+            lock: None,
+            durability: DurabilityTracker::new(DurabilityPolicy::Manual),
+            unsynced_dirs: HashSet::new(),
+            map_document: false,
         };
 
         s.sizes.changes = s.get_changes()?.iter().map(|v| v.len() as u64).sum();
@@ -191,20 +312,264 @@ impl FsPersister {
             .collect::<Result<Vec<u64>, _>>()?
             .iter()
             .sum();
+        s.sizes.metadata = s
+            .get_metadata_keys()?
+            .iter()
+            .map(|key| {
+                s.get_metadata(key)
+                    .map(|o| o.unwrap_or_default().len() as u64)
+            })
+            .collect::<Result<Vec<u64>, _>>()?
+            .iter()
+            .sum();
 
         Ok(s)
     }
 
+    /// Like [`Self::new`] but first taking an exclusive advisory lock on the directory, so that
+    /// no other instance can open it while this one is alive.
+    ///
+    /// The lock is released when the persister is dropped, or the process exits.
+    ///
+    /// ```rust
+    /// # use automerge_persistent_fs::{FsPersister, FsPersisterError};
+    /// let root = std::env::temp_dir().join(format!("fs-lock-{}", std::process::id()));
+    /// let persister = FsPersister::open_exclusive(&root, "doc").unwrap();
+    ///
+    /// // another instance can't open it, and learns who has it
+    /// assert!(matches!(
+    ///     FsPersister::open_exclusive(&root, "doc"),
+    ///     Err(FsPersisterError::Locked(Some(pid))) if pid == std::process::id()
+    /// ));
+    /// assert!(FsPersister::open_shared_read(&root, "doc").is_err());
+    ///
+    /// drop(persister);
+    /// assert!(FsPersister::open_shared_read(&root, "doc").is_ok());
+    /// # std::fs::remove_dir_all(&root).unwrap();
+    /// ```
+    pub fn open_exclusive<R: AsRef<Path>, P: AsRef<Path>>(
+        root: R,
+        prefix: P,
+    ) -> Result<Self, FsPersisterError> {
+        Self::open_locked(root, prefix, LockMode::Exclusive)
+    }
+
+    /// Like [`Self::new`] but first taking a shared advisory lock on the directory, so that other
+    /// readers can open it but no exclusive instance can.
+    ///
+    /// Any writes through the persister fail with [`FsPersisterError::ReadOnly`].
+    pub fn open_shared_read<R: AsRef<Path>, P: AsRef<Path>>(
+        root: R,
+        prefix: P,
+    ) -> Result<Self, FsPersisterError> {
+        Self::open_locked(root, prefix, LockMode::SharedRead)
+    }
+
+    fn open_locked<R: AsRef<Path>, P: AsRef<Path>>(
+        root: R,
+        prefix: P,
+        mode: LockMode,
+    ) -> Result<Self, FsPersisterError> {
+        let root_path = root.as_ref().join(&prefix);
+        fs::create_dir_all(&root_path)?;
+        // taken before reading anything so a writer can't be part way through a flush
+        let lock = LockFile::acquire(&root_path.join(LOCK_FILE), mode)?;
+        let mut s = Self::new(root, prefix)?;
+        if mode == LockMode::Exclusive {
+            // nothing else can be part way through writing them
+            s.remove_temps()?;
+        }
+        s.lock = Some(lock);
+        Ok(s)
+    }
+
+    /// Remove the temporary files left by writes that were interrupted.
+    fn remove_temps(&self) -> std::io::Result<()> {
+        for dir in self.dirs() {
+            atomic::remove_temps(dir)?;
+        }
+        Ok(())
+    }
+
+    /// The directories records are written in to.
+    fn dirs(&self) -> [&Path; 4] {
+        [
+            self.doc_path.parent().unwrap_or(&self.doc_path),
+            &self.changes_path,
+            &self.sync_states_path,
+            &self.metadata_path,
+        ]
+    }
+
+    /// Set when writes are flushed to disk and synced, by default only when flushed.
+    ///
+    /// Writes are otherwise kept in memory, so this bounds how many are lost if the process
+    /// crashes.
+    ///
+    /// ```rust
+    /// # use automerge_persistent_core::{DurabilityPolicy, Persister};
+    /// # use automerge_persistent_fs::FsPersister;
+    /// let root = std::env::temp_dir().join(format!("fs-durability-{}", std::process::id()));
+    /// let mut persister =
+    ///     FsPersister::new(&root, "doc").unwrap().with_durability(DurabilityPolicy::EveryWrite);
+    /// persister.set_document(vec![1, 2, 3]).unwrap();
+    ///
+    /// // already on disk without flushing
+    /// let reopened = FsPersister::new(&root, "doc").unwrap();
+    /// assert_eq!(reopened.get_document().unwrap(), Some(vec![1, 2, 3]));
+    /// # std::fs::remove_dir_all(&root).unwrap();
+    /// ```
+    pub fn with_durability(mut self, policy: DurabilityPolicy) -> Self {
+        self.durability = DurabilityTracker::new(policy);
+        self
+    }
+
+    /// Set when writes are flushed to disk and synced, see [`Self::with_durability`].
+    pub fn set_durability(&mut self, policy: DurabilityPolicy) -> &mut Self {
+        self.durability = DurabilityTracker::new(policy);
+        self
+    }
+
+    /// Crash part way through writing a record at each [`CrashPoint`] in turn and report what it
+    /// reads back as, to check the write discipline against torn writes.
+    ///
+    /// Each crash is simulated by stopping the write of a new document over an old one at that
+    /// point and reopening the persister, in a fresh directory under the root each time. This can
+    /// only show what a crashed process leaves behind, not what the operating system might lose
+    /// of unsynced writes on power loss.
+    ///
+    /// ```rust
+    /// # use automerge_persistent_fs::{CrashOutcome, CrashPoint, FsPersister};
+    /// let root = std::env::temp_dir().join(format!("fs-durability-report-{}", std::process::id()));
+    /// let report = FsPersister::durability_report(&root).unwrap();
+    /// assert!(report.is_torn_write_safe());
+    /// assert!(report.outcomes.contains(&(CrashPoint::TornTemp, CrashOutcome::Old)));
+    /// assert!(report.outcomes.contains(&(CrashPoint::BeforeDirSync, CrashOutcome::New)));
+    /// assert!(report.outcomes.contains(&(CrashPoint::TornRecord, CrashOutcome::Detected)));
+    /// # std::fs::remove_dir_all(&root).unwrap();
+    /// ```
+    pub fn durability_report<R: AsRef<Path>>(
+        root: R,
+    ) -> Result<DurabilityReport, FsPersisterError> {
+        let old = b"old document".to_vec();
+        let new = b"new document".to_vec();
+        let mut outcomes = Vec::new();
+        for point in CrashPoint::ALL {
+            let prefix = format!("crash-{:?}", point);
+            let mut persister = Self::new(&root, &prefix)?;
+            persister.set_document(old.clone())?;
+            persister.flush()?;
+            atomic::write_until(&persister.doc_path, &new, WriteMode::Synced, Some(point))?;
+            drop(persister);
+
+            // opening reads the document too, to size it
+            let reopened = Self::open_exclusive(&root, &prefix);
+            let outcome = match reopened.and_then(|p| p.get_document()) {
+                Ok(Some(doc)) if doc == old => CrashOutcome::Old,
+                Ok(Some(doc)) if doc == new => CrashOutcome::New,
+                Ok(_) => CrashOutcome::Torn,
+                Err(FsPersisterError::Corrupt(_)) => CrashOutcome::Detected,
+                Err(e) => return Err(e),
+            };
+            outcomes.push((point, outcome));
+        }
+        Ok(DurabilityReport { outcomes })
+    }
+
+    /// Memory map the saved document when loading rather than reading it on to the heap, so
+    /// large documents are paged in by the backend as it loads them.
+    ///
+    /// The map stays valid while the document is replaced as records are only ever renamed over,
+    /// never written in place.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// # use automerge_persistent_fs::FsPersister;
+    /// let root = std::env::temp_dir().join(format!("fs-mapped-{}", std::process::id()));
+    /// let mut doc = PersistentAutomerge::load(FsPersister::new(&root, "doc").unwrap()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// doc.compact(&[]).unwrap();
+    /// doc.close().unwrap();
+    ///
+    /// let persister = FsPersister::new(&root, "doc").unwrap().with_mapped_document();
+    /// let doc = PersistentAutomerge::load(persister).unwrap();
+    /// assert_eq!(doc.document().length(ROOT), 1);
+    /// # std::fs::remove_dir_all(&root).unwrap();
+    /// ```
+    pub fn with_mapped_document(mut self) -> Self {
+        self.map_document = true;
+        self
+    }
+
+    /// Write the files of changes with the given mode, such as with `O_DSYNC` and aligned writes
+    /// for predictable latency on storage with a fast write cache.
+    ///
+    /// The document, sync states and metadata are written less often and are always synced.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// # use automerge_persistent_fs::{FsPersister, WriteMode};
+    /// let root = std::env::temp_dir().join(format!("fs-dsync-{}", std::process::id()));
+    /// let persister = FsPersister::new(&root, "doc")
+    ///     .unwrap()
+    ///     .with_change_write_mode(WriteMode::Dsync { alignment: 4096 });
+    /// let mut doc = PersistentAutomerge::load(persister).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// doc.close().unwrap();
+    ///
+    /// let doc = PersistentAutomerge::load(FsPersister::new(&root, "doc").unwrap()).unwrap();
+    /// assert_eq!(doc.document().length(ROOT), 1);
+    /// # std::fs::remove_dir_all(&root).unwrap();
+    /// ```
+    pub fn with_change_write_mode(mut self, mode: WriteMode) -> Self {
+        self.cache.change_write_mode = mode;
+        self
+    }
+
+    /// Flush the writes if the durability policy says they are due.
+    fn wrote(&mut self) -> Result<(), FsPersisterError> {
+        if self.durability.record_write() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), FsPersisterError> {
+        if self.lock.as_ref().is_some_and(LockFile::read_only) {
+            return Err(FsPersisterError::ReadOnly);
+        }
+        Ok(())
+    }
+
     #[cfg(feature = "async")]
     pub fn flush_cache_async(&mut self) -> impl Future<Output = Result<usize, std::io::Error>> {
         let doc_path = self.doc_path.clone();
         let changes_path = self.changes_path.clone();
         let sync_states_path = self.sync_states_path.clone();
+        let metadata_path = self.metadata_path.clone();
+        let mut dirs = self
+            .cache
+            .dirs(&doc_path, &changes_path, &sync_states_path, &metadata_path);
+        dirs.extend(self.unsynced_dirs.drain());
         let mut cache = self.cache.drain_clone();
         async move {
-            cache
-                .flush_async(doc_path, changes_path, sync_states_path)
-                .await
+            let flushed = cache
+                .flush_async(doc_path, changes_path, sync_states_path, metadata_path)
+                .await?;
+            for dir in dirs {
+                atomic::sync_dir_async(dir).await?;
+            }
+            Ok(flushed)
         }
     }
 
@@ -220,6 +585,16 @@ impl FsPersister {
     }
 }
 
+/// Read the record at the path, checking its integrity.
+fn read_record(path: &Path) -> Result<Vec<u8>, FsPersisterError> {
+    atomic::decode(fs::read(path)?).ok_or_else(|| FsPersisterError::Corrupt(path.to_owned()))
+}
+
+/// Whether the directory entry is a record, rather than a record being written.
+fn is_record(file_type: fs::FileType, path: &Path) -> bool {
+    file_type.is_file() && !atomic::is_temp(path)
+}
+
 fn make_changes_path<P: AsRef<Path>>(changes_path: P, actor_id: &ActorId, seq: u64) -> PathBuf {
     changes_path
         .as_ref()
@@ -230,6 +605,10 @@ fn make_peer_path<P: AsRef<Path>>(sync_states_path: P, peer_id: &[u8]) -> PathBu
     sync_states_path.as_ref().join(hex::encode(peer_id))
 }
 
+fn make_metadata_path<P: AsRef<Path>>(metadata_path: P, key: &[u8]) -> PathBuf {
+    metadata_path.as_ref().join(hex::encode(key))
+}
+
 impl Persister for FsPersister {
     type Error = FsPersisterError;
 
@@ -239,8 +618,8 @@ impl Persister for FsPersister {
                 if let Ok((Ok(file_type), path)) =
                     entry.map(|entry| (entry.file_type(), entry.path()))
                 {
-                    if file_type.is_file() {
-                        Some(fs::read(path).map_err(FsPersisterError::from))
+                    if is_record(file_type, &path) {
+                        Some(read_record(&path))
                     } else {
                         None
                     }
@@ -252,16 +631,18 @@ impl Persister for FsPersister {
     }
 
     fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        self.check_writable()?;
         for (a, s, c) in changes {
             self.sizes.changes += c.len() as u64;
             if let Some(old) = self.cache.changes.insert((a, s), c) {
                 self.sizes.changes -= old.len() as u64;
             }
         }
-        Ok(())
+        self.wrote()
     }
 
     fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.check_writable()?;
         for (a, s) in changes {
             if let Some(old) = self.cache.changes.remove(&(a.clone(), s)) {
                 // not flushed yet
@@ -272,12 +653,13 @@ impl Persister for FsPersister {
             let path = make_changes_path(&self.changes_path, a, s);
             if let Ok(meta) = fs::metadata(&path) {
                 if meta.is_file() {
+                    self.sizes.changes -= atomic::data_len(&path)?;
                     fs::remove_file(&path)?;
-                    self.sizes.changes -= meta.len();
+                    self.unsynced_dirs.insert(self.changes_path.clone());
                 }
             }
         }
-        Ok(())
+        self.wrote()
     }
 
     fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
@@ -285,15 +667,37 @@ impl Persister for FsPersister {
             return Ok(Some(doc.clone()));
         }
         if fs::metadata(&self.doc_path).is_ok() {
-            return Ok(fs::read(&self.doc_path).map(|v| if v.is_empty() { None } else { Some(v) })?);
+            return read_record(&self.doc_path).map(|v| if v.is_empty() { None } else { Some(v) });
         }
         Ok(None)
     }
 
+    /// Returns the document mapped in to memory if [`FsPersister::with_mapped_document`] was set
+    /// and it has been flushed.
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        if !self.map_document || self.cache.document.is_some() {
+            let document = self.get_document()?;
+            return Ok((document.map(|d| Box::new(d) as Box<dyn AsRef<[u8]>>), None));
+        }
+        let map = match Mmap::open(&self.doc_path) {
+            Ok(Some(map)) => map,
+            Ok(None) => return Ok((None, None)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((None, None)),
+            Err(e) => return Err(e.into()),
+        };
+        let range = atomic::data_range(map.as_ref())
+            .ok_or_else(|| FsPersisterError::Corrupt(self.doc_path.clone()))?;
+        if range.is_empty() {
+            return Ok((None, None));
+        }
+        Ok((Some(Box::new(MappedRecord { map, range })), None))
+    }
+
     fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.check_writable()?;
         self.sizes.document = data.len() as u64;
         self.cache.document = Some(data);
-        Ok(())
+        self.wrote()
     }
 
     fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
@@ -302,20 +706,22 @@ impl Persister for FsPersister {
         }
         let path = make_peer_path(&self.sync_states_path, peer_id);
         if fs::metadata(&path).is_ok() {
-            return Ok(fs::read(&path).map(|v| if v.is_empty() { None } else { Some(v) })?);
+            return read_record(&path).map(|v| if v.is_empty() { None } else { Some(v) });
         }
         Ok(None)
     }
 
     fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.check_writable()?;
         self.sizes.sync_states += sync_state.len() as u64;
         if let Some(old) = self.cache.sync_states.insert(peer_id, sync_state) {
             self.sizes.sync_states -= old.len() as u64;
         }
-        Ok(())
+        self.wrote()
     }
 
     fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.check_writable()?;
         for peer_id in peer_ids {
             if let Some(old) = self.cache.sync_states.remove(*peer_id) {
                 // not flushed yet
@@ -325,12 +731,13 @@ impl Persister for FsPersister {
             let path = make_peer_path(&self.sync_states_path, peer_id);
             if let Ok(meta) = fs::metadata(&path) {
                 if meta.is_file() {
+                    self.sizes.sync_states -= atomic::data_len(&path)?;
                     fs::remove_file(&path)?;
-                    self.sizes.sync_states -= meta.len();
+                    self.unsynced_dirs.insert(self.sync_states_path.clone());
                 }
             }
         }
-        Ok(())
+        self.wrote()
     }
 
     fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
@@ -339,7 +746,7 @@ impl Persister for FsPersister {
                 if let Ok((Ok(file_type), path)) =
                     entry.map(|entry| (entry.file_type(), entry.path()))
                 {
-                    if file_type.is_file() {
+                    if is_record(file_type, &path) {
                         Some(
                             hex::decode(path.file_name().unwrap().as_bytes())
                                 .map_err(FsPersisterError::from),
@@ -354,18 +761,128 @@ impl Persister for FsPersister {
             .collect()
     }
 
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(value) = self.cache.metadata.get(key) {
+            return Ok(Some(value.clone()));
+        }
+        let path = make_metadata_path(&self.metadata_path, key);
+        if fs::metadata(&path).is_ok() {
+            return read_record(&path).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        self.sizes.metadata += value.len() as u64;
+        if let Some(old) = self.cache.metadata.insert(key, value) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        self.wrote()
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        if let Some(old) = self.cache.metadata.remove(key) {
+            // not flushed yet
+            self.sizes.metadata -= old.len() as u64;
+        }
+        // an older value may have been flushed already
+        let path = make_metadata_path(&self.metadata_path, key);
+        if let Ok(meta) = fs::metadata(&path) {
+            if meta.is_file() {
+                self.sizes.metadata -= atomic::data_len(&path)?;
+                fs::remove_file(&path)?;
+                self.unsynced_dirs.insert(self.metadata_path.clone());
+            }
+        }
+        self.wrote()
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let mut keys = self.cache.metadata.keys().cloned().collect::<Vec<_>>();
+        for entry in fs::read_dir(&self.metadata_path)? {
+            let entry = entry?;
+            if is_record(entry.file_type()?, &entry.path()) {
+                let key = hex::decode(entry.file_name().as_bytes())?;
+                if !self.cache.metadata.contains_key(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+
     fn sizes(&self) -> StoredSizes {
         self.sizes.clone()
     }
 
+    /// Write the cached records, each atomically, then sync the directories written to so the
+    /// renames and any removals are durable.
     fn flush(&mut self) -> Result<usize, Self::Error> {
-        self.cache
-            .drain_clone()
-            .flush(
-                self.doc_path.clone(),
-                self.changes_path.clone(),
-                self.sync_states_path.clone(),
-            )
-            .map_err(FsPersisterError::from)
+        let mut dirs = self.cache.dirs(
+            &self.doc_path,
+            &self.changes_path,
+            &self.sync_states_path,
+            &self.metadata_path,
+        );
+        dirs.extend(self.unsynced_dirs.drain());
+        let flushed = self.cache.drain_clone().flush(
+            self.doc_path.clone(),
+            self.changes_path.clone(),
+            self.sync_states_path.clone(),
+            self.metadata_path.clone(),
+        )?;
+        for dir in dirs {
+            atomic::sync_dir(&dir)?;
+        }
+        self.durability.synced();
+        Ok(flushed)
+    }
+
+    /// Flush, then remove the temporary files left by writes that were interrupted, such as by
+    /// dropping the future from [`FsPersister::flush_cache_async`] part way through.
+    ///
+    /// Another instance could be part way through writing them unless this one holds the lock
+    /// from [`FsPersister::open_exclusive`], so otherwise they are left alone.
+    ///
+    /// ```rust
+    /// # use automerge_persistent_core::Persister;
+    /// # use automerge_persistent_fs::FsPersister;
+    /// # fn size(dir: &std::path::Path) -> u64 {
+    /// #     std::fs::read_dir(dir)
+    /// #         .unwrap()
+    /// #         .map(|entry| {
+    /// #             let entry = entry.unwrap();
+    /// #             if entry.file_type().unwrap().is_dir() {
+    /// #                 size(&entry.path())
+    /// #             } else {
+    /// #                 entry.metadata().unwrap().len()
+    /// #             }
+    /// #         })
+    /// #         .sum()
+    /// # }
+    /// let root = std::env::temp_dir().join(format!("fs-vacuum-{}", std::process::id()));
+    /// let mut persister = FsPersister::open_exclusive(&root, "doc").unwrap();
+    /// // what an interrupted write leaves behind
+    /// std::fs::write(root.join("doc/changes/record.tmp"), vec![0; 4096]).unwrap();
+    ///
+    /// let before = size(&root);
+    /// persister.vacuum().unwrap();
+    /// assert!(size(&root) < before);
+    /// # drop(persister);
+    /// # std::fs::remove_dir_all(&root).unwrap();
+    /// ```
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        self.flush()?;
+        // a shared lock was rejected above so this is the exclusive one
+        if self.lock.is_some() {
+            self.remove_temps()?;
+            for dir in self.dirs() {
+                atomic::sync_dir(dir)?;
+            }
+        }
+        Ok(())
     }
 }
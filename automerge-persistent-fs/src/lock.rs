@@ -0,0 +1,77 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// How a persister holds the lock on its storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockMode {
+    /// The only instance, which can read and write.
+    Exclusive,
+    /// One of any number of readers, with no writer.
+    SharedRead,
+}
+
+/// An advisory lock on a lock file, released when dropped or when the process exits.
+///
+/// The exclusive holder writes its process id into the file so that others failing to take the
+/// lock can report who holds it.
+#[derive(Debug)]
+pub(crate) struct LockFile {
+    file: File,
+    mode: LockMode,
+}
+
+/// Why the lock could not be taken.
+#[derive(Debug)]
+pub(crate) enum LockError {
+    Io(std::io::Error),
+    /// Another instance holds a conflicting lock, the process id is known if it is exclusive.
+    Locked(Option<u32>),
+}
+
+impl LockFile {
+    /// Take the lock on the file at the path without waiting, creating the file if needed.
+    pub(crate) fn acquire(path: &Path, mode: LockMode) -> Result<Self, LockError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(LockError::Io)?;
+        let locked = match mode {
+            LockMode::Exclusive => file.try_lock(),
+            LockMode::SharedRead => file.try_lock_shared(),
+        };
+        match locked {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid).map_err(LockError::Io)?;
+                return Err(LockError::Locked(pid.trim().parse().ok()));
+            }
+            Err(TryLockError::Error(e)) => return Err(LockError::Io(e)),
+        }
+        if mode == LockMode::Exclusive {
+            file.set_len(0).map_err(LockError::Io)?;
+            file.seek(SeekFrom::Start(0)).map_err(LockError::Io)?;
+            write!(file, "{}", std::process::id()).map_err(LockError::Io)?;
+        }
+        Ok(Self { file, mode })
+    }
+
+    pub(crate) fn read_only(&self) -> bool {
+        self.mode == LockMode::SharedRead
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // a stale id would mislead anyone reading it, the lock itself goes with the file
+        if self.mode == LockMode::Exclusive {
+            let _ = self.file.set_len(0);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::{convert::TryFrom, fs::File, io, ops::Range, os::unix::io::AsRawFd, path::Path, ptr};
+
+/// A read only memory map of a whole file, unmapped when dropped.
+#[derive(Debug)]
+pub(crate) struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    /// Map the file at the path, or `None` if it is empty as empty maps aren't allowed.
+    pub(crate) fn open(path: &Path) -> io::Result<Option<Self>> {
+        let file = File::open(path)?;
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::other("file too large to map"))?;
+        if len == 0 {
+            return Ok(None);
+        }
+        // SAFETY: a new private read only map of the whole file, which stays valid after the
+        // file is closed
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(Self { ptr, len }))
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: the map is valid for `len` bytes until dropped, and records are only ever
+        // replaced by renaming over them so the mapped file isn't changed underneath it
+        unsafe { std::slice::from_raw_parts(self.ptr.cast(), self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: the map was created in `open` and no slices of it outlive it
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// The data of a mapped record, without its integrity header.
+#[derive(Debug)]
+pub(crate) struct MappedRecord {
+    pub(crate) map: Mmap,
+    pub(crate) range: Range<usize>,
+}
+
+impl AsRef<[u8]> for MappedRecord {
+    fn as_ref(&self) -> &[u8] {
+        &self.map.as_ref()[self.range.clone()]
+    }
+}
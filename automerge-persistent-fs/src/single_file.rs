@@ -0,0 +1,576 @@
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use automerge::ActorId;
+use automerge_persistent_core::{DurabilityPolicy, DurabilityTracker, Persister, StoredSizes};
+
+use crate::lock::{LockError, LockFile, LockMode};
+
+const MAGIC: &[u8; 4] = b"AMPF";
+const VERSION: u32 = 1;
+/// Each header slot is padded to this many bytes.
+const HEADER_SIZE: u64 = 64;
+/// Two header slots come before the data.
+const DATA_START: u64 = 2 * HEADER_SIZE;
+
+/// Possible errors from the single file persister.
+#[derive(Debug, thiserror::Error)]
+pub enum SingleFileError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// Neither header, or the data they point to, could be read.
+    #[error("the file is corrupt: {0}")]
+    Corrupt(&'static str),
+    /// The file was written by a newer version of this format.
+    #[error("unsupported format version {0}")]
+    UnsupportedVersion(u32),
+    /// Another instance holds a conflicting lock on the file, along with its process id if it
+    /// holds it exclusively.
+    #[error("the file is locked by another instance (pid {0:?})")]
+    Locked(Option<u32>),
+    /// The persister was opened for reading only.
+    #[error("the persister is read only")]
+    ReadOnly,
+}
+
+impl From<LockError> for SingleFileError {
+    fn from(error: LockError) -> Self {
+        match error {
+            LockError::Io(e) => Self::Io(e),
+            LockError::Locked(pid) => Self::Locked(pid),
+        }
+    }
+}
+
+/// Where the current snapshot lives, as recorded in a header slot.
+#[derive(Debug, Clone, Copy, Default)]
+struct Header {
+    generation: u64,
+    offset: u64,
+    len: u64,
+    checksum: u64,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_SIZE as usize] {
+        let mut bytes = [0; HEADER_SIZE as usize];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4..8].copy_from_slice(&VERSION.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.generation.to_be_bytes());
+        bytes[16..24].copy_from_slice(&self.offset.to_be_bytes());
+        bytes[24..32].copy_from_slice(&self.len.to_be_bytes());
+        bytes[32..40].copy_from_slice(&self.checksum.to_be_bytes());
+        let header_checksum = fnv1a(&bytes[..40]);
+        bytes[40..48].copy_from_slice(&header_checksum.to_be_bytes());
+        bytes
+    }
+
+    /// Decode a header slot, returning `None` if it is torn or was never written.
+    fn decode(bytes: &[u8]) -> Result<Option<Self>, SingleFileError> {
+        if bytes.len() < 48 || &bytes[..4] != MAGIC || fnv1a(&bytes[..40]) != read_u64(bytes, 40) {
+            return Ok(None);
+        }
+        let version = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+        if version > VERSION {
+            return Err(SingleFileError::UnsupportedVersion(version));
+        }
+        Ok(Some(Self {
+            generation: read_u64(bytes, 8),
+            offset: read_u64(bytes, 16),
+            len: read_u64(bytes, 24),
+            checksum: read_u64(bytes, 32),
+        }))
+    }
+
+    fn slot_offset(&self) -> u64 {
+        (self.generation % 2) * HEADER_SIZE
+    }
+}
+
+/// A persister that keeps a whole document in one file, for applications where a user-visible
+/// file is a document.
+///
+/// The file starts with two versioned header slots followed by snapshots of the data. A snapshot
+/// holds the document region, an index of the change records along with their data, and the
+/// sync states and metadata. Writes are held in memory until [`Persister::flush`], which writes a
+/// new snapshot to space not used by the current one and only then points the other header slot
+/// at it, so a crash at any point leaves either the old or the new snapshot intact. Checksums on
+/// the headers and snapshots detect torn writes.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::PersistentAutomerge;
+/// # use automerge_persistent_fs::SingleFilePersister;
+/// let path = std::env::temp_dir().join(format!("single-file-{}.amp", std::process::id()));
+/// let mut doc = PersistentAutomerge::load(SingleFilePersister::open(&path).unwrap()).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+/// doc.close().unwrap();
+///
+/// let doc = PersistentAutomerge::load(SingleFilePersister::open(&path).unwrap()).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 1);
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct SingleFilePersister {
+    file: File,
+    header: Header,
+    changes: HashMap<(ActorId, u64), Vec<u8>>,
+    document: Option<Vec<u8>>,
+    sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    metadata: HashMap<Vec<u8>, Vec<u8>>,
+    sizes: StoredSizes,
+    /// Whether there are writes not yet flushed to the file.
+    dirty: bool,
+    lock: Option<LockFile>,
+    durability: DurabilityTracker,
+}
+
+impl SingleFilePersister {
+    /// Open the file at the path, creating it if it doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or is corrupt.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SingleFileError> {
+        Self::open_with(path.as_ref(), None)
+    }
+
+    /// Like [`Self::open`] but first taking an exclusive advisory lock, on a `.lock` file next to
+    /// it, so that no other instance can open the file while this one is alive.
+    ///
+    /// The lock is released when the persister is dropped, or the process exits.
+    ///
+    /// ```rust
+    /// # use automerge_persistent_fs::{SingleFileError, SingleFilePersister};
+    /// let path = std::env::temp_dir().join(format!("single-file-lock-{}.amp", std::process::id()));
+    /// let persister = SingleFilePersister::open_exclusive(&path).unwrap();
+    /// assert!(matches!(
+    ///     SingleFilePersister::open_exclusive(&path),
+    ///     Err(SingleFileError::Locked(Some(pid))) if pid == std::process::id()
+    /// ));
+    /// drop(persister);
+    ///
+    /// // any number of readers can share it
+    /// let reader = SingleFilePersister::open_shared_read(&path).unwrap();
+    /// let _other = SingleFilePersister::open_shared_read(&path).unwrap();
+    /// assert!(SingleFilePersister::open_exclusive(&path).is_err());
+    /// # drop(reader);
+    /// # std::fs::remove_file(&path).unwrap();
+    /// # std::fs::remove_file(path.with_extension("amp.lock")).unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SingleFileError::Locked`] if another instance has the file open, or an error if
+    /// it can't be read or is corrupt.
+    pub fn open_exclusive<P: AsRef<Path>>(path: P) -> Result<Self, SingleFileError> {
+        let lock = LockFile::acquire(&lock_path(path.as_ref()), LockMode::Exclusive)?;
+        Self::open_with(path.as_ref(), Some(lock))
+    }
+
+    /// Like [`Self::open`] but first taking a shared advisory lock, so that other readers can open
+    /// the file but no exclusive instance can.
+    ///
+    /// Any writes through the persister fail with [`SingleFileError::ReadOnly`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SingleFileError::Locked`] if an exclusive instance has the file open, or an error
+    /// if it can't be read or is corrupt.
+    pub fn open_shared_read<P: AsRef<Path>>(path: P) -> Result<Self, SingleFileError> {
+        let lock = LockFile::acquire(&lock_path(path.as_ref()), LockMode::SharedRead)?;
+        Self::open_with(path.as_ref(), Some(lock))
+    }
+
+    fn open_with(path: &Path, lock: Option<LockFile>) -> Result<Self, SingleFileError> {
+        let read_only = lock.as_ref().is_some_and(LockFile::read_only);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false)
+            .open(path)?;
+        let mut headers = vec![0; DATA_START as usize];
+        let read = read_up_to(&mut file, &mut headers)?;
+
+        let mut s = Self {
+            file,
+            header: Header::default(),
+            changes: HashMap::new(),
+            document: None,
+            sync_states: HashMap::new(),
+            metadata: HashMap::new(),
+            sizes: StoredSizes::default(),
+            dirty: false,
+            lock,
+            durability: DurabilityTracker::new(DurabilityPolicy::Manual),
+        };
+        if read == 0 {
+            return Ok(s);
+        }
+
+        let mut candidates = [
+            Header::decode(&headers[..HEADER_SIZE as usize])?,
+            Header::decode(&headers[HEADER_SIZE as usize..])?,
+        ];
+        candidates.sort_by_key(|h| std::cmp::Reverse(h.map(|h| h.generation)));
+        for header in candidates.iter().flatten() {
+            let mut snapshot = vec![0; header.len as usize];
+            s.file.seek(SeekFrom::Start(header.offset))?;
+            if read_up_to(&mut s.file, &mut snapshot)? == snapshot.len()
+                && fnv1a(&snapshot) == header.checksum
+            {
+                s.decode_snapshot(&snapshot)
+                    .ok_or(SingleFileError::Corrupt("malformed snapshot"))?;
+                s.header = *header;
+                return Ok(s);
+            }
+        }
+        Err(SingleFileError::Corrupt("no valid header"))
+    }
+
+    /// Set when writes are flushed to the file, by default only when flushed.
+    ///
+    /// Each flush writes a whole new snapshot, so policies flushing often suit small documents.
+    pub fn with_durability(mut self, policy: DurabilityPolicy) -> Self {
+        self.durability = DurabilityTracker::new(policy);
+        self
+    }
+
+    /// Set when writes are flushed to the file, see [`Self::with_durability`].
+    pub fn set_durability(&mut self, policy: DurabilityPolicy) -> &mut Self {
+        self.durability = DurabilityTracker::new(policy);
+        self
+    }
+
+    /// Flush the writes if the durability policy says they are due.
+    fn wrote(&mut self) -> Result<(), SingleFileError> {
+        if self.durability.record_write() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), SingleFileError> {
+        if self.lock.as_ref().is_some_and(LockFile::read_only) {
+            return Err(SingleFileError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn encode_snapshot(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let document = self.document.as_deref().unwrap_or_default();
+        bytes.extend((document.len() as u64).to_be_bytes());
+        bytes.extend(document);
+
+        // the index of change records, followed by their data in the same order
+        bytes.extend((self.changes.len() as u32).to_be_bytes());
+        for ((actor, seq), change) in &self.changes {
+            let actor = actor.to_bytes();
+            bytes.extend((actor.len() as u32).to_be_bytes());
+            bytes.extend(actor);
+            bytes.extend(seq.to_be_bytes());
+            bytes.extend((change.len() as u64).to_be_bytes());
+        }
+        for change in self.changes.values() {
+            bytes.extend(change);
+        }
+
+        for map in [&self.sync_states, &self.metadata] {
+            bytes.extend((map.len() as u32).to_be_bytes());
+            for (key, value) in map {
+                bytes.extend((key.len() as u32).to_be_bytes());
+                bytes.extend(key);
+                bytes.extend((value.len() as u64).to_be_bytes());
+                bytes.extend(value);
+            }
+        }
+        bytes
+    }
+
+    fn decode_snapshot(&mut self, bytes: &[u8]) -> Option<()> {
+        let mut reader = Reader(bytes);
+        let document_len = reader.u64()?;
+        let document = reader.bytes(document_len)?;
+        self.document = (!document.is_empty()).then(|| document.to_vec());
+        self.sizes.document = document_len;
+
+        let mut index = Vec::new();
+        for _ in 0..reader.u32()? {
+            let actor_len = reader.u32()?;
+            let actor = ActorId::from(reader.bytes(u64::from(actor_len))?);
+            let seq = reader.u64()?;
+            index.push((actor, seq, reader.u64()?));
+        }
+        for (actor, seq, len) in index {
+            let change = reader.bytes(len)?.to_vec();
+            self.sizes.changes += len;
+            self.changes.insert((actor, seq), change);
+        }
+
+        for (map, size) in [
+            (&mut self.sync_states, &mut self.sizes.sync_states),
+            (&mut self.metadata, &mut self.sizes.metadata),
+        ] {
+            for _ in 0..reader.u32()? {
+                let key_len = reader.u32()?;
+                let key = reader.bytes(u64::from(key_len))?.to_vec();
+                let value_len = reader.u64()?;
+                let value = reader.bytes(value_len)?.to_vec();
+                *size += value_len;
+                map.insert(key, value);
+            }
+        }
+        Some(())
+    }
+
+    /// Write the snapshot at the offset, point the other header slot at it and drop anything
+    /// after it.
+    fn write_snapshot(&mut self, snapshot: &[u8], offset: u64) -> Result<(), SingleFileError> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(snapshot)?;
+        self.file.sync_data()?;
+
+        let header = Header {
+            generation: self.header.generation + 1,
+            offset,
+            len: snapshot.len() as u64,
+            checksum: fnv1a(snapshot),
+        };
+        self.file.seek(SeekFrom::Start(header.slot_offset()))?;
+        self.file.write_all(&header.encode())?;
+        self.file.sync_data()?;
+
+        // the old snapshot is no longer referenced so anything after the new one can go
+        self.file.set_len(offset + header.len)?;
+        self.header = header;
+        Ok(())
+    }
+}
+
+/// The lock file for the file at the path, the path with `.lock` appended.
+fn lock_path(path: &Path) -> std::path::PathBuf {
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(".lock");
+    lock.into()
+}
+
+/// Read as much of the buffer as the file has.
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// The 64 bit FNV-1a hash, used as a checksum.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Reads big endian values from the front of a snapshot.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: u64) -> Option<&'a [u8]> {
+        let len = usize::try_from(len).ok()?;
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8)
+            .map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+    }
+}
+
+impl Persister for SingleFilePersister {
+    type Error = SingleFileError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.changes.values().cloned().collect())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        for (a, s, c) in changes {
+            self.sizes.changes += c.len() as u64;
+            if let Some(old) = self.changes.insert((a, s), c) {
+                self.sizes.changes -= old.len() as u64;
+            }
+            self.dirty = true;
+        }
+        self.wrote()
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        for (a, s) in changes {
+            if let Some(old) = self.changes.remove(&(a.clone(), s)) {
+                self.sizes.changes -= old.len() as u64;
+                self.dirty = true;
+            }
+        }
+        self.wrote()
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.document.clone())
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        self.sizes.document = data.len() as u64;
+        self.document = Some(data);
+        self.dirty = true;
+        self.wrote()
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.get(peer_id).cloned())
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        self.sizes.sync_states += sync_state.len() as u64;
+        if let Some(old) = self.sync_states.insert(peer_id, sync_state) {
+            self.sizes.sync_states -= old.len() as u64;
+        }
+        self.dirty = true;
+        self.wrote()
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        for id in peer_ids {
+            if let Some(old) = self.sync_states.remove(*id) {
+                self.sizes.sync_states -= old.len() as u64;
+                self.dirty = true;
+            }
+        }
+        self.wrote()
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.keys().cloned().collect())
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.get(key).cloned())
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        self.sizes.metadata += value.len() as u64;
+        if let Some(old) = self.metadata.insert(key, value) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        self.dirty = true;
+        self.wrote()
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        if let Some(old) = self.metadata.remove(key) {
+            self.sizes.metadata -= old.len() as u64;
+            self.dirty = true;
+        }
+        self.wrote()
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.keys().cloned().collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Write a new snapshot then switch the header over to it.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        if !self.dirty {
+            self.durability.synced();
+            return Ok(0);
+        }
+        let snapshot = self.encode_snapshot();
+        let len = snapshot.len() as u64;
+        // reuse the space before the current snapshot if it fits, otherwise go after it
+        let offset = if self.header.len == 0 || DATA_START + len <= self.header.offset {
+            DATA_START
+        } else {
+            self.header.offset + self.header.len
+        };
+        self.write_snapshot(&snapshot, offset)?;
+        self.dirty = false;
+        self.durability.synced();
+        Ok(snapshot.len())
+    }
+
+    /// Flush, then move the snapshot to the start of the data and truncate the file after it.
+    ///
+    /// A snapshot that doesn't fit before the current one is written after it, which can leave
+    /// the file up to twice the size of the data. If the snapshot overlaps the start it is first
+    /// copied after itself, so that a crash still leaves one intact.
+    ///
+    /// ```rust
+    /// # use automerge_persistent_core::Persister;
+    /// # use automerge_persistent_fs::SingleFilePersister;
+    /// let path = std::env::temp_dir().join(format!("single-file-vacuum-{}.amp", std::process::id()));
+    /// let mut persister = SingleFilePersister::open(&path).unwrap();
+    /// persister.set_document(vec![1; 1024]).unwrap();
+    /// persister.flush().unwrap();
+    /// // too big to fit before the first snapshot so written after it
+    /// persister.set_document(vec![2; 2048]).unwrap();
+    /// persister.flush().unwrap();
+    ///
+    /// let before = std::fs::metadata(&path).unwrap().len();
+    /// persister.vacuum().unwrap();
+    /// assert!(std::fs::metadata(&path).unwrap().len() < before);
+    ///
+    /// let reopened = SingleFilePersister::open(&path).unwrap();
+    /// assert_eq!(reopened.get_document().unwrap(), Some(vec![2; 2048]));
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        self.flush()?;
+        if self.header.len == 0 || self.header.offset == DATA_START {
+            return Ok(());
+        }
+        let snapshot = self.encode_snapshot();
+        let len = snapshot.len() as u64;
+        if DATA_START + len > self.header.offset {
+            self.write_snapshot(&snapshot, self.header.offset + self.header.len)?;
+        }
+        self.write_snapshot(&snapshot, DATA_START)
+    }
+}
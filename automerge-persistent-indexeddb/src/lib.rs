@@ -0,0 +1,388 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+// the browser futures are tied to the one thread so can never be `Send`
+#![allow(clippy::future_not_send)]
+
+//! A persister targetting `IndexedDB` in the browser.
+//!
+//! `IndexedDB` is async so the persister is opened with an async constructor, which reads the
+//! stored records into memory. Writes are kept in memory until [`Persister::flush`] sends them in
+//! a single transaction, and [`IndexedDbPersister::flush_async`] also waits for that transaction
+//! to commit.
+//!
+//! ```rust,no_run
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_indexeddb::{IndexedDbPersister, IndexedDbPersisterError};
+//! # async fn open() -> Result<(), IndexedDbPersisterError> {
+//! let factory = web_sys::window()
+//!     .unwrap()
+//!     .indexed_db()
+//!     .map_err(IndexedDbPersisterError::IndexedDbError)?
+//!     .unwrap();
+//!
+//! let persister = IndexedDbPersister::open(&factory, "my-document").await?;
+//! let mut doc = PersistentAutomerge::load(persister).unwrap();
+//! doc.persister_mut().flush_async().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+
+use automerge::ActorId;
+use automerge_persistent_core::{Persister, StoredSizes};
+use js_sys::{Array, Promise, Uint8Array};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    IdbCursorWithValue, IdbDatabase, IdbFactory, IdbObjectStore, IdbRequest, IdbTransactionMode,
+};
+
+/// The name of the object store changes are stored in.
+pub const CHANGES_STORE: &str = "changes";
+/// The name of the object store the document is stored in.
+pub const DOCUMENT_STORE: &str = "document";
+/// The name of the object store sync states are stored in.
+pub const SYNC_STATES_STORE: &str = "sync_states";
+/// The name of the object store metadata is stored in.
+pub const METADATA_STORE: &str = "metadata";
+
+const STORES: [&str; 4] = [
+    CHANGES_STORE,
+    DOCUMENT_STORE,
+    SYNC_STATES_STORE,
+    METADATA_STORE,
+];
+/// The key of the document in its object store.
+const DOCUMENT_KEY: &str = "document";
+const VERSION: u32 = 1;
+
+/// Persist changes and documents in to `IndexedDB`.
+///
+/// Each document gets its own database, with an object store each for the changes, the
+/// document, the sync states and the metadata. Records are keyed by strings, hex encoding the
+/// binary parts.
+#[derive(Debug)]
+pub struct IndexedDbPersister {
+    database: IdbDatabase,
+    changes: HashMap<(ActorId, u64), Vec<u8>>,
+    document: Option<Vec<u8>>,
+    sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    metadata: HashMap<Vec<u8>, Vec<u8>>,
+    /// Writes not yet sent, by store and key, with `None` for a delete.
+    pending: HashMap<(&'static str, String), Option<Vec<u8>>>,
+    /// Transactions that have been sent, resolving once they have committed.
+    in_flight: Vec<Promise>,
+    sizes: StoredSizes,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum IndexedDbPersisterError {
+    /// An underlying `IndexedDB` error.
+    #[error("indexeddb error {0:?}")]
+    IndexedDbError(JsValue),
+    /// A stored key was not in the expected form.
+    #[error("invalid key {0:?}")]
+    InvalidKey(String),
+}
+
+fn change_key(actor_id: &ActorId, seq: u64) -> String {
+    format!("{}/{}", actor_id.to_hex_string(), seq)
+}
+
+fn decode_change_key(key: &str) -> Option<(ActorId, u64)> {
+    let (actor, seq) = key.split_once('/')?;
+    Some((ActorId::from(hex::decode(actor).ok()?), seq.parse().ok()?))
+}
+
+fn store_names() -> Array {
+    STORES.iter().copied().map(JsValue::from).collect()
+}
+
+/// Wait for the next success event of the request, returning its result.
+///
+/// The handlers are replaced each call so this can be called repeatedly to step a cursor.
+async fn next_result(request: &IdbRequest) -> Result<JsValue, IndexedDbPersisterError> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+    JsFuture::from(promise)
+        .await
+        .map_err(IndexedDbPersisterError::IndexedDbError)?;
+    request
+        .result()
+        .map_err(IndexedDbPersisterError::IndexedDbError)
+}
+
+/// Read every record in the store by stepping a cursor through it, rather than fetching them
+/// all in one go, to keep memory bounded on large documents.
+async fn read_store(
+    store: &IdbObjectStore,
+) -> Result<Vec<(String, Vec<u8>)>, IndexedDbPersisterError> {
+    let request = store
+        .open_cursor()
+        .map_err(IndexedDbPersisterError::IndexedDbError)?;
+    let mut records = Vec::new();
+    loop {
+        let cursor = next_result(&request).await?;
+        if cursor.is_null() {
+            return Ok(records);
+        }
+        let cursor = cursor.unchecked_into::<IdbCursorWithValue>();
+        let key = cursor
+            .key()
+            .map_err(IndexedDbPersisterError::IndexedDbError)?
+            .as_string()
+            .unwrap_or_default();
+        let value = cursor
+            .value()
+            .map_err(IndexedDbPersisterError::IndexedDbError)?;
+        records.push((key, Uint8Array::new(&value).to_vec()));
+        cursor
+            .continue_()
+            .map_err(IndexedDbPersisterError::IndexedDbError)?;
+    }
+}
+
+fn decode_hex_records(
+    records: Vec<(String, Vec<u8>)>,
+) -> Result<HashMap<Vec<u8>, Vec<u8>>, IndexedDbPersisterError> {
+    records
+        .into_iter()
+        .map(|(k, v)| {
+            hex::decode(&k)
+                .map(|k| (k, v))
+                .map_err(|_| IndexedDbPersisterError::InvalidKey(k))
+        })
+        .collect()
+}
+
+impl IndexedDbPersister {
+    /// Open the database with the given name, creating it if it does not exist, and read its
+    /// records.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database could not be opened or read.
+    pub async fn open(factory: &IdbFactory, name: &str) -> Result<Self, IndexedDbPersisterError> {
+        let request = factory
+            .open_with_u32(name, VERSION)
+            .map_err(IndexedDbPersisterError::IndexedDbError)?;
+        let upgrade_request = request.clone();
+        let on_upgrade = Closure::once(move || -> Result<(), JsValue> {
+            let database = upgrade_request.result()?.unchecked_into::<IdbDatabase>();
+            for store in STORES {
+                database.create_object_store(store)?;
+            }
+            Ok(())
+        });
+        request.set_onupgradeneeded(Some(on_upgrade.as_ref().unchecked_ref()));
+        let database = next_result(&request).await?.unchecked_into::<IdbDatabase>();
+        request.set_onupgradeneeded(None);
+        drop(on_upgrade);
+
+        let transaction = database
+            .transaction_with_str_sequence(&store_names())
+            .map_err(IndexedDbPersisterError::IndexedDbError)?;
+        let store = |name| {
+            transaction
+                .object_store(name)
+                .map_err(IndexedDbPersisterError::IndexedDbError)
+        };
+        let changes = read_store(&store(CHANGES_STORE)?)
+            .await?
+            .into_iter()
+            .map(|(k, v)| {
+                decode_change_key(&k)
+                    .map(|key| (key, v))
+                    .ok_or(IndexedDbPersisterError::InvalidKey(k))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        let document = read_store(&store(DOCUMENT_STORE)?)
+            .await?
+            .into_iter()
+            .find(|(k, _)| k == DOCUMENT_KEY)
+            .map(|(_, v)| v);
+        let sync_states = decode_hex_records(read_store(&store(SYNC_STATES_STORE)?).await?)?;
+        let metadata = decode_hex_records(read_store(&store(METADATA_STORE)?).await?)?;
+
+        let sizes = StoredSizes {
+            changes: changes.values().map(Vec::len).sum::<usize>() as u64,
+            document: document.as_ref().map_or(0, Vec::len) as u64,
+            sync_states: sync_states.values().map(Vec::len).sum::<usize>() as u64,
+            metadata: metadata.values().map(Vec::len).sum::<usize>() as u64,
+        };
+        Ok(Self {
+            database,
+            changes,
+            document,
+            sync_states,
+            metadata,
+            pending: HashMap::new(),
+            in_flight: Vec::new(),
+            sizes,
+        })
+    }
+
+    /// Send the pending writes, like [`Persister::flush`], and wait for all sent transactions to
+    /// commit.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a transaction failed or was aborted, the writes it held are lost.
+    pub async fn flush_async(&mut self) -> Result<usize, IndexedDbPersisterError> {
+        let flushed = self.flush()?;
+        for transaction in std::mem::take(&mut self.in_flight) {
+            JsFuture::from(transaction)
+                .await
+                .map_err(IndexedDbPersisterError::IndexedDbError)?;
+        }
+        Ok(flushed)
+    }
+
+    fn put(&mut self, store: &'static str, key: String, value: Vec<u8>) {
+        self.pending.insert((store, key), Some(value));
+    }
+
+    fn delete(&mut self, store: &'static str, key: String) {
+        self.pending.insert((store, key), None);
+    }
+}
+
+impl Persister for IndexedDbPersister {
+    type Error = IndexedDbPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.changes.values().cloned().collect())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        for (a, s, c) in changes {
+            self.put(CHANGES_STORE, change_key(&a, s), c.clone());
+            self.sizes.changes += c.len() as u64;
+            if let Some(old) = self.changes.insert((a, s), c) {
+                self.sizes.changes -= old.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        for (a, s) in changes {
+            if let Some(old) = self.changes.remove(&(a.clone(), s)) {
+                self.sizes.changes -= old.len() as u64;
+                self.delete(CHANGES_STORE, change_key(a, s));
+            }
+        }
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.document.clone())
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.sizes.document = data.len() as u64;
+        self.put(DOCUMENT_STORE, DOCUMENT_KEY.to_owned(), data.clone());
+        self.document = Some(data);
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.get(peer_id).cloned())
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.put(SYNC_STATES_STORE, hex::encode(&peer_id), sync_state.clone());
+        self.sizes.sync_states += sync_state.len() as u64;
+        if let Some(old) = self.sync_states.insert(peer_id, sync_state) {
+            self.sizes.sync_states -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for id in peer_ids {
+            if let Some(old) = self.sync_states.remove(*id) {
+                self.sizes.sync_states -= old.len() as u64;
+                self.delete(SYNC_STATES_STORE, hex::encode(id));
+            }
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.keys().cloned().collect())
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.get(key).cloned())
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.put(METADATA_STORE, hex::encode(&key), value.clone());
+        self.sizes.metadata += value.len() as u64;
+        if let Some(old) = self.metadata.insert(key, value) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        if let Some(old) = self.metadata.remove(key) {
+            self.sizes.metadata -= old.len() as u64;
+            self.delete(METADATA_STORE, hex::encode(key));
+        }
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.keys().cloned().collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Send all of the pending writes in a single transaction.
+    ///
+    /// This can't wait for the transaction to commit, use [`IndexedDbPersister::flush_async`]
+    /// for that.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
+        let transaction = self
+            .database
+            .transaction_with_str_sequence_and_mode(&store_names(), IdbTransactionMode::Readwrite)
+            .map_err(IndexedDbPersisterError::IndexedDbError)?;
+        // set up the handlers before any requests so the completion can't be missed
+        self.in_flight.push(Promise::new(&mut |resolve, reject| {
+            transaction.set_oncomplete(Some(&resolve));
+            transaction.set_onerror(Some(&reject));
+            transaction.set_onabort(Some(&reject));
+        }));
+
+        let mut flushed = 0;
+        for ((store, key), value) in self.pending.drain() {
+            let store = transaction
+                .object_store(store)
+                .map_err(IndexedDbPersisterError::IndexedDbError)?;
+            let key = JsValue::from(key);
+            let request = value.map_or_else(
+                || store.delete(&key),
+                |value| {
+                    flushed += value.len();
+                    store.put_with_key(&Uint8Array::from(value.as_slice()), &key)
+                },
+            );
+            request.map_err(IndexedDbPersisterError::IndexedDbError)?;
+        }
+        Ok(flushed)
+    }
+}
@@ -0,0 +1,421 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [jammdb](https://github.com/pjtatlow/jammdb), an embedded single-file
+//! B+tree database in the style of Bolt.
+//!
+//! Each document is kept in a bucket of the database, holding nested buckets for its changes,
+//! document, sync states and metadata, so many documents can share one file.
+//!
+//! ```rust,no_run
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_jammdb::JammdbPersister;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = jammdb::DB::open("documents.db")?;
+//! let persister = JammdbPersister::new(db, "my-document")?;
+//! let doc = PersistentAutomerge::load(persister)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::HashSet,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use automerge::ActorId;
+use automerge_persistent_core::{DocumentVersion, SharedPersister, StoredSizes, VersionConflict};
+use jammdb::{Bucket, Data, DB};
+
+/// The name of the bucket changes are stored in, within the bucket of the document.
+pub const CHANGES_BUCKET: &str = "changes";
+/// The name of the bucket the document is stored in, within the bucket of the document.
+pub const DOCUMENT_BUCKET: &str = "document";
+/// The name of the bucket sync states are stored in, within the bucket of the document.
+pub const SYNC_STATES_BUCKET: &str = "sync_states";
+/// The name of the bucket metadata is stored in, within the bucket of the document.
+pub const METADATA_BUCKET: &str = "metadata";
+
+/// The key the document is stored under in its bucket.
+const DOCUMENT_KEY: &[u8] = b"document";
+
+/// The persister that stores a document in a bucket of a jammdb database.
+///
+/// Every operation runs in its own transaction, so a batch of changes is inserted or removed as a
+/// whole and a replaced document is never seen half written, even if the process crashes part
+/// way through. Compacting writes the document and removes the changes it includes in a single
+/// transaction too, so storage is never seen part way through a compaction.
+///
+/// jammdb allows one writing transaction at a time and locks the file for the process, so this
+/// is a [`SharedPersister`] and can be shared behind an [`std::sync::Arc`] without extra locking.
+pub struct JammdbPersister {
+    db: DB,
+    name: String,
+    sizes: Mutex<StoredSizes>,
+}
+
+impl std::fmt::Debug for JammdbPersister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JammdbPersister")
+            .field("name", &self.name)
+            .field("sizes", &self.sizes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum JammdbPersisterError {
+    /// Internal errors from jammdb.
+    #[error(transparent)]
+    JammdbError(#[from] jammdb::Error),
+}
+
+/// Lock a mutex, ignoring poisoning as the sizes are only an estimate.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Make the key of a change from the `actor_id` and `sequence_number`.
+fn change_key(actor_id: &ActorId, seq: u64) -> Vec<u8> {
+    let mut key = actor_id.to_bytes().to_vec();
+    key.extend(&seq.to_be_bytes());
+    key
+}
+
+/// The keys and values of a bucket, skipping nested buckets.
+fn pairs(bucket: &Bucket<'_, '_>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    bucket
+        .cursor()
+        .filter_map(|data| match data {
+            Data::KeyValue(kv) => Some((kv.key().to_vec(), kv.value().to_vec())),
+            Data::Bucket(_) => None,
+        })
+        .collect()
+}
+
+/// Put a value in a bucket, returning the length of the value it replaced.
+fn put(bucket: &Bucket<'_, '_>, key: &[u8], value: Vec<u8>) -> Result<u64, jammdb::Error> {
+    let old = bucket.get_kv(key).map_or(0, |kv| kv.value().len() as u64);
+    bucket.put(key.to_vec(), value)?;
+    Ok(old)
+}
+
+/// Delete a value from a bucket if it is there, returning its length.
+fn delete(bucket: &Bucket<'_, '_>, key: &[u8]) -> Result<u64, jammdb::Error> {
+    match bucket.get_kv(key) {
+        Some(kv) => {
+            let old = kv.value().len() as u64;
+            bucket.delete(key)?;
+            Ok(old)
+        }
+        None => Ok(0),
+    }
+}
+
+impl JammdbPersister {
+    /// Construct a new persister for the document in the bucket with the name, creating the
+    /// buckets if they don't exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the buckets could not be created or their existing contents could not
+    /// be read to calculate the stored sizes.
+    pub fn new<S>(db: DB, name: S) -> Result<Self, JammdbPersisterError>
+    where
+        S: Into<String>,
+    {
+        let name = name.into();
+        let sizes = {
+            let tx = db.tx(true)?;
+            let sizes = {
+                let root = tx.get_or_create_bucket(name.as_str())?;
+                let size = |bucket: &'static str| -> Result<u64, jammdb::Error> {
+                    let bucket = root.get_or_create_bucket(bucket)?;
+                    Ok(pairs(&bucket).iter().map(|(_, v)| v.len() as u64).sum())
+                };
+                StoredSizes {
+                    changes: size(CHANGES_BUCKET)?,
+                    document: size(DOCUMENT_BUCKET)?,
+                    sync_states: size(SYNC_STATES_BUCKET)?,
+                    metadata: size(METADATA_BUCKET)?,
+                }
+            };
+            tx.commit()?;
+            sizes
+        };
+        Ok(Self {
+            db,
+            name,
+            sizes: Mutex::new(sizes),
+        })
+    }
+
+    /// Update the stored sizes, holding the lock only for the update.
+    fn update_sizes(&self, f: impl FnOnce(&mut StoredSizes)) {
+        f(&mut lock(&self.sizes));
+    }
+
+    /// Get a reference to the database.
+    #[must_use]
+    pub const fn db(&self) -> &DB {
+        &self.db
+    }
+
+    /// Read from one of the buckets of the document.
+    // the transaction has to outlive the buckets borrowed from it
+    #[allow(clippy::significant_drop_tightening)]
+    fn read<T, F>(&self, bucket: &str, f: F) -> Result<T, JammdbPersisterError>
+    where
+        F: FnOnce(&Bucket<'_, '_>) -> T,
+    {
+        let tx = self.db.tx(false)?;
+        let root = tx.get_bucket(self.name.as_str())?;
+        let bucket = root.get_bucket(bucket)?;
+        Ok(f(&bucket))
+    }
+
+    /// Write to one of the buckets of the document in a transaction, committing it if the writes
+    /// succeed.
+    // the transaction has to outlive the buckets borrowed from it
+    #[allow(clippy::significant_drop_tightening)]
+    fn write<T, F>(&self, bucket: &str, f: F) -> Result<T, JammdbPersisterError>
+    where
+        F: FnOnce(&Bucket<'_, '_>) -> Result<T, jammdb::Error>,
+    {
+        let tx = self.db.tx(true)?;
+        let result = {
+            let root = tx.get_bucket(self.name.as_str())?;
+            let bucket = root.get_bucket(bucket)?;
+            f(&bucket)?
+        };
+        tx.commit()?;
+        Ok(result)
+    }
+}
+
+impl SharedPersister for JammdbPersister {
+    type Error = JammdbPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.read(CHANGES_BUCKET, |bucket| {
+            pairs(bucket).into_iter().map(|(_, v)| v).collect()
+        })
+    }
+
+    /// List the actors from the keys of the changes.
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        self.read(CHANGES_BUCKET, |bucket| {
+            pairs(bucket)
+                .iter()
+                .filter_map(|(key, _)| key.len().checked_sub(8).map(|end| &key[..end]))
+                .map(ActorId::from)
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect()
+        })
+    }
+
+    /// Insert all of the given changes in one transaction.
+    fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let (added, replaced) = self.write(CHANGES_BUCKET, |bucket| {
+            let mut added = 0;
+            let mut replaced = 0;
+            for (actor_id, seq, change) in changes {
+                added += change.len() as u64;
+                replaced += put(bucket, &change_key(&actor_id, seq), change)?;
+            }
+            Ok((added, replaced))
+        })?;
+        self.update_sizes(|sizes| sizes.changes = (sizes.changes + added).saturating_sub(replaced));
+        Ok(())
+    }
+
+    /// Remove all of the given changes in one transaction.
+    fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let removed = self.write(CHANGES_BUCKET, |bucket| {
+            let mut removed = 0;
+            for (actor_id, seq) in changes {
+                removed += delete(bucket, &change_key(actor_id, seq))?;
+            }
+            Ok(removed)
+        })?;
+        self.update_sizes(|sizes| sizes.changes = sizes.changes.saturating_sub(removed));
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.read(DOCUMENT_BUCKET, |bucket| {
+            bucket.get_kv(DOCUMENT_KEY).map(|kv| kv.value().to_vec())
+        })
+    }
+
+    fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let len = data.len() as u64;
+        self.write(DOCUMENT_BUCKET, |bucket| put(bucket, DOCUMENT_KEY, data))?;
+        self.update_sizes(|sizes| sizes.document = len);
+        Ok(())
+    }
+
+    /// Set the document and remove the changes in one transaction, versions aren't tracked so
+    /// `expected` is ignored.
+    // the transaction has to outlive the buckets borrowed from it
+    #[allow(clippy::significant_drop_tightening)]
+    fn compact_if(
+        &self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let _ = expected;
+        let len = data.len() as u64;
+        let tx = self.db.tx(true)?;
+        let removed = {
+            let root = tx.get_bucket(self.name.as_str())?;
+            let bucket = root.get_bucket(CHANGES_BUCKET)?;
+            let mut removed = 0;
+            for (actor_id, seq) in changes {
+                removed += delete(&bucket, &change_key(actor_id, seq))?;
+            }
+            put(&root.get_bucket(DOCUMENT_BUCKET)?, DOCUMENT_KEY, data)?;
+            removed
+        };
+        tx.commit()?;
+        self.update_sizes(|sizes| {
+            sizes.document = len;
+            sizes.changes = sizes.changes.saturating_sub(removed);
+        });
+        Ok(Ok(None))
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.read(SYNC_STATES_BUCKET, |bucket| {
+            bucket.get_kv(peer_id).map(|kv| kv.value().to_vec())
+        })
+    }
+
+    fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let len = sync_state.len() as u64;
+        let old = self.write(SYNC_STATES_BUCKET, |bucket| {
+            put(bucket, &peer_id, sync_state)
+        })?;
+        self.update_sizes(|sizes| {
+            sizes.sync_states = (sizes.sync_states + len).saturating_sub(old);
+        });
+        Ok(())
+    }
+
+    fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let removed = self.write(SYNC_STATES_BUCKET, |bucket| {
+            let mut removed = 0;
+            for peer_id in peer_ids {
+                removed += delete(bucket, peer_id)?;
+            }
+            Ok(removed)
+        })?;
+        self.update_sizes(|sizes| sizes.sync_states = sizes.sync_states.saturating_sub(removed));
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.read(SYNC_STATES_BUCKET, |bucket| {
+            pairs(bucket).into_iter().map(|(k, _)| k).collect()
+        })
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.read(METADATA_BUCKET, |bucket| {
+            bucket.get_kv(key).map(|kv| kv.value().to_vec())
+        })
+    }
+
+    fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let len = value.len() as u64;
+        let old = self.write(METADATA_BUCKET, |bucket| put(bucket, &key, value))?;
+        self.update_sizes(|sizes| sizes.metadata = (sizes.metadata + len).saturating_sub(old));
+        Ok(())
+    }
+
+    fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+        let removed = self.write(METADATA_BUCKET, |bucket| delete(bucket, key))?;
+        self.update_sizes(|sizes| sizes.metadata = sizes.metadata.saturating_sub(removed));
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.read(METADATA_BUCKET, |bucket| {
+            pairs(bucket).into_iter().map(|(k, _)| k).collect()
+        })
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        lock(&self.sizes).clone()
+    }
+
+    /// Every committed transaction is already synced to the file so there is nothing to flush.
+    fn flush(&self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{transaction::Transactable, ROOT};
+    use automerge_persistent::PersistentAutomerge;
+
+    use super::{JammdbPersister, DOCUMENT_BUCKET, DOCUMENT_KEY};
+    use crate::SharedPersister;
+
+    fn open(dir: &tempfile::TempDir) -> JammdbPersister {
+        let db = jammdb::DB::open(dir.path().join("documents.db")).unwrap();
+        JammdbPersister::new(db, "doc").unwrap()
+    }
+
+    fn edit<P: automerge_persistent::Persister + 'static>(doc: &mut PersistentAutomerge<P>) {
+        doc.transact::<_, _, std::convert::Infallible>(|tx| {
+            tx.put(ROOT, "a", 1).unwrap();
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn compaction_is_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut doc = PersistentAutomerge::load(open(&dir)).unwrap();
+        edit(&mut doc);
+        doc.compact(&[]).unwrap();
+        drop(doc);
+
+        let persister = open(&dir);
+        assert!(SharedPersister::get_document(&persister).unwrap().is_some());
+        assert!(SharedPersister::get_changes(&persister).unwrap().is_empty());
+        assert_eq!(SharedPersister::sizes(&persister).changes, 0);
+    }
+
+    #[test]
+    fn failed_compaction_keeps_the_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut doc = PersistentAutomerge::load(open(&dir)).unwrap();
+        edit(&mut doc);
+        // a bucket in place of the document fails the write after the changes are removed
+        {
+            let tx = doc.persister().db().tx(true).unwrap();
+            tx.get_bucket("doc")
+                .unwrap()
+                .get_bucket(DOCUMENT_BUCKET)
+                .unwrap()
+                .create_bucket(DOCUMENT_KEY)
+                .unwrap();
+            tx.commit().unwrap();
+        }
+        assert!(doc.compact(&[]).is_err());
+        drop(doc);
+
+        let persister = open(&dir);
+        assert_eq!(SharedPersister::get_changes(&persister).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{BaseConsumer, Consumer},
+    error::{KafkaError, RDKafkaErrorCode},
+    message::Message,
+    producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext},
+    ClientContext, Offset, TopicPartitionList,
+};
+
+use crate::{Record, ReplayTopic, Topic};
+
+/// How long to wait for the brokers by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The consumer group replaying uses if the config doesn't give one.
+const REPLAY_GROUP: &str = "automerge-persistent-replay";
+
+/// Possible errors from an [`RdKafkaTopic`].
+#[derive(Debug, thiserror::Error)]
+pub enum RdKafkaTopicError {
+    /// Internal errors from rdkafka, including records that failed to be delivered.
+    #[error(transparent)]
+    KafkaError(#[from] KafkaError),
+    /// Replaying didn't reach the end offsets of the topic in time.
+    #[error("timed out replaying the topic")]
+    ReplayTimedOut,
+}
+
+/// Keeps the first delivery failure since the last flush, as the producer reports them in the
+/// background.
+#[derive(Debug, Default)]
+pub struct DeliveryContext {
+    failure: Mutex<Option<KafkaError>>,
+}
+
+impl ClientContext for DeliveryContext {}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, (): Self::DeliveryOpaque) {
+        if let Err((error, _)) = result {
+            self.failure
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_or_insert_with(|| error.clone());
+        }
+    }
+}
+
+/// A [`Topic`] produced to and replayed through [rdkafka](https://github.com/fede1024/rust-rdkafka).
+///
+/// Records are produced keyed so each key stays in one partition, keeping its records in order,
+/// and tombstones are produced without a payload. [`Topic::flush`] waits for every record to be
+/// acknowledged, returning the first that failed to be delivered.
+///
+/// Replaying fetches the low and high offsets of each partition and reads from the low offset
+/// until the last record before the high one, so records produced while replaying aren't waited
+/// for. The consumer is assigned its partitions directly rather than subscribing and never
+/// commits offsets, so it doesn't disturb the offsets of other consumers in its group.
+///
+/// ```rust,no_run
+/// # use automerge_persistent::PersistentAutomerge;
+/// # use automerge_persistent_kafka::{KafkaPersister, RdKafkaTopic};
+/// # use rdkafka::ClientConfig;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut config = ClientConfig::new();
+/// config.set("bootstrap.servers", "localhost:9092");
+/// let persister = KafkaPersister::open(
+///     RdKafkaTopic::new(&config, "my-document.changes")?,
+///     RdKafkaTopic::new(&config, "my-document.snapshots")?,
+/// )?;
+/// let doc = PersistentAutomerge::load(persister)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct RdKafkaTopic {
+    topic: String,
+    config: ClientConfig,
+    producer: BaseProducer<DeliveryContext>,
+    timeout: Duration,
+}
+
+impl std::fmt::Debug for RdKafkaTopic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RdKafkaTopic")
+            .field("topic", &self.topic)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RdKafkaTopic {
+    /// Create a producer for the topic with the config, which is also used for the consumer
+    /// when replaying.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the producer could not be created from the config.
+    pub fn new<S>(config: &ClientConfig, topic: S) -> Result<Self, RdKafkaTopicError>
+    where
+        S: Into<String>,
+    {
+        let producer = config.create_with_context(DeliveryContext::default())?;
+        Ok(Self {
+            topic: topic.into(),
+            config: config.clone(),
+            producer,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Wait at most this long for the brokers when flushing or replaying, rather than 30
+    /// seconds.
+    #[must_use]
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The name of the topic.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.topic
+    }
+
+    /// The partitions of the topic with their low and high offsets, skipping empty ones.
+    fn partition_offsets(
+        &self,
+        consumer: &BaseConsumer,
+    ) -> Result<Vec<(i32, i64, i64)>, RdKafkaTopicError> {
+        let metadata = consumer.fetch_metadata(Some(&self.topic), self.timeout)?;
+        let mut offsets = Vec::new();
+        for topic in metadata.topics() {
+            if let Some(error) = topic.error() {
+                return Err(KafkaError::MetadataFetch(RDKafkaErrorCode::from(error)).into());
+            }
+            for partition in topic.partitions() {
+                let (low, high) =
+                    consumer.fetch_watermarks(&self.topic, partition.id(), self.timeout)?;
+                if high > low {
+                    offsets.push((partition.id(), low, high));
+                }
+            }
+        }
+        Ok(offsets)
+    }
+}
+
+impl Topic for RdKafkaTopic {
+    type Error = RdKafkaTopicError;
+
+    fn produce(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<(), Self::Error> {
+        let mut record = BaseRecord::<[u8], [u8]>::to(&self.topic).key(key.as_slice());
+        if let Some(value) = &value {
+            record = record.payload(value.as_slice());
+        }
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => return Ok(()),
+                // serve the deliveries so the queue drains, then try again
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) => {
+                    record = returned;
+                    self.producer.poll(Duration::from_millis(100));
+                }
+                Err((error, _)) => return Err(error.into()),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.producer.flush(self.timeout)?;
+        let failure = self
+            .producer
+            .context()
+            .failure
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        failure.map_or(Ok(()), |error| Err(error.into()))
+    }
+}
+
+impl ReplayTopic for RdKafkaTopic {
+    fn replay(&mut self) -> Result<Vec<Record>, Self::Error> {
+        let mut config = self.config.clone();
+        // librdkafka needs a group to assign partitions, though nothing is committed to it
+        if config.get("group.id").is_none() {
+            config.set("group.id", REPLAY_GROUP);
+        }
+        let consumer: BaseConsumer = config
+            .set("enable.auto.commit", "false")
+            .set("enable.partition.eof", "false")
+            .create()?;
+        let mut remaining = self.partition_offsets(&consumer)?;
+        let mut assignment = TopicPartitionList::new();
+        for (partition, low, _) in &remaining {
+            assignment.add_partition_offset(&self.topic, *partition, Offset::Offset(*low))?;
+        }
+        consumer.assign(&assignment)?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut records = Vec::new();
+        while !remaining.is_empty() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(RdKafkaTopicError::ReplayTimedOut);
+            }
+            let Some(message) = consumer.poll(timeout) else {
+                continue;
+            };
+            let message = message?;
+            let Some(index) = remaining
+                .iter()
+                .position(|(partition, _, _)| *partition == message.partition())
+            else {
+                continue;
+            };
+            if message.offset() >= remaining[index].2 {
+                continue;
+            }
+            if let Some(key) = message.key() {
+                records.push((key.to_vec(), message.payload().map(<[u8]>::to_vec)));
+            }
+            // compaction can leave gaps, so the last record may be anywhere before the end
+            if message.offset() + 1 >= remaining[index].2 {
+                remaining.swap_remove(index);
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{transaction::Transactable, ROOT};
+    use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    use rdkafka::{mocking::MockCluster, ClientConfig};
+
+    use super::RdKafkaTopic;
+    use crate::{KafkaPersister, KafkaTee, Persister, ReplayTopic};
+
+    fn config(cluster: &MockCluster<'_, impl rdkafka::producer::ProducerContext>) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", cluster.bootstrap_servers());
+        config
+    }
+
+    fn edit<P: Persister + 'static>(doc: &mut PersistentAutomerge<P>, key: &str) {
+        doc.transact::<_, _, std::convert::Infallible>(|tx| {
+            tx.put(ROOT, key, 1).unwrap();
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn persister_replays_its_topics() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("changes", 3, 1).unwrap();
+        cluster.create_topic("snapshots", 1, 1).unwrap();
+        let config = config(&cluster);
+        let topics = || {
+            (
+                RdKafkaTopic::new(&config, "changes").unwrap(),
+                RdKafkaTopic::new(&config, "snapshots").unwrap(),
+            )
+        };
+
+        let (changes, snapshots) = topics();
+        let mut doc =
+            PersistentAutomerge::load(KafkaPersister::open(changes, snapshots).unwrap()).unwrap();
+        edit(&mut doc, "a");
+        doc.compact(&[]).unwrap();
+        edit(&mut doc, "b");
+        doc.flush().unwrap();
+        let heads = doc.document().get_heads();
+        drop(doc);
+
+        let (changes, snapshots) = topics();
+        let doc =
+            PersistentAutomerge::load(KafkaPersister::open(changes, snapshots).unwrap()).unwrap();
+        assert_eq!(doc.document().get_heads(), heads);
+        assert_eq!(doc.persister().get_changes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn tee_produces_changes() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("changes", 1, 1).unwrap();
+        let config = config(&cluster);
+
+        let topic = RdKafkaTopic::new(&config, "changes").unwrap();
+        let mut doc =
+            PersistentAutomerge::load(KafkaTee::new(MemoryPersister::default(), topic)).unwrap();
+        edit(&mut doc, "a");
+        edit(&mut doc, "b");
+        doc.flush().unwrap();
+
+        let mut topic = RdKafkaTopic::new(&config, "changes").unwrap();
+        assert_eq!(topic.replay().unwrap().len(), 2);
+    }
+}
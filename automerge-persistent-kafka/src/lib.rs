@@ -0,0 +1,523 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! Treat the changes of a document as an event stream in [Kafka](https://kafka.apache.org).
+//!
+//! There are two ways to use it:
+//!
+//! - [`KafkaPersister`] keeps everything in Kafka. Changes are appended to a change topic and
+//!   the document, sync states and metadata to a snapshot topic, which should be compacted so
+//!   only the latest value of each key is kept. Opening it replays both topics.
+//! - [`KafkaTee`] wraps another persister, appending each change it stores to a topic so that
+//!   pipelines downstream can consume them while the persister stays the source of truth.
+//!
+//! Topics are reached through the [`Topic`] trait rather than a particular client. With the
+//! `rdkafka` feature [`RdKafkaTopic`] produces to and replays a topic through librdkafka, and
+//! [`MemoryTopic`] stands in for tests.
+//!
+//! ```rust
+//! # use automerge::{transaction::Transactable, ROOT};
+//! # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+//! # use automerge_persistent_kafka::{KafkaTee, MemoryTopic};
+//! let persister = KafkaTee::new(MemoryPersister::default(), MemoryTopic::default());
+//! let mut doc = PersistentAutomerge::load(persister).unwrap();
+//! doc.transact::<_, _, std::convert::Infallible>(|tx| {
+//!     tx.put(ROOT, "a", 1).unwrap();
+//!     Ok(())
+//! })
+//! .unwrap();
+//! assert_eq!(doc.persister().topic().records().len(), 1);
+//! ```
+
+#[cfg(feature = "rdkafka")]
+mod client;
+
+use std::{collections::HashMap, convert::Infallible, error::Error};
+
+use automerge::{ActorId, ChangeHash};
+use automerge_persistent::{
+    forward_persister, DocumentVersion, Forward, Persister, StoredSizes, VersionConflict,
+    VersionedDocument,
+};
+#[cfg(feature = "rdkafka")]
+pub use client::{DeliveryContext, RdKafkaTopic, RdKafkaTopicError};
+
+/// A record of a topic, with a value of `None` being a tombstone that lets a compacted topic
+/// drop the key.
+pub type Record = (Vec<u8>, Option<Vec<u8>>);
+
+/// The key the document is stored under in the snapshot topic.
+pub const DOCUMENT_KEY: &[u8] = b"document";
+/// The prefix of the keys sync states are stored under in the snapshot topic.
+pub const SYNC_STATE_PREFIX: &[u8] = b"sync_state/";
+/// The prefix of the keys metadata is stored under in the snapshot topic.
+pub const METADATA_PREFIX: &[u8] = b"metadata/";
+
+/// A topic records can be produced to.
+pub trait Topic {
+    /// The error type that producing can give.
+    type Error: Error + 'static;
+
+    /// Append a record to the topic.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the record could not be queued to send.
+    fn produce(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<(), Self::Error>;
+
+    /// Wait for the records produced so far to be acknowledged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the records could not be delivered.
+    fn flush(&mut self) -> Result<(), Self::Error>;
+}
+
+/// A topic that can also be read back from the start, as needed by [`KafkaPersister`].
+pub trait ReplayTopic: Topic {
+    /// Read every record remaining in the topic, oldest first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the topic could not be consumed.
+    fn replay(&mut self) -> Result<Vec<Record>, Self::Error>;
+}
+
+/// A topic held in memory, for tests.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryTopic {
+    records: Vec<Record>,
+}
+
+impl MemoryTopic {
+    /// The records in the topic, oldest first.
+    #[must_use]
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Compact the topic as Kafka would, keeping only the latest record of each key and dropping
+    /// the keys whose latest record is a tombstone.
+    pub fn compact(&mut self) {
+        let mut latest = HashMap::new();
+        for (index, (key, _)) in self.records.iter().enumerate() {
+            latest.insert(key.clone(), index);
+        }
+        let mut index = 0;
+        self.records.retain(|(key, value)| {
+            let keep = value.is_some() && latest.get(key) == Some(&index);
+            index += 1;
+            keep
+        });
+    }
+}
+
+impl Topic for MemoryTopic {
+    type Error = Infallible;
+
+    fn produce(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<(), Self::Error> {
+        self.records.push((key, value));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl ReplayTopic for MemoryTopic {
+    fn replay(&mut self) -> Result<Vec<Record>, Self::Error> {
+        Ok(self.records.clone())
+    }
+}
+
+/// Make the key of a change in the change topic from the `actor_id` and `sequence_number`.
+fn change_key(actor_id: &ActorId, seq: u64) -> Vec<u8> {
+    let mut key = actor_id.to_bytes().to_vec();
+    key.extend(&seq.to_be_bytes());
+    key
+}
+
+/// Make the key of a record in the snapshot topic.
+fn prefixed(prefix: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut prefixed = prefix.to_vec();
+    prefixed.extend(key);
+    prefixed
+}
+
+/// A persister that keeps the document entirely in Kafka.
+///
+/// Changes are appended to the change topic keyed by their actor and sequence number, and
+/// removed ones are tombstoned. The document, sync states and metadata are appended to the
+/// snapshot topic. Both should be compacted topics so that tombstoned and superseded records are
+/// eventually dropped, with the snapshot topic keeping the latest compacted document to load
+/// from rather than the whole history.
+///
+/// The contents are replayed into memory when opened and reads are served from there, so a
+/// single process should write to the topics of a document.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::PersistentAutomerge;
+/// # use automerge_persistent_kafka::{KafkaPersister, MemoryTopic};
+/// let persister = KafkaPersister::open(MemoryTopic::default(), MemoryTopic::default()).unwrap();
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+/// doc.compact(&[]).unwrap();
+///
+/// let (mut changes, mut snapshots) = doc.close().unwrap().into_topics();
+/// changes.compact();
+/// snapshots.compact();
+/// assert!(changes.records().is_empty());
+///
+/// let doc = PersistentAutomerge::load(KafkaPersister::open(changes, snapshots).unwrap()).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 1);
+/// ```
+#[derive(Debug)]
+pub struct KafkaPersister<T> {
+    change_topic: T,
+    snapshot_topic: T,
+    changes: HashMap<Vec<u8>, Vec<u8>>,
+    document: Option<Vec<u8>>,
+    document_generation: u64,
+    sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    metadata: HashMap<Vec<u8>, Vec<u8>>,
+    sizes: StoredSizes,
+}
+
+/// Apply a replayed record to a map of the latest values.
+fn apply_record(map: &mut HashMap<Vec<u8>, Vec<u8>>, key: Vec<u8>, value: Option<Vec<u8>>) {
+    match value {
+        Some(value) => {
+            map.insert(key, value);
+        }
+        None => {
+            map.remove(&key);
+        }
+    }
+}
+
+/// The total length of the values of a map.
+fn values_size(map: &HashMap<Vec<u8>, Vec<u8>>) -> u64 {
+    map.values().map(|v| v.len() as u64).sum()
+}
+
+impl<T> KafkaPersister<T>
+where
+    T: ReplayTopic,
+{
+    /// Open the document kept in the topics, replaying them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either topic could not be replayed.
+    pub fn open(mut change_topic: T, mut snapshot_topic: T) -> Result<Self, T::Error> {
+        let mut changes = HashMap::new();
+        for (key, value) in change_topic.replay()? {
+            apply_record(&mut changes, key, value);
+        }
+        let mut document = None;
+        let mut sync_states = HashMap::new();
+        let mut metadata = HashMap::new();
+        for (key, value) in snapshot_topic.replay()? {
+            if key == DOCUMENT_KEY {
+                document = value;
+            } else if let Some(peer_id) = key.strip_prefix(SYNC_STATE_PREFIX) {
+                apply_record(&mut sync_states, peer_id.to_vec(), value);
+            } else if let Some(key) = key.strip_prefix(METADATA_PREFIX) {
+                apply_record(&mut metadata, key.to_vec(), value);
+            }
+        }
+        let sizes = StoredSizes {
+            changes: values_size(&changes),
+            document: document.as_ref().map_or(0, |d: &Vec<u8>| d.len() as u64),
+            sync_states: values_size(&sync_states),
+            metadata: values_size(&metadata),
+        };
+        Ok(Self {
+            change_topic,
+            snapshot_topic,
+            changes,
+            document,
+            document_generation: 0,
+            sync_states,
+            metadata,
+            sizes,
+        })
+    }
+}
+
+impl<T> KafkaPersister<T> {
+    /// Get references to the change and snapshot topics.
+    pub const fn topics(&self) -> (&T, &T) {
+        (&self.change_topic, &self.snapshot_topic)
+    }
+
+    /// Take the change and snapshot topics back out.
+    pub fn into_topics(self) -> (T, T) {
+        (self.change_topic, self.snapshot_topic)
+    }
+
+    /// The version of the document held, changing each time it is set.
+    fn document_version(&self) -> Option<DocumentVersion> {
+        self.document
+            .as_ref()
+            .map(|_| DocumentVersion(self.document_generation.to_be_bytes().to_vec()))
+    }
+}
+
+impl<T> Persister for KafkaPersister<T>
+where
+    T: Topic,
+{
+    type Error = T::Error;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.changes.values().cloned().collect())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        for (actor_id, seq, change) in changes {
+            let key = change_key(&actor_id, seq);
+            self.change_topic
+                .produce(key.clone(), Some(change.clone()))?;
+            self.sizes.changes += change.len() as u64;
+            if let Some(old) = self.changes.insert(key, change) {
+                self.sizes.changes -= old.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        for (actor_id, seq) in changes {
+            let key = change_key(actor_id, seq);
+            if let Some(old) = self.changes.remove(&key) {
+                self.change_topic.produce(key, None)?;
+                self.sizes.changes -= old.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.document.clone())
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.snapshot_topic
+            .produce(DOCUMENT_KEY.to_vec(), Some(data.clone()))?;
+        self.sizes.document = data.len() as u64;
+        self.document = Some(data);
+        self.document_generation += 1;
+        Ok(())
+    }
+
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        Ok((self.document.clone(), self.document_version()))
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let current = self.document_version();
+        if expected.is_some() && expected != current.as_ref() {
+            return Ok(Err(VersionConflict { current }));
+        }
+        self.set_document(data)?;
+        Ok(Ok(self.document_version()))
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.get(peer_id).cloned())
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.snapshot_topic.produce(
+            prefixed(SYNC_STATE_PREFIX, &peer_id),
+            Some(sync_state.clone()),
+        )?;
+        self.sizes.sync_states += sync_state.len() as u64;
+        if let Some(old) = self.sync_states.insert(peer_id, sync_state) {
+            self.sizes.sync_states -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for peer_id in peer_ids {
+            if let Some(old) = self.sync_states.remove(*peer_id) {
+                self.snapshot_topic
+                    .produce(prefixed(SYNC_STATE_PREFIX, peer_id), None)?;
+                self.sizes.sync_states -= old.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.keys().cloned().collect())
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.get(key).cloned())
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.snapshot_topic
+            .produce(prefixed(METADATA_PREFIX, &key), Some(value.clone()))?;
+        self.sizes.metadata += value.len() as u64;
+        if let Some(old) = self.metadata.insert(key, value) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        if let Some(old) = self.metadata.remove(key) {
+            self.snapshot_topic
+                .produce(prefixed(METADATA_PREFIX, key), None)?;
+            self.sizes.metadata -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.keys().cloned().collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.change_topic.flush()?;
+        self.snapshot_topic.flush()?;
+        Ok(0)
+    }
+}
+
+/// Possible errors from a [`KafkaTee`].
+#[derive(Debug, thiserror::Error)]
+pub enum KafkaTeeError<P, T>
+where
+    P: Error + 'static,
+    T: Error + 'static,
+{
+    /// The wrapped persister failed.
+    #[error(transparent)]
+    PersisterError(P),
+    /// Producing the changes to the topic failed, after they were stored.
+    #[error("producing to the topic: {0}")]
+    TopicError(T),
+}
+
+/// A persister that appends each change stored by another persister to a topic.
+///
+/// Changes are produced once the wrapped persister has stored them, keyed by their actor and
+/// sequence number, or by their hash if the persister is content-addressed. Nothing else is
+/// produced, so removing changes in a compaction leaves them in the topic for consumers that
+/// haven't read them yet.
+///
+/// A change that was stored but failed to be produced gives a [`KafkaTeeError::TopicError`], so
+/// consumers may miss changes that are in storage, but never see changes that aren't.
+#[derive(Debug)]
+pub struct KafkaTee<P, T> {
+    inner: P,
+    topic: T,
+}
+
+impl<P, T> KafkaTee<P, T> {
+    /// Wrap the persister, producing its changes to the topic.
+    pub const fn new(inner: P, topic: T) -> Self {
+        Self { inner, topic }
+    }
+
+    /// Get a reference to the wrapped persister.
+    pub const fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Get a reference to the topic.
+    pub const fn topic(&self) -> &T {
+        &self.topic
+    }
+
+    /// Take the wrapped persister and the topic back out.
+    pub fn into_parts(self) -> (P, T) {
+        (self.inner, self.topic)
+    }
+}
+
+impl<P, T> Forward for KafkaTee<P, T>
+where
+    P: Persister,
+    T: Topic,
+{
+    type Inner = P;
+    type Error = KafkaTeeError<P::Error, T::Error>;
+
+    fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn map_error(error: P::Error) -> Self::Error {
+        KafkaTeeError::PersisterError(error)
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let records = changes
+            .iter()
+            .map(|(actor_id, seq, change)| (change_key(actor_id, *seq), change.clone()))
+            .collect::<Vec<_>>();
+        self.inner
+            .insert_changes(changes)
+            .map_err(KafkaTeeError::PersisterError)?;
+        for (key, change) in records {
+            self.topic
+                .produce(key, Some(change))
+                .map_err(KafkaTeeError::TopicError)?;
+        }
+        Ok(())
+    }
+
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        let records = changes
+            .iter()
+            .map(|(hash, change)| (hash.0.to_vec(), change.clone()))
+            .collect::<Vec<_>>();
+        self.inner
+            .insert_changes_by_hash(changes)
+            .map_err(KafkaTeeError::PersisterError)?;
+        for (key, change) in records {
+            self.topic
+                .produce(key, Some(change))
+                .map_err(KafkaTeeError::TopicError)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        let flushed = self.inner.flush().map_err(KafkaTeeError::PersisterError)?;
+        self.topic.flush().map_err(KafkaTeeError::TopicError)?;
+        Ok(flushed)
+    }
+}
+
+forward_persister!(impl<P, T> for KafkaTee<P, T> where P: Persister, T: Topic);
@@ -8,8 +8,7 @@
 //!
 //! ```rust,no_run
 //! # use automerge_persistent_localstorage::{LocalStoragePersister, LocalStoragePersisterError};
-//! # use automerge_persistent::PersistentBackend;
-//! # use automerge::Backend;
+//! # use automerge_persistent::PersistentAutomerge;
 //! # fn main() -> Result<(), LocalStoragePersisterError> {
 //! let storage = web_sys::window()
 //!     .unwrap()
@@ -17,8 +16,14 @@
 //!     .map_err(LocalStoragePersisterError::StorageError)?
 //!     .unwrap();
 //!
-//! let persister = LocalStoragePersister::new(storage, "document".to_owned(), "changes".to_owned(), "sync-states".to_owned())?;
-//! let backend = PersistentBackend::<_, Backend>::load(persister).unwrap();
+//! let persister = LocalStoragePersister::new(
+//!     storage,
+//!     "document".to_owned(),
+//!     "changes".to_owned(),
+//!     "sync-states".to_owned(),
+//!     "metadata".to_owned(),
+//! )?;
+//! let doc = PersistentAutomerge::load(persister).unwrap();
 //! # Ok(())
 //! # }
 //! ```
@@ -26,7 +31,7 @@
 use std::collections::HashMap;
 
 use automerge::ActorId;
-use automerge_persistent::{Persister, StoredSizes};
+use automerge_persistent_core::{Persister, StoredSizes};
 
 /// Persist changes and documents in to `LocalStorage`.
 ///
@@ -38,11 +43,14 @@ use automerge_persistent::{Persister, StoredSizes};
 pub struct LocalStoragePersister {
     storage: web_sys::Storage,
     changes: HashMap<String, Vec<u8>>,
-    /// Base64 encoded peer_ids are used for the keys so they can be serialized to json.
+    /// Base64 encoded `peer_ids` are used for the keys so they can be serialized to json.
     sync_states: HashMap<String, Vec<u8>>,
+    /// Base64 encoded metadata keys, as for the sync states.
+    metadata: HashMap<String, Vec<u8>>,
     document_key: String,
     changes_key: String,
     sync_states_key: String,
+    metadata_key: String,
     sizes: StoredSizes,
 }
 
@@ -59,11 +67,17 @@ pub enum LocalStoragePersisterError {
 
 impl LocalStoragePersister {
     /// Construct a new `LocalStoragePersister`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the existing changes, sync states or metadata could not be read from the
+    /// storage.
     pub fn new(
         storage: web_sys::Storage,
         document_key: String,
         changes_key: String,
         sync_states_key: String,
+        metadata_key: String,
     ) -> Result<Self, LocalStoragePersisterError> {
         let changes = if let Some(stored) = storage
             .get_item(&changes_key)
@@ -81,6 +95,14 @@ impl LocalStoragePersister {
         } else {
             HashMap::new()
         };
+        let metadata = if let Some(stored) = storage
+            .get_item(&metadata_key)
+            .map_err(LocalStoragePersisterError::StorageError)?
+        {
+            serde_json::from_str(&stored)?
+        } else {
+            HashMap::new()
+        };
         let document = if let Some(doc_string) = storage
             .get_item(&document_key)
             .map_err(LocalStoragePersisterError::StorageError)?
@@ -94,14 +116,17 @@ impl LocalStoragePersister {
             changes: changes.values().map(Vec::len).sum::<usize>() as u64,
             document: document.unwrap_or_default().len() as u64,
             sync_states: sync_states.values().map(Vec::len).sum::<usize>() as u64,
+            metadata: metadata.values().map(Vec::len).sum::<usize>() as u64,
         };
         Ok(Self {
             storage,
             changes,
             sync_states,
+            metadata,
             document_key,
             changes_key,
             sync_states_key,
+            metadata_key,
             sizes,
         })
     }
@@ -214,6 +239,42 @@ impl Persister for LocalStoragePersister {
             .collect())
     }
 
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let key = base64::encode(key);
+        Ok(self.metadata.get(&key).cloned())
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.sizes.metadata += value.len() as u64;
+        let key = base64::encode(key);
+        if let Some(old) = self.metadata.insert(key, value) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        self.storage
+            .set_item(&self.metadata_key, &serde_json::to_string(&self.metadata)?)
+            .map_err(LocalStoragePersisterError::StorageError)?;
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        let key = base64::encode(key);
+        if let Some(old) = self.metadata.remove(&key) {
+            self.sizes.metadata -= old.len() as u64;
+            self.storage
+                .set_item(&self.metadata_key, &serde_json::to_string(&self.metadata)?)
+                .map_err(LocalStoragePersisterError::StorageError)?;
+        }
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .metadata
+            .keys()
+            .map(|key| base64::decode(key).expect("Failed to base64 decode the metadata key"))
+            .collect())
+    }
+
     fn sizes(&self) -> StoredSizes {
         self.sizes.clone()
     }
@@ -0,0 +1,315 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! [Prometheus](https://prometheus.io) metrics for persisting documents, for server deployments.
+//!
+//! [`PersisterMetrics`] registers the metrics with a [`prometheus::Registry`]. Wrapping a
+//! persister with it counts the changes persisted and the bytes written, and the document loads
+//! and compactions it is given to time are recorded as histograms.
+//!
+//! ```rust
+//! # use automerge::{transaction::Transactable, ROOT};
+//! # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Stack};
+//! # use automerge_persistent_metrics::PersisterMetrics;
+//! let registry = prometheus::Registry::new();
+//! let metrics = PersisterMetrics::register(&registry).unwrap();
+//!
+//! let persister = Stack::new(MemoryPersister::default())
+//!     .layer(metrics.clone())
+//!     .into_persister();
+//! let mut doc = metrics
+//!     .time_load(|| PersistentAutomerge::load(persister))
+//!     .unwrap();
+//! doc.transact::<_, _, std::convert::Infallible>(|tx| {
+//!     tx.put(ROOT, "a", 1).unwrap();
+//!     Ok(())
+//! })
+//! .unwrap();
+//! metrics.time_compaction(|| doc.compact(&[])).unwrap();
+//!
+//! assert_eq!(metrics.changes_persisted().get(), 1);
+//! assert_eq!(metrics.compaction_duration().get_sample_count(), 1);
+//! assert!(registry
+//!     .gather()
+//!     .iter()
+//!     .any(|family| family.get_name() == "automerge_persistent_bytes_written_total"));
+//! ```
+
+use std::time::Instant;
+
+use automerge::{ActorId, ChangeHash};
+use automerge_persistent::{
+    forward_persister, DocumentVersion, Forward, Persister, PersisterLayer, VersionConflict,
+};
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry};
+
+/// The label value of [`PersisterMetrics::bytes_written`] for each kind of data.
+const CHANGES: &str = "changes";
+const DOCUMENT: &str = "document";
+const SYNC_STATES: &str = "sync_states";
+const METADATA: &str = "metadata";
+
+/// The metrics of persisters, registered with a [`Registry`].
+///
+/// This is cheap to clone, with clones updating the same metrics, so one instance can be shared
+/// between the persisters of many documents.
+#[derive(Debug, Clone)]
+pub struct PersisterMetrics {
+    changes_persisted: IntCounter,
+    bytes_written: IntCounterVec,
+    flush_duration: Histogram,
+    compaction_duration: Histogram,
+    load_duration: Histogram,
+}
+
+impl PersisterMetrics {
+    /// Create the metrics and register them with the registry.
+    ///
+    /// The metrics are named with an `automerge_persistent_` prefix, use
+    /// [`Registry::new_custom`] for a further prefix or labels to tell deployments apart.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if metrics with the same names are already registered.
+    pub fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let changes_persisted = IntCounter::new(
+            "automerge_persistent_changes_persisted_total",
+            "Changes written to persisters",
+        )?;
+        let bytes_written = IntCounterVec::new(
+            Opts::new(
+                "automerge_persistent_bytes_written_total",
+                "Bytes written to persisters, by the kind of data",
+            ),
+            &["kind"],
+        )?;
+        let flush_duration = Histogram::with_opts(HistogramOpts::new(
+            "automerge_persistent_flush_duration_seconds",
+            "Time taken to flush persisters",
+        ))?;
+        let compaction_duration = Histogram::with_opts(HistogramOpts::new(
+            "automerge_persistent_compaction_duration_seconds",
+            "Time taken to compact documents",
+        ))?;
+        let load_duration = Histogram::with_opts(HistogramOpts::new(
+            "automerge_persistent_load_duration_seconds",
+            "Time taken to load documents",
+        ))?;
+
+        registry.register(Box::new(changes_persisted.clone()))?;
+        registry.register(Box::new(bytes_written.clone()))?;
+        registry.register(Box::new(flush_duration.clone()))?;
+        registry.register(Box::new(compaction_duration.clone()))?;
+        registry.register(Box::new(load_duration.clone()))?;
+
+        Ok(Self {
+            changes_persisted,
+            bytes_written,
+            flush_duration,
+            compaction_duration,
+            load_duration,
+        })
+    }
+
+    /// Wrap the persister so that its writes update these metrics.
+    #[must_use]
+    pub fn wrap<P>(&self, persister: P) -> MetricsPersister<P> {
+        MetricsPersister {
+            inner: persister,
+            metrics: self.clone(),
+        }
+    }
+
+    /// Run the load of a document, recording how long it took.
+    pub fn time_load<T>(&self, load: impl FnOnce() -> T) -> T {
+        time(&self.load_duration, load)
+    }
+
+    /// Run the compaction of a document, recording how long it took.
+    pub fn time_compaction<T>(&self, compact: impl FnOnce() -> T) -> T {
+        time(&self.compaction_duration, compact)
+    }
+
+    /// The number of changes written to persisters.
+    #[must_use]
+    pub const fn changes_persisted(&self) -> &IntCounter {
+        &self.changes_persisted
+    }
+
+    /// The bytes written to persisters, labelled by `kind` as one of `changes`, `document`,
+    /// `sync_states` or `metadata`.
+    #[must_use]
+    pub const fn bytes_written(&self) -> &IntCounterVec {
+        &self.bytes_written
+    }
+
+    /// The time taken to flush persisters.
+    #[must_use]
+    pub const fn flush_duration(&self) -> &Histogram {
+        &self.flush_duration
+    }
+
+    /// The time taken by compactions given to [`Self::time_compaction`].
+    #[must_use]
+    pub const fn compaction_duration(&self) -> &Histogram {
+        &self.compaction_duration
+    }
+
+    /// The time taken by loads given to [`Self::time_load`].
+    #[must_use]
+    pub const fn load_duration(&self) -> &Histogram {
+        &self.load_duration
+    }
+
+    fn written(&self, kind: &str, bytes: usize) {
+        self.bytes_written
+            .with_label_values(&[kind])
+            .inc_by(bytes as u64);
+    }
+}
+
+impl<P> PersisterLayer<P> for PersisterMetrics
+where
+    P: Persister,
+{
+    type Persister = MetricsPersister<P>;
+
+    fn layer(self, inner: P) -> Self::Persister {
+        self.wrap(inner)
+    }
+}
+
+fn time<T>(histogram: &Histogram, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let out = f();
+    histogram.observe(start.elapsed().as_secs_f64());
+    out
+}
+
+/// A persister recording the writes made to an inner persister in [`PersisterMetrics`].
+///
+/// Only writes that succeed are counted.
+#[derive(Debug)]
+pub struct MetricsPersister<P> {
+    inner: P,
+    metrics: PersisterMetrics,
+}
+
+impl<P> MetricsPersister<P> {
+    /// Get the metrics being updated.
+    #[must_use]
+    pub const fn metrics(&self) -> &PersisterMetrics {
+        &self.metrics
+    }
+
+    /// Get a reference to the inner persister.
+    #[must_use]
+    pub const fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Take the inner persister back out.
+    #[must_use]
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P> Forward for MetricsPersister<P>
+where
+    P: Persister,
+{
+    type Inner = P;
+    type Error = P::Error;
+
+    fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn map_error(error: P::Error) -> Self::Error {
+        error
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let count = changes.len();
+        let bytes = changes.iter().map(|(_, _, c)| c.len()).sum();
+        self.inner.insert_changes(changes)?;
+        self.metrics.changes_persisted.inc_by(count as u64);
+        self.metrics.written(CHANGES, bytes);
+        Ok(())
+    }
+
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        let count = changes.len();
+        let bytes = changes.iter().map(|(_, c)| c.len()).sum();
+        self.inner.insert_changes_by_hash(changes)?;
+        self.metrics.changes_persisted.inc_by(count as u64);
+        self.metrics.written(CHANGES, bytes);
+        Ok(())
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let bytes = data.len();
+        self.inner.set_document(data)?;
+        self.metrics.written(DOCUMENT, bytes);
+        Ok(())
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let bytes = data.len();
+        let result = self.inner.set_document_if(data, expected)?;
+        if result.is_ok() {
+            self.metrics.written(DOCUMENT, bytes);
+        }
+        Ok(result)
+    }
+
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let bytes = data.len();
+        let result = self.inner.compact_if(data, expected, changes)?;
+        if result.is_ok() {
+            self.metrics.written(DOCUMENT, bytes);
+        }
+        Ok(result)
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let bytes = sync_state.len();
+        self.inner.set_sync_state(peer_id, sync_state)?;
+        self.metrics.written(SYNC_STATES, bytes);
+        Ok(())
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let bytes = value.len();
+        self.inner.set_metadata(key, value)?;
+        self.metrics.written(METADATA, bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        let inner = &mut self.inner;
+        time(&self.metrics.flush_duration, || inner.flush())
+    }
+}
+
+forward_persister!(impl<P> for MetricsPersister<P> where P: Persister);
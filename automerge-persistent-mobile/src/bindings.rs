@@ -0,0 +1,212 @@
+use std::{
+    convert::TryFrom,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use automerge::{sync, ChangeHash};
+use automerge_persistent::{Error, PersistentAutomerge};
+
+use crate::{MobilePersister, MobilePersisterError};
+
+/// Errors from a [`MobileDocument`], flattened to messages so they can cross the bindings.
+#[derive(Debug, thiserror::Error)]
+pub enum MobileError {
+    /// The storage failed.
+    #[error("storage error: {0}")]
+    Storage(String),
+    /// The document, or something given to it, was invalid.
+    #[error("automerge error: {0}")]
+    Automerge(String),
+    /// A previous call panicked while holding the document, leaving it in an unknown state.
+    #[error("document poisoned by a previous panic")]
+    Poisoned,
+}
+
+impl From<Error<MobilePersisterError>> for MobileError {
+    fn from(error: Error<MobilePersisterError>) -> Self {
+        match error {
+            Error::PersisterError(e) => Self::Storage(e.to_string()),
+            e => Self::Automerge(e.to_string()),
+        }
+    }
+}
+
+fn decode_heads(heads: &[Vec<u8>]) -> Result<Vec<ChangeHash>, MobileError> {
+    heads
+        .iter()
+        .map(|h| {
+            ChangeHash::try_from(h.as_slice()).map_err(|e| MobileError::Automerge(e.to_string()))
+        })
+        .collect()
+}
+
+/// A persistent document for use from Swift or Kotlin.
+///
+/// Everything crossing the API is plain bytes, strings and options, the document being shared as
+/// an `Arc` and locked internally, so it is `Send` and `Sync` and fits what uniffi generates
+/// bindings for: an object with constructor [`MobileDocument::open`] and the methods below, with
+/// [`MobileError`] as its error.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, Automerge, ROOT};
+/// # use automerge_persistent_mobile::MobileDocument;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let dir = tempfile::tempdir()?;
+/// let doc = MobileDocument::open(dir.path().join("doc").to_string_lossy().into_owned())?;
+/// let other = MobileDocument::open(dir.path().join("other").to_string_lossy().into_owned())?;
+///
+/// let mut edits = Automerge::new();
+/// edits.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+/// let changes = edits.get_changes(&[]).unwrap();
+/// doc.apply_changes(changes.into_iter().map(|c| c.raw_bytes().to_vec()).collect())?;
+///
+/// loop {
+///     let to_other = doc.generate_sync_message(b"other".to_vec())?;
+///     let to_doc = other.generate_sync_message(b"doc".to_vec())?;
+///     if to_other.is_none() && to_doc.is_none() {
+///         break;
+///     }
+///     if let Some(message) = to_other {
+///         other.receive_sync_message(b"doc".to_vec(), message)?;
+///     }
+///     if let Some(message) = to_doc {
+///         doc.receive_sync_message(b"other".to_vec(), message)?;
+///     }
+/// }
+/// assert_eq!(doc.heads()?, other.heads()?);
+///
+/// // the app is moving to the background
+/// doc.flush()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MobileDocument {
+    inner: Mutex<PersistentAutomerge<MobilePersister>>,
+}
+
+// uniffi passes arguments by value
+#[allow(clippy::needless_pass_by_value)]
+impl MobileDocument {
+    /// Open the document stored in the directory at the path, creating it if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stored document could not be read or loaded.
+    pub fn open(path: String) -> Result<Arc<Self>, MobileError> {
+        let persister = MobilePersister::open(PathBuf::from(path))
+            .map_err(|e| MobileError::Storage(e.to_string()))?;
+        let doc = PersistentAutomerge::load(persister)?;
+        Ok(Arc::new(Self {
+            inner: Mutex::new(doc),
+        }))
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, PersistentAutomerge<MobilePersister>>, MobileError> {
+        self.inner.lock().map_err(|_| MobileError::Poisoned)
+    }
+
+    /// The hashes of the document's heads.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the document is poisoned.
+    pub fn heads(&self) -> Result<Vec<Vec<u8>>, MobileError> {
+        Ok(self
+            .lock()?
+            .document()
+            .get_heads()
+            .into_iter()
+            .map(|h| h.0.to_vec())
+            .collect())
+    }
+
+    /// The changes made since the given heads, or all of them if there are none.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a head is not a change hash.
+    pub fn changes_since(&self, heads: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, MobileError> {
+        let heads = decode_heads(&heads)?;
+        let changes = self
+            .lock()?
+            .document()
+            .get_changes(&heads)
+            .map_err(|e| MobileError::Automerge(e.to_string()))?
+            .into_iter()
+            .map(|c| c.raw_bytes().to_vec())
+            .collect();
+        Ok(changes)
+    }
+
+    /// Persist and apply the encoded changes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a change could not be decoded or persisted.
+    pub fn apply_changes(&self, changes: Vec<Vec<u8>>) -> Result<(), MobileError> {
+        Ok(self.lock()?.apply_raw_changes(changes)?)
+    }
+
+    /// Save the whole document.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the document is poisoned.
+    pub fn save(&self) -> Result<Vec<u8>, MobileError> {
+        Ok(self.lock()?.document_mut().save())
+    }
+
+    /// Generate the next sync message for the peer, if there is anything to send.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the peer's sync state could not be loaded or persisted.
+    pub fn generate_sync_message(&self, peer_id: Vec<u8>) -> Result<Option<Vec<u8>>, MobileError> {
+        Ok(self
+            .lock()?
+            .generate_sync_message(peer_id)?
+            .map(sync::Message::encode))
+    }
+
+    /// Receive an encoded sync message from the peer.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message could not be decoded, or its changes persisted.
+    pub fn receive_sync_message(
+        &self,
+        peer_id: Vec<u8>,
+        message: Vec<u8>,
+    ) -> Result<(), MobileError> {
+        let message =
+            sync::Message::decode(&message).map_err(|e| MobileError::Automerge(e.to_string()))?;
+        Ok(self.lock()?.receive_sync_message(peer_id, message)?)
+    }
+
+    /// Save the document in place of its changes, keeping the sync states.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the document could not be persisted.
+    pub fn compact(&self) -> Result<(), MobileError> {
+        Ok(self.lock()?.compact(&[])?)
+    }
+
+    /// Make everything persisted so far durable, such as when the app moves to the background.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage could not be synced.
+    pub fn flush(&self) -> Result<(), MobileError> {
+        self.lock()?
+            .flush()
+            .map_err(|e| MobileError::Storage(e.to_string()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister for documents kept by iOS and Android apps.
+//!
+//! Mobile apps can be suspended and killed at any point with no warning, and have little memory
+//! to spare, so the [`MobilePersister`] keeps nothing but the stored sizes in memory and writes
+//! every record atomically: in to a temporary file that is synced and then renamed over the
+//! record. A record is always either the old or the new one, never torn.
+//!
+//! The renames are made durable by [`Persister::flush`], which only has to sync the directories
+//! written to so it is cheap enough to call from the app's lifecycle callbacks, such as when it
+//! moves to the background.
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_mobile::MobilePersister;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let dir = tempfile::tempdir()?;
+//! let persister = MobilePersister::open(dir.path().join("my-document"))?;
+//! let mut doc = PersistentAutomerge::load(persister)?;
+//!
+//! // the app is moving to the background
+//! doc.flush()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The [`MobileDocument`] wraps a document in an API for generating bindings to Swift and
+//! Kotlin with uniffi.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use automerge::ActorId;
+use automerge_persistent_core::{Persister, StoredSizes};
+
+mod bindings;
+
+pub use bindings::{MobileDocument, MobileError};
+
+const CHANGES_DIR: &str = "changes";
+const SYNC_STATES_DIR: &str = "sync_states";
+const METADATA_DIR: &str = "metadata";
+const DOCUMENT_FILE: &str = "document";
+/// Appended to the name of a record while it is being written.
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Persist changes and documents in to a directory, one file per record.
+///
+/// Changes are named by their hex encoded actor and sequence number in the `changes` directory,
+/// sync states and metadata by their hex encoded key in the `sync_states` and `metadata`
+/// directories, and the document is in the `document` file.
+#[derive(Debug)]
+pub struct MobilePersister {
+    root: PathBuf,
+    /// Directories with renames in them that have not been synced.
+    unsynced: HashSet<PathBuf>,
+    /// Bytes written since the last flush.
+    unflushed: usize,
+    sizes: StoredSizes,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum MobilePersisterError {
+    /// An underlying IO error.
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    /// A file was not named in the expected form.
+    #[error("invalid file name {0:?}")]
+    InvalidFileName(PathBuf),
+}
+
+fn change_name(actor_id: &ActorId, seq: u64) -> String {
+    format!("{}-{}", actor_id.to_hex_string(), seq)
+}
+
+/// The records in the directory, skipping any left half written.
+fn records(dir: &Path) -> Result<Vec<PathBuf>, MobilePersisterError> {
+    let mut records = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.to_string_lossy().ends_with(TEMP_SUFFIX) {
+            records.push(path);
+        }
+    }
+    Ok(records)
+}
+
+fn decode_name(path: &Path) -> Result<Vec<u8>, MobilePersisterError> {
+    path.file_name()
+        .and_then(|name| hex::decode(name.to_str()?).ok())
+        .ok_or_else(|| MobilePersisterError::InvalidFileName(path.to_owned()))
+}
+
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>, MobilePersisterError> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn file_len(path: &Path) -> Result<u64, MobilePersisterError> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Remove the temporary files left by writes that were interrupted, returning the total size of
+/// the records.
+fn clean_dir(dir: &Path) -> Result<u64, MobilePersisterError> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX) {
+            fs::remove_file(entry.path())?;
+        } else {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+impl MobilePersister {
+    /// Open the persister in the given directory, creating it if it does not exist.
+    ///
+    /// This removes any temporary files left by writes that were interrupted, such as by the app
+    /// being killed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directories could not be created or read.
+    pub fn open<R: AsRef<Path>>(root: R) -> Result<Self, MobilePersisterError> {
+        let root = root.as_ref().to_owned();
+        for dir in [CHANGES_DIR, SYNC_STATES_DIR, METADATA_DIR] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+        let document = root.join(DOCUMENT_FILE);
+        let mut temp = document.clone().into_os_string();
+        temp.push(TEMP_SUFFIX);
+        if let Err(e) = fs::remove_file(temp) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        let sizes = StoredSizes {
+            changes: clean_dir(&root.join(CHANGES_DIR))?,
+            document: file_len(&document)?,
+            sync_states: clean_dir(&root.join(SYNC_STATES_DIR))?,
+            metadata: clean_dir(&root.join(METADATA_DIR))?,
+        };
+        Ok(Self {
+            root,
+            unsynced: HashSet::new(),
+            unflushed: 0,
+            sizes,
+        })
+    }
+
+    /// The directory the persister stores its records in.
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Atomically replace the record at the path with the data, returning the size of the record
+    /// it replaced.
+    fn write(&mut self, path: &Path, data: &[u8]) -> Result<u64, MobilePersisterError> {
+        let old = file_len(path)?;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(TEMP_SUFFIX);
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(data)?;
+        file.sync_data()?;
+        fs::rename(&temp, path)?;
+        self.unflushed += data.len();
+        self.wrote(path);
+        Ok(old)
+    }
+
+    /// Remove the record at the path, returning its size.
+    fn remove(&mut self, path: &Path) -> Result<u64, MobilePersisterError> {
+        let old = file_len(path)?;
+        match fs::remove_file(path) {
+            Ok(()) => {
+                self.wrote(path);
+                Ok(old)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn wrote(&mut self, path: &Path) {
+        if let Some(dir) = path.parent() {
+            self.unsynced.insert(dir.to_owned());
+        }
+    }
+
+    fn keys(&self, dir: &str) -> Result<Vec<Vec<u8>>, MobilePersisterError> {
+        records(&self.root.join(dir))?
+            .iter()
+            .map(|path| decode_name(path))
+            .collect()
+    }
+}
+
+impl Persister for MobilePersister {
+    type Error = MobilePersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        records(&self.root.join(CHANGES_DIR))?
+            .into_iter()
+            .map(|path| Ok(fs::read(path)?))
+            .collect()
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        for (a, s, c) in changes {
+            let path = self.root.join(CHANGES_DIR).join(change_name(&a, s));
+            let old = self.write(&path, &c)?;
+            self.sizes.changes += c.len() as u64;
+            self.sizes.changes -= old;
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        for (a, s) in changes {
+            let path = self.root.join(CHANGES_DIR).join(change_name(a, s));
+            self.sizes.changes -= self.remove(&path)?;
+        }
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        read_optional(&self.root.join(DOCUMENT_FILE))
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.write(&self.root.join(DOCUMENT_FILE), &data)?;
+        self.sizes.document = data.len() as u64;
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        read_optional(&self.root.join(SYNC_STATES_DIR).join(hex::encode(peer_id)))
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let path = self.root.join(SYNC_STATES_DIR).join(hex::encode(peer_id));
+        let old = self.write(&path, &sync_state)?;
+        self.sizes.sync_states += sync_state.len() as u64;
+        self.sizes.sync_states -= old;
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for id in peer_ids {
+            let path = self.root.join(SYNC_STATES_DIR).join(hex::encode(id));
+            self.sizes.sync_states -= self.remove(&path)?;
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.keys(SYNC_STATES_DIR)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        read_optional(&self.root.join(METADATA_DIR).join(hex::encode(key)))
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let path = self.root.join(METADATA_DIR).join(hex::encode(key));
+        let old = self.write(&path, &value)?;
+        self.sizes.metadata += value.len() as u64;
+        self.sizes.metadata -= old;
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        let path = self.root.join(METADATA_DIR).join(hex::encode(key));
+        self.sizes.metadata -= self.remove(&path)?;
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.keys(METADATA_DIR)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Sync the directories written to since the last flush, making the renames of the records
+    /// in them durable.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        let dirs = self.unsynced.iter().cloned().collect::<Vec<_>>();
+        for dir in dirs {
+            fs::File::open(&dir)?.sync_all()?;
+            self.unsynced.remove(&dir);
+        }
+        Ok(std::mem::take(&mut self.unflushed))
+    }
+}
@@ -0,0 +1,294 @@
+use async_nats::jetstream::{
+    consumer::{pull::OrderedConfig, DeliverPolicy},
+    kv::{Operation, Store, UpdateErrorKind},
+    stream::DeleteMessageErrorKind,
+    Context, ErrorCode,
+};
+use futures::{StreamExt, TryStreamExt};
+use tokio::runtime::Handle;
+
+use crate::{KeyValue, Stream, StreamMessage};
+
+/// Errors from the NATS server or the connection to it.
+#[derive(Debug, thiserror::Error)]
+pub enum NatsError {
+    /// A request to the server failed.
+    #[error("{0}")]
+    Nats(async_nats::Error),
+}
+
+fn nats<E: Into<async_nats::Error>>(error: E) -> NatsError {
+    NatsError::Nats(error.into())
+}
+
+/// A [`Stream`] over a `JetStream` stream through [async-nats](https://github.com/nats-io/nats.rs).
+///
+/// The stream must already exist with subjects covering those changes are published to, such as
+/// `<prefix>.changes.>`. Messages are published through the context and waited on until the
+/// stream acknowledges them, and read back with an ephemeral ordered consumer delivering all of
+/// the messages on the subjects up to those pending when it was created.
+///
+/// async-nats is async so this blocks on the given runtime handle, methods must therefore not be
+/// called from within an async context (use `spawn_blocking` from async code).
+///
+/// ```rust,no_run
+/// # use automerge_persistent::PersistentAutomerge;
+/// # use automerge_persistent_nats::{NatsKeyValue, NatsPersister, NatsStream};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let runtime = tokio::runtime::Runtime::new()?;
+/// let client = runtime.block_on(async_nats::connect("localhost:4222"))?;
+/// let context = async_nats::jetstream::new(client);
+///
+/// let stream = runtime.block_on(context.get_or_create_stream(
+///     async_nats::jetstream::stream::Config {
+///         name: "automerge".to_owned(),
+///         subjects: vec!["my-document.changes.>".to_owned()],
+///         ..Default::default()
+///     },
+/// ))?;
+/// let bucket = runtime.block_on(context.create_key_value(async_nats::jetstream::kv::Config {
+///     bucket: "automerge".to_owned(),
+///     ..Default::default()
+/// }))?;
+///
+/// let handle = runtime.handle().clone();
+/// let persister = NatsPersister::open(
+///     NatsStream::new(context, stream, handle.clone()),
+///     NatsKeyValue::new(bucket, handle),
+///     "my-document",
+/// )?;
+/// let doc = PersistentAutomerge::load(persister)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct NatsStream {
+    context: Context,
+    stream: async_nats::jetstream::stream::Stream,
+    handle: Handle,
+}
+
+impl std::fmt::Debug for NatsStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NatsStream")
+            .field("stream", &self.stream.cached_info().config.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NatsStream {
+    /// Publish through the context to the stream, blocking on the runtime handle.
+    #[must_use]
+    pub const fn new(
+        context: Context,
+        stream: async_nats::jetstream::stream::Stream,
+        handle: Handle,
+    ) -> Self {
+        Self {
+            context,
+            stream,
+            handle,
+        }
+    }
+}
+
+impl Stream for NatsStream {
+    type Error = NatsError;
+
+    fn publish(&mut self, subject: String, payload: Vec<u8>) -> Result<u64, Self::Error> {
+        self.handle.block_on(async {
+            let ack = self
+                .context
+                .publish(subject, payload.into())
+                .await
+                .map_err(nats)?
+                .await
+                .map_err(nats)?;
+            Ok(ack.sequence)
+        })
+    }
+
+    fn delete(&mut self, sequence: u64) -> Result<(), Self::Error> {
+        match self.handle.block_on(self.stream.delete_message(sequence)) {
+            Ok(_) => Ok(()),
+            Err(error) => match error.kind() {
+                DeleteMessageErrorKind::JetStream(error)
+                    if error.error_code() == ErrorCode::NO_MESSAGE_FOUND =>
+                {
+                    Ok(())
+                }
+                _ => Err(nats(error)),
+            },
+        }
+    }
+
+    fn messages(&self, filter: &str) -> Result<Vec<StreamMessage>, Self::Error> {
+        self.handle.block_on(async {
+            let consumer = self
+                .stream
+                .create_consumer(OrderedConfig {
+                    filter_subject: filter.to_owned(),
+                    deliver_policy: DeliverPolicy::All,
+                    ..OrderedConfig::default()
+                })
+                .await
+                .map_err(nats)?;
+            let mut messages = Vec::new();
+            if consumer.cached_info().num_pending == 0 {
+                return Ok(messages);
+            }
+            let mut stream = consumer.messages().await.map_err(nats)?;
+            while let Some(message) = stream.next().await {
+                let message = message.map_err(nats)?;
+                let info = message.info().map_err(nats)?;
+                let pending = info.pending;
+                messages.push(StreamMessage {
+                    sequence: info.stream_sequence,
+                    subject: message.subject.to_string(),
+                    payload: message.payload.to_vec(),
+                });
+                if pending == 0 {
+                    break;
+                }
+            }
+            Ok(messages)
+        })
+    }
+}
+
+/// A [`KeyValue`] bucket through [async-nats](https://github.com/nats-io/nats.rs), blocking on the
+/// runtime handle like [`NatsStream`].
+///
+/// Deleted keys are treated as missing, and updates at a revision that has moved on are reported
+/// as such rather than as errors, so conflicting writes of the document are detected.
+pub struct NatsKeyValue {
+    store: Store,
+    handle: Handle,
+}
+
+impl std::fmt::Debug for NatsKeyValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NatsKeyValue")
+            .field("bucket", &self.store.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NatsKeyValue {
+    /// Use the bucket, blocking on the runtime handle.
+    #[must_use]
+    pub const fn new(store: Store, handle: Handle) -> Self {
+        Self { store, handle }
+    }
+}
+
+impl KeyValue for NatsKeyValue {
+    type Error = NatsError;
+
+    fn entry(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>, Self::Error> {
+        let entry = self.handle.block_on(self.store.entry(key)).map_err(nats)?;
+        Ok(entry
+            .filter(|entry| entry.operation == Operation::Put)
+            .map(|entry| (entry.value.to_vec(), entry.revision)))
+    }
+
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<u64, Self::Error> {
+        self.handle
+            .block_on(self.store.put(key, value.into()))
+            .map_err(nats)
+    }
+
+    fn update(
+        &mut self,
+        key: &str,
+        value: Vec<u8>,
+        revision: u64,
+    ) -> Result<Option<u64>, Self::Error> {
+        match self
+            .handle
+            .block_on(self.store.update(key, value.into(), revision))
+        {
+            Ok(revision) => Ok(Some(revision)),
+            Err(error) if error.kind() == UpdateErrorKind::WrongLastRevision => Ok(None),
+            Err(error) => Err(nats(error)),
+        }
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), Self::Error> {
+        self.handle.block_on(self.store.delete(key)).map_err(nats)
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Self::Error> {
+        self.handle.block_on(async {
+            self.store
+                .keys()
+                .await
+                .map_err(nats)?
+                .try_collect()
+                .await
+                .map_err(nats)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_nats::jetstream::{kv, stream};
+    use automerge::{transaction::Transactable, ROOT};
+    use automerge_persistent::{PersistentAutomerge, Persister};
+    use tokio::runtime::Runtime;
+
+    use super::{NatsKeyValue, NatsStream};
+    use crate::NatsPersister;
+
+    /// Open the persister on the server at `NATS_URL`, which must have `JetStream` enabled.
+    fn open(runtime: &Runtime, name: &str) -> NatsPersister<NatsStream, NatsKeyValue> {
+        let url = std::env::var("NATS_URL").expect("NATS_URL should be set");
+        let client = runtime.block_on(async_nats::connect(url)).unwrap();
+        let context = async_nats::jetstream::new(client);
+        let stream = runtime
+            .block_on(context.get_or_create_stream(stream::Config {
+                name: name.to_owned(),
+                subjects: vec![format!("{name}.changes.>")],
+                ..stream::Config::default()
+            }))
+            .unwrap();
+        let bucket = runtime
+            .block_on(context.create_key_value(kv::Config {
+                bucket: name.to_owned(),
+                ..kv::Config::default()
+            }))
+            .unwrap();
+        let handle = runtime.handle().clone();
+        NatsPersister::open(
+            NatsStream::new(context, stream, handle.clone()),
+            NatsKeyValue::new(bucket, handle),
+            name,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs a NATS server with JetStream at NATS_URL"]
+    fn document_round_trips_through_compaction() {
+        let runtime = Runtime::new().unwrap();
+        let name = "automerge-persistent-test";
+        let mut doc = PersistentAutomerge::load(open(&runtime, name)).unwrap();
+        for key in ["a", "b"] {
+            doc.transact::<_, _, std::convert::Infallible>(|tx| {
+                tx.put(ROOT, key, 1).unwrap();
+                Ok(())
+            })
+            .unwrap();
+            if key == "a" {
+                doc.compact(&[]).unwrap();
+            }
+        }
+        let heads = doc.document().get_heads();
+        drop(doc);
+
+        let persister = open(&runtime, name);
+        assert_eq!(persister.get_changes().unwrap().len(), 1);
+        let doc = PersistentAutomerge::load(persister).unwrap();
+        assert_eq!(doc.document().get_heads(), heads);
+    }
+}
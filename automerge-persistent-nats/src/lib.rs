@@ -0,0 +1,662 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [NATS JetStream](https://docs.nats.io/nats-concepts/jetstream).
+//!
+//! Changes are published to a stream, so other services can subscribe to the subjects to
+//! receive them as they are stored, while the document, sync states and metadata are kept in a
+//! key-value bucket. This gives durable storage and fan-out of changes from one system.
+//!
+//! The stream and bucket are reached through the [`Stream`] and [`KeyValue`] traits rather than
+//! a particular client. With the `jetstream` feature [`NatsStream`] and [`NatsKeyValue`]
+//! implement them over async-nats, and [`MemoryStream`] and [`MemoryKeyValue`] stand in for
+//! tests.
+//!
+//! ```rust
+//! # use automerge::{transaction::Transactable, ROOT};
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_nats::{MemoryKeyValue, MemoryStream, NatsPersister};
+//! let persister =
+//!     NatsPersister::open(MemoryStream::default(), MemoryKeyValue::default(), "my-document").unwrap();
+//! let mut doc = PersistentAutomerge::load(persister).unwrap();
+//! doc.transact::<_, _, std::convert::Infallible>(|tx| {
+//!     tx.put(ROOT, "a", 1).unwrap();
+//!     Ok(())
+//! })
+//! .unwrap();
+//!
+//! let (stream, bucket) = doc.close().unwrap().into_parts();
+//! let doc = PersistentAutomerge::load(NatsPersister::open(stream, bucket, "my-document").unwrap())
+//!     .unwrap();
+//! assert_eq!(doc.document().length(ROOT), 1);
+//! ```
+
+#[cfg(feature = "jetstream")]
+mod jetstream;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::{Infallible, TryFrom},
+    error::Error,
+    fmt::Write,
+};
+
+use automerge::ActorId;
+use automerge_persistent::{
+    DocumentVersion, Persister, StoredSizes, VersionConflict, VersionedDocument,
+};
+#[cfg(feature = "jetstream")]
+pub use jetstream::{NatsError, NatsKeyValue, NatsStream};
+
+/// A message read back from a [`Stream`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamMessage {
+    /// The sequence number of the message in the stream.
+    pub sequence: u64,
+    /// The subject the message was published to.
+    pub subject: String,
+    /// The contents of the message.
+    pub payload: Vec<u8>,
+}
+
+/// A `JetStream` stream that changes are published to.
+pub trait Stream {
+    /// The error type that the operations can produce.
+    type Error: Error + 'static;
+
+    /// Publish a message to the subject, returning its sequence number once it has been
+    /// acknowledged by the stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message was not acknowledged.
+    fn publish(&mut self, subject: String, payload: Vec<u8>) -> Result<u64, Self::Error>;
+
+    /// Delete the message with the sequence number from the stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message could not be deleted. A message that does not exist
+    /// should not return an error.
+    fn delete(&mut self, sequence: u64) -> Result<(), Self::Error>;
+
+    /// Read the messages on the subjects matching the filter, such as `doc.changes.>`, oldest
+    /// first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream could not be read.
+    fn messages(&self, filter: &str) -> Result<Vec<StreamMessage>, Self::Error>;
+}
+
+/// A `JetStream` key-value bucket that the document, sync states and metadata are kept in.
+pub trait KeyValue {
+    /// The error type that the operations can produce.
+    type Error: Error + 'static;
+
+    /// Get the value of the key along with its revision.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the bucket could not be read.
+    fn entry(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>, Self::Error>;
+
+    /// Set the value of the key, returning its new revision.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value could not be written.
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<u64, Self::Error>;
+
+    /// Set the value of the key only if it is still at the revision, returning its new revision
+    /// or `None` if it has moved on.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value could not be written.
+    fn update(
+        &mut self,
+        key: &str,
+        value: Vec<u8>,
+        revision: u64,
+    ) -> Result<Option<u64>, Self::Error>;
+
+    /// Delete the key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key could not be deleted. A key that does not exist should not
+    /// return an error.
+    fn delete(&mut self, key: &str) -> Result<(), Self::Error>;
+
+    /// List the keys in the bucket.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the bucket could not be read.
+    fn keys(&self) -> Result<Vec<String>, Self::Error>;
+}
+
+/// A stream held in memory, for tests.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStream {
+    messages: BTreeMap<u64, StreamMessage>,
+    last_sequence: u64,
+}
+
+impl Stream for MemoryStream {
+    type Error = Infallible;
+
+    fn publish(&mut self, subject: String, payload: Vec<u8>) -> Result<u64, Self::Error> {
+        self.last_sequence += 1;
+        let sequence = self.last_sequence;
+        self.messages.insert(
+            sequence,
+            StreamMessage {
+                sequence,
+                subject,
+                payload,
+            },
+        );
+        Ok(sequence)
+    }
+
+    fn delete(&mut self, sequence: u64) -> Result<(), Self::Error> {
+        self.messages.remove(&sequence);
+        Ok(())
+    }
+
+    fn messages(&self, filter: &str) -> Result<Vec<StreamMessage>, Self::Error> {
+        Ok(self
+            .messages
+            .values()
+            .filter(|m| subject_matches(filter, &m.subject))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Whether the subject matches the filter, with `*` matching one token and a trailing `>` the
+/// rest.
+fn subject_matches(filter: &str, subject: &str) -> bool {
+    let mut subject = subject.split('.');
+    for token in filter.split('.') {
+        match (token, subject.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (token, Some(s)) if token == s => {}
+            _ => return false,
+        }
+    }
+    subject.next().is_none()
+}
+
+/// A key-value bucket held in memory, for tests.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryKeyValue {
+    entries: HashMap<String, (Vec<u8>, u64)>,
+    last_revision: u64,
+}
+
+impl KeyValue for MemoryKeyValue {
+    type Error = Infallible;
+
+    fn entry(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>, Self::Error> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &str, value: Vec<u8>) -> Result<u64, Self::Error> {
+        self.last_revision += 1;
+        self.entries
+            .insert(key.to_owned(), (value, self.last_revision));
+        Ok(self.last_revision)
+    }
+
+    fn update(
+        &mut self,
+        key: &str,
+        value: Vec<u8>,
+        revision: u64,
+    ) -> Result<Option<u64>, Self::Error> {
+        if self.entries.get(key).map(|(_, r)| *r) != Some(revision) {
+            return Ok(None);
+        }
+        self.put(key, value).map(Some)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), Self::Error> {
+        self.entries.remove(key);
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Self::Error> {
+        Ok(self.entries.keys().cloned().collect())
+    }
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum NatsPersisterError<S, K>
+where
+    S: Error + 'static,
+    K: Error + 'static,
+{
+    /// Errors from the stream of changes.
+    #[error("stream: {0}")]
+    Stream(S),
+    /// Errors from the key-value bucket.
+    #[error("key-value bucket: {0}")]
+    KeyValue(K),
+}
+
+/// The key of the document in the bucket, after the prefix.
+pub const DOCUMENT_KEY: &str = "document";
+/// The token after the prefix of the subjects changes are published to.
+pub const CHANGES_TOKEN: &str = "changes";
+/// The token after the prefix of the keys sync states are kept under.
+pub const SYNC_STATES_TOKEN: &str = "sync_states";
+/// The token after the prefix of the keys metadata is kept under.
+pub const METADATA_TOKEN: &str = "metadata";
+
+/// Encode bytes as lowercase hex, as subjects and keys are limited to a few characters.
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+/// Decode lowercase hex made by [`hex`].
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A persister that publishes changes to a `JetStream` stream and keeps everything else in a
+/// key-value bucket.
+///
+/// Each change is published to the subject `<prefix>.changes.<actor>.<seq>`, with the actor in
+/// hex, so subscribers can follow every change of the document with `<prefix>.changes.>` or
+/// those of one actor. The document is kept under the key `<prefix>.document` and sync states
+/// and metadata under `<prefix>.sync_states.<peer>` and `<prefix>.metadata.<key>`. Many
+/// documents can share a stream and bucket with different prefixes.
+///
+/// The stream sequence of each stored change is indexed in memory when opened so that removing
+/// changes during compaction deletes their messages, so a single process should write to the
+/// same prefix. The bucket's revisions are used as the document version so lost updates of the
+/// document are detected.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::PersistentAutomerge;
+/// # use automerge_persistent_nats::{MemoryKeyValue, MemoryStream, NatsPersister, Stream};
+/// let persister = NatsPersister::open(MemoryStream::default(), MemoryKeyValue::default(), "doc")
+///     .unwrap();
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// let filter = doc.persister().changes_filter();
+/// assert_eq!(filter, "doc.changes.>");
+/// doc.compact(&[]).unwrap();
+/// let (stream, _) = doc.close().unwrap().into_parts();
+/// assert!(stream.messages(&filter).unwrap().is_empty());
+/// ```
+#[derive(Debug)]
+pub struct NatsPersister<S, K> {
+    stream: S,
+    bucket: K,
+    prefix: String,
+    /// The sequence and length of the message of each stored change.
+    changes: HashMap<(ActorId, u64), (u64, u64)>,
+    sizes: StoredSizes,
+}
+
+impl<S, K> NatsPersister<S, K>
+where
+    S: Stream,
+    K: KeyValue,
+{
+    /// Open the document with the prefix, indexing its changes in the stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream or bucket could not be read.
+    pub fn open<P>(
+        stream: S,
+        bucket: K,
+        prefix: P,
+    ) -> Result<Self, NatsPersisterError<S::Error, K::Error>>
+    where
+        P: Into<String>,
+    {
+        let prefix = prefix.into();
+        let mut s = Self {
+            stream,
+            bucket,
+            prefix,
+            changes: HashMap::new(),
+            sizes: StoredSizes::default(),
+        };
+        for message in s
+            .stream
+            .messages(&format!("{}.{CHANGES_TOKEN}.>", s.prefix))
+            .map_err(NatsPersisterError::Stream)?
+        {
+            if let Some(key) = s.parse_change_subject(&message.subject) {
+                let len = message.payload.len() as u64;
+                s.sizes.changes += len;
+                if let Some((_, old)) = s.changes.insert(key, (message.sequence, len)) {
+                    s.sizes.changes -= old;
+                }
+            }
+        }
+        for key in s.bucket.keys().map_err(NatsPersisterError::KeyValue)? {
+            let Some(rest) = key
+                .strip_prefix(s.prefix.as_str())
+                .and_then(|k| k.strip_prefix('.'))
+            else {
+                continue;
+            };
+            let size = s
+                .bucket
+                .entry(&key)
+                .map_err(NatsPersisterError::KeyValue)?
+                .map_or(0, |(v, _)| v.len() as u64);
+            if rest == DOCUMENT_KEY {
+                s.sizes.document = size;
+            } else if rest.starts_with(SYNC_STATES_TOKEN) {
+                s.sizes.sync_states += size;
+            } else if rest.starts_with(METADATA_TOKEN) {
+                s.sizes.metadata += size;
+            }
+        }
+        Ok(s)
+    }
+}
+
+impl<S, K> NatsPersister<S, K> {
+    /// The filter of the subjects the changes of this document are published to, for
+    /// subscribers to follow them.
+    pub fn changes_filter(&self) -> String {
+        format!("{}.{CHANGES_TOKEN}.>", self.prefix)
+    }
+
+    /// Take the stream and bucket back out.
+    pub fn into_parts(self) -> (S, K) {
+        (self.stream, self.bucket)
+    }
+
+    fn change_subject(&self, actor_id: &ActorId, seq: u64) -> String {
+        format!(
+            "{}.{CHANGES_TOKEN}.{}.{seq}",
+            self.prefix,
+            hex(actor_id.to_bytes())
+        )
+    }
+
+    fn parse_change_subject(&self, subject: &str) -> Option<(ActorId, u64)> {
+        let rest = subject
+            .strip_prefix(self.prefix.as_str())?
+            .strip_prefix('.')?
+            .strip_prefix(CHANGES_TOKEN)?
+            .strip_prefix('.')?;
+        let (actor, seq) = rest.split_once('.')?;
+        Some((ActorId::from(unhex(actor)?), seq.parse().ok()?))
+    }
+
+    fn key(&self, token: &str, key: &[u8]) -> String {
+        format!("{}.{token}.{}", self.prefix, hex(key))
+    }
+
+    fn keys_of(&self, token: &str, keys: &[String]) -> Vec<Vec<u8>> {
+        let prefix = format!("{}.{token}.", self.prefix);
+        keys.iter()
+            .filter_map(|k| unhex(k.strip_prefix(prefix.as_str())?))
+            .collect()
+    }
+
+    fn document_key(&self) -> String {
+        format!("{}.{DOCUMENT_KEY}", self.prefix)
+    }
+}
+
+/// Make a document version from a bucket revision.
+fn version(revision: u64) -> DocumentVersion {
+    DocumentVersion(revision.to_be_bytes().to_vec())
+}
+
+impl<S, K> NatsPersister<S, K>
+where
+    K: KeyValue,
+{
+    /// Put a value in the bucket, returning the length of the value it replaced.
+    fn put_sized(&mut self, key: &str, value: Vec<u8>) -> Result<u64, K::Error> {
+        let old = self.bucket.entry(key)?.map_or(0, |(v, _)| v.len() as u64);
+        self.bucket.put(key, value)?;
+        Ok(old)
+    }
+
+    /// Delete a value from the bucket, returning its length.
+    fn delete_sized(&mut self, key: &str) -> Result<u64, K::Error> {
+        let old = self.bucket.entry(key)?.map_or(0, |(v, _)| v.len() as u64);
+        if old > 0 {
+            self.bucket.delete(key)?;
+        }
+        Ok(old)
+    }
+}
+
+impl<S, K> Persister for NatsPersister<S, K>
+where
+    S: Stream,
+    K: KeyValue,
+{
+    type Error = NatsPersisterError<S::Error, K::Error>;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .stream
+            .messages(&self.changes_filter())
+            .map_err(NatsPersisterError::Stream)?
+            .into_iter()
+            .filter(|m| {
+                self.parse_change_subject(&m.subject)
+                    .and_then(|key| self.changes.get(&key))
+                    .is_some_and(|(sequence, _)| *sequence == m.sequence)
+            })
+            .map(|m| m.payload)
+            .collect())
+    }
+
+    /// Publish each change, deleting the message of any change it replaces.
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        for (actor_id, seq, change) in changes {
+            let subject = self.change_subject(&actor_id, seq);
+            let len = change.len() as u64;
+            let sequence = self
+                .stream
+                .publish(subject, change)
+                .map_err(NatsPersisterError::Stream)?;
+            self.sizes.changes += len;
+            if let Some((old, old_len)) = self.changes.insert((actor_id, seq), (sequence, len)) {
+                self.sizes.changes -= old_len;
+                self.stream
+                    .delete(old)
+                    .map_err(NatsPersisterError::Stream)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        for (actor_id, seq) in changes {
+            if let Some((sequence, len)) = self.changes.remove(&(actor_id.clone(), seq)) {
+                self.stream
+                    .delete(sequence)
+                    .map_err(NatsPersisterError::Stream)?;
+                self.sizes.changes -= len;
+            }
+        }
+        Ok(())
+    }
+
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        let mut actors = self
+            .changes
+            .keys()
+            .map(|(actor, _)| actor.clone())
+            .collect::<Vec<_>>();
+        actors.sort_unstable();
+        actors.dedup();
+        Ok(actors)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.get_document_versioned()?.0)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.sizes.document = data.len() as u64;
+        self.bucket
+            .put(&self.document_key(), data)
+            .map_err(NatsPersisterError::KeyValue)?;
+        Ok(())
+    }
+
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        let entry = self
+            .bucket
+            .entry(&self.document_key())
+            .map_err(NatsPersisterError::KeyValue)?;
+        Ok(entry.map_or((None, None), |(document, revision)| {
+            (Some(document), Some(version(revision)))
+        }))
+    }
+
+    /// Set the document with an update of its key at the expected revision, so a concurrent
+    /// write in between is detected by the bucket.
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let key = self.document_key();
+        let len = data.len() as u64;
+        let revision = match expected {
+            None => Some(
+                self.bucket
+                    .put(&key, data)
+                    .map_err(NatsPersisterError::KeyValue)?,
+            ),
+            Some(expected) => {
+                let revision = <[u8; 8]>::try_from(expected.0.as_slice())
+                    .ok()
+                    .map(u64::from_be_bytes);
+                match revision {
+                    Some(revision) => self
+                        .bucket
+                        .update(&key, data, revision)
+                        .map_err(NatsPersisterError::KeyValue)?,
+                    None => None,
+                }
+            }
+        };
+        match revision {
+            Some(revision) => {
+                self.sizes.document = len;
+                Ok(Ok(Some(version(revision))))
+            }
+            None => Ok(Err(VersionConflict {
+                current: self.get_document_versioned()?.1,
+            })),
+        }
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .bucket
+            .entry(&self.key(SYNC_STATES_TOKEN, peer_id))
+            .map_err(NatsPersisterError::KeyValue)?
+            .map(|(v, _)| v))
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let key = self.key(SYNC_STATES_TOKEN, &peer_id);
+        self.sizes.sync_states += sync_state.len() as u64;
+        let old = self
+            .put_sized(&key, sync_state)
+            .map_err(NatsPersisterError::KeyValue)?;
+        self.sizes.sync_states -= old;
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for peer_id in peer_ids {
+            let key = self.key(SYNC_STATES_TOKEN, peer_id);
+            let old = self
+                .delete_sized(&key)
+                .map_err(NatsPersisterError::KeyValue)?;
+            self.sizes.sync_states -= old;
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let keys = self.bucket.keys().map_err(NatsPersisterError::KeyValue)?;
+        Ok(self.keys_of(SYNC_STATES_TOKEN, &keys))
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .bucket
+            .entry(&self.key(METADATA_TOKEN, key))
+            .map_err(NatsPersisterError::KeyValue)?
+            .map(|(v, _)| v))
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let key = self.key(METADATA_TOKEN, &key);
+        self.sizes.metadata += value.len() as u64;
+        let old = self
+            .put_sized(&key, value)
+            .map_err(NatsPersisterError::KeyValue)?;
+        self.sizes.metadata -= old;
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        let key = self.key(METADATA_TOKEN, key);
+        let old = self
+            .delete_sized(&key)
+            .map_err(NatsPersisterError::KeyValue)?;
+        self.sizes.metadata -= old;
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let keys = self.bucket.keys().map_err(NatsPersisterError::KeyValue)?;
+        Ok(self.keys_of(METADATA_TOKEN, &keys))
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Messages and values are acknowledged as they are written so there is nothing to flush.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
@@ -0,0 +1,706 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting any [`ObjectStore`], covering S3, GCS, Azure Blob and the local
+//! filesystem with one implementation.
+//!
+//! Each record is stored as its own object under the given prefix:
+//!
+//! - `<prefix>/document`
+//! - `<prefix>/changes/<hex actor id>/<seq>`
+//! - `<prefix>/sync_states/<hex peer id>`
+//! - `<prefix>/metadata/<hex key>`
+//!
+//! Large documents can be split into chunks, see [`ObjectStorePersister::with_chunk_size`], and
+//! uploaded in parts, see [`ObjectStorePersister::with_multipart_part_size`].
+//!
+//! The cloud stores are enabled with the `aws`, `gcp` and `azure` features.
+//!
+//! Object stores are async so the persister blocks on the given runtime handle, methods must
+//! therefore not be called from within an async context (use `spawn_blocking` from async code).
+//!
+//! ```rust
+//! # use std::sync::Arc;
+//! # use automerge::{transaction::Transactable, ROOT};
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_objectstore::ObjectStorePersister;
+//! # use object_store::memory::InMemory;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let runtime = tokio::runtime::Runtime::new()?;
+//! let store = Arc::new(InMemory::new());
+//!
+//! let persister = ObjectStorePersister::new(
+//!     Arc::clone(&store) as _,
+//!     runtime.handle().clone(),
+//!     "my-document",
+//! )?;
+//! let mut doc = PersistentAutomerge::load(persister)?;
+//! doc.transact::<_, _, std::convert::Infallible>(|tx| {
+//!     tx.put(ROOT, "a", 1).unwrap();
+//!     Ok(())
+//! })
+//! .unwrap();
+//!
+//! let persister = ObjectStorePersister::new(store, runtime.handle().clone(), "my-document")?;
+//! let doc = PersistentAutomerge::load(persister)?;
+//! assert_eq!(doc.document().length(ROOT), 1);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Multiple writers
+//!
+//! The document is versioned by its etag, so [`SharedPersister::set_document_if`] uses a
+//! conditional put and writers compacting the same document can't lose each other's updates.
+//!
+//! ```rust
+//! # use std::sync::Arc;
+//! # use automerge_persistent::{Error, PersistentAutomerge};
+//! # use automerge_persistent_objectstore::ObjectStorePersister;
+//! # use object_store::memory::InMemory;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let runtime = tokio::runtime::Runtime::new()?;
+//! let store = Arc::new(InMemory::new());
+//! let open = || ObjectStorePersister::new(Arc::clone(&store) as _, runtime.handle().clone(), "doc");
+//!
+//! let mut doc1 = PersistentAutomerge::load(open()?)?;
+//! let mut doc2 = PersistentAutomerge::load(open()?)?;
+//! doc1.compact(&[])?;
+//! assert!(matches!(doc2.compact(&[]), Err(Error::VersionConflict(_))));
+//!
+//! // once reconciled with the storage it can compact again
+//! assert!(doc2.reconcile_with_storage()?.document_replaced);
+//! doc2.compact(&[])?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    convert::{TryFrom, TryInto},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use automerge::ActorId;
+use automerge_persistent_core::{
+    DocumentVersion, SharedPersister, StoredSizes, VersionConflict, VersionedDocument,
+};
+use futures::{future, TryStreamExt};
+use hex::FromHexError;
+use object_store::{
+    path::Path, ObjectMeta, ObjectStore, PutMode, PutOptions, PutPayload, UpdateVersion,
+    WriteMultipart,
+};
+
+const DOCUMENT: &str = "document";
+const DOCUMENT_CHUNKS_DIR: &str = "document_chunks";
+const CHANGES_DIR: &str = "changes";
+const SYNC_STATES_DIR: &str = "sync_states";
+const METADATA_DIR: &str = "metadata";
+
+/// The start of a manifest stored in place of a chunked document.
+///
+/// Saved documents start with the automerge magic bytes so can't be mistaken for a manifest.
+const MANIFEST_MAGIC: &[u8] = b"automerge-persistent-manifest";
+/// A manifest is the magic followed by the generation, document length and chunk size.
+const MANIFEST_LEN: usize = MANIFEST_MAGIC.len() + 24;
+
+/// Where the chunks of a chunked document are and how to put them back together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Manifest {
+    /// Distinguishes the chunks of each write so a new document never overwrites the chunks of
+    /// the current one.
+    generation: u64,
+    len: u64,
+    chunk_size: u64,
+}
+
+impl Manifest {
+    fn encode(self) -> Vec<u8> {
+        let mut bytes = MANIFEST_MAGIC.to_vec();
+        bytes.extend(self.generation.to_be_bytes());
+        bytes.extend(self.len.to_be_bytes());
+        bytes.extend(self.chunk_size.to_be_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != MANIFEST_LEN || !bytes.starts_with(MANIFEST_MAGIC) {
+            return None;
+        }
+        let field = |i: usize| {
+            let start = MANIFEST_MAGIC.len() + 8 * i;
+            u64::from_be_bytes(bytes[start..start + 8].try_into().unwrap())
+        };
+        let manifest = Self {
+            generation: field(0),
+            len: field(1),
+            chunk_size: field(2),
+        };
+        (manifest.chunk_size > 0).then_some(manifest)
+    }
+
+    /// The number of chunks, the chunk size is never 0.
+    const fn chunks(self) -> u64 {
+        self.len.div_ceil(self.chunk_size)
+    }
+}
+
+/// Encode the etag and version of the document object as a [`DocumentVersion`].
+///
+/// Neither can contain a newline so it separates them, with a missing one left empty.
+fn encode_version(e_tag: Option<&str>, version: Option<&str>) -> DocumentVersion {
+    DocumentVersion(
+        format!(
+            "{}\n{}",
+            e_tag.unwrap_or_default(),
+            version.unwrap_or_default()
+        )
+        .into_bytes(),
+    )
+}
+
+fn decode_version(version: &DocumentVersion) -> UpdateVersion {
+    let version = String::from_utf8_lossy(&version.0);
+    let (e_tag, version) = version.split_once('\n').unwrap_or((&version, ""));
+    let non_empty = |s: &str| (!s.is_empty()).then(|| s.to_owned());
+    UpdateVersion {
+        e_tag: non_empty(e_tag),
+        version: non_empty(version),
+    }
+}
+
+/// Whether the error is from the condition of a conditional put not holding.
+const fn is_conflict(error: &object_store::Error) -> bool {
+    matches!(
+        error,
+        object_store::Error::Precondition { .. } | object_store::Error::AlreadyExists { .. }
+    )
+}
+
+/// The persister that stores changes and documents as objects in an [`ObjectStore`].
+///
+/// Object stores are safe to use concurrently so this is a [`SharedPersister`] and can be shared
+/// behind an [`Arc`] without extra locking.
+#[derive(Debug)]
+pub struct ObjectStorePersister {
+    store: Arc<dyn ObjectStore>,
+    handle: tokio::runtime::Handle,
+    prefix: Path,
+    chunk_size: Option<usize>,
+    multipart_part_size: Option<usize>,
+    sizes: AtomicSizes,
+}
+
+#[derive(Debug, Default)]
+struct AtomicSizes {
+    changes: AtomicU64,
+    document: AtomicU64,
+    sync_states: AtomicU64,
+    metadata: AtomicU64,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum ObjectStorePersisterError {
+    /// Internal errors from the object store.
+    #[error(transparent)]
+    ObjectStoreError(#[from] object_store::Error),
+    /// An object name could not be decoded.
+    #[error(transparent)]
+    FromHexError(#[from] FromHexError),
+    /// The chunks of a chunked document did not add up to the length in its manifest.
+    #[error("the document chunks do not match the manifest")]
+    InvalidManifest,
+}
+
+impl ObjectStorePersister {
+    /// Construct a new persister storing objects under the given prefix.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the existing objects could not be listed to calculate the stored
+    /// sizes.
+    pub fn new<S>(
+        store: Arc<dyn ObjectStore>,
+        handle: tokio::runtime::Handle,
+        prefix: S,
+    ) -> Result<Self, ObjectStorePersisterError>
+    where
+        S: AsRef<str>,
+    {
+        let s = Self {
+            store,
+            handle,
+            prefix: Path::from(prefix.as_ref()),
+            chunk_size: None,
+            multipart_part_size: None,
+            sizes: AtomicSizes::default(),
+        };
+        let size = |dir| -> Result<u64, ObjectStorePersisterError> {
+            Ok(s.list(dir)?.iter().map(|meta| meta.size).sum())
+        };
+        s.sizes.changes.store(size(CHANGES_DIR)?, Ordering::Relaxed);
+        s.sizes
+            .sync_states
+            .store(size(SYNC_STATES_DIR)?, Ordering::Relaxed);
+        s.sizes
+            .metadata
+            .store(size(METADATA_DIR)?, Ordering::Relaxed);
+        let mut document = s
+            .head(&s.prefix.child(DOCUMENT))?
+            .map_or(0, |meta| meta.size);
+        if document == MANIFEST_LEN as u64 {
+            if let Some(manifest) = s.get_manifest()? {
+                document = manifest.len;
+            }
+        }
+        s.sizes.document.store(document, Ordering::Relaxed);
+        Ok(s)
+    }
+
+    /// Store documents larger than `chunk_size` bytes as chunks of that size, with a manifest in
+    /// place of the document.
+    ///
+    /// The chunks are uploaded concurrently and a failed upload only retries its own chunk, which
+    /// keeps writing large documents reliable and avoids the size limits on single objects. The
+    /// chunks are written before the manifest that refers to them so readers see either the old
+    /// or the new document, and the chunks of the old one are removed afterwards.
+    ///
+    /// Chunked documents are read back regardless of this setting.
+    ///
+    /// ```rust
+    /// # use std::sync::Arc;
+    /// # use automerge_persistent::{Persister, SharedPersister};
+    /// # use automerge_persistent_objectstore::ObjectStorePersister;
+    /// # use object_store::memory::InMemory;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let runtime = tokio::runtime::Runtime::new()?;
+    /// let store = Arc::new(InMemory::new());
+    /// let persister = ObjectStorePersister::new(Arc::clone(&store) as _, runtime.handle().clone(), "doc")?
+    ///     .with_chunk_size(4);
+    ///
+    /// SharedPersister::set_document(&persister, b"0123456789".to_vec())?;
+    /// assert_eq!(SharedPersister::get_document(&persister)?, Some(b"0123456789".to_vec()));
+    ///
+    /// let persister = ObjectStorePersister::new(store, runtime.handle().clone(), "doc")?;
+    /// assert_eq!(SharedPersister::sizes(&persister).document, 10);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub const fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Upload objects larger than `part_size` bytes with a multipart upload of parts of that size.
+    ///
+    /// Stores have a minimum part size, 5 MiB for S3.
+    #[must_use]
+    pub const fn with_multipart_part_size(mut self, part_size: usize) -> Self {
+        self.multipart_part_size = Some(part_size);
+        self
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.handle.block_on(future)
+    }
+
+    fn make_change_path(&self, actor_id: &ActorId, seq: u64) -> Path {
+        self.prefix
+            .child(CHANGES_DIR)
+            .child(actor_id.to_hex_string())
+            .child(seq.to_string())
+    }
+
+    fn make_hex_path(&self, dir: &str, key: &[u8]) -> Path {
+        self.prefix.child(dir).child(hex::encode(key))
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<ObjectMeta>, ObjectStorePersisterError> {
+        let prefix = self.prefix.child(dir);
+        Ok(self.block_on(self.store.list(Some(&prefix)).try_collect())?)
+    }
+
+    fn list_hex_keys(&self, dir: &str) -> Result<Vec<Vec<u8>>, ObjectStorePersisterError> {
+        self.list(dir)?
+            .iter()
+            .filter_map(|meta| meta.location.filename().map(hex::decode))
+            .map(|key| key.map_err(ObjectStorePersisterError::FromHexError))
+            .collect()
+    }
+
+    fn head(&self, path: &Path) -> Result<Option<ObjectMeta>, ObjectStorePersisterError> {
+        match self.block_on(self.store.head(path)) {
+            Ok(meta) => Ok(Some(meta)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Put an object, with a multipart upload if it is large enough.
+    async fn put_object(&self, path: &Path, value: Vec<u8>) -> Result<(), object_store::Error> {
+        match self.multipart_part_size {
+            Some(part_size) if value.len() > part_size => {
+                let upload = self.store.put_multipart(path).await?;
+                let mut write = WriteMultipart::new_with_chunk_size(upload, part_size);
+                write.put(value.into());
+                write.finish().await?;
+            }
+            _ => {
+                self.store.put(path, value.into()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn make_chunk_path(&self, generation: u64, index: u64) -> Path {
+        self.make_generation_path(generation)
+            .child(index.to_string())
+    }
+
+    /// Get the manifest if the document is chunked.
+    fn get_manifest(&self) -> Result<Option<Manifest>, ObjectStorePersisterError> {
+        Ok(self
+            .get(&self.prefix.child(DOCUMENT))?
+            .and_then(|bytes| Manifest::decode(&bytes)))
+    }
+
+    /// Fetch the chunks of a document concurrently and join them.
+    fn get_chunked(&self, manifest: Manifest) -> Result<Vec<u8>, ObjectStorePersisterError> {
+        let chunks = self.block_on(future::try_join_all((0..manifest.chunks()).map(|i| {
+            let path = self.make_chunk_path(manifest.generation, i);
+            async move { self.store.get(&path).await?.bytes().await }
+        })))?;
+        let document = chunks.concat();
+        if document.len() as u64 == manifest.len {
+            Ok(document)
+        } else {
+            Err(ObjectStorePersisterError::InvalidManifest)
+        }
+    }
+
+    /// Write the chunks of a document concurrently, then the manifest pointing at them with the
+    /// given mode, returning the generation of the chunks along with the result of putting the
+    /// manifest.
+    fn put_chunked(
+        &self,
+        data: &[u8],
+        chunk_size: usize,
+        mode: PutMode,
+    ) -> Result<(u64, object_store::Result<object_store::PutResult>), ObjectStorePersisterError>
+    {
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| u64::try_from(d.as_nanos()).unwrap_or_default());
+        let manifest = Manifest {
+            generation,
+            len: data.len() as u64,
+            chunk_size: chunk_size as u64,
+        };
+        self.block_on(future::try_join_all(
+            (0..).zip(data.chunks(chunk_size)).map(|(i, chunk)| {
+                let path = self.make_chunk_path(generation, i);
+                async move { self.put_object(&path, chunk.to_vec()).await }
+            }),
+        ))?;
+        let result = self.block_on(self.store.put_opts(
+            &self.prefix.child(DOCUMENT),
+            manifest.encode().into(),
+            PutOptions::from(mode),
+        ));
+        Ok((generation, result))
+    }
+
+    /// Delete the chunks of all documents except the given generation.
+    fn remove_chunks(&self, keep: Option<u64>) -> Result<(), ObjectStorePersisterError> {
+        let keep = keep.map(|generation| self.make_generation_path(generation));
+        let old = self.list(DOCUMENT_CHUNKS_DIR)?.into_iter().filter(|meta| {
+            keep.as_ref()
+                .is_none_or(|keep| !meta.location.prefix_matches(keep))
+        });
+        self.delete_all(old)
+    }
+
+    /// Delete the chunks of the given generation only.
+    fn remove_generation(&self, generation: u64) -> Result<(), ObjectStorePersisterError> {
+        let path = self.make_generation_path(generation);
+        let chunks = self.list(DOCUMENT_CHUNKS_DIR)?.into_iter();
+        self.delete_all(chunks.filter(|meta| meta.location.prefix_matches(&path)))
+    }
+
+    fn make_generation_path(&self, generation: u64) -> Path {
+        self.prefix
+            .child(DOCUMENT_CHUNKS_DIR)
+            .child(generation.to_string())
+    }
+
+    fn delete_all(
+        &self,
+        objects: impl Iterator<Item = ObjectMeta>,
+    ) -> Result<(), ObjectStorePersisterError> {
+        self.block_on(future::try_join_all(objects.map(|meta| async move {
+            match self.store.delete(&meta.location).await {
+                Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+                Err(e) => Err(e),
+            }
+        })))?;
+        Ok(())
+    }
+
+    fn get(&self, path: &Path) -> Result<Option<Vec<u8>>, ObjectStorePersisterError> {
+        self.block_on(async {
+            match self.store.get(path).await {
+                Ok(result) => Ok(Some(result.bytes().await?.to_vec())),
+                Err(object_store::Error::NotFound { .. }) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    /// Put an object, keeping the stored size up to date.
+    fn put(
+        &self,
+        size: &AtomicU64,
+        path: &Path,
+        value: Vec<u8>,
+    ) -> Result<(), ObjectStorePersisterError> {
+        let old = self.head(path)?;
+        let len = value.len() as u64;
+        self.block_on(self.put_object(path, value))?;
+        size.fetch_add(len, Ordering::Relaxed);
+        if let Some(old) = old {
+            size.fetch_sub(old.size, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Delete an object, keeping the stored size up to date.
+    fn delete(&self, size: &AtomicU64, path: &Path) -> Result<(), ObjectStorePersisterError> {
+        if let Some(old) = self.head(path)? {
+            match self.block_on(self.store.delete(path)) {
+                Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+                Err(e) => return Err(e.into()),
+            }
+            size.fetch_sub(old.size, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+impl SharedPersister for ObjectStorePersister {
+    type Error = ObjectStorePersisterError;
+
+    /// Get all of the current changes, fetching them concurrently.
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let changes = self.list(CHANGES_DIR)?;
+        self.block_on(future::try_join_all(changes.iter().map(
+            |meta| async move {
+                Ok(self
+                    .store
+                    .get(&meta.location)
+                    .await?
+                    .bytes()
+                    .await?
+                    .to_vec())
+            },
+        )))
+    }
+
+    /// Put all of the given changes concurrently.
+    ///
+    /// Changes are never modified once stored so existing objects are assumed to be identical.
+    fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let puts = changes.into_iter().map(|(a, s, c)| {
+            let path = self.make_change_path(&a, s);
+            let len = c.len() as u64;
+            async move {
+                self.put_object(&path, c).await?;
+                self.sizes.changes.fetch_add(len, Ordering::Relaxed);
+                Ok::<_, Self::Error>(())
+            }
+        });
+        self.block_on(future::try_join_all(puts))?;
+        Ok(())
+    }
+
+    fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        for (a, s) in changes {
+            self.delete(&self.sizes.changes, &self.make_change_path(a, s))?;
+        }
+        Ok(())
+    }
+
+    /// Get the document, joining its chunks if it is chunked.
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        let bytes = self.get(&self.prefix.child(DOCUMENT))?;
+        bytes.as_deref().and_then(Manifest::decode).map_or_else(
+            || Ok(bytes),
+            |manifest| self.get_chunked(manifest).map(Some),
+        )
+    }
+
+    /// Object puts are atomic so the document, or the manifest of a chunked document, is replaced
+    /// in one go.
+    fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let len = data.len() as u64;
+        match self.chunk_size {
+            Some(chunk_size) if data.len() > chunk_size && chunk_size > 0 => {
+                let (generation, result) =
+                    self.put_chunked(&data, chunk_size, PutMode::Overwrite)?;
+                result?;
+                self.remove_chunks(Some(generation))?;
+            }
+            chunk_size => {
+                self.block_on(self.put_object(&self.prefix.child(DOCUMENT), data))?;
+                // only chunking persisters can have left chunks behind
+                if chunk_size.is_some() {
+                    self.remove_chunks(None)?;
+                }
+            }
+        }
+        self.sizes.document.store(len, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Get the document along with the etag of its object, or of its manifest when chunked.
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        let (bytes, version) = self
+            .block_on(async {
+                match self.store.get(&self.prefix.child(DOCUMENT)).await {
+                    Ok(result) => {
+                        let version = encode_version(
+                            result.meta.e_tag.as_deref(),
+                            result.meta.version.as_deref(),
+                        );
+                        Ok(Some((result.bytes().await?.to_vec(), version)))
+                    }
+                    Err(object_store::Error::NotFound { .. }) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            })?
+            .unzip();
+        let document = bytes.as_deref().and_then(Manifest::decode).map_or_else(
+            || Ok(bytes),
+            |manifest| self.get_chunked(manifest).map(Some),
+        )?;
+        Ok((document, version))
+    }
+
+    /// Set the document with a conditional put on its object, or on its manifest when chunked.
+    ///
+    /// Chunks are only written under a new generation so a rejected document just has its own
+    /// chunks removed. Multipart uploads can't be conditional so are not used here.
+    fn set_document_if(
+        &self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let path = self.prefix.child(DOCUMENT);
+        let mode = expected.map_or(PutMode::Create, |v| PutMode::Update(decode_version(v)));
+        let len = data.len() as u64;
+        let (generation, result) = match self.chunk_size {
+            Some(chunk_size) if data.len() > chunk_size && chunk_size > 0 => {
+                let (generation, result) = self.put_chunked(&data, chunk_size, mode)?;
+                (Some(generation), result)
+            }
+            _ => (
+                None,
+                self.block_on(self.store.put_opts(
+                    &path,
+                    PutPayload::from(data),
+                    PutOptions::from(mode),
+                )),
+            ),
+        };
+        let put = match result {
+            Ok(put) => put,
+            Err(e) if is_conflict(&e) => {
+                if let Some(generation) = generation {
+                    self.remove_generation(generation)?;
+                }
+                let current = self
+                    .head(&path)?
+                    .map(|meta| encode_version(meta.e_tag.as_deref(), meta.version.as_deref()));
+                return Ok(Err(VersionConflict { current }));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if self.chunk_size.is_some() {
+            self.remove_chunks(generation)?;
+        }
+        self.sizes.document.store(len, Ordering::Relaxed);
+        Ok(Ok(Some(encode_version(
+            put.e_tag.as_deref(),
+            put.version.as_deref(),
+        ))))
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(&self.make_hex_path(SYNC_STATES_DIR, peer_id))
+    }
+
+    fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.put(
+            &self.sizes.sync_states,
+            &self.make_hex_path(SYNC_STATES_DIR, &peer_id),
+            sync_state,
+        )
+    }
+
+    fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for id in peer_ids {
+            self.delete(
+                &self.sizes.sync_states,
+                &self.make_hex_path(SYNC_STATES_DIR, id),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.list_hex_keys(SYNC_STATES_DIR)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(&self.make_hex_path(METADATA_DIR, key))
+    }
+
+    fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.put(
+            &self.sizes.metadata,
+            &self.make_hex_path(METADATA_DIR, &key),
+            value,
+        )
+    }
+
+    fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.delete(&self.sizes.metadata, &self.make_hex_path(METADATA_DIR, key))
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.list_hex_keys(METADATA_DIR)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        StoredSizes {
+            changes: self.sizes.changes.load(Ordering::Relaxed),
+            document: self.sizes.document.load(Ordering::Relaxed),
+            sync_states: self.sizes.sync_states.load(Ordering::Relaxed),
+            metadata: self.sizes.metadata.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Puts are durable once they return so there is nothing to flush.
+    fn flush(&self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
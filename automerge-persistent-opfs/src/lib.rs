@@ -0,0 +1,527 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+// the browser futures are tied to the one thread so can never be `Send`
+#![allow(clippy::future_not_send)]
+
+//! A persister targetting the browser's Origin Private File System.
+//!
+//! Records are kept in an append-only log written through synchronous access handles, so unlike
+//! `IndexedDB` a write goes straight to the file without a transaction, and large documents only
+//! count against the origin's file quota. Synchronous access handles are only available in
+//! dedicated workers so the persister has to be opened in one.
+//!
+//! Opening is async, it creates the access handles and reads the log back into memory. Each write
+//! appends to the log and [`Persister::flush`] flushes it to disk, first rewriting the log without
+//! its stale records once they outweigh the live ones.
+//!
+//! ```rust,no_run
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_opfs::{OpfsPersister, OpfsPersisterError};
+//! # async fn open() -> Result<(), OpfsPersisterError> {
+//! let persister = OpfsPersister::open_named("my-document").await?;
+//! let mut doc = PersistentAutomerge::load(persister).unwrap();
+//! doc.flush().unwrap();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashMap, convert::TryInto};
+
+use automerge::ActorId;
+use automerge_persistent_core::{Persister, StoredSizes};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    FileSystemDirectoryHandle, FileSystemFileHandle, FileSystemGetDirectoryOptions,
+    FileSystemGetFileOptions, FileSystemReadWriteOptions, FileSystemSyncAccessHandle,
+    WorkerGlobalScope,
+};
+
+/// The names of the two files the log alternates between, kept in the persister's directory.
+pub const LOG_FILES: [&str; 2] = ["log.0", "log.1"];
+
+/// Each log file starts with its generation, the file with the highest one holding the log.
+const HEADER_LEN: usize = 8;
+/// Logs smaller than this are never rewritten.
+const REWRITE_MIN_LEN: u64 = 1 << 20;
+
+const DELETE: u8 = 0;
+const PUT: u8 = 1;
+
+const CHANGES: u8 = 0;
+const DOCUMENT: u8 = 1;
+const SYNC_STATES: u8 = 2;
+const METADATA: u8 = 3;
+
+/// Persist changes and documents in to a directory of the Origin Private File System.
+///
+/// Each record in the log is an operation and store byte followed by the lengths of the key and
+/// value and then the bytes themselves, changes being keyed by their actor followed by their
+/// sequence number. A record only partly written when the worker went away is dropped when the
+/// log is next opened.
+///
+/// The log is rewritten into the other of the [`LOG_FILES`], switching over only once the new
+/// log is complete by bumping its generation, so an interrupted rewrite leaves the old log in
+/// place.
+#[derive(Debug)]
+pub struct OpfsPersister {
+    handles: [FileSystemSyncAccessHandle; 2],
+    /// Which of the handles the log is in.
+    active: usize,
+    generation: u64,
+    /// The length of the log, where the next record is written.
+    len: u64,
+    /// Bytes written since the last flush.
+    unflushed: usize,
+    changes: HashMap<(ActorId, u64), Vec<u8>>,
+    document: Option<Vec<u8>>,
+    sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    metadata: HashMap<Vec<u8>, Vec<u8>>,
+    sizes: StoredSizes,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum OpfsPersisterError {
+    /// An underlying file system error.
+    #[error("opfs error {0:?}")]
+    OpfsError(JsValue),
+    /// A record in the log was not in the expected form.
+    #[error("invalid record at offset {0}")]
+    InvalidRecord(u64),
+}
+
+fn change_key(actor_id: &ActorId, seq: u64) -> Vec<u8> {
+    let mut key = actor_id.to_bytes().to_vec();
+    key.extend(seq.to_be_bytes());
+    key
+}
+
+fn decode_change_key(key: &[u8]) -> Option<(ActorId, u64)> {
+    let split = key.len().checked_sub(8)?;
+    let (actor, seq) = key.split_at(split);
+    Some((
+        ActorId::from(actor),
+        u64::from_be_bytes(seq.try_into().ok()?),
+    ))
+}
+
+fn encode(log: &mut Vec<u8>, store: u8, key: &[u8], value: Option<&[u8]>) {
+    log.push(if value.is_some() { PUT } else { DELETE });
+    log.push(store);
+    let value = value.unwrap_or_default();
+    log.extend((key.len() as u64).to_be_bytes());
+    log.extend((value.len() as u64).to_be_bytes());
+    log.extend(key);
+    log.extend(value);
+}
+
+/// A record read back from the log.
+struct Record<'a> {
+    store: u8,
+    key: &'a [u8],
+    value: Option<&'a [u8]>,
+}
+
+/// Decode the records after the header of the log, along with the offset the last whole record
+/// ends at.
+fn decode(log: &[u8]) -> Result<(Vec<Record<'_>>, u64), OpfsPersisterError> {
+    let mut records = Vec::new();
+    let mut offset = HEADER_LEN;
+    let read = |offset: &mut usize, len: usize| {
+        let bytes = log.get(*offset..offset.checked_add(len)?)?;
+        *offset += len;
+        Some(bytes)
+    };
+    loop {
+        let start = offset;
+        let header = read(&mut offset, 18);
+        let record = header.and_then(|header| {
+            let key_len = u64::from_be_bytes(header[2..10].try_into().ok()?);
+            let value_len = u64::from_be_bytes(header[10..].try_into().ok()?);
+            let key = read(&mut offset, key_len.try_into().ok()?)?;
+            let value = read(&mut offset, value_len.try_into().ok()?)?;
+            Some((header[0], header[1], key, value))
+        });
+        let Some((op, store, key, value)) = record else {
+            // the rest was only partly written
+            return Ok((records, start as u64));
+        };
+        let value = match op {
+            PUT => Some(value),
+            DELETE => None,
+            _ => return Err(OpfsPersisterError::InvalidRecord(start as u64)),
+        };
+        if store > METADATA || (store == CHANGES && decode_change_key(key).is_none()) {
+            return Err(OpfsPersisterError::InvalidRecord(start as u64));
+        }
+        records.push(Record { store, key, value });
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn at(offset: u64) -> FileSystemReadWriteOptions {
+    let options = FileSystemReadWriteOptions::new();
+    options.set_at_f64(offset as f64);
+    options
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn read_all(handle: &FileSystemSyncAccessHandle) -> Result<Vec<u8>, OpfsPersisterError> {
+    let size = handle.get_size().map_err(OpfsPersisterError::OpfsError)?;
+    let mut bytes = vec![0; size as usize];
+    handle
+        .read_with_u8_array_and_options(&mut bytes, &at(0))
+        .map_err(OpfsPersisterError::OpfsError)?;
+    Ok(bytes)
+}
+
+fn write(
+    handle: &FileSystemSyncAccessHandle,
+    offset: u64,
+    bytes: &[u8],
+) -> Result<(), OpfsPersisterError> {
+    handle
+        .write_with_u8_array_and_options(bytes, &at(offset))
+        .map_err(OpfsPersisterError::OpfsError)?;
+    Ok(())
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn truncate(handle: &FileSystemSyncAccessHandle, len: u64) -> Result<(), OpfsPersisterError> {
+    handle
+        .truncate_with_f64(len as f64)
+        .map_err(OpfsPersisterError::OpfsError)
+}
+
+fn flush(handle: &FileSystemSyncAccessHandle) -> Result<(), OpfsPersisterError> {
+    handle.flush().map_err(OpfsPersisterError::OpfsError)
+}
+
+fn generation(log: &[u8]) -> u64 {
+    log.get(..HEADER_LEN)
+        .map_or(0, |header| u64::from_be_bytes(header.try_into().unwrap()))
+}
+
+async fn open_handle(
+    directory: &FileSystemDirectoryHandle,
+    name: &str,
+) -> Result<FileSystemSyncAccessHandle, OpfsPersisterError> {
+    let options = FileSystemGetFileOptions::new();
+    options.set_create(true);
+    let file = JsFuture::from(directory.get_file_handle_with_options(name, &options))
+        .await
+        .map_err(OpfsPersisterError::OpfsError)?
+        .unchecked_into::<FileSystemFileHandle>();
+    Ok(JsFuture::from(file.create_sync_access_handle())
+        .await
+        .map_err(OpfsPersisterError::OpfsError)?
+        .unchecked_into())
+}
+
+impl OpfsPersister {
+    /// Open the persister in the given directory, creating its files if they do not exist, and
+    /// read its records.
+    ///
+    /// The files stay locked to this persister until it is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the files could not be opened or read, such as when this is not run in
+    /// a dedicated worker, or the log holds an invalid record.
+    pub async fn open(directory: &FileSystemDirectoryHandle) -> Result<Self, OpfsPersisterError> {
+        let handles = [
+            open_handle(directory, LOG_FILES[0]).await?,
+            open_handle(directory, LOG_FILES[1]).await?,
+        ];
+        let logs = [read_all(&handles[0])?, read_all(&handles[1])?];
+        let active = usize::from(generation(&logs[1]) > generation(&logs[0]));
+        let log = &logs[active];
+
+        let mut persister = Self {
+            handles,
+            active,
+            generation: generation(log),
+            len: HEADER_LEN as u64,
+            unflushed: 0,
+            changes: HashMap::new(),
+            document: None,
+            sync_states: HashMap::new(),
+            metadata: HashMap::new(),
+            sizes: StoredSizes::default(),
+        };
+        if persister.generation == 0 {
+            // a new log
+            persister.generation = 1;
+            write(
+                &persister.handles[active],
+                0,
+                &persister.generation.to_be_bytes(),
+            )?;
+        } else {
+            let (records, len) = decode(log)?;
+            persister.len = len;
+            for record in records {
+                persister.replay(&record);
+            }
+        }
+        truncate(&persister.handles[active], persister.len)?;
+        Ok(persister)
+    }
+
+    /// Open the persister in the directory with the given name at the root of the origin's file
+    /// system, creating it if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if this is not run in a worker, or as for [`OpfsPersister::open`].
+    pub async fn open_named(name: &str) -> Result<Self, OpfsPersisterError> {
+        let scope = js_sys::global()
+            .dyn_into::<WorkerGlobalScope>()
+            .map_err(|global| OpfsPersisterError::OpfsError(global.into()))?;
+        let root = JsFuture::from(scope.navigator().storage().get_directory())
+            .await
+            .map_err(OpfsPersisterError::OpfsError)?
+            .unchecked_into::<FileSystemDirectoryHandle>();
+        let options = FileSystemGetDirectoryOptions::new();
+        options.set_create(true);
+        let directory = JsFuture::from(root.get_directory_handle_with_options(name, &options))
+            .await
+            .map_err(OpfsPersisterError::OpfsError)?
+            .unchecked_into::<FileSystemDirectoryHandle>();
+        Self::open(&directory).await
+    }
+
+    fn replay(&mut self, record: &Record<'_>) {
+        let value = record.value.map(<[u8]>::to_vec);
+        let (size, old) = match record.store {
+            CHANGES => {
+                // checked when decoding
+                let key = decode_change_key(record.key).unwrap();
+                let old = match value {
+                    Some(value) => {
+                        self.sizes.changes += value.len() as u64;
+                        self.changes.insert(key, value)
+                    }
+                    None => self.changes.remove(&key),
+                };
+                (&mut self.sizes.changes, old)
+            }
+            DOCUMENT => {
+                self.sizes.document = value.as_ref().map_or(0, Vec::len) as u64;
+                self.document = value;
+                return;
+            }
+            store => {
+                let (map, size) = if store == SYNC_STATES {
+                    (&mut self.sync_states, &mut self.sizes.sync_states)
+                } else {
+                    (&mut self.metadata, &mut self.sizes.metadata)
+                };
+                let old = match value {
+                    Some(value) => {
+                        *size += value.len() as u64;
+                        map.insert(record.key.to_vec(), value)
+                    }
+                    None => map.remove(record.key),
+                };
+                (size, old)
+            }
+        };
+        if let Some(old) = old {
+            *size -= old.len() as u64;
+        }
+    }
+
+    /// Write the encoded records to the end of the log.
+    fn append(&mut self, records: &[u8]) -> Result<(), OpfsPersisterError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        write(&self.handles[self.active], self.len, records)?;
+        self.len += records.len() as u64;
+        self.unflushed += records.len();
+        Ok(())
+    }
+
+    /// Write just the live records in to the other file, switching over to it once it has been
+    /// flushed.
+    fn rewrite(&mut self) -> Result<(), OpfsPersisterError> {
+        let mut log = Vec::new();
+        for ((actor, seq), change) in &self.changes {
+            encode(&mut log, CHANGES, &change_key(actor, *seq), Some(change));
+        }
+        if let Some(document) = &self.document {
+            encode(&mut log, DOCUMENT, &[], Some(document));
+        }
+        for (store, map) in [(SYNC_STATES, &self.sync_states), (METADATA, &self.metadata)] {
+            for (key, value) in map {
+                encode(&mut log, store, key, Some(value));
+            }
+        }
+
+        let next = 1 - self.active;
+        let handle = &self.handles[next];
+        let len = (HEADER_LEN + log.len()) as u64;
+        write(handle, HEADER_LEN as u64, &log)?;
+        truncate(handle, len)?;
+        flush(handle)?;
+        write(handle, 0, &(self.generation + 1).to_be_bytes())?;
+        flush(handle)?;
+
+        let old = &self.handles[self.active];
+        truncate(old, 0)?;
+        flush(old)?;
+        self.active = next;
+        self.generation += 1;
+        self.len = len;
+        Ok(())
+    }
+
+    const fn stored(&self) -> u64 {
+        self.sizes.changes + self.sizes.document + self.sizes.sync_states + self.sizes.metadata
+    }
+}
+
+impl Drop for OpfsPersister {
+    fn drop(&mut self) {
+        for handle in &self.handles {
+            handle.close();
+        }
+    }
+}
+
+impl Persister for OpfsPersister {
+    type Error = OpfsPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.changes.values().cloned().collect())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        for (a, s, c) in &changes {
+            encode(&mut log, CHANGES, &change_key(a, *s), Some(c));
+        }
+        self.append(&log)?;
+        for (a, s, c) in changes {
+            self.sizes.changes += c.len() as u64;
+            if let Some(old) = self.changes.insert((a, s), c) {
+                self.sizes.changes -= old.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        for (a, s) in &changes {
+            if self.changes.contains_key(&((*a).clone(), *s)) {
+                encode(&mut log, CHANGES, &change_key(a, *s), None);
+            }
+        }
+        self.append(&log)?;
+        for (a, s) in changes {
+            if let Some(old) = self.changes.remove(&(a.clone(), s)) {
+                self.sizes.changes -= old.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.document.clone())
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        encode(&mut log, DOCUMENT, &[], Some(&data));
+        self.append(&log)?;
+        self.sizes.document = data.len() as u64;
+        self.document = Some(data);
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.get(peer_id).cloned())
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        encode(&mut log, SYNC_STATES, &peer_id, Some(&sync_state));
+        self.append(&log)?;
+        self.sizes.sync_states += sync_state.len() as u64;
+        if let Some(old) = self.sync_states.insert(peer_id, sync_state) {
+            self.sizes.sync_states -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        for id in peer_ids {
+            if self.sync_states.contains_key(*id) {
+                encode(&mut log, SYNC_STATES, id, None);
+            }
+        }
+        self.append(&log)?;
+        for id in peer_ids {
+            if let Some(old) = self.sync_states.remove(*id) {
+                self.sizes.sync_states -= old.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.keys().cloned().collect())
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.get(key).cloned())
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        encode(&mut log, METADATA, &key, Some(&value));
+        self.append(&log)?;
+        self.sizes.metadata += value.len() as u64;
+        if let Some(old) = self.metadata.insert(key, value) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        if self.metadata.contains_key(key) {
+            let mut log = Vec::new();
+            encode(&mut log, METADATA, key, None);
+            self.append(&log)?;
+        }
+        if let Some(old) = self.metadata.remove(key) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.keys().cloned().collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Flush the log to disk, rewriting it first if less than half of it is still live.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        if self.len > REWRITE_MIN_LEN && self.len - HEADER_LEN as u64 > 2 * self.stored() {
+            self.rewrite()?;
+        } else if self.unflushed > 0 {
+            flush(&self.handles[self.active])?;
+        }
+        Ok(std::mem::take(&mut self.unflushed))
+    }
+}
@@ -0,0 +1,536 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [PostgreSQL](https://www.postgresql.org).
+//!
+//! Changes, documents, sync states and metadata are kept in separate tables keyed by the document
+//! id, so many documents can share the same tables. The tables can be created with
+//! [`create_tables`].
+//!
+//! ```rust,no_run
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_postgres::PostgresPersister;
+//! # use postgres::{Client, NoTls};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut client = Client::connect("host=localhost user=postgres", NoTls)?;
+//! automerge_persistent_postgres::create_tables(&mut client)?;
+//!
+//! let persister = PostgresPersister::new(client, "my-document")?;
+//! let doc = PersistentAutomerge::load(persister)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Change propagation
+//!
+//! Nodes sharing the database can learn of each other's changes through `LISTEN`/`NOTIFY`. A
+//! persister with a notify channel sends a notification for each change it inserts, in the same
+//! transaction, and a [`ChangeListener`] on another node receives them and fetches the changes
+//! so they can be applied.
+//!
+//! ```rust,no_run
+//! # use std::time::Duration;
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_postgres::{ChangeListener, PostgresPersister};
+//! # use postgres::{Client, NoTls};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let config = "host=localhost user=postgres";
+//! let persister = PostgresPersister::new(Client::connect(config, NoTls)?, "my-document")?
+//!     .with_notify_channel("automerge_changes");
+//! let mut doc = PersistentAutomerge::load(persister)?;
+//!
+//! let mut listener = ChangeListener::new(
+//!     Client::connect(config, NoTls)?,
+//!     "automerge_changes",
+//!     "my-document",
+//! )?;
+//! loop {
+//!     let changes = listener.next_changes(Duration::from_secs(1))?;
+//!     doc.apply_raw_changes(changes)?;
+//! }
+//! # }
+//! ```
+
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use automerge::ActorId;
+use automerge_persistent_core::{SharedPersister, StoredSizes};
+use postgres::{fallible_iterator::FallibleIterator, Client};
+
+/// The name of the table changes are stored in.
+pub const CHANGES_TABLE: &str = "automerge_changes";
+/// The name of the table documents are stored in.
+pub const DOCUMENTS_TABLE: &str = "automerge_documents";
+/// The name of the table sync states are stored in.
+pub const SYNC_STATES_TABLE: &str = "automerge_sync_states";
+/// The name of the table metadata is stored in.
+pub const METADATA_TABLE: &str = "automerge_metadata";
+
+/// The persister that stores changes and documents in `PostgreSQL` tables.
+///
+/// The client is internally locked so this is a [`SharedPersister`] and can be shared behind an
+/// [`std::sync::Arc`] without extra locking.
+pub struct PostgresPersister {
+    client: Mutex<Client>,
+    document_id: Vec<u8>,
+    notify_channel: Option<String>,
+    sizes: Mutex<StoredSizes>,
+}
+
+impl std::fmt::Debug for PostgresPersister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PostgresPersister")
+            .field("document_id", &self.document_id)
+            .field("notify_channel", &self.notify_channel)
+            .field("sizes", &self.sizes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum PostgresPersisterError {
+    /// Internal errors from `PostgreSQL`.
+    #[error(transparent)]
+    PostgresError(#[from] postgres::Error),
+}
+
+/// Create the tables used by the persister, if they do not already exist.
+///
+/// # Errors
+///
+/// Returns an error if any of the tables could not be created.
+pub fn create_tables(client: &mut Client) -> Result<(), PostgresPersisterError> {
+    client.batch_execute(&format!(
+        "CREATE TABLE IF NOT EXISTS {CHANGES_TABLE} (
+            document_id bytea, actor_id bytea, seq bigint, change bytea NOT NULL,
+            PRIMARY KEY (document_id, actor_id, seq)
+        );
+        CREATE TABLE IF NOT EXISTS {DOCUMENTS_TABLE} (
+            document_id bytea PRIMARY KEY, document bytea NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS {SYNC_STATES_TABLE} (
+            document_id bytea, peer_id bytea, sync_state bytea NOT NULL,
+            PRIMARY KEY (document_id, peer_id)
+        );
+        CREATE TABLE IF NOT EXISTS {METADATA_TABLE} (
+            document_id bytea, key bytea, value bytea NOT NULL,
+            PRIMARY KEY (document_id, key)
+        );"
+    ))?;
+    Ok(())
+}
+
+/// Sequence numbers are stored as a `bigint`, which is signed, so reinterpret the bits.
+const fn seq_to_bigint(seq: u64) -> i64 {
+    i64::from_be_bytes(seq.to_be_bytes())
+}
+
+/// Quote a channel name so it can be used as an identifier.
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Lock a mutex, ignoring poisoning as a panic while it was held cannot leave a transaction half
+/// applied.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl PostgresPersister {
+    /// Construct a new persister for the document with the given id.
+    ///
+    /// The tables must already exist, see [`create_tables`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the existing contents of the tables could not be read to calculate the
+    /// stored sizes.
+    pub fn new<D>(mut client: Client, document_id: D) -> Result<Self, PostgresPersisterError>
+    where
+        D: Into<Vec<u8>>,
+    {
+        let document_id = document_id.into();
+        let mut table_size = |table: &str, column: &str| -> Result<u64, PostgresPersisterError> {
+            let size: i64 = client
+                .query_one(
+                    &format!(
+                        "SELECT COALESCE(SUM(LENGTH({column})), 0)::bigint FROM {table} WHERE document_id = $1"
+                    ),
+                    &[&document_id],
+                )?
+                .get(0);
+            Ok(size.unsigned_abs())
+        };
+        let sizes = StoredSizes {
+            changes: table_size(CHANGES_TABLE, "change")?,
+            document: table_size(DOCUMENTS_TABLE, "document")?,
+            sync_states: table_size(SYNC_STATES_TABLE, "sync_state")?,
+            metadata: table_size(METADATA_TABLE, "value")?,
+        };
+        Ok(Self {
+            client: Mutex::new(client),
+            document_id,
+            notify_channel: None,
+            sizes: Mutex::new(sizes),
+        })
+    }
+
+    /// Send a notification on the channel for each inserted change, for a [`ChangeListener`] to
+    /// receive.
+    #[must_use]
+    pub fn with_notify_channel<S: Into<String>>(mut self, channel: S) -> Self {
+        self.notify_channel = Some(channel.into());
+        self
+    }
+
+    /// Query a single blob.
+    fn blob(
+        &self,
+        query: &str,
+        key: Option<&[u8]>,
+    ) -> Result<Option<Vec<u8>>, PostgresPersisterError> {
+        let row = match key {
+            Some(key) => lock(&self.client).query_opt(query, &[&self.document_id, &key])?,
+            None => lock(&self.client).query_opt(query, &[&self.document_id])?,
+        };
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Query the first column of all rows for this document.
+    fn column(&self, query: &str) -> Result<Vec<Vec<u8>>, PostgresPersisterError> {
+        let rows = lock(&self.client).query(query, &[&self.document_id])?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Upsert a value in one of the keyed tables, returning the size of the replaced value.
+    fn upsert(
+        &self,
+        table: &str,
+        column: &str,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<u64, PostgresPersisterError> {
+        let mut client = lock(&self.client);
+        let mut transaction = client.transaction()?;
+        let old = transaction
+            .query_opt(
+                &format!(
+                    "SELECT LENGTH({column}) FROM {table} WHERE document_id = $1 AND {} = $2 FOR UPDATE",
+                    key_column(table)
+                ),
+                &[&self.document_id, &key],
+            )?
+            .map(|row| row.get::<_, i32>(0).unsigned_abs());
+        transaction.execute(
+            &format!(
+                "INSERT INTO {table} (document_id, {key}, {column}) VALUES ($1, $2, $3)
+                ON CONFLICT (document_id, {key}) DO UPDATE SET {column} = EXCLUDED.{column}",
+                key = key_column(table)
+            ),
+            &[&self.document_id, &key, &value],
+        )?;
+        transaction.commit()?;
+        drop(client);
+        Ok(old.map_or(0, u64::from))
+    }
+
+    /// Delete a value from one of the keyed tables, returning the size of the removed value.
+    fn delete(&self, table: &str, column: &str, key: &[u8]) -> Result<u64, PostgresPersisterError> {
+        let old = lock(&self.client)
+            .query_opt(
+                &format!(
+                    "DELETE FROM {table} WHERE document_id = $1 AND {} = $2 RETURNING LENGTH({column})",
+                    key_column(table)
+                ),
+                &[&self.document_id, &key],
+            )?
+            .map(|row| row.get::<_, i32>(0).unsigned_abs());
+        Ok(old.map_or(0, u64::from))
+    }
+}
+
+/// The column keying values within a document in the sync state and metadata tables.
+fn key_column(table: &str) -> &'static str {
+    if table == SYNC_STATES_TABLE {
+        "peer_id"
+    } else {
+        "key"
+    }
+}
+
+impl SharedPersister for PostgresPersister {
+    type Error = PostgresPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.column(&format!(
+            "SELECT change FROM {CHANGES_TABLE} WHERE document_id = $1"
+        ))
+    }
+
+    /// Insert all of the given changes in a single transaction.
+    ///
+    /// Changes that are already stored are left as they are. With a notify channel a
+    /// notification is sent for each newly stored change, delivered when the transaction
+    /// commits.
+    fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let mut client = lock(&self.client);
+        let mut transaction = client.transaction()?;
+        let insert = transaction.prepare(&format!(
+            "INSERT INTO {CHANGES_TABLE} (document_id, actor_id, seq, change) VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING"
+        ))?;
+        let mut added = 0;
+        for (a, s, c) in changes {
+            let actor = a.to_bytes();
+            let inserted = transaction
+                .execute(&insert, &[&self.document_id, &actor, &seq_to_bigint(s), &c])?;
+            if inserted == 0 {
+                continue;
+            }
+            added += c.len() as u64;
+            if let Some(channel) = &self.notify_channel {
+                let payload = format!(
+                    "{} {} {}",
+                    hex::encode(&self.document_id),
+                    hex::encode(actor),
+                    s
+                );
+                transaction.execute("SELECT pg_notify($1, $2)", &[channel, &payload])?;
+            }
+        }
+        transaction.commit()?;
+        drop(client);
+        lock(&self.sizes).changes += added;
+        Ok(())
+    }
+
+    /// Remove all of the given changes in a single transaction.
+    fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let mut client = lock(&self.client);
+        let mut transaction = client.transaction()?;
+        let remove = transaction.prepare(&format!(
+            "DELETE FROM {CHANGES_TABLE} WHERE document_id = $1 AND actor_id = $2 AND seq = $3
+            RETURNING LENGTH(change)"
+        ))?;
+        let mut removed = 0;
+        for (a, s) in changes {
+            if let Some(row) = transaction.query_opt(
+                &remove,
+                &[&self.document_id, &a.to_bytes(), &seq_to_bigint(s)],
+            )? {
+                removed += u64::from(row.get::<_, i32>(0).unsigned_abs());
+            }
+        }
+        transaction.commit()?;
+        drop(client);
+        lock(&self.sizes).changes -= removed;
+        Ok(())
+    }
+
+    /// List the actors from the changes table without reading the changes.
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        Ok(self
+            .column(&format!(
+                "SELECT DISTINCT actor_id FROM {CHANGES_TABLE} WHERE document_id = $1"
+            ))?
+            .into_iter()
+            .map(ActorId::from)
+            .collect())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.blob(
+            &format!("SELECT document FROM {DOCUMENTS_TABLE} WHERE document_id = $1"),
+            None,
+        )
+    }
+
+    fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+        lock(&self.client).execute(
+            &format!(
+                "INSERT INTO {DOCUMENTS_TABLE} (document_id, document) VALUES ($1, $2)
+                ON CONFLICT (document_id) DO UPDATE SET document = EXCLUDED.document"
+            ),
+            &[&self.document_id, &data],
+        )?;
+        lock(&self.sizes).document = data.len() as u64;
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.blob(
+            &format!(
+                "SELECT sync_state FROM {SYNC_STATES_TABLE} WHERE document_id = $1 AND peer_id = $2"
+            ),
+            Some(peer_id),
+        )
+    }
+
+    fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let old = self.upsert(SYNC_STATES_TABLE, "sync_state", &peer_id, &sync_state)?;
+        let mut sizes = lock(&self.sizes);
+        sizes.sync_states += sync_state.len() as u64;
+        sizes.sync_states -= old;
+        drop(sizes);
+        Ok(())
+    }
+
+    fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for id in peer_ids {
+            let old = self.delete(SYNC_STATES_TABLE, "sync_state", id)?;
+            lock(&self.sizes).sync_states -= old;
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.column(&format!(
+            "SELECT peer_id FROM {SYNC_STATES_TABLE} WHERE document_id = $1"
+        ))
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.blob(
+            &format!("SELECT value FROM {METADATA_TABLE} WHERE document_id = $1 AND key = $2"),
+            Some(key),
+        )
+    }
+
+    fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let old = self.upsert(METADATA_TABLE, "value", &key, &value)?;
+        let mut sizes = lock(&self.sizes);
+        sizes.metadata += value.len() as u64;
+        sizes.metadata -= old;
+        drop(sizes);
+        Ok(())
+    }
+
+    fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+        let old = self.delete(METADATA_TABLE, "value", key)?;
+        lock(&self.sizes).metadata -= old;
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.column(&format!(
+            "SELECT key FROM {METADATA_TABLE} WHERE document_id = $1"
+        ))
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        lock(&self.sizes).clone()
+    }
+
+    /// Writes are committed as they are made so there is nothing to flush.
+    fn flush(&self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
+
+/// Receives the changes inserted for a document by persisters on other nodes.
+///
+/// This listens on the notify channel set with [`PostgresPersister::with_notify_channel`] using
+/// its own connection, which is needed as notifications are only delivered to connections that
+/// are listening. The received changes are fetched from the changes table, ready to be passed to
+/// `apply_raw_changes`. A node also receives the notifications for its own changes, applying
+/// those again does nothing.
+///
+/// Notifications are not queued for connections that are not listening, so changes made while a
+/// listener is disconnected are missed and should be caught up on through syncing or reloading.
+pub struct ChangeListener {
+    client: Client,
+    document_id: Vec<u8>,
+}
+
+impl std::fmt::Debug for ChangeListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChangeListener")
+            .field("document_id", &self.document_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChangeListener {
+    /// Start listening on the channel for changes to the document.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the `LISTEN` failed.
+    pub fn new<D>(
+        mut client: Client,
+        channel: &str,
+        document_id: D,
+    ) -> Result<Self, PostgresPersisterError>
+    where
+        D: Into<Vec<u8>>,
+    {
+        client.batch_execute(&format!("LISTEN {}", quote_identifier(channel)))?;
+        Ok(Self {
+            client,
+            document_id: document_id.into(),
+        })
+    }
+
+    /// Wait up to the timeout for notifications, returning the changes they refer to.
+    ///
+    /// Once one notification has arrived any others already received are taken too without
+    /// waiting further. Returns an empty list if nothing arrived in time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if receiving the notifications or fetching the changes failed.
+    pub fn next_changes(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Vec<Vec<u8>>, PostgresPersisterError> {
+        let mut payloads = Vec::new();
+        let mut notifications = self.client.notifications();
+        let first = notifications.timeout_iter(timeout).next()?;
+        if let Some(notification) = first {
+            payloads.push(notification.payload().to_owned());
+            let mut pending = notifications.iter();
+            while let Some(notification) = pending.next()? {
+                payloads.push(notification.payload().to_owned());
+            }
+        }
+        drop(notifications);
+
+        let mut changes = Vec::new();
+        for payload in payloads {
+            if let Some((actor, seq)) = self.parse_payload(&payload) {
+                let row = self.client.query_opt(
+                    &format!(
+                        "SELECT change FROM {CHANGES_TABLE} WHERE document_id = $1 AND actor_id = $2 AND seq = $3"
+                    ),
+                    &[&self.document_id, &actor, &seq_to_bigint(seq)],
+                )?;
+                // the change may have been removed by a compaction since
+                changes.extend(row.map(|row| row.get(0)));
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Parse a notification payload, returning `None` if it is for another document or is not
+    /// from a persister.
+    fn parse_payload(&self, payload: &str) -> Option<(Vec<u8>, u64)> {
+        let mut parts = payload.split(' ');
+        let document_id = hex::decode(parts.next()?).ok()?;
+        let actor = hex::decode(parts.next()?).ok()?;
+        let seq = parts.next()?.parse().ok()?;
+        (document_id == self.document_id && parts.next().is_none()).then_some((actor, seq))
+    }
+}
@@ -0,0 +1,411 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [Redis](https://redis.io).
+//!
+//! Each document is kept in a few keys starting with a prefix, so many documents can share the
+//! same database.
+//!
+//! ```rust,no_run
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_redis::RedisPersister;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = redis::Client::open("redis://127.0.0.1/")?;
+//! let persister = RedisPersister::new(client.get_connection()?, "my-document:")?;
+//! let doc = PersistentAutomerge::load(persister)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Fan-out of changes
+//!
+//! Servers sharing Redis can keep their copies of a document up to date by having the persister
+//! publish each inserted change on a channel, and running a [`ChangeSubscriber`] that applies
+//! the published changes to their local document.
+//!
+//! ```rust,no_run
+//! # use std::time::Duration;
+//! # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+//! # use automerge_persistent_redis::{ChangeSubscriber, RedisPersister};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = redis::Client::open("redis://127.0.0.1/")?;
+//! let persister = RedisPersister::new(client.get_connection()?, "my-document:")?
+//!     .with_publish_channel("my-document");
+//! let mut doc = PersistentAutomerge::load(persister)?;
+//!
+//! let mut replica = PersistentAutomerge::load(MemoryPersister::default())?;
+//! let mut connection = client.get_connection()?;
+//! let mut subscriber = ChangeSubscriber::new(&mut connection, "my-document")?;
+//! loop {
+//!     subscriber.apply_next(&mut replica, Duration::from_secs(1))?;
+//! }
+//! # }
+//! ```
+
+use std::{
+    collections::HashSet,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use automerge::ActorId;
+use automerge_persistent::{
+    Backend, Error, PersistentAutomerge, Persister, SharedPersister, StoredSizes,
+};
+use redis::{Commands, Connection, PubSub, RedisError};
+
+/// The suffix of the key of the hash changes are stored in.
+pub const CHANGES_KEY: &str = "changes";
+/// The suffix of the key the document is stored in.
+pub const DOCUMENT_KEY: &str = "document";
+/// The suffix of the key of the hash sync states are stored in.
+pub const SYNC_STATES_KEY: &str = "sync_states";
+/// The suffix of the key of the hash metadata is stored in.
+pub const METADATA_KEY: &str = "metadata";
+
+/// The persister that stores changes and documents in Redis.
+///
+/// Changes, sync states and metadata are kept in hashes and the document in a string, each under
+/// a key made of the prefix and a suffix.
+///
+/// The connection is internally locked so this is a [`SharedPersister`] and can be shared behind
+/// an [`std::sync::Arc`] without extra locking.
+pub struct RedisPersister {
+    connection: Mutex<Connection>,
+    changes_key: String,
+    document_key: String,
+    sync_states_key: String,
+    metadata_key: String,
+    publish_channel: Option<String>,
+    sizes: Mutex<StoredSizes>,
+}
+
+impl std::fmt::Debug for RedisPersister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisPersister")
+            .field("document_key", &self.document_key)
+            .field("publish_channel", &self.publish_channel)
+            .field("sizes", &self.sizes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum RedisPersisterError {
+    /// Internal errors from Redis.
+    #[error(transparent)]
+    RedisError(#[from] RedisError),
+}
+
+/// Lock a mutex, ignoring poisoning as a panic while it was held cannot leave a command half
+/// sent.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Make the field of a change in the changes hash from the `actor_id` and `sequence_number`.
+fn change_field(actor_id: &ActorId, seq: u64) -> Vec<u8> {
+    let mut field = actor_id.to_bytes().to_vec();
+    field.extend(&seq.to_be_bytes());
+    field
+}
+
+impl RedisPersister {
+    /// Construct a new persister for the keys starting with the prefix.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the existing contents could not be read to calculate the stored sizes.
+    pub fn new<S>(mut connection: Connection, prefix: S) -> Result<Self, RedisPersisterError>
+    where
+        S: AsRef<str>,
+    {
+        let prefix = prefix.as_ref();
+        let changes_key = format!("{prefix}{CHANGES_KEY}");
+        let document_key = format!("{prefix}{DOCUMENT_KEY}");
+        let sync_states_key = format!("{prefix}{SYNC_STATES_KEY}");
+        let metadata_key = format!("{prefix}{METADATA_KEY}");
+        let mut hash_size = |key: &str| -> Result<u64, RedisError> {
+            let values: Vec<Vec<u8>> = connection.hvals(key)?;
+            Ok(values.iter().map(|v| v.len() as u64).sum())
+        };
+        let sizes = StoredSizes {
+            changes: hash_size(&changes_key)?,
+            sync_states: hash_size(&sync_states_key)?,
+            metadata: hash_size(&metadata_key)?,
+            document: connection.strlen(&document_key)?,
+        };
+        Ok(Self {
+            connection: Mutex::new(connection),
+            changes_key,
+            document_key,
+            sync_states_key,
+            metadata_key,
+            publish_channel: None,
+            sizes: Mutex::new(sizes),
+        })
+    }
+
+    /// Publish the bytes of each newly inserted change on the channel, for a [`ChangeSubscriber`]
+    /// to receive.
+    #[must_use]
+    pub fn with_publish_channel<S: Into<String>>(mut self, channel: S) -> Self {
+        self.publish_channel = Some(channel.into());
+        self
+    }
+
+    /// Set a field of a hash, returning the size of the replaced value.
+    fn hash_set(&self, key: &str, field: &[u8], value: &[u8]) -> Result<u64, RedisError> {
+        let (old,): (u64,) = redis::pipe()
+            .atomic()
+            .cmd("HSTRLEN")
+            .arg(key)
+            .arg(field)
+            .hset(key, field, value)
+            .ignore()
+            .query(&mut *lock(&self.connection))?;
+        Ok(old)
+    }
+
+    /// Remove fields of a hash, returning the size of the removed values.
+    fn hash_remove(&self, key: &str, fields: &[&[u8]]) -> Result<u64, RedisError> {
+        if fields.is_empty() {
+            return Ok(0);
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for field in fields {
+            pipe.cmd("HSTRLEN").arg(key).arg(*field);
+        }
+        pipe.hdel(key, fields).ignore();
+        let old: Vec<u64> = pipe.query(&mut *lock(&self.connection))?;
+        Ok(old.iter().sum())
+    }
+}
+
+impl SharedPersister for RedisPersister {
+    type Error = RedisPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(lock(&self.connection).hvals(&self.changes_key)?)
+    }
+
+    /// Insert all of the given changes in a single transaction.
+    ///
+    /// With a publish channel the changes that were not already stored are published, in the
+    /// same transaction.
+    fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let fields = changes
+            .iter()
+            .map(|(a, s, _)| change_field(a, *s))
+            .collect::<Vec<_>>();
+        let mut connection = lock(&self.connection);
+        let mut lengths = redis::pipe();
+        for field in &fields {
+            lengths.cmd("HSTRLEN").arg(&self.changes_key).arg(field);
+        }
+        let old: Vec<u64> = lengths.query(&mut *connection)?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let mut added = 0;
+        for ((field, (_, _, change)), old) in fields.iter().zip(&changes).zip(&old) {
+            pipe.hset(&self.changes_key, field, change).ignore();
+            if let (Some(channel), 0) = (&self.publish_channel, old) {
+                pipe.publish(channel, change).ignore();
+            }
+            added += change.len() as u64;
+        }
+        pipe.query::<()>(&mut *connection)?;
+        drop(connection);
+
+        let mut sizes = lock(&self.sizes);
+        sizes.changes += added;
+        sizes.changes -= old.iter().sum::<u64>();
+        drop(sizes);
+        Ok(())
+    }
+
+    /// Remove all of the given changes in a single transaction.
+    fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let fields = changes
+            .into_iter()
+            .map(|(a, s)| change_field(a, s))
+            .collect::<Vec<_>>();
+        let fields = fields.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let removed = self.hash_remove(&self.changes_key, &fields)?;
+        lock(&self.sizes).changes -= removed;
+        Ok(())
+    }
+
+    /// List the actors from the fields of the changes hash without reading the changes.
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        let fields: Vec<Vec<u8>> = lock(&self.connection).hkeys(&self.changes_key)?;
+        let actors = fields
+            .into_iter()
+            .filter(|field| field.len() >= 8)
+            .map(|field| field[..field.len() - 8].to_vec())
+            .collect::<HashSet<_>>();
+        Ok(actors.into_iter().map(ActorId::from).collect())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(lock(&self.connection).get(&self.document_key)?)
+    }
+
+    fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+        lock(&self.connection).set::<_, _, ()>(&self.document_key, &data)?;
+        lock(&self.sizes).document = data.len() as u64;
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(lock(&self.connection).hget(&self.sync_states_key, peer_id)?)
+    }
+
+    fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let old = self.hash_set(&self.sync_states_key, &peer_id, &sync_state)?;
+        let mut sizes = lock(&self.sizes);
+        sizes.sync_states += sync_state.len() as u64;
+        sizes.sync_states -= old;
+        drop(sizes);
+        Ok(())
+    }
+
+    fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let removed = self.hash_remove(&self.sync_states_key, peer_ids)?;
+        lock(&self.sizes).sync_states -= removed;
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(lock(&self.connection).hkeys(&self.sync_states_key)?)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(lock(&self.connection).hget(&self.metadata_key, key)?)
+    }
+
+    fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let old = self.hash_set(&self.metadata_key, &key, &value)?;
+        let mut sizes = lock(&self.sizes);
+        sizes.metadata += value.len() as u64;
+        sizes.metadata -= old;
+        drop(sizes);
+        Ok(())
+    }
+
+    fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+        let removed = self.hash_remove(&self.metadata_key, &[key])?;
+        lock(&self.sizes).metadata -= removed;
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(lock(&self.connection).hkeys(&self.metadata_key)?)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        lock(&self.sizes).clone()
+    }
+
+    /// Redis persists writes according to its own configuration so there is nothing to flush.
+    fn flush(&self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
+
+/// Possible errors from applying published changes.
+#[derive(Debug, thiserror::Error)]
+pub enum SubscribeError<E>
+where
+    E: std::error::Error + 'static,
+{
+    /// Errors from the persistent document.
+    #[error(transparent)]
+    PersistentError(#[from] Error<E>),
+    /// Errors from Redis.
+    #[error(transparent)]
+    RedisError(#[from] RedisError),
+}
+
+/// Receives the changes published by persisters on other servers.
+///
+/// This subscribes to the channel set with [`RedisPersister::with_publish_channel`]. A
+/// subscribed connection can't run other commands so it needs its own connection, separate
+/// from any persister. A server also receives the changes it published itself, applying those
+/// again does nothing.
+///
+/// Published messages are not kept for subscribers that are not connected, so changes published
+/// while disconnected are missed and should be caught up on through syncing or reloading.
+pub struct ChangeSubscriber<'a> {
+    pubsub: PubSub<'a>,
+}
+
+impl std::fmt::Debug for ChangeSubscriber<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChangeSubscriber").finish_non_exhaustive()
+    }
+}
+
+impl<'a> ChangeSubscriber<'a> {
+    /// Subscribe to the channel on the connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if subscribing failed.
+    pub fn new(connection: &'a mut Connection, channel: &str) -> Result<Self, RedisError> {
+        let mut pubsub = connection.as_pubsub();
+        pubsub.subscribe(channel)?;
+        Ok(Self { pubsub })
+    }
+
+    /// Wait up to the timeout for a published change, returning its bytes.
+    ///
+    /// Returns `None` if nothing arrived in time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if receiving failed.
+    pub fn next_change(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, RedisError> {
+        // a zero timeout is rejected by redis
+        self.pubsub
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        match self.pubsub.get_message() {
+            Ok(message) => Ok(Some(message.get_payload_bytes().to_vec())),
+            Err(error) if error.is_timeout() => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Wait up to the timeout for a published change and apply it to the document, returning
+    /// whether one arrived.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if receiving failed or the change could not be applied.
+    pub fn apply_next<P, B>(
+        &mut self,
+        doc: &mut PersistentAutomerge<P, B>,
+        timeout: Duration,
+    ) -> Result<bool, SubscribeError<P::Error>>
+    where
+        P: Persister + 'static,
+        B: Backend,
+    {
+        match self.next_change(timeout)? {
+            Some(change) => {
+                doc.apply_raw_changes(vec![change])?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,461 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [Cassandra](https://cassandra.apache.org) and
+//! [ScyllaDB](https://www.scylladb.com).
+//!
+//! Each document lives in its own partition of each table, with changes clustered by actor and
+//! sequence number. Statements are prepared once when the persister is created and changes are
+//! written in unlogged batches, which are cheap as they only ever touch a single partition.
+//!
+//! The driver is async so the persister blocks on the given runtime handle, methods must therefore
+//! not be called from within an async context (use `spawn_blocking` from async code).
+//!
+//! The keyspace is left to be created by the user so that they can choose the replication, the
+//! tables can then be created with [`create_tables`].
+//!
+//! ```rust,no_run
+//! # use std::sync::Arc;
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_scylla::ScyllaPersister;
+//! # use scylla::client::session_builder::SessionBuilder;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let runtime = tokio::runtime::Runtime::new()?;
+//! let session = runtime.block_on(SessionBuilder::new().known_node("127.0.0.1:9042").build())?;
+//! runtime.block_on(automerge_persistent_scylla::create_tables(&session, "automerge"))?;
+//!
+//! let persister = ScyllaPersister::new(
+//!     Arc::new(session),
+//!     runtime.handle().clone(),
+//!     "automerge",
+//!     "my-document",
+//! )?;
+//! let doc = PersistentAutomerge::load(persister)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{future::Future, sync::Arc};
+
+use automerge::ActorId;
+use automerge_persistent_core::{Persister, StoredSizes};
+use futures::TryStreamExt;
+use scylla::{
+    client::session::Session,
+    deserialize::row::DeserializeRow,
+    errors::{
+        ExecutionError, IntoRowsResultError, MaybeFirstRowError, NextRowError, PagerExecutionError,
+        PrepareError, TypeCheckError,
+    },
+    serialize::row::SerializeRow,
+    statement::{
+        batch::{Batch, BatchType},
+        prepared::PreparedStatement,
+    },
+};
+
+/// The persister that stores changes and documents in Cassandra/ScyllaDB tables.
+///
+/// Changes, documents, sync states and metadata are kept in separate tables, partitioned by the
+/// document id so many documents can share the same tables.
+#[derive(Debug)]
+pub struct ScyllaPersister {
+    session: Arc<Session>,
+    handle: tokio::runtime::Handle,
+    document_id: Vec<u8>,
+    statements: Statements,
+    sizes: StoredSizes,
+}
+
+#[derive(Debug)]
+struct Statements {
+    get_changes: PreparedStatement,
+    get_change: PreparedStatement,
+    insert_change: PreparedStatement,
+    remove_change: PreparedStatement,
+    get_document: PreparedStatement,
+    set_document: PreparedStatement,
+    get_sync_state: PreparedStatement,
+    get_sync_states: PreparedStatement,
+    set_sync_state: PreparedStatement,
+    remove_sync_state: PreparedStatement,
+    get_metadata: PreparedStatement,
+    get_all_metadata: PreparedStatement,
+    set_metadata: PreparedStatement,
+    remove_metadata: PreparedStatement,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum ScyllaPersisterError {
+    /// A statement could not be prepared.
+    #[error(transparent)]
+    Prepare(#[from] PrepareError),
+    /// A statement failed to execute.
+    #[error(transparent)]
+    Execution(Box<ExecutionError>),
+    /// A paged statement failed to execute.
+    #[error(transparent)]
+    PagerExecution(Box<PagerExecutionError>),
+    /// A statement did not return rows.
+    #[error(transparent)]
+    IntoRowsResult(Box<IntoRowsResultError>),
+    /// The returned rows were not of the expected type.
+    #[error(transparent)]
+    TypeCheck(#[from] TypeCheckError),
+    /// The first returned row could not be read.
+    #[error(transparent)]
+    MaybeFirstRow(#[from] MaybeFirstRowError),
+    /// A returned row could not be read.
+    #[error(transparent)]
+    NextRow(#[from] NextRowError),
+}
+
+impl From<ExecutionError> for ScyllaPersisterError {
+    fn from(error: ExecutionError) -> Self {
+        Self::Execution(Box::new(error))
+    }
+}
+
+impl From<PagerExecutionError> for ScyllaPersisterError {
+    fn from(error: PagerExecutionError) -> Self {
+        Self::PagerExecution(Box::new(error))
+    }
+}
+
+impl From<IntoRowsResultError> for ScyllaPersisterError {
+    fn from(error: IntoRowsResultError) -> Self {
+        Self::IntoRowsResult(Box::new(error))
+    }
+}
+
+/// Create the tables used by the persister in the given keyspace, if they do not already exist.
+///
+/// # Errors
+///
+/// Returns an error if any of the tables could not be created.
+pub async fn create_tables(session: &Session, keyspace: &str) -> Result<(), ScyllaPersisterError> {
+    let tables = [
+        "changes (document_id blob, actor_id blob, seq bigint, change blob, PRIMARY KEY (document_id, actor_id, seq))",
+        "documents (document_id blob PRIMARY KEY, document blob)",
+        "sync_states (document_id blob, peer_id blob, sync_state blob, PRIMARY KEY (document_id, peer_id))",
+        "metadata (document_id blob, key blob, value blob, PRIMARY KEY (document_id, key))",
+    ];
+    for table in &tables {
+        session
+            .query_unpaged(format!("CREATE TABLE IF NOT EXISTS {keyspace}.{table}"), ())
+            .await?;
+    }
+    Ok(())
+}
+
+impl ScyllaPersister {
+    /// Construct a new persister for the document with the given id.
+    ///
+    /// The tables must already exist in the keyspace, see [`create_tables`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the statements could not be prepared or the existing contents of the
+    /// tables could not be read to calculate the stored sizes.
+    pub fn new<K, D>(
+        session: Arc<Session>,
+        handle: tokio::runtime::Handle,
+        keyspace: K,
+        document_id: D,
+    ) -> Result<Self, ScyllaPersisterError>
+    where
+        K: AsRef<str>,
+        D: Into<Vec<u8>>,
+    {
+        let statements = handle.block_on(Statements::prepare(&session, keyspace.as_ref()))?;
+        let mut s = Self {
+            session,
+            handle,
+            document_id: document_id.into(),
+            statements,
+            sizes: StoredSizes::default(),
+        };
+        s.sizes.changes = s.get_changes()?.iter().map(Vec::len).sum::<usize>() as u64;
+        s.sizes.document = s.get_document()?.unwrap_or_default().len() as u64;
+        s.sizes.sync_states = s
+            .rows::<(Vec<u8>, Vec<u8>), _>(&s.statements.get_sync_states, (&s.document_id,))?
+            .iter()
+            .map(|(_, v)| v.len())
+            .sum::<usize>() as u64;
+        s.sizes.metadata = s
+            .rows::<(Vec<u8>, Vec<u8>), _>(&s.statements.get_all_metadata, (&s.document_id,))?
+            .iter()
+            .map(|(_, v)| v.len())
+            .sum::<usize>() as u64;
+        Ok(s)
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.handle.block_on(future)
+    }
+
+    /// Execute a statement and collect all of the rows, fetching them a page at a time.
+    fn rows<R, V>(
+        &self,
+        statement: &PreparedStatement,
+        values: V,
+    ) -> Result<Vec<R>, ScyllaPersisterError>
+    where
+        R: for<'frame, 'metadata> DeserializeRow<'frame, 'metadata>,
+        V: SerializeRow,
+    {
+        self.block_on(async {
+            let rows = self
+                .session
+                .execute_iter(statement.clone(), values)
+                .await?
+                .rows_stream::<R>()?
+                .try_collect()
+                .await?;
+            Ok(rows)
+        })
+    }
+
+    /// Execute a statement that returns at most a single blob.
+    fn blob<V>(
+        &self,
+        statement: &PreparedStatement,
+        values: V,
+    ) -> Result<Option<Vec<u8>>, ScyllaPersisterError>
+    where
+        V: SerializeRow,
+    {
+        self.block_on(async {
+            let row = self
+                .session
+                .execute_unpaged(statement, values)
+                .await?
+                .into_rows_result()?
+                .maybe_first_row::<(Vec<u8>,)>()?;
+            Ok(row.map(|(v,)| v))
+        })
+    }
+
+    fn execute<V>(
+        &self,
+        statement: &PreparedStatement,
+        values: V,
+    ) -> Result<(), ScyllaPersisterError>
+    where
+        V: SerializeRow,
+    {
+        self.block_on(self.session.execute_unpaged(statement, values))?;
+        Ok(())
+    }
+}
+
+impl Statements {
+    async fn prepare(session: &Session, keyspace: &str) -> Result<Self, PrepareError> {
+        let prepare = |statement: String| session.prepare(statement);
+        Ok(Self {
+            get_changes: prepare(format!(
+                "SELECT change FROM {keyspace}.changes WHERE document_id = ?"
+            ))
+            .await?,
+            get_change: prepare(format!(
+                "SELECT change FROM {keyspace}.changes WHERE document_id = ? AND actor_id = ? AND seq = ?"
+            ))
+            .await?,
+            insert_change: prepare(format!(
+                "INSERT INTO {keyspace}.changes (document_id, actor_id, seq, change) VALUES (?, ?, ?, ?)"
+            ))
+            .await?,
+            remove_change: prepare(format!(
+                "DELETE FROM {keyspace}.changes WHERE document_id = ? AND actor_id = ? AND seq = ?"
+            ))
+            .await?,
+            get_document: prepare(format!(
+                "SELECT document FROM {keyspace}.documents WHERE document_id = ?"
+            ))
+            .await?,
+            set_document: prepare(format!(
+                "INSERT INTO {keyspace}.documents (document_id, document) VALUES (?, ?)"
+            ))
+            .await?,
+            get_sync_state: prepare(format!(
+                "SELECT sync_state FROM {keyspace}.sync_states WHERE document_id = ? AND peer_id = ?"
+            ))
+            .await?,
+            get_sync_states: prepare(format!(
+                "SELECT peer_id, sync_state FROM {keyspace}.sync_states WHERE document_id = ?"
+            ))
+            .await?,
+            set_sync_state: prepare(format!(
+                "INSERT INTO {keyspace}.sync_states (document_id, peer_id, sync_state) VALUES (?, ?, ?)"
+            ))
+            .await?,
+            remove_sync_state: prepare(format!(
+                "DELETE FROM {keyspace}.sync_states WHERE document_id = ? AND peer_id = ?"
+            ))
+            .await?,
+            get_metadata: prepare(format!(
+                "SELECT value FROM {keyspace}.metadata WHERE document_id = ? AND key = ?"
+            ))
+            .await?,
+            get_all_metadata: prepare(format!(
+                "SELECT key, value FROM {keyspace}.metadata WHERE document_id = ?"
+            ))
+            .await?,
+            set_metadata: prepare(format!(
+                "INSERT INTO {keyspace}.metadata (document_id, key, value) VALUES (?, ?, ?)"
+            ))
+            .await?,
+            remove_metadata: prepare(format!(
+                "DELETE FROM {keyspace}.metadata WHERE document_id = ? AND key = ?"
+            ))
+            .await?,
+        })
+    }
+}
+
+/// Sequence numbers are stored as a `bigint`, which is signed, so reinterpret the bits.
+const fn seq_to_bigint(seq: u64) -> i64 {
+    i64::from_be_bytes(seq.to_be_bytes())
+}
+
+impl Persister for ScyllaPersister {
+    type Error = ScyllaPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .rows::<(Vec<u8>,), _>(&self.statements.get_changes, (&self.document_id,))?
+            .into_iter()
+            .map(|(c,)| c)
+            .collect())
+    }
+
+    /// Insert all of the given changes in a single batch.
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let mut batch = Batch::new(BatchType::Unlogged);
+        let mut values = Vec::with_capacity(changes.len());
+        for (a, s, c) in changes {
+            batch.append_statement(self.statements.insert_change.clone());
+            self.sizes.changes += c.len() as u64;
+            values.push((
+                &self.document_id,
+                a.to_bytes().to_vec(),
+                seq_to_bigint(s),
+                c,
+            ));
+        }
+        self.block_on(self.session.batch(&batch, values))?;
+        Ok(())
+    }
+
+    /// Remove all of the given changes in a single batch.
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let mut batch = Batch::new(BatchType::Unlogged);
+        let mut values = Vec::with_capacity(changes.len());
+        for (a, s) in changes {
+            let key = (&self.document_id, a.to_bytes().to_vec(), seq_to_bigint(s));
+            if let Some(old) = self.blob(&self.statements.get_change, &key)? {
+                self.sizes.changes -= old.len() as u64;
+            }
+            batch.append_statement(self.statements.remove_change.clone());
+            values.push(key);
+        }
+        self.block_on(self.session.batch(&batch, values))?;
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.blob(&self.statements.get_document, (&self.document_id,))
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.sizes.document = data.len() as u64;
+        self.execute(&self.statements.set_document, (&self.document_id, data))
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.blob(
+            &self.statements.get_sync_state,
+            (&self.document_id, peer_id),
+        )
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        if let Some(old) = self.get_sync_state(&peer_id)? {
+            self.sizes.sync_states -= old.len() as u64;
+        }
+        self.sizes.sync_states += sync_state.len() as u64;
+        self.execute(
+            &self.statements.set_sync_state,
+            (&self.document_id, peer_id, sync_state),
+        )
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for id in peer_ids {
+            if let Some(old) = self.get_sync_state(id)? {
+                self.sizes.sync_states -= old.len() as u64;
+                self.execute(&self.statements.remove_sync_state, (&self.document_id, id))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .rows::<(Vec<u8>, Vec<u8>), _>(&self.statements.get_sync_states, (&self.document_id,))?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.blob(&self.statements.get_metadata, (&self.document_id, key))
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        if let Some(old) = self.get_metadata(&key)? {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        self.sizes.metadata += value.len() as u64;
+        self.execute(
+            &self.statements.set_metadata,
+            (&self.document_id, key, value),
+        )
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        if let Some(old) = self.get_metadata(key)? {
+            self.sizes.metadata -= old.len() as u64;
+            self.execute(&self.statements.remove_metadata, (&self.document_id, key))?;
+        }
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .rows::<(Vec<u8>, Vec<u8>), _>(&self.statements.get_all_metadata, (&self.document_id,))?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Writes are acknowledged by the cluster according to the consistency level of the session
+    /// so there is nothing to flush.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
@@ -9,10 +9,9 @@
 //! # Single persister
 //!
 //! ```rust
-//! # use automerge_persistent::PersistentBackend;
+//! # use automerge_persistent::PersistentAutomerge;
 //! # use automerge_persistent_sled::SledPersister;
 //! # use automerge_persistent_sled::SledPersisterError;
-//! # use automerge::Backend;
 //! # fn main() -> Result<(), SledPersisterError> {
 //! let db = sled::Config::new().temporary(true).open()?;
 //! let changes_tree = db.open_tree("changes")?;
@@ -20,7 +19,7 @@
 //! let sync_states_tree = db.open_tree("sync-states")?;
 //!
 //! let persister = SledPersister::new(changes_tree, documents_tree, sync_states_tree, "")?;
-//! let backend = PersistentBackend::<_, Backend>::load(persister);
+//! let doc = PersistentAutomerge::load(persister);
 //! # Ok(())
 //! # }
 //! ```
@@ -28,10 +27,9 @@
 //! # Multiple persisters sharing the same trees
 //!
 //! ```rust
-//! # use automerge_persistent::PersistentBackend;
+//! # use automerge_persistent::PersistentAutomerge;
 //! # use automerge_persistent_sled::SledPersister;
 //! # use automerge_persistent_sled::SledPersisterError;
-//! # use automerge::Backend;
 //! # fn main() -> Result<(), SledPersisterError> {
 //! let db = sled::Config::new().temporary(true).open()?;
 //! let changes_tree = db.open_tree("changes")?;
@@ -44,29 +42,140 @@
 //!     sync_states_tree.clone(),
 //!     "1",
 //! )?;
-//! let backend1 = PersistentBackend::<_, Backend>::load(persister1);
+//! let doc1 = PersistentAutomerge::load(persister1);
 //!
 //! let persister2 = SledPersister::new(changes_tree, documents_tree, sync_states_tree, "2")?;
-//! let backend2 = PersistentBackend::<_, Backend>::load(persister2);
+//! let doc2 = PersistentAutomerge::load(persister2);
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! # Many documents
+//!
+//! A [`SledStore`] keeps any number of documents in one set of trees, under prefixes made from
+//! their ids, and can list, measure and delete them.
+//!
+//! # Sharing a persister
+//!
+//! ```rust
+//! # use std::sync::Arc;
+//! # use automerge_persistent::{PersistentAutomerge, SharedPersister};
+//! # use automerge_persistent_sled::SledPersister;
+//! # use automerge_persistent_sled::SledPersisterError;
+//! # fn main() -> Result<(), SledPersisterError> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let persister = Arc::new(SledPersister::new(
+//!     db.open_tree("changes")?,
+//!     db.open_tree("documents")?,
+//!     db.open_tree("sync-states")?,
+//!     "",
+//! )?);
+//! let doc = PersistentAutomerge::load(Arc::clone(&persister));
+//!
+//! // the persister can still be used from elsewhere, without locking
+//! let sizes = persister.sizes();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Concurrent compaction
+//!
+//! Compacting a document that another persister has compacted since it was loaded fails instead
+//! of overwriting the newer snapshot.
+//!
+//! ```rust
+//! # use automerge_persistent::{Error, PersistentAutomerge};
+//! # use automerge_persistent_sled::SledPersister;
+//! # use automerge_persistent_sled::SledPersisterError;
+//! # fn main() -> Result<(), SledPersisterError> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let open = || {
+//!     SledPersister::new(
+//!         db.open_tree("changes")?,
+//!         db.open_tree("documents")?,
+//!         db.open_tree("sync-states")?,
+//!         "",
+//!     )
+//! };
+//! let mut doc1 = PersistentAutomerge::load(open()?).unwrap();
+//! let mut doc2 = PersistentAutomerge::load(open()?).unwrap();
+//!
+//! doc1.compact(&[]).unwrap();
+//! assert!(matches!(
+//!     doc2.compact(&[]),
+//!     Err(Error::VersionConflict(_))
+//! ));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+};
 
 use automerge::ActorId;
-use automerge_persistent::{Persister, StoredSizes};
+use automerge_persistent_core::{
+    DocumentVersion, DurabilityPolicy, DurabilityTracker, SharedPersister, StoredSizes,
+    VersionConflict, VersionedDocument,
+};
+
+mod store;
+
+pub use store::SledStore;
+
+/// Follows the prefix in the keys of metadata kept in the document tree.
+const METADATA_MARKER: &[u8] = b"\0metadata\0";
+
+/// The version of a stored document, its length and FNV-1a hash.
+fn document_version(document: &[u8]) -> DocumentVersion {
+    let hash = document
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    let mut version = (document.len() as u64).to_be_bytes().to_vec();
+    version.extend(hash.to_be_bytes());
+    DocumentVersion(version)
+}
 
 /// The persister that stores changes and documents in sled trees.
 ///
-/// Changes and documents are kept in separate trees.
+/// Changes, documents and sync states are kept in separate trees. Metadata is kept in the
+/// document tree after the document's key, unless given its own tree with
+/// [`SledPersister::with_metadata_tree`].
 ///
 /// An optional prefix can be used in case multiple persisters may share the same trees.
+///
+/// Sled trees are internally synchronised so this is a [`SharedPersister`] and can be shared
+/// behind an [`std::sync::Arc`] without extra locking.
+///
+/// The version of the document is a hash of it, and [`SharedPersister::set_document_if`] writes
+/// it with a compare-and-swap against the document that was hashed, so if another persister with
+/// the same prefix replaced it in the meantime the write fails with a [`VersionConflict`] rather
+/// than replacing the newer snapshot.
 #[derive(Debug)]
 pub struct SledPersister {
     changes_tree: sled::Tree,
     document_tree: sled::Tree,
     sync_states_tree: sled::Tree,
-    prefix: String,
-    sizes: StoredSizes,
+    metadata_tree: sled::Tree,
+    prefix: Vec<u8>,
+    /// The prefix of the metadata keys in the metadata tree.
+    metadata_prefix: Vec<u8>,
+    sizes: AtomicSizes,
+    durability: Mutex<DurabilityTracker>,
+}
+
+#[derive(Debug, Default)]
+struct AtomicSizes {
+    changes: AtomicU64,
+    document: AtomicU64,
+    sync_states: AtomicU64,
+    metadata: AtomicU64,
 }
 
 /// Possible errors from persisting.
@@ -79,7 +188,11 @@ pub enum SledPersisterError {
 
 impl SledPersister {
     /// Construct a new persister.
-    #[must_use]
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the existing contents of the trees could not be read to calculate the
+    /// stored sizes.
     pub fn new<S>(
         changes_tree: sled::Tree,
         document_tree: sled::Tree,
@@ -89,32 +202,176 @@ impl SledPersister {
     where
         S: Into<String>,
     {
-        let prefix = prefix.into();
+        let prefix = prefix.into().into_bytes();
+        let mut metadata_prefix = prefix.clone();
+        metadata_prefix.extend(METADATA_MARKER);
+        Self::with_trees(
+            changes_tree,
+            document_tree.clone(),
+            sync_states_tree,
+            document_tree,
+            prefix,
+            metadata_prefix,
+        )
+    }
 
-        let mut s = Self {
+    /// Keep the metadata in its own tree rather than in the document tree.
+    ///
+    /// Metadata already stored in the document tree is not moved, so this should be chosen
+    /// consistently for a document.
+    ///
+    /// ```rust
+    /// # use automerge_persistent::{PersistentAutomerge, SharedPersister};
+    /// # use automerge_persistent_sled::{SledPersister, SledPersisterError};
+    /// # fn main() -> Result<(), SledPersisterError> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let metadata_tree = db.open_tree("metadata")?;
+    /// let persister = SledPersister::new(
+    ///     db.open_tree("changes")?,
+    ///     db.open_tree("documents")?,
+    ///     db.open_tree("sync-states")?,
+    ///     "",
+    /// )?
+    /// .with_metadata_tree(metadata_tree.clone())?;
+    /// persister.set_metadata(b"key".to_vec(), b"value".to_vec())?;
+    /// assert_eq!(metadata_tree.len(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the existing metadata in the tree could not be read to calculate its
+    /// size.
+    pub fn with_metadata_tree(
+        mut self,
+        metadata_tree: sled::Tree,
+    ) -> Result<Self, SledPersisterError> {
+        self.metadata_tree = metadata_tree;
+        self.metadata_prefix = self.prefix.clone();
+        self.sizes
+            .metadata
+            .store(self.metadata_size()?, Ordering::Relaxed);
+        Ok(self)
+    }
+
+    /// Construct a new persister for the keys under the prefix, with its own metadata tree.
+    pub(crate) fn with_prefix(
+        changes_tree: sled::Tree,
+        document_tree: sled::Tree,
+        sync_states_tree: sled::Tree,
+        metadata_tree: sled::Tree,
+        prefix: Vec<u8>,
+    ) -> Result<Self, SledPersisterError> {
+        Self::with_trees(
+            changes_tree,
+            document_tree,
+            sync_states_tree,
+            metadata_tree,
+            prefix.clone(),
+            prefix,
+        )
+    }
+
+    fn with_trees(
+        changes_tree: sled::Tree,
+        document_tree: sled::Tree,
+        sync_states_tree: sled::Tree,
+        metadata_tree: sled::Tree,
+        prefix: Vec<u8>,
+        metadata_prefix: Vec<u8>,
+    ) -> Result<Self, SledPersisterError> {
+        let s = Self {
             changes_tree,
             document_tree,
             sync_states_tree,
+            metadata_tree,
             prefix,
-            sizes: StoredSizes::default(),
+            metadata_prefix,
+            sizes: AtomicSizes::default(),
+            durability: Mutex::new(DurabilityTracker::new(DurabilityPolicy::Manual)),
         };
-        s.sizes.changes = s.get_changes()?.iter().map(Vec::len).sum::<usize>() as u64;
-        s.sizes.document = s.get_document()?.unwrap_or_default().len() as u64;
-        s.sizes.sync_states = s
+        let changes = s.get_changes()?.iter().map(Vec::len).sum::<usize>() as u64;
+        let document = s.get_document()?.unwrap_or_default().len() as u64;
+        let sync_states = s
             .get_peer_ids()?
             .iter()
             .map(|id| s.get_sync_state(id).map(|o| o.unwrap_or_default().len()))
             .collect::<Result<Vec<usize>, _>>()?
             .iter()
             .sum::<usize>() as u64;
+        s.sizes.changes.store(changes, Ordering::Relaxed);
+        s.sizes.document.store(document, Ordering::Relaxed);
+        s.sizes.sync_states.store(sync_states, Ordering::Relaxed);
+        s.sizes
+            .metadata
+            .store(s.metadata_size()?, Ordering::Relaxed);
         Ok(s)
     }
 
+    /// The total length of the stored metadata values.
+    fn metadata_size(&self) -> Result<u64, SledPersisterError> {
+        Ok(self
+            .metadata_tree
+            .scan_prefix(&self.metadata_prefix)
+            .values()
+            .map(|v| v.map(|v| v.len()))
+            .collect::<Result<Vec<usize>, _>>()?
+            .iter()
+            .sum::<usize>() as u64)
+    }
+
+    /// Set when writes are flushed to disk, by default only when flushed.
+    ///
+    /// Sled also flushes in the background on its own schedule, see
+    /// [`sled::Config::flush_every_ms`], so this bounds the loss window further.
+    ///
+    /// ```rust
+    /// # use automerge_persistent_core::DurabilityPolicy;
+    /// # use automerge_persistent_sled::{SledPersister, SledPersisterError};
+    /// # fn main() -> Result<(), SledPersisterError> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let persister = SledPersister::new(
+    ///     db.open_tree("changes")?,
+    ///     db.open_tree("documents")?,
+    ///     db.open_tree("sync-states")?,
+    ///     "",
+    /// )?
+    /// .with_durability(DurabilityPolicy::EveryNWrites(100));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn with_durability(self, policy: DurabilityPolicy) -> Self {
+        self.set_durability(policy);
+        self
+    }
+
+    /// Set when writes are flushed to disk, see [`Self::with_durability`].
+    pub fn set_durability(&self, policy: DurabilityPolicy) {
+        *self.lock_durability() = DurabilityTracker::new(policy);
+    }
+
+    fn lock_durability(&self) -> std::sync::MutexGuard<'_, DurabilityTracker> {
+        self.durability
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Flush the trees if the durability policy says the writes are due.
+    fn wrote(&self) -> Result<(), SledPersisterError> {
+        let due = self.lock_durability().record_write();
+        if due {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
     /// Make a key from the prefix, `actor_id` and `sequence_number`.
     ///
     /// Converts the `actor_id` to bytes and appends the `sequence_number` in big endian form.
     fn make_key(&self, actor_id: &ActorId, seq: u64) -> Vec<u8> {
-        let mut key = self.prefix.as_bytes().to_vec();
+        let mut key = self.prefix.clone();
         key.extend(actor_id.to_bytes());
         key.extend(&seq.to_be_bytes());
         key
@@ -123,17 +380,23 @@ impl SledPersister {
     /// Make a key just from the prefix.
     /// Since each document only has one thing to store in this tree we can just use the prefix.
     fn make_document_key(&self) -> Vec<u8> {
-        self.prefix.as_bytes().to_vec()
+        self.prefix.clone()
     }
 
     fn make_peer_key(&self, peer_id: &[u8]) -> Vec<u8> {
-        let mut key = self.prefix.as_bytes().to_vec();
+        let mut key = self.prefix.clone();
         key.extend(peer_id);
         key
     }
+
+    fn make_metadata_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut metadata_key = self.metadata_prefix.clone();
+        metadata_key.extend(key);
+        metadata_key
+    }
 }
 
-impl Persister for SledPersister {
+impl SharedPersister for SledPersister {
     type Error = SledPersisterError;
 
     /// Get all of the current changes.
@@ -145,27 +408,44 @@ impl Persister for SledPersister {
             .collect()
     }
 
+    /// List the actors from the keys of the changes, without reading the changes themselves.
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        let mut actors = HashSet::new();
+        for key in self.changes_tree.scan_prefix(&self.prefix).keys() {
+            let key = key?;
+            let actor = &key[self.prefix.len()..key.len() - 8];
+            actors.insert(ActorId::from(actor));
+        }
+        Ok(actors.into_iter().collect())
+    }
+
     /// Insert all of the given changes into the tree.
-    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+    fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
         for (a, s, c) in changes {
             let key = self.make_key(&a, s);
-            self.sizes.changes += c.len() as u64;
+            self.sizes
+                .changes
+                .fetch_add(c.len() as u64, Ordering::Relaxed);
             if let Some(old) = self.changes_tree.insert(key, c)? {
-                self.sizes.changes -= old.len() as u64;
+                self.sizes
+                    .changes
+                    .fetch_sub(old.len() as u64, Ordering::Relaxed);
             }
         }
-        Ok(())
+        self.wrote()
     }
 
     /// Remove all of the given changes from the tree.
-    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+    fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
         for (a, s) in changes {
             let key = self.make_key(a, s);
             if let Some(old) = self.changes_tree.remove(key)? {
-                self.sizes.changes -= old.len() as u64;
+                self.sizes
+                    .changes
+                    .fetch_sub(old.len() as u64, Ordering::Relaxed);
             }
         }
-        Ok(())
+        self.wrote()
     }
 
     /// Retrieve the document from the tree.
@@ -177,10 +457,48 @@ impl Persister for SledPersister {
     }
 
     /// Set the document in the tree.
-    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
-        self.sizes.document = data.len() as u64;
+    fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let len = data.len() as u64;
         self.document_tree.insert(self.make_document_key(), data)?;
-        Ok(())
+        self.sizes.document.store(len, Ordering::Relaxed);
+        self.wrote()
+    }
+
+    /// Retrieve the document from the tree, with a hash of it as its version.
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        let document = self.document_tree.get(self.make_document_key())?;
+        let version = document.as_deref().map(document_version);
+        Ok((document.map(|v| v.to_vec()), version))
+    }
+
+    /// Set the document in the tree with a compare-and-swap against the stored one, if its hash
+    /// is the expected version.
+    fn set_document_if(
+        &self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let key = self.make_document_key();
+        let len = data.len() as u64;
+        let data = sled::IVec::from(data);
+        loop {
+            let current = self.document_tree.get(&key)?;
+            let version = current.as_deref().map(document_version);
+            if version.as_ref() != expected {
+                return Ok(Err(VersionConflict { current: version }));
+            }
+            // replaced since it was read if this fails, so check the new one again
+            if self
+                .document_tree
+                .compare_and_swap(&key, current, Some(data.clone()))?
+                .is_ok()
+            {
+                break;
+            }
+        }
+        self.sizes.document.store(len, Ordering::Relaxed);
+        self.wrote()?;
+        Ok(Ok(Some(document_version(&data))))
     }
 
     fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
@@ -191,42 +509,218 @@ impl Persister for SledPersister {
             .map(|v| v.to_vec()))
     }
 
-    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+    fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
         let sync_state_key = self.make_peer_key(&peer_id);
-        self.sizes.sync_states += sync_state.len() as u64;
+        self.sizes
+            .sync_states
+            .fetch_add(sync_state.len() as u64, Ordering::Relaxed);
         if let Some(old) = self.sync_states_tree.insert(sync_state_key, sync_state)? {
-            self.sizes.sync_states -= old.len() as u64;
+            self.sizes
+                .sync_states
+                .fetch_sub(old.len() as u64, Ordering::Relaxed);
         }
-        Ok(())
+        self.wrote()
     }
 
-    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+    fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
         for id in peer_ids {
             let key = self.make_peer_key(id);
             if let Some(old) = self.sync_states_tree.remove(key)? {
-                self.sizes.sync_states -= old.len() as u64;
+                self.sizes
+                    .sync_states
+                    .fetch_sub(old.len() as u64, Ordering::Relaxed);
             }
         }
-        Ok(())
+        self.wrote()
     }
 
     fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
         self.sync_states_tree
             .scan_prefix(&self.prefix)
             .keys()
-            .map(|v| v.map(|v| v.to_vec()).map_err(Self::Error::SledError))
+            .map(|v| {
+                v.map(|v| v[self.prefix.len()..].to_vec())
+                    .map_err(Self::Error::SledError)
+            })
+            .collect()
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .metadata_tree
+            .get(self.make_metadata_key(key))?
+            .map(|v| v.to_vec()))
+    }
+
+    fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let metadata_key = self.make_metadata_key(&key);
+        self.sizes
+            .metadata
+            .fetch_add(value.len() as u64, Ordering::Relaxed);
+        if let Some(old) = self.metadata_tree.insert(metadata_key, value)? {
+            self.sizes
+                .metadata
+                .fetch_sub(old.len() as u64, Ordering::Relaxed);
+        }
+        self.wrote()
+    }
+
+    fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+        if let Some(old) = self.metadata_tree.remove(self.make_metadata_key(key))? {
+            self.sizes
+                .metadata
+                .fetch_sub(old.len() as u64, Ordering::Relaxed);
+        }
+        self.wrote()
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.metadata_tree
+            .scan_prefix(&self.metadata_prefix)
+            .keys()
+            .map(|v| {
+                v.map(|v| v[self.metadata_prefix.len()..].to_vec())
+                    .map_err(Self::Error::SledError)
+            })
             .collect()
     }
 
     fn sizes(&self) -> StoredSizes {
-        self.sizes.clone()
+        StoredSizes {
+            changes: self.sizes.changes.load(Ordering::Relaxed),
+            document: self.sizes.document.load(Ordering::Relaxed),
+            sync_states: self.sizes.sync_states.load(Ordering::Relaxed),
+            metadata: self.sizes.metadata.load(Ordering::Relaxed),
+        }
     }
 
-    fn flush(&mut self) -> Result<usize, Self::Error> {
+    fn flush(&self) -> Result<usize, Self::Error> {
         let mut flushed = 0;
         flushed += self.changes_tree.flush()?;
         flushed += self.document_tree.flush()?;
         flushed += self.sync_states_tree.flush()?;
+        flushed += self.metadata_tree.flush()?;
+        self.lock_durability().synced();
         Ok(flushed)
     }
+
+    /// Flush the trees so that the segments emptied by removals are released.
+    ///
+    /// Sled frees a segment once none of its pages are live and cleans up the rest in the
+    /// background, with no way to compact further through its trees, so flushing the removals is
+    /// what can be done here.
+    ///
+    /// ```rust
+    /// # use automerge::ActorId;
+    /// # use automerge_persistent::SharedPersister;
+    /// # use automerge_persistent_sled::{SledPersister, SledPersisterError};
+    /// # fn main() -> Result<(), SledPersisterError> {
+    /// let db = sled::Config::new()
+    ///     .temporary(true)
+    ///     .segment_size(64 * 1024)
+    ///     .open()?;
+    /// let persister = SledPersister::new(
+    ///     db.open_tree("changes")?,
+    ///     db.open_tree("documents")?,
+    ///     db.open_tree("sync-states")?,
+    ///     "",
+    /// )?;
+    /// let actor = ActorId::random();
+    /// persister.insert_changes((0..1000).map(|seq| (actor.clone(), seq, vec![0; 4096])).collect())?;
+    /// persister.flush()?;
+    /// let before = db.size_on_disk()?;
+    ///
+    /// persister.remove_changes((0..1000).map(|seq| (&actor, seq)).collect())?;
+    /// persister.vacuum()?;
+    /// assert!(db.size_on_disk()? < before);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn vacuum(&self) -> Result<(), Self::Error> {
+        self.flush().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(db: &sled::Db) -> SledPersister {
+        SledPersister::new(
+            db.open_tree("changes").unwrap(),
+            db.open_tree("documents").unwrap(),
+            db.open_tree("sync-states").unwrap(),
+            "",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn document_replaced_since_it_was_read_is_a_conflict() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let first = open(&db);
+        let second = open(&db);
+
+        let (_, version) = first.get_document_versioned().unwrap();
+        let (_, other_version) = second.get_document_versioned().unwrap();
+        let written = second
+            .set_document_if(vec![2], other_version.as_ref())
+            .unwrap()
+            .unwrap();
+
+        let conflict = first
+            .set_document_if(vec![1], version.as_ref())
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(conflict.current, written);
+        assert_eq!(first.get_document().unwrap(), Some(vec![2]));
+
+        // once read again the write goes through
+        let (_, version) = first.get_document_versioned().unwrap();
+        assert!(first
+            .set_document_if(vec![1], version.as_ref())
+            .unwrap()
+            .is_ok());
+        assert_eq!(second.get_document().unwrap(), Some(vec![1]));
+    }
+
+    #[test]
+    fn racing_writers_have_one_winner() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let persisters = (0..8).map(|_| open(&db)).collect::<Vec<_>>();
+        let (_, version) = persisters[0].get_document_versioned().unwrap();
+
+        let written = std::thread::scope(|scope| {
+            persisters
+                .iter()
+                .zip(0_u8..)
+                .map(|(persister, i)| {
+                    let version = version.clone();
+                    scope.spawn(move || {
+                        persister
+                            .set_document_if(vec![i; 1024], version.as_ref())
+                            .unwrap()
+                            .is_ok()
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .filter(|written| *written)
+                .count()
+        });
+        assert_eq!(written, 1);
+    }
+
+    #[test]
+    fn set_document_is_a_plain_write() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let first = open(&db);
+        let second = open(&db);
+
+        first.get_document().unwrap();
+        second.set_document(vec![2]).unwrap();
+        first.set_document(vec![1]).unwrap();
+        assert_eq!(second.get_document().unwrap(), Some(vec![1]));
+    }
 }
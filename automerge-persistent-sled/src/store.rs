@@ -0,0 +1,199 @@
+use std::{
+    collections::BTreeSet,
+    convert::{TryFrom, TryInto},
+};
+
+use automerge_persistent_core::{DocumentId, StoredSizes};
+
+use crate::{SledPersister, SledPersisterError};
+
+/// Many documents kept in one set of sled trees, each under a prefix made from its id.
+///
+/// The prefix is the length of the id followed by the id, so no document's keys are a prefix of
+/// another's as they can be with the string prefixes given to [`SledPersister::new`]. The trees
+/// shouldn't be shared with persisters created that way.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{DocumentId, PersistentAutomerge};
+/// # use automerge_persistent_sled::{SledPersisterError, SledStore};
+/// # fn main() -> Result<(), SledPersisterError> {
+/// let db = sled::Config::new().temporary(true).open()?;
+/// let store = SledStore::new(
+///     db.open_tree("changes")?,
+///     db.open_tree("documents")?,
+///     db.open_tree("sync-states")?,
+///     db.open_tree("metadata")?,
+/// );
+///
+/// for name in ["a", "ab"] {
+///     let mut doc = PersistentAutomerge::load(store.persister(&name.into())?).unwrap();
+///     doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///         tx.put(ROOT, "name", name).unwrap();
+///         Ok(())
+///     })
+///     .unwrap();
+/// }
+///
+/// assert_eq!(store.list_documents()?, vec![DocumentId::from("a"), DocumentId::from("ab")]);
+/// assert!(store.document_sizes(&"a".into())?.changes > 0);
+///
+/// store.delete_document(&"a".into())?;
+/// assert_eq!(store.list_documents()?, vec![DocumentId::from("ab")]);
+/// assert_eq!(store.document_sizes(&"a".into())?.changes, 0);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SledStore {
+    changes: sled::Tree,
+    document: sled::Tree,
+    sync_states: sled::Tree,
+    metadata: sled::Tree,
+}
+
+impl SledStore {
+    /// Construct a store over the trees.
+    #[must_use]
+    pub const fn new(
+        changes_tree: sled::Tree,
+        document_tree: sled::Tree,
+        sync_states_tree: sled::Tree,
+        metadata_tree: sled::Tree,
+    ) -> Self {
+        Self {
+            changes: changes_tree,
+            document: document_tree,
+            sync_states: sync_states_tree,
+            metadata: metadata_tree,
+        }
+    }
+
+    /// Construct a persister for the document with the given id.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the existing contents of the document could not be read to calculate
+    /// the stored sizes.
+    pub fn persister(&self, id: &DocumentId) -> Result<SledPersister, SledPersisterError> {
+        SledPersister::with_prefix(
+            self.changes.clone(),
+            self.document.clone(),
+            self.sync_states.clone(),
+            self.metadata.clone(),
+            document_prefix(id),
+        )
+    }
+
+    /// List the documents that have anything stored, in order of their ids.
+    ///
+    /// This seeks past the keys of each document rather than reading them all, so takes time in
+    /// the number of documents rather than the amount stored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the trees could not be read.
+    pub fn list_documents(&self) -> Result<Vec<DocumentId>, SledPersisterError> {
+        let mut ids = BTreeSet::new();
+        for tree in self.trees() {
+            let mut start = Vec::new();
+            while let Some(entry) = tree.range(start.as_slice()..).next() {
+                let (key, _) = entry?;
+                let id = decode_document_id(&key);
+                let next = id.as_ref().map_or_else(
+                    || {
+                        // not written by a store, skip just this key
+                        let mut next = key.to_vec();
+                        next.push(0);
+                        Some(next)
+                    },
+                    |id| prefix_end(&document_prefix(id)),
+                );
+                ids.extend(id);
+                match next {
+                    Some(next) => start = next,
+                    None => break,
+                }
+            }
+        }
+        Ok(ids.into_iter().collect())
+    }
+
+    /// The bytes stored for the document, read from the trees.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the trees could not be read.
+    pub fn document_sizes(&self, id: &DocumentId) -> Result<StoredSizes, SledPersisterError> {
+        let prefix = document_prefix(id);
+        Ok(StoredSizes {
+            changes: prefix_size(&self.changes, &prefix)?,
+            document: prefix_size(&self.document, &prefix)?,
+            sync_states: prefix_size(&self.sync_states, &prefix)?,
+            metadata: prefix_size(&self.metadata, &prefix)?,
+        })
+    }
+
+    /// Remove everything stored for the document.
+    ///
+    /// The saved document is removed first, so a deletion that fails part way leaves the document
+    /// listed to try again. Persisters for the document shouldn't be in use.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the trees could not be read or written.
+    pub fn delete_document(&self, id: &DocumentId) -> Result<(), SledPersisterError> {
+        let prefix = document_prefix(id);
+        for tree in self.trees() {
+            let mut batch = sled::Batch::default();
+            for key in tree.scan_prefix(&prefix).keys() {
+                batch.remove(key?);
+            }
+            tree.apply_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    const fn trees(&self) -> [&sled::Tree; 4] {
+        [
+            &self.document,
+            &self.changes,
+            &self.sync_states,
+            &self.metadata,
+        ]
+    }
+}
+
+/// The prefix of the keys for the document, its length as a big endian `u32` then the id.
+fn document_prefix(id: &DocumentId) -> Vec<u8> {
+    let len = u32::try_from(id.0.len()).expect("document id too long");
+    let mut prefix = len.to_be_bytes().to_vec();
+    prefix.extend(&id.0);
+    prefix
+}
+
+fn decode_document_id(key: &[u8]) -> Option<DocumentId> {
+    let (len, rest) = key.split_at_checked(4)?;
+    let len = usize::try_from(u32::from_be_bytes(len.try_into().ok()?)).ok()?;
+    rest.get(..len).map(DocumentId::from)
+}
+
+/// The first key after all of those starting with the prefix, if there is one.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+fn prefix_size(tree: &sled::Tree, prefix: &[u8]) -> Result<u64, SledPersisterError> {
+    let mut size = 0;
+    for value in tree.scan_prefix(prefix).values() {
+        size += value?.len() as u64;
+    }
+    Ok(size)
+}
@@ -0,0 +1,677 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [SQLite](https://sqlite.org).
+//!
+//! # Single persister
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_sqlite::{SqliteOptions, SqlitePersister};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let dir = tempfile::tempdir()?;
+//! let persister = SqlitePersister::open(dir.path().join("docs.db"), "", &SqliteOptions::default())?;
+//! let doc = PersistentAutomerge::load(persister)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Multiple persisters sharing the same connection
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_sqlite::{SqliteConnection, SqliteOptions, SqlitePersister};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let dir = tempfile::tempdir()?;
+//! let connection = SqliteConnection::open(dir.path().join("docs.db"), &SqliteOptions::default())?;
+//!
+//! let persister1 = SqlitePersister::new(connection.clone(), "1")?;
+//! let doc1 = PersistentAutomerge::load(persister1)?;
+//!
+//! let persister2 = SqlitePersister::new(connection, "2")?;
+//! let doc2 = PersistentAutomerge::load(persister2)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::Duration,
+};
+
+use automerge::ActorId;
+use automerge_persistent_core::{
+    DurabilityPolicy, DurabilityTracker, SharedPersister, StoredSizes,
+};
+use rusqlite::{params, types::FromSql, Connection, OptionalExtension};
+
+/// The name of the table changes are stored in.
+pub const CHANGES_TABLE: &str = "changes";
+/// The name of the table documents are stored in.
+pub const DOCUMENTS_TABLE: &str = "documents";
+/// The name of the table sync states are stored in.
+pub const SYNC_STATES_TABLE: &str = "sync_states";
+/// The name of the table metadata is stored in.
+pub const METADATA_TABLE: &str = "metadata";
+
+/// How `SQLite` journals writes, see
+/// [`journal_mode`](https://sqlite.org/pragma.html#pragma_journal_mode).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    /// A rollback journal that is deleted at the end of each transaction.
+    Delete,
+    /// A rollback journal that is truncated at the end of each transaction.
+    Truncate,
+    /// A rollback journal whose header is zeroed at the end of each transaction.
+    Persist,
+    /// A rollback journal kept in memory, a crash mid transaction may corrupt the database.
+    Memory,
+    /// A write-ahead log, letting readers run alongside a writer.
+    Wal,
+    /// No journal, a crash mid transaction may corrupt the database.
+    Off,
+}
+
+impl JournalMode {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Delete => "DELETE",
+            Self::Truncate => "TRUNCATE",
+            Self::Persist => "PERSIST",
+            Self::Memory => "MEMORY",
+            Self::Wal => "WAL",
+            Self::Off => "OFF",
+        }
+    }
+}
+
+/// How often `SQLite` syncs to disk, see
+/// [`synchronous`](https://sqlite.org/pragma.html#pragma_synchronous).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    /// Leave syncing to the operating system, fastest but committed transactions can be lost on
+    /// power failure.
+    Off,
+    /// Sync at the most critical moments, in WAL mode committed transactions may be lost on power
+    /// failure but the database stays intact.
+    Normal,
+    /// Sync on every commit.
+    Full,
+    /// Like [`Self::Full`] but also syncs the directory when a rollback journal is removed.
+    Extra,
+}
+
+impl Synchronous {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Off => "OFF",
+            Self::Normal => "NORMAL",
+            Self::Full => "FULL",
+            Self::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Options for opening a `SQLite` connection.
+///
+/// The defaults use a write-ahead log with full syncing, favouring durability. Mobile apps may
+/// prefer [`Synchronous::Normal`] to save battery and latency at the cost of the last
+/// transactions on power failure.
+///
+/// ```rust
+/// # use std::time::Duration;
+/// # use automerge_persistent_sqlite::{JournalMode, SqliteOptions, Synchronous};
+/// let options = SqliteOptions::default()
+///     .with_journal_mode(JournalMode::Wal)
+///     .with_synchronous(Synchronous::Normal)
+///     .with_busy_timeout(Duration::from_secs(1));
+/// assert_eq!(options.synchronous, Synchronous::Normal);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SqliteOptions {
+    /// How writes are journalled.
+    pub journal_mode: JournalMode,
+    /// How often `SQLite` syncs to disk.
+    pub synchronous: Synchronous,
+    /// How long to wait for a lock held by another connection before failing.
+    pub busy_timeout: Duration,
+    /// When commits are made durable.
+    ///
+    /// With [`DurabilityPolicy::EveryWrite`] each commit is synced as set by [`Self::synchronous`].
+    /// Other policies lower that to at most [`Synchronous::Normal`], so in WAL mode commits
+    /// aren't synced until the log is checkpointed, which is done when the policy says writes
+    /// are due and on flush.
+    pub durability: DurabilityPolicy,
+}
+
+impl Default for SqliteOptions {
+    fn default() -> Self {
+        Self {
+            journal_mode: JournalMode::Wal,
+            synchronous: Synchronous::Full,
+            busy_timeout: Duration::from_secs(5),
+            durability: DurabilityPolicy::EveryWrite,
+        }
+    }
+}
+
+impl SqliteOptions {
+    /// Set how writes are journalled.
+    #[must_use]
+    pub const fn with_journal_mode(mut self, journal_mode: JournalMode) -> Self {
+        self.journal_mode = journal_mode;
+        self
+    }
+
+    /// Set how writes are journalled.
+    pub const fn set_journal_mode(&mut self, journal_mode: JournalMode) -> &mut Self {
+        self.journal_mode = journal_mode;
+        self
+    }
+
+    /// Set how often `SQLite` syncs to disk.
+    #[must_use]
+    pub const fn with_synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.synchronous = synchronous;
+        self
+    }
+
+    /// Set how often `SQLite` syncs to disk.
+    pub const fn set_synchronous(&mut self, synchronous: Synchronous) -> &mut Self {
+        self.synchronous = synchronous;
+        self
+    }
+
+    /// Set how long to wait for a lock held by another connection.
+    #[must_use]
+    pub const fn with_busy_timeout(mut self, busy_timeout: Duration) -> Self {
+        self.busy_timeout = busy_timeout;
+        self
+    }
+
+    /// Set how long to wait for a lock held by another connection.
+    pub const fn set_busy_timeout(&mut self, busy_timeout: Duration) -> &mut Self {
+        self.busy_timeout = busy_timeout;
+        self
+    }
+
+    /// Set when commits are made durable.
+    #[must_use]
+    pub const fn with_durability(mut self, durability: DurabilityPolicy) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Set when commits are made durable.
+    pub const fn set_durability(&mut self, durability: DurabilityPolicy) -> &mut Self {
+        self.durability = durability;
+        self
+    }
+
+    /// The level of syncing to configure the connection with, given the durability policy.
+    const fn effective_synchronous(&self) -> Synchronous {
+        match (self.durability, self.synchronous) {
+            (DurabilityPolicy::EveryWrite, synchronous)
+            | (_, synchronous @ (Synchronous::Off | Synchronous::Normal)) => synchronous,
+            (_, Synchronous::Full | Synchronous::Extra) => Synchronous::Normal,
+        }
+    }
+}
+
+/// A configured connection to a `SQLite` database that can be shared by many persisters.
+///
+/// Cloning gives another handle to the same connection. Persisters sharing a connection take
+/// turns using it, which avoids the cost of a connection per document when there are many
+/// documents in one database.
+#[derive(Debug, Clone)]
+pub struct SqliteConnection {
+    connection: Arc<Mutex<Connection>>,
+    /// Shared by all persisters of the connection as a checkpoint covers the whole database.
+    durability: Arc<Mutex<DurabilityTracker>>,
+}
+
+impl SqliteConnection {
+    /// Open the database at the path, creating it and the tables if they do not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database could not be opened or configured.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        options: &SqliteOptions,
+    ) -> Result<Self, SqlitePersisterError> {
+        Self::configure(Connection::open(path)?, options)
+    }
+
+    /// Open a database in memory, for testing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database could not be configured.
+    pub fn open_in_memory(options: &SqliteOptions) -> Result<Self, SqlitePersisterError> {
+        Self::configure(Connection::open_in_memory()?, options)
+    }
+
+    fn configure(
+        connection: Connection,
+        options: &SqliteOptions,
+    ) -> Result<Self, SqlitePersisterError> {
+        connection.busy_timeout(options.busy_timeout)?;
+        // journal_mode returns the resulting mode so has to be queried
+        connection.pragma_update_and_check(
+            None,
+            "journal_mode",
+            options.journal_mode.as_str(),
+            |_| Ok(()),
+        )?;
+        connection.pragma_update(
+            None,
+            "synchronous",
+            options.effective_synchronous().as_str(),
+        )?;
+        connection.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {CHANGES_TABLE} (
+                prefix TEXT NOT NULL, actor BLOB NOT NULL, seq INTEGER NOT NULL, data BLOB NOT NULL,
+                PRIMARY KEY (prefix, actor, seq)
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS {DOCUMENTS_TABLE} (
+                prefix TEXT NOT NULL PRIMARY KEY, data BLOB NOT NULL
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS {SYNC_STATES_TABLE} (
+                prefix TEXT NOT NULL, key BLOB NOT NULL, data BLOB NOT NULL,
+                PRIMARY KEY (prefix, key)
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS {METADATA_TABLE} (
+                prefix TEXT NOT NULL, key BLOB NOT NULL, data BLOB NOT NULL,
+                PRIMARY KEY (prefix, key)
+            ) WITHOUT ROWID;",
+        ))?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            durability: Arc::new(Mutex::new(DurabilityTracker::new(options.durability))),
+        })
+    }
+
+    /// Lock the connection for use.
+    ///
+    /// A panic while another persister held the lock cannot leave a transaction half applied, so
+    /// poisoning is ignored.
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_durability(&self) -> MutexGuard<'_, DurabilityTracker> {
+        self.durability
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Checkpoint the log if the durability policy says the commits are due.
+    // the tracker stays locked over the checkpoint so writes made meanwhile aren't marked synced
+    #[allow(clippy::significant_drop_tightening)]
+    fn wrote(&self) -> Result<(), SqlitePersisterError> {
+        let mut durability = self.lock_durability();
+        // every commit is already synced
+        if durability.policy() == DurabilityPolicy::EveryWrite {
+            return Ok(());
+        }
+        if durability.record_write() {
+            self.checkpoint()?;
+            durability.synced();
+        }
+        Ok(())
+    }
+
+    /// Sync the log, making the commits in it durable, and move them into the database.
+    fn checkpoint(&self) -> Result<(), SqlitePersisterError> {
+        self.lock()
+            .query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_| Ok(()))?;
+        Ok(())
+    }
+}
+
+/// The persister that stores changes and documents in `SQLite` tables.
+///
+/// Changes, documents, sync states and metadata are kept in separate tables, with a prefix column
+/// so that multiple persisters may share the same database.
+///
+/// The connection is internally locked so this is a [`SharedPersister`] and can be shared behind
+/// an [`std::sync::Arc`] without extra locking.
+#[derive(Debug)]
+pub struct SqlitePersister {
+    connection: SqliteConnection,
+    prefix: String,
+    sizes: AtomicSizes,
+}
+
+#[derive(Debug, Default)]
+struct AtomicSizes {
+    changes: AtomicU64,
+    document: AtomicU64,
+    sync_states: AtomicU64,
+    metadata: AtomicU64,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum SqlitePersisterError {
+    /// Internal errors from `SQLite`.
+    #[error(transparent)]
+    SqliteError(#[from] rusqlite::Error),
+}
+
+impl SqlitePersister {
+    /// Open the database at the path with its own connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database could not be opened or the stored sizes could not be
+    /// calculated.
+    pub fn open<P, S>(
+        path: P,
+        prefix: S,
+        options: &SqliteOptions,
+    ) -> Result<Self, SqlitePersisterError>
+    where
+        P: AsRef<Path>,
+        S: Into<String>,
+    {
+        Self::new(SqliteConnection::open(path, options)?, prefix)
+    }
+
+    /// Construct a new persister using a possibly shared connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the existing contents could not be read to calculate the stored sizes.
+    pub fn new<S>(connection: SqliteConnection, prefix: S) -> Result<Self, SqlitePersisterError>
+    where
+        S: Into<String>,
+    {
+        let s = Self {
+            connection,
+            prefix: prefix.into(),
+            sizes: AtomicSizes::default(),
+        };
+        {
+            let connection = s.connection.lock();
+            let table_size = |table: &str| -> Result<u64, SqlitePersisterError> {
+                Ok(connection.query_row(
+                    &format!(
+                        "SELECT COALESCE(SUM(LENGTH(data)), 0) FROM {table} WHERE prefix = ?1"
+                    ),
+                    [&s.prefix],
+                    |row| row.get(0),
+                )?)
+            };
+            s.sizes
+                .changes
+                .store(table_size(CHANGES_TABLE)?, Ordering::Relaxed);
+            s.sizes
+                .document
+                .store(table_size(DOCUMENTS_TABLE)?, Ordering::Relaxed);
+            s.sizes
+                .sync_states
+                .store(table_size(SYNC_STATES_TABLE)?, Ordering::Relaxed);
+            s.sizes
+                .metadata
+                .store(table_size(METADATA_TABLE)?, Ordering::Relaxed);
+        }
+        Ok(s)
+    }
+
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>, SqlitePersisterError> {
+        Ok(self
+            .connection
+            .lock()
+            .query_row(
+                &format!("SELECT data FROM {table} WHERE prefix = ?1 AND key = ?2"),
+                params![self.prefix, key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Insert a value, keeping the stored size up to date.
+    fn insert(
+        &self,
+        table: &str,
+        size: &AtomicU64,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), SqlitePersisterError> {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction()?;
+        let old: Option<u64> = transaction
+            .query_row(
+                &format!("SELECT LENGTH(data) FROM {table} WHERE prefix = ?1 AND key = ?2"),
+                params![self.prefix, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        transaction.execute(
+            &format!("INSERT OR REPLACE INTO {table} (prefix, key, data) VALUES (?1, ?2, ?3)"),
+            params![self.prefix, key, value],
+        )?;
+        transaction.commit()?;
+        drop(connection);
+        size.fetch_add(value.len() as u64, Ordering::Relaxed);
+        if let Some(old) = old {
+            size.fetch_sub(old, Ordering::Relaxed);
+        }
+        self.connection.wrote()
+    }
+
+    /// Remove values, keeping the stored size up to date.
+    fn remove(
+        &self,
+        table: &str,
+        size: &AtomicU64,
+        keys: &[&[u8]],
+    ) -> Result<(), SqlitePersisterError> {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction()?;
+        let mut removed = 0;
+        for key in keys {
+            let old: Option<u64> = transaction
+                .query_row(
+                    &format!(
+                        "DELETE FROM {table} WHERE prefix = ?1 AND key = ?2 RETURNING LENGTH(data)"
+                    ),
+                    params![self.prefix, key],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            removed += old.unwrap_or_default();
+        }
+        transaction.commit()?;
+        drop(connection);
+        size.fetch_sub(removed, Ordering::Relaxed);
+        self.connection.wrote()
+    }
+
+    /// Read the first column of the rows for this prefix returned by the query.
+    fn column<T: FromSql>(&self, sql: &str) -> Result<Vec<T>, SqlitePersisterError> {
+        let values = self
+            .connection
+            .lock()
+            .prepare(sql)?
+            .query_map([&self.prefix], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(values)
+    }
+}
+
+impl SharedPersister for SqlitePersister {
+    type Error = SqlitePersisterError;
+
+    /// Get all of the current changes.
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.column(&format!(
+            "SELECT data FROM {CHANGES_TABLE} WHERE prefix = ?1"
+        ))
+    }
+
+    /// Insert all of the given changes atomically in a single transaction.
+    fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction()?;
+        let mut added = 0;
+        let mut removed = 0;
+        for (a, s, c) in changes {
+            let old: Option<u64> = transaction
+                .query_row(
+                    &format!("SELECT LENGTH(data) FROM {CHANGES_TABLE} WHERE prefix = ?1 AND actor = ?2 AND seq = ?3"),
+                    params![self.prefix, a.to_bytes(), s],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            removed += old.unwrap_or_default();
+            added += c.len() as u64;
+            transaction.execute(
+                &format!("INSERT OR REPLACE INTO {CHANGES_TABLE} (prefix, actor, seq, data) VALUES (?1, ?2, ?3, ?4)"),
+                params![self.prefix, a.to_bytes(), s, c],
+            )?;
+        }
+        transaction.commit()?;
+        drop(connection);
+        self.sizes.changes.fetch_add(added, Ordering::Relaxed);
+        self.sizes.changes.fetch_sub(removed, Ordering::Relaxed);
+        self.connection.wrote()
+    }
+
+    /// Remove all of the given changes atomically in a single transaction.
+    fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction()?;
+        let mut removed = 0;
+        for (a, s) in changes {
+            let old: Option<u64> = transaction
+                .query_row(
+                    &format!("DELETE FROM {CHANGES_TABLE} WHERE prefix = ?1 AND actor = ?2 AND seq = ?3 RETURNING LENGTH(data)"),
+                    params![self.prefix, a.to_bytes(), s],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            removed += old.unwrap_or_default();
+        }
+        transaction.commit()?;
+        drop(connection);
+        self.sizes.changes.fetch_sub(removed, Ordering::Relaxed);
+        self.connection.wrote()
+    }
+
+    /// List the actors from the changes table without reading the changes.
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        let actors = self.column::<Vec<u8>>(&format!(
+            "SELECT DISTINCT actor FROM {CHANGES_TABLE} WHERE prefix = ?1"
+        ))?;
+        Ok(actors.into_iter().map(ActorId::from).collect())
+    }
+
+    /// Retrieve the document from the table.
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .connection
+            .lock()
+            .query_row(
+                &format!("SELECT data FROM {DOCUMENTS_TABLE} WHERE prefix = ?1"),
+                [&self.prefix],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Set the document in the table.
+    fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.connection.lock().execute(
+            &format!("INSERT OR REPLACE INTO {DOCUMENTS_TABLE} (prefix, data) VALUES (?1, ?2)"),
+            params![self.prefix, data],
+        )?;
+        self.sizes
+            .document
+            .store(data.len() as u64, Ordering::Relaxed);
+        self.connection.wrote()
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(SYNC_STATES_TABLE, peer_id)
+    }
+
+    fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.insert(
+            SYNC_STATES_TABLE,
+            &self.sizes.sync_states,
+            &peer_id,
+            &sync_state,
+        )
+    }
+
+    fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.remove(SYNC_STATES_TABLE, &self.sizes.sync_states, peer_ids)
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.column(&format!(
+            "SELECT key FROM {SYNC_STATES_TABLE} WHERE prefix = ?1"
+        ))
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(METADATA_TABLE, key)
+    }
+
+    fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.insert(METADATA_TABLE, &self.sizes.metadata, &key, &value)
+    }
+
+    fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.remove(METADATA_TABLE, &self.sizes.metadata, &[key])
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.column(&format!(
+            "SELECT key FROM {METADATA_TABLE} WHERE prefix = ?1"
+        ))
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        StoredSizes {
+            changes: self.sizes.changes.load(Ordering::Relaxed),
+            document: self.sizes.document.load(Ordering::Relaxed),
+            sync_states: self.sizes.sync_states.load(Ordering::Relaxed),
+            metadata: self.sizes.metadata.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Every write is committed in its own transaction, synced as set by
+    /// [`SqliteOptions::synchronous`]. Unless [`SqliteOptions::durability`] is
+    /// [`DurabilityPolicy::EveryWrite`] this checkpoints the log to sync them, either way nothing
+    /// is written so this always returns 0.
+    #[allow(clippy::significant_drop_tightening)]
+    fn flush(&self) -> Result<usize, Self::Error> {
+        let mut durability = self.connection.lock_durability();
+        if durability.policy() != DurabilityPolicy::EveryWrite {
+            self.connection.checkpoint()?;
+            durability.synced();
+        }
+        Ok(0)
+    }
+
+    /// Rebuild the database file, returning the space of removed rows to the file system.
+    ///
+    /// This rebuilds the whole database so affects all persisters sharing it.
+    fn vacuum(&self) -> Result<(), Self::Error> {
+        self.connection.lock().execute_batch("VACUUM")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,16 @@
+-- keys are bounded so they can be part of a primary key
+CREATE TABLE IF NOT EXISTS automerge_changes (
+    document_id varbinary(255), actor_id varbinary(255), seq bigint, `change` longblob NOT NULL,
+    PRIMARY KEY (document_id, actor_id, seq)
+);
+CREATE TABLE IF NOT EXISTS automerge_documents (
+    document_id varbinary(255) PRIMARY KEY, document longblob NOT NULL
+);
+CREATE TABLE IF NOT EXISTS automerge_sync_states (
+    document_id varbinary(255), peer_id varbinary(255), sync_state longblob NOT NULL,
+    PRIMARY KEY (document_id, peer_id)
+);
+CREATE TABLE IF NOT EXISTS automerge_metadata (
+    document_id varbinary(255), `key` varbinary(255), value longblob NOT NULL,
+    PRIMARY KEY (document_id, `key`)
+);
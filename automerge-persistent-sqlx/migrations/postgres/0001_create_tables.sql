@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS automerge_changes (
+    document_id bytea, actor_id bytea, seq bigint, change bytea NOT NULL,
+    PRIMARY KEY (document_id, actor_id, seq)
+);
+CREATE TABLE IF NOT EXISTS automerge_documents (
+    document_id bytea PRIMARY KEY, document bytea NOT NULL
+);
+CREATE TABLE IF NOT EXISTS automerge_sync_states (
+    document_id bytea, peer_id bytea, sync_state bytea NOT NULL,
+    PRIMARY KEY (document_id, peer_id)
+);
+CREATE TABLE IF NOT EXISTS automerge_metadata (
+    document_id bytea, key bytea, value bytea NOT NULL,
+    PRIMARY KEY (document_id, key)
+);
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS automerge_changes (
+    document_id blob, actor_id blob, seq integer, change blob NOT NULL,
+    PRIMARY KEY (document_id, actor_id, seq)
+);
+CREATE TABLE IF NOT EXISTS automerge_documents (
+    document_id blob PRIMARY KEY, document blob NOT NULL
+);
+CREATE TABLE IF NOT EXISTS automerge_sync_states (
+    document_id blob, peer_id blob, sync_state blob NOT NULL,
+    PRIMARY KEY (document_id, peer_id)
+);
+CREATE TABLE IF NOT EXISTS automerge_metadata (
+    document_id blob, key blob, value blob NOT NULL,
+    PRIMARY KEY (document_id, key)
+);
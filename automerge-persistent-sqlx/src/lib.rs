@@ -0,0 +1,611 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [`PostgreSQL`](https://www.postgresql.org), [MySQL](https://www.mysql.com)
+//! or [SQLite](https://www.sqlite.org) through [sqlx](https://github.com/launchbadge/sqlx), so one
+//! implementation covers whichever relational database is already in use.
+//!
+//! Changes, documents, sync states and metadata are kept in separate tables keyed by the document
+//! id, so many documents can share the same tables. The tables are created by the migrations in
+//! this crate, run with [`migrate`], which are written for each database as their column types
+//! differ.
+//!
+//! sqlx is async so the persister blocks on the given runtime handle, methods must therefore not
+//! be called from within an async context (use `spawn_blocking` from async code).
+//!
+//! ```rust,no_run
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_sqlx::SqlxPersister;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let runtime = tokio::runtime::Runtime::new()?;
+//! sqlx::any::install_default_drivers();
+//! let pool = runtime.block_on(sqlx::AnyPool::connect("postgres://localhost/automerge"))?;
+//! runtime.block_on(automerge_persistent_sqlx::migrate(&pool))?;
+//!
+//! let persister = SqlxPersister::new(pool, runtime.handle().clone(), "my-document")?;
+//! let doc = PersistentAutomerge::load(persister)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    future::Future,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use automerge::ActorId;
+use automerge_persistent_core::{SharedPersister, StoredSizes};
+use sqlx::{any::AnyRow, migrate::Migrator, AnyPool, Row};
+use tokio::runtime::Handle;
+
+/// The name of the table changes are stored in.
+pub const CHANGES_TABLE: &str = "automerge_changes";
+/// The name of the table documents are stored in.
+pub const DOCUMENTS_TABLE: &str = "automerge_documents";
+/// The name of the table sync states are stored in.
+pub const SYNC_STATES_TABLE: &str = "automerge_sync_states";
+/// The name of the table metadata is stored in.
+pub const METADATA_TABLE: &str = "automerge_metadata";
+
+static POSTGRES_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/postgres");
+static MYSQL_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/mysql");
+static SQLITE_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// The databases supported, as the SQL they accept differs in places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dialect {
+    Postgres,
+    MySql,
+    Sqlite,
+}
+
+impl Dialect {
+    /// Find the dialect of the database the pool connects to.
+    async fn of(pool: &AnyPool) -> Result<Self, SqlxPersisterError> {
+        let connection = pool.acquire().await?;
+        match connection.backend_name() {
+            "PostgreSQL" => Ok(Self::Postgres),
+            "MySQL" => Ok(Self::MySql),
+            "SQLite" => Ok(Self::Sqlite),
+            other => Err(SqlxPersisterError::UnsupportedDatabase(other.to_owned())),
+        }
+    }
+
+    const fn migrator(self) -> &'static Migrator {
+        match self {
+            Self::Postgres => &POSTGRES_MIGRATIONS,
+            Self::MySql => &MYSQL_MIGRATIONS,
+            Self::Sqlite => &SQLITE_MIGRATIONS,
+        }
+    }
+
+    /// The placeholder of the `n`th parameter of a query, counting from one.
+    fn param(self, n: usize) -> String {
+        match self {
+            Self::Postgres => format!("${n}"),
+            Self::MySql | Self::Sqlite => "?".to_owned(),
+        }
+    }
+
+    /// Quote a column name, as some are reserved words in `MySQL`.
+    fn quote(self, column: &str) -> String {
+        match self {
+            Self::MySql => format!("`{column}`"),
+            Self::Postgres | Self::Sqlite => format!("\"{column}\""),
+        }
+    }
+
+    /// Insert a row, replacing the value of an existing one with the same key.
+    fn upsert(self, table: &str, keys: &[&str], column: &str) -> String {
+        let columns = keys
+            .iter()
+            .chain(Some(&column))
+            .map(|c| self.quote(c))
+            .collect::<Vec<_>>();
+        let params = (1..=columns.len())
+            .map(|n| self.param(n))
+            .collect::<Vec<_>>();
+        let column = self.quote(column);
+        let conflict = match self {
+            Self::MySql => format!("ON DUPLICATE KEY UPDATE {column} = VALUES({column})"),
+            Self::Postgres | Self::Sqlite => format!(
+                "ON CONFLICT ({}) DO UPDATE SET {column} = excluded.{column}",
+                columns[..keys.len()].join(", ")
+            ),
+        };
+        format!(
+            "INSERT INTO {table} ({}) VALUES ({}) {conflict}",
+            columns.join(", "),
+            params.join(", ")
+        )
+    }
+}
+
+/// Run the migrations creating the tables used by the persister for the database the pool
+/// connects to.
+///
+/// # Errors
+///
+/// Returns an error if the database isn't supported or the migrations fail.
+pub async fn migrate(pool: &AnyPool) -> Result<(), SqlxPersisterError> {
+    Dialect::of(pool).await?.migrator().run(pool).await?;
+    Ok(())
+}
+
+/// The persister that stores changes and documents in tables of a `PostgreSQL`, `MySQL` or
+/// `SQLite` database.
+///
+/// The pool hands out connections as needed so this is a [`SharedPersister`] and can be shared
+/// behind an [`std::sync::Arc`] without extra locking.
+pub struct SqlxPersister {
+    pool: AnyPool,
+    handle: Handle,
+    dialect: Dialect,
+    document_id: Vec<u8>,
+    sizes: Mutex<StoredSizes>,
+}
+
+impl std::fmt::Debug for SqlxPersister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqlxPersister")
+            .field("dialect", &self.dialect)
+            .field("document_id", &self.document_id)
+            .field("sizes", &self.sizes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum SqlxPersisterError {
+    /// Internal errors from sqlx.
+    #[error(transparent)]
+    SqlxError(#[from] sqlx::Error),
+    /// Errors running the migrations.
+    #[error(transparent)]
+    MigrateError(#[from] sqlx::migrate::MigrateError),
+    /// The pool connects to a database other than `PostgreSQL`, `MySQL` or `SQLite`.
+    #[error("unsupported database {0}")]
+    UnsupportedDatabase(String),
+}
+
+/// Sequence numbers are stored as a `bigint`, which is signed, so reinterpret the bits.
+const fn seq_to_bigint(seq: u64) -> i64 {
+    i64::from_be_bytes(seq.to_be_bytes())
+}
+
+/// Lock a mutex, ignoring poisoning as the sizes are only an estimate.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The first column of each row.
+fn first_column(rows: &[AnyRow]) -> Result<Vec<Vec<u8>>, sqlx::Error> {
+    rows.iter().map(|row| row.try_get(0)).collect()
+}
+
+/// The column holding the values of a table.
+fn value_column(table: &str) -> &'static str {
+    match table {
+        CHANGES_TABLE => "change",
+        DOCUMENTS_TABLE => "document",
+        SYNC_STATES_TABLE => "sync_state",
+        _ => "value",
+    }
+}
+
+/// The column keying values within a document in the sync state and metadata tables.
+fn key_column(table: &str) -> &'static str {
+    if table == SYNC_STATES_TABLE {
+        "peer_id"
+    } else {
+        "key"
+    }
+}
+
+impl SqlxPersister {
+    /// Construct a new persister for the document with the given id.
+    ///
+    /// The tables must already exist, see [`migrate`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database isn't supported or the existing contents of the tables
+    /// could not be read to calculate the stored sizes.
+    pub fn new<D>(pool: AnyPool, handle: Handle, document_id: D) -> Result<Self, SqlxPersisterError>
+    where
+        D: Into<Vec<u8>>,
+    {
+        let dialect = handle.block_on(Dialect::of(&pool))?;
+        let s = Self {
+            pool,
+            handle,
+            dialect,
+            document_id: document_id.into(),
+            sizes: Mutex::new(StoredSizes::default()),
+        };
+        let table_size = |table: &str| -> Result<u64, SqlxPersisterError> {
+            Ok(s.values(table)?.iter().map(|v| v.len() as u64).sum())
+        };
+        let sizes = StoredSizes {
+            changes: table_size(CHANGES_TABLE)?,
+            document: table_size(DOCUMENTS_TABLE)?,
+            sync_states: table_size(SYNC_STATES_TABLE)?,
+            metadata: table_size(METADATA_TABLE)?,
+        };
+        *lock(&s.sizes) = sizes;
+        Ok(s)
+    }
+
+    /// Update the stored sizes, holding the lock only for the update.
+    fn update_sizes(&self, f: impl FnOnce(&mut StoredSizes)) {
+        f(&mut lock(&self.sizes));
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.handle.block_on(future)
+    }
+
+    /// The values of a table for this document.
+    fn values(&self, table: &str) -> Result<Vec<Vec<u8>>, SqlxPersisterError> {
+        self.column(table, value_column(table))
+    }
+
+    /// A column of a table for the rows of this document.
+    fn column(&self, table: &str, column: &str) -> Result<Vec<Vec<u8>>, SqlxPersisterError> {
+        let query = format!(
+            "SELECT {} FROM {table} WHERE document_id = {}",
+            self.dialect.quote(column),
+            self.dialect.param(1)
+        );
+        let rows = self.block_on(
+            sqlx::query(&query)
+                .bind(self.document_id.clone())
+                .fetch_all(&self.pool),
+        )?;
+        Ok(first_column(&rows)?)
+    }
+
+    /// Get a value from one of the keyed tables.
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>, SqlxPersisterError> {
+        let query = format!(
+            "SELECT {} FROM {table} WHERE document_id = {} AND {} = {}",
+            self.dialect.quote(value_column(table)),
+            self.dialect.param(1),
+            self.dialect.quote(key_column(table)),
+            self.dialect.param(2)
+        );
+        let row = self.block_on(
+            sqlx::query(&query)
+                .bind(self.document_id.clone())
+                .bind(key.to_vec())
+                .fetch_optional(&self.pool),
+        )?;
+        Ok(row.map(|row| row.try_get(0)).transpose()?)
+    }
+
+    /// Upsert a value in one of the keyed tables in a transaction, returning the size of the
+    /// replaced value.
+    fn upsert(&self, table: &str, key: &[u8], value: Vec<u8>) -> Result<u64, SqlxPersisterError> {
+        let select = format!(
+            "SELECT {} FROM {table} WHERE document_id = {} AND {} = {}",
+            self.dialect.quote(value_column(table)),
+            self.dialect.param(1),
+            self.dialect.quote(key_column(table)),
+            self.dialect.param(2)
+        );
+        let upsert = self.dialect.upsert(
+            table,
+            &["document_id", key_column(table)],
+            value_column(table),
+        );
+        self.block_on(async {
+            let mut transaction = self.pool.begin().await?;
+            let old = sqlx::query(&select)
+                .bind(self.document_id.clone())
+                .bind(key.to_vec())
+                .fetch_optional(&mut *transaction)
+                .await?
+                .map(|row| row.try_get::<Vec<u8>, _>(0))
+                .transpose()?
+                .map_or(0, |old| old.len() as u64);
+            sqlx::query(&upsert)
+                .bind(self.document_id.clone())
+                .bind(key.to_vec())
+                .bind(value)
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await?;
+            Ok(old)
+        })
+    }
+
+    /// Delete values from one of the keyed tables in a transaction, returning the size of the
+    /// removed values.
+    fn delete(&self, table: &str, keys: &[&[u8]]) -> Result<u64, SqlxPersisterError> {
+        let select = format!(
+            "SELECT {} FROM {table} WHERE document_id = {} AND {} = {}",
+            self.dialect.quote(value_column(table)),
+            self.dialect.param(1),
+            self.dialect.quote(key_column(table)),
+            self.dialect.param(2)
+        );
+        let delete = format!(
+            "DELETE FROM {table} WHERE document_id = {} AND {} = {}",
+            self.dialect.param(1),
+            self.dialect.quote(key_column(table)),
+            self.dialect.param(2)
+        );
+        self.block_on(async {
+            let mut transaction = self.pool.begin().await?;
+            let mut removed = 0;
+            for key in keys {
+                if let Some(row) = sqlx::query(&select)
+                    .bind(self.document_id.clone())
+                    .bind(key.to_vec())
+                    .fetch_optional(&mut *transaction)
+                    .await?
+                {
+                    removed += row.try_get::<Vec<u8>, _>(0)?.len() as u64;
+                    sqlx::query(&delete)
+                        .bind(self.document_id.clone())
+                        .bind(key.to_vec())
+                        .execute(&mut *transaction)
+                        .await?;
+                }
+            }
+            transaction.commit().await?;
+            Ok(removed)
+        })
+    }
+}
+
+impl SharedPersister for SqlxPersister {
+    type Error = SqlxPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.values(CHANGES_TABLE)
+    }
+
+    /// List the actors from the keys of the changes rather than decoding them.
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        let query = format!(
+            "SELECT DISTINCT actor_id FROM {CHANGES_TABLE} WHERE document_id = {}",
+            self.dialect.param(1)
+        );
+        let rows = self.block_on(
+            sqlx::query(&query)
+                .bind(self.document_id.clone())
+                .fetch_all(&self.pool),
+        )?;
+        Ok(first_column(&rows)?
+            .into_iter()
+            .map(ActorId::from)
+            .collect())
+    }
+
+    /// Insert all of the given changes in a single transaction.
+    fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let select = format!(
+            "SELECT {} FROM {CHANGES_TABLE} WHERE document_id = {} AND actor_id = {} AND seq = {}",
+            self.dialect.quote("change"),
+            self.dialect.param(1),
+            self.dialect.param(2),
+            self.dialect.param(3)
+        );
+        let upsert =
+            self.dialect
+                .upsert(CHANGES_TABLE, &["document_id", "actor_id", "seq"], "change");
+        let (added, replaced) = self.block_on(async {
+            let mut transaction = self.pool.begin().await?;
+            let mut added = 0;
+            let mut replaced = 0;
+            for (actor_id, seq, change) in changes {
+                let actor_id = actor_id.to_bytes().to_vec();
+                if let Some(row) = sqlx::query(&select)
+                    .bind(self.document_id.clone())
+                    .bind(actor_id.clone())
+                    .bind(seq_to_bigint(seq))
+                    .fetch_optional(&mut *transaction)
+                    .await?
+                {
+                    replaced += row.try_get::<Vec<u8>, _>(0)?.len() as u64;
+                }
+                added += change.len() as u64;
+                sqlx::query(&upsert)
+                    .bind(self.document_id.clone())
+                    .bind(actor_id)
+                    .bind(seq_to_bigint(seq))
+                    .bind(change)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+            transaction.commit().await?;
+            Ok::<_, SqlxPersisterError>((added, replaced))
+        })?;
+        self.update_sizes(|sizes| sizes.changes = (sizes.changes + added).saturating_sub(replaced));
+        Ok(())
+    }
+
+    /// Remove all of the given changes in a single transaction.
+    fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let select = format!(
+            "SELECT {} FROM {CHANGES_TABLE} WHERE document_id = {} AND actor_id = {} AND seq = {}",
+            self.dialect.quote("change"),
+            self.dialect.param(1),
+            self.dialect.param(2),
+            self.dialect.param(3)
+        );
+        let delete = format!(
+            "DELETE FROM {CHANGES_TABLE} WHERE document_id = {} AND actor_id = {} AND seq = {}",
+            self.dialect.param(1),
+            self.dialect.param(2),
+            self.dialect.param(3)
+        );
+        let removed = self.block_on(async {
+            let mut transaction = self.pool.begin().await?;
+            let mut removed = 0;
+            for (actor_id, seq) in changes {
+                let actor_id = actor_id.to_bytes().to_vec();
+                if let Some(row) = sqlx::query(&select)
+                    .bind(self.document_id.clone())
+                    .bind(actor_id.clone())
+                    .bind(seq_to_bigint(seq))
+                    .fetch_optional(&mut *transaction)
+                    .await?
+                {
+                    removed += row.try_get::<Vec<u8>, _>(0)?.len() as u64;
+                    sqlx::query(&delete)
+                        .bind(self.document_id.clone())
+                        .bind(actor_id)
+                        .bind(seq_to_bigint(seq))
+                        .execute(&mut *transaction)
+                        .await?;
+                }
+            }
+            transaction.commit().await?;
+            Ok::<_, SqlxPersisterError>(removed)
+        })?;
+        self.update_sizes(|sizes| sizes.changes = sizes.changes.saturating_sub(removed));
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.values(DOCUMENTS_TABLE)?.pop())
+    }
+
+    fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let len = data.len() as u64;
+        let upsert = self
+            .dialect
+            .upsert(DOCUMENTS_TABLE, &["document_id"], "document");
+        self.block_on(
+            sqlx::query(&upsert)
+                .bind(self.document_id.clone())
+                .bind(data)
+                .execute(&self.pool),
+        )?;
+        self.update_sizes(|sizes| sizes.document = len);
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(SYNC_STATES_TABLE, peer_id)
+    }
+
+    fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let len = sync_state.len() as u64;
+        let old = self.upsert(SYNC_STATES_TABLE, &peer_id, sync_state)?;
+        self.update_sizes(|sizes| {
+            sizes.sync_states = (sizes.sync_states + len).saturating_sub(old);
+        });
+        Ok(())
+    }
+
+    fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let removed = self.delete(SYNC_STATES_TABLE, peer_ids)?;
+        self.update_sizes(|sizes| sizes.sync_states = sizes.sync_states.saturating_sub(removed));
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.column(SYNC_STATES_TABLE, "peer_id")
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(METADATA_TABLE, key)
+    }
+
+    fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let len = value.len() as u64;
+        let old = self.upsert(METADATA_TABLE, &key, value)?;
+        self.update_sizes(|sizes| sizes.metadata = (sizes.metadata + len).saturating_sub(old));
+        Ok(())
+    }
+
+    fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+        let removed = self.delete(METADATA_TABLE, &[key])?;
+        self.update_sizes(|sizes| sizes.metadata = sizes.metadata.saturating_sub(removed));
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.column(METADATA_TABLE, "key")
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        lock(&self.sizes).clone()
+    }
+
+    /// Writes are committed as they are made so there is nothing to flush.
+    fn flush(&self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{transaction::Transactable, ROOT};
+    use automerge_persistent::PersistentAutomerge;
+    use sqlx::any::AnyPoolOptions;
+    use tokio::runtime::Runtime;
+
+    use super::{migrate, SqlxPersister};
+    use crate::SharedPersister;
+
+    /// A pool over an in-memory `SQLite` database, with one connection so that every query sees
+    /// the same database.
+    fn pool(runtime: &Runtime) -> sqlx::AnyPool {
+        sqlx::any::install_default_drivers();
+        let pool = runtime
+            .block_on(
+                AnyPoolOptions::new()
+                    .max_connections(1)
+                    .connect("sqlite::memory:"),
+            )
+            .unwrap();
+        runtime.block_on(migrate(&pool)).unwrap();
+        pool
+    }
+
+    #[test]
+    fn document_round_trips_through_compaction() {
+        let runtime = Runtime::new().unwrap();
+        let pool = pool(&runtime);
+        let persister = SqlxPersister::new(pool.clone(), runtime.handle().clone(), "doc").unwrap();
+        let mut doc = PersistentAutomerge::load(persister).unwrap();
+        doc.transact::<_, _, std::convert::Infallible>(|tx| {
+            tx.put(ROOT, "a", 1).unwrap();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            SharedPersister::get_changes(doc.persister()).unwrap().len(),
+            1
+        );
+        doc.compact(&[]).unwrap();
+        let heads = doc.document().get_heads();
+        drop(doc);
+
+        let persister = SqlxPersister::new(pool, runtime.handle().clone(), "doc").unwrap();
+        assert!(SharedPersister::get_changes(&persister).unwrap().is_empty());
+        assert_eq!(SharedPersister::sizes(&persister).changes, 0);
+        let doc = PersistentAutomerge::load(persister).unwrap();
+        assert_eq!(doc.document().get_heads(), heads);
+    }
+
+    #[test]
+    fn documents_are_kept_apart() {
+        let runtime = Runtime::new().unwrap();
+        let pool = pool(&runtime);
+        let a = SqlxPersister::new(pool.clone(), runtime.handle().clone(), "a").unwrap();
+        let b = SqlxPersister::new(pool, runtime.handle().clone(), "b").unwrap();
+        a.set_metadata(b"key".to_vec(), b"value".to_vec()).unwrap();
+
+        assert_eq!(a.get_metadata(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(b.get_metadata(b"key").unwrap(), None);
+    }
+}
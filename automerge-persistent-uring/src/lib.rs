@@ -0,0 +1,580 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![cfg(target_os = "linux")]
+
+//! A persister for Linux writing through [`io_uring`](https://github.com/tokio-rs/tokio-uring),
+//! for sync servers where the syscalls of thousands of small change writes a second add up.
+//!
+//! Changes, sync states and metadata are appended to a log, each batch of changes going to the
+//! ring as a single write, and [`Persister::flush`] syncs the log with one more. The document is
+//! written to a file of its own and renamed over the old one, so compacting doesn't rewrite the
+//! log.
+//!
+//! The persister runs its own `io_uring` runtime on the calling thread and blocks on each
+//! operation, so it should be used from a thread of its own rather than within another runtime.
+//!
+//! `io_uring` is Linux only, on other targets this crate is empty.
+//!
+//! ```rust,no_run
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_uring::UringPersister;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let persister = UringPersister::open("my-document")?;
+//! let mut doc = PersistentAutomerge::load(persister)?;
+//! doc.flush()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use automerge::ActorId;
+use automerge_persistent_core::{Persister, StoredSizes};
+use tokio_uring::{
+    fs::{File, OpenOptions},
+    Runtime,
+};
+
+/// The name of the log file, in the persister's directory.
+pub const LOG_FILE: &str = "log";
+/// The name of the document file, in the persister's directory.
+pub const DOCUMENT_FILE: &str = "document";
+/// Appended to the name of a file while it is being written.
+const TEMP_SUFFIX: &str = ".tmp";
+/// Logs smaller than this are never rewritten.
+const REWRITE_MIN_LEN: u64 = 1 << 20;
+/// The operation and store bytes followed by the lengths of the key and value.
+const RECORD_HEADER_LEN: usize = 18;
+
+const DELETE: u8 = 0;
+const PUT: u8 = 1;
+
+const CHANGES: u8 = 0;
+const SYNC_STATES: u8 = 1;
+const METADATA: u8 = 2;
+
+/// Persist changes and documents in to a directory through `io_uring`.
+///
+/// Each record in the log is an operation and store byte followed by the lengths of the key and
+/// value and then the bytes themselves, changes being keyed by their actor followed by their
+/// sequence number. A record only partly written when the process crashed is dropped when the
+/// log is next opened.
+///
+/// All of the log is read back in to memory when opening. Once its stale records outweigh the
+/// live ones it is rewritten by [`Persister::flush`], in to a temporary file that is synced and
+/// renamed over it.
+pub struct UringPersister {
+    root: PathBuf,
+    /// Taken to close it on the runtime when the persister is dropped.
+    log: Option<File>,
+    /// The length of the log, where the next record is written.
+    len: u64,
+    /// Bytes written since the last flush.
+    unflushed: usize,
+    /// Whether the document has been renamed since the directory was last synced.
+    document_unsynced: bool,
+    changes: HashMap<(ActorId, u64), Vec<u8>>,
+    sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    metadata: HashMap<Vec<u8>, Vec<u8>>,
+    sizes: StoredSizes,
+    runtime: Runtime,
+}
+
+impl fmt::Debug for UringPersister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UringPersister")
+            .field("root", &self.root)
+            .field("len", &self.len)
+            .field("sizes", &self.sizes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum UringPersisterError {
+    /// An underlying IO error.
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    /// A record in the log was not in the expected form.
+    #[error("invalid record at offset {0}")]
+    InvalidRecord(u64),
+}
+
+fn change_key(actor_id: &ActorId, seq: u64) -> Vec<u8> {
+    let mut key = actor_id.to_bytes().to_vec();
+    key.extend(seq.to_be_bytes());
+    key
+}
+
+fn decode_change_key(key: &[u8]) -> Option<(ActorId, u64)> {
+    let split = key.len().checked_sub(8)?;
+    let (actor, seq) = key.split_at(split);
+    Some((
+        ActorId::from(actor),
+        u64::from_be_bytes(seq.try_into().ok()?),
+    ))
+}
+
+fn encode(log: &mut Vec<u8>, store: u8, key: &[u8], value: Option<&[u8]>) {
+    log.push(if value.is_some() { PUT } else { DELETE });
+    log.push(store);
+    let value = value.unwrap_or_default();
+    log.extend((key.len() as u64).to_be_bytes());
+    log.extend((value.len() as u64).to_be_bytes());
+    log.extend(key);
+    log.extend(value);
+}
+
+/// A record read back from the log.
+struct Record<'a> {
+    store: u8,
+    key: &'a [u8],
+    value: Option<&'a [u8]>,
+}
+
+/// Decode the records of the log, along with the offset the last whole record ends at.
+fn decode(log: &[u8]) -> Result<(Vec<Record<'_>>, u64), UringPersisterError> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    let read = |offset: &mut usize, len: usize| {
+        let bytes = log.get(*offset..offset.checked_add(len)?)?;
+        *offset += len;
+        Some(bytes)
+    };
+    loop {
+        let start = offset;
+        let header = read(&mut offset, RECORD_HEADER_LEN);
+        let record = header.and_then(|header| {
+            let key_len = u64::from_be_bytes(header[2..10].try_into().ok()?);
+            let value_len = u64::from_be_bytes(header[10..].try_into().ok()?);
+            let key = read(&mut offset, key_len.try_into().ok()?)?;
+            let value = read(&mut offset, value_len.try_into().ok()?)?;
+            Some((header[0], header[1], key, value))
+        });
+        let Some((op, store, key, value)) = record else {
+            // the rest was only partly written
+            return Ok((records, start as u64));
+        };
+        let value = match op {
+            PUT => Some(value),
+            DELETE => None,
+            _ => return Err(UringPersisterError::InvalidRecord(start as u64)),
+        };
+        if store > METADATA || (store == CHANGES && decode_change_key(key).is_none()) {
+            return Err(UringPersisterError::InvalidRecord(start as u64));
+        }
+        records.push(Record { store, key, value });
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(TEMP_SUFFIX);
+    temp.into()
+}
+
+fn read_optional(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+// the futures run on the persister's own single threaded runtime
+#[allow(clippy::future_not_send)]
+async fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .await
+}
+
+/// Write the file in to a temporary one that is synced and renamed over it, leaving the
+/// directory to be synced by the caller.
+#[allow(clippy::future_not_send)]
+async fn write_file(path: &Path, data: Vec<u8>) -> io::Result<()> {
+    let temp = temp_path(path);
+    let file = File::create(&temp).await?;
+    let (res, _) = file.write_all_at(data, 0).await;
+    res?;
+    file.sync_data().await?;
+    file.close().await?;
+    fs::rename(&temp, path)
+}
+
+impl UringPersister {
+    /// Open the persister in the given directory, creating it if it does not exist, and read
+    /// the log.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the kernel doesn't support `io_uring`, the files could not be read, or
+    /// the log holds an invalid record.
+    pub fn open<R: AsRef<Path>>(root: R) -> Result<Self, UringPersisterError> {
+        let root = root.as_ref().to_owned();
+        fs::create_dir_all(&root)?;
+        let runtime = Runtime::new(&tokio_uring::builder())?;
+
+        let log_path = root.join(LOG_FILE);
+        let document_path = root.join(DOCUMENT_FILE);
+        for path in [&log_path, &document_path] {
+            if let Err(e) = fs::remove_file(temp_path(path)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+        }
+        let log = read_optional(&log_path)?.unwrap_or_default();
+        let (records, len) = decode(&log)?;
+
+        let mut persister = Self {
+            root,
+            log: None,
+            len,
+            unflushed: 0,
+            document_unsynced: false,
+            changes: HashMap::new(),
+            sync_states: HashMap::new(),
+            metadata: HashMap::new(),
+            sizes: StoredSizes {
+                document: match fs::metadata(&document_path) {
+                    Ok(metadata) => metadata.len(),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                    Err(e) => return Err(e.into()),
+                },
+                ..StoredSizes::default()
+            },
+            runtime,
+        };
+        for record in records {
+            persister.replay(&record);
+        }
+
+        let file = persister.runtime.block_on(open_log(&log_path))?;
+        if len < log.len() as u64 {
+            // drop the partly written record at the end
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&log_path)?
+                .set_len(len)?;
+        }
+        sync_dir(&persister.root)?;
+        persister.log = Some(file);
+        Ok(persister)
+    }
+
+    /// The directory the persister stores its files in.
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn replay(&mut self, record: &Record<'_>) {
+        let value = record.value.map(<[u8]>::to_vec);
+        let (size, old) = if record.store == CHANGES {
+            // checked when decoding
+            let key = decode_change_key(record.key).unwrap();
+            let old = match value {
+                Some(value) => {
+                    self.sizes.changes += value.len() as u64;
+                    self.changes.insert(key, value)
+                }
+                None => self.changes.remove(&key),
+            };
+            (&mut self.sizes.changes, old)
+        } else {
+            let (map, size) = if record.store == SYNC_STATES {
+                (&mut self.sync_states, &mut self.sizes.sync_states)
+            } else {
+                (&mut self.metadata, &mut self.sizes.metadata)
+            };
+            let old = match value {
+                Some(value) => {
+                    *size += value.len() as u64;
+                    map.insert(record.key.to_vec(), value)
+                }
+                None => map.remove(record.key),
+            };
+            (size, old)
+        };
+        if let Some(old) = old {
+            *size -= old.len() as u64;
+        }
+    }
+
+    const fn log(&self) -> &File {
+        self.log
+            .as_ref()
+            .expect("the log is open until the persister is dropped")
+    }
+
+    /// Write the encoded records to the end of the log in a single submission.
+    fn append(&mut self, records: Vec<u8>) -> Result<(), UringPersisterError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let len = records.len();
+        let (res, _) = self
+            .runtime
+            .block_on(self.log().write_all_at(records, self.len));
+        res?;
+        self.len += len as u64;
+        self.unflushed += len;
+        Ok(())
+    }
+
+    /// Write just the live records in to a new log, renaming it over the old one.
+    fn rewrite(&mut self) -> Result<(), UringPersisterError> {
+        let mut log = Vec::new();
+        for ((actor, seq), change) in &self.changes {
+            encode(&mut log, CHANGES, &change_key(actor, *seq), Some(change));
+        }
+        for (store, map) in [(SYNC_STATES, &self.sync_states), (METADATA, &self.metadata)] {
+            for (key, value) in map {
+                encode(&mut log, store, key, Some(value));
+            }
+        }
+
+        let len = log.len() as u64;
+        let log_path = self.root.join(LOG_FILE);
+        self.runtime.block_on(write_file(&log_path, log))?;
+        sync_dir(&self.root)?;
+        self.document_unsynced = false;
+
+        let file = self.runtime.block_on(open_log(&log_path))?;
+        if let Some(old) = self.log.replace(file) {
+            self.runtime.block_on(old.close())?;
+        }
+        self.len = len;
+        Ok(())
+    }
+
+    /// The size of the live records in the log.
+    const fn stored(&self) -> u64 {
+        self.sizes.changes + self.sizes.sync_states + self.sizes.metadata
+    }
+}
+
+impl Drop for UringPersister {
+    fn drop(&mut self) {
+        if let Some(log) = self.log.take() {
+            // nothing is lost if closing fails as the log is only relied on once flushed
+            let _ = self.runtime.block_on(log.close());
+        }
+    }
+}
+
+impl Persister for UringPersister {
+    type Error = UringPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.changes.values().cloned().collect())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        for (a, s, c) in &changes {
+            encode(&mut log, CHANGES, &change_key(a, *s), Some(c));
+        }
+        self.append(log)?;
+        for (a, s, c) in changes {
+            self.sizes.changes += c.len() as u64;
+            if let Some(old) = self.changes.insert((a, s), c) {
+                self.sizes.changes -= old.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        for (a, s) in &changes {
+            if self.changes.contains_key(&((*a).clone(), *s)) {
+                encode(&mut log, CHANGES, &change_key(a, *s), None);
+            }
+        }
+        self.append(log)?;
+        for (a, s) in changes {
+            if let Some(old) = self.changes.remove(&(a.clone(), s)) {
+                self.sizes.changes -= old.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(read_optional(&self.root.join(DOCUMENT_FILE))?)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let len = data.len();
+        self.runtime
+            .block_on(write_file(&self.root.join(DOCUMENT_FILE), data))?;
+        self.document_unsynced = true;
+        self.sizes.document = len as u64;
+        self.unflushed += len;
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.get(peer_id).cloned())
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        encode(&mut log, SYNC_STATES, &peer_id, Some(&sync_state));
+        self.append(log)?;
+        self.sizes.sync_states += sync_state.len() as u64;
+        if let Some(old) = self.sync_states.insert(peer_id, sync_state) {
+            self.sizes.sync_states -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        for id in peer_ids {
+            if self.sync_states.contains_key(*id) {
+                encode(&mut log, SYNC_STATES, id, None);
+            }
+        }
+        self.append(log)?;
+        for id in peer_ids {
+            if let Some(old) = self.sync_states.remove(*id) {
+                self.sizes.sync_states -= old.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.keys().cloned().collect())
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.get(key).cloned())
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let mut log = Vec::new();
+        encode(&mut log, METADATA, &key, Some(&value));
+        self.append(log)?;
+        self.sizes.metadata += value.len() as u64;
+        if let Some(old) = self.metadata.insert(key, value) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        if self.metadata.contains_key(key) {
+            let mut log = Vec::new();
+            encode(&mut log, METADATA, key, None);
+            self.append(log)?;
+        }
+        if let Some(old) = self.metadata.remove(key) {
+            self.sizes.metadata -= old.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.metadata.keys().cloned().collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Sync the log, rewriting it instead if less than half of it is still live, and the
+    /// directory if the document has been replaced.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        if self.len > REWRITE_MIN_LEN && self.len > 2 * self.stored() {
+            self.rewrite()?;
+        } else if self.unflushed > 0 {
+            self.runtime.block_on(self.log().sync_data())?;
+        }
+        if self.document_unsynced {
+            sync_dir(&self.root)?;
+            self.document_unsynced = false;
+        }
+        Ok(std::mem::take(&mut self.unflushed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use automerge::{transaction::Transactable, ROOT};
+    use automerge_persistent::PersistentAutomerge;
+
+    use super::{UringPersister, LOG_FILE};
+    use crate::Persister;
+
+    fn edit(doc: &mut PersistentAutomerge<UringPersister>, value: i64) {
+        doc.transact::<_, _, std::convert::Infallible>(|tx| {
+            tx.put(ROOT, "a", value).unwrap();
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn changes_and_documents_are_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut doc = PersistentAutomerge::load(UringPersister::open(dir.path()).unwrap()).unwrap();
+        edit(&mut doc, 1);
+        doc.compact(&[]).unwrap();
+        edit(&mut doc, 2);
+        doc.flush().unwrap();
+        let heads = doc.document().get_heads();
+        drop(doc);
+
+        let persister = UringPersister::open(dir.path()).unwrap();
+        assert!(persister.get_document().unwrap().is_some());
+        assert_eq!(persister.get_changes().unwrap().len(), 1);
+        let doc = PersistentAutomerge::load(persister).unwrap();
+        assert_eq!(doc.document().get_heads(), heads);
+    }
+
+    #[test]
+    fn partly_written_record_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut persister = UringPersister::open(dir.path()).unwrap();
+        persister
+            .set_metadata(b"key".to_vec(), b"value".to_vec())
+            .unwrap();
+        persister.flush().unwrap();
+        drop(persister);
+
+        let log = dir.path().join(LOG_FILE);
+        let len = fs::metadata(&log).unwrap().len();
+        let mut bytes = fs::read(&log).unwrap();
+        bytes.extend([1, 2, 0, 0]);
+        fs::write(&log, bytes).unwrap();
+
+        let persister = UringPersister::open(dir.path()).unwrap();
+        assert_eq!(
+            persister.get_metadata(b"key").unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(fs::metadata(&log).unwrap().len(), len);
+    }
+}
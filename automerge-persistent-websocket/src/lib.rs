@@ -0,0 +1,235 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! Replicate a [`PersistentAutomerge`] with a peer over a WebSocket.
+//!
+//! Sync messages are exchanged as binary frames. Received changes and the sync state for the
+//! peer are persisted as they arrive, so a replicator created for the same peer after a
+//! reconnect resumes from what was already known to be shared.
+//!
+//! The connection is driven by one side calling [`WebSocketReplicator::sync`] whenever it wants to
+//! bring the peers up to date, while the other side calls [`WebSocketReplicator::serve`] to
+//! respond until the connection is closed. An empty binary frame is sent in reply to a sync
+//! message when there is nothing more to say, which ends a round.
+//!
+//! ```rust
+//! # use std::net::{TcpListener, TcpStream};
+//! # use automerge::{transaction::Transactable, ROOT};
+//! # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+//! # use automerge_persistent_websocket::WebSocketReplicator;
+//! let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+//! let addr = listener.local_addr().unwrap();
+//!
+//! let server = std::thread::spawn(move || {
+//!     let (stream, _) = listener.accept().unwrap();
+//!     let socket = tungstenite::accept(stream).unwrap();
+//!     let doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+//!     let mut replicator = WebSocketReplicator::new(doc, socket, b"client".to_vec());
+//!     replicator.serve().unwrap();
+//!     replicator.into_document()
+//! });
+//!
+//! let stream = TcpStream::connect(addr).unwrap();
+//! let (socket, _) = tungstenite::client(format!("ws://{}", addr), stream).unwrap();
+//! let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+//! doc.transact::<_, _, std::convert::Infallible>(|tx| {
+//!     tx.put(ROOT, "a", 1).unwrap();
+//!     Ok(())
+//! })
+//! .unwrap();
+//!
+//! let mut replicator = WebSocketReplicator::new(doc, socket, b"server".to_vec());
+//! replicator.sync().unwrap();
+//! replicator.close().unwrap();
+//!
+//! let server_doc = server.join().unwrap();
+//! assert_eq!(server_doc.document().length(ROOT), 1);
+//! ```
+
+use std::io::{Read, Write};
+
+use automerge::sync;
+use automerge_persistent::{Error, PersistentAutomerge, Persister};
+use tungstenite::{Message, WebSocket};
+
+/// Possible errors from replicating.
+#[derive(Debug, thiserror::Error)]
+pub enum ReplicationError<E>
+where
+    E: std::error::Error + 'static,
+{
+    /// Errors from the persistent document.
+    #[error(transparent)]
+    PersistentError(#[from] Error<E>),
+    /// Errors from the WebSocket.
+    #[error(transparent)]
+    WebSocketError(Box<tungstenite::Error>),
+    /// A sync message from the peer could not be decoded.
+    #[error(transparent)]
+    DecodingError(#[from] automerge::DecodingError),
+    /// The peer sent a frame that was not part of the sync protocol.
+    #[error("unexpected message from peer")]
+    UnexpectedMessage,
+    /// The peer closed the connection part way through a sync.
+    #[error("connection closed by peer")]
+    ConnectionClosed,
+}
+
+impl<E> From<tungstenite::Error> for ReplicationError<E>
+where
+    E: std::error::Error + 'static,
+{
+    fn from(error: tungstenite::Error) -> Self {
+        Self::WebSocketError(Box::new(error))
+    }
+}
+
+/// A frame of the sync protocol.
+enum Frame {
+    Message(sync::Message),
+    Empty,
+    Closed,
+}
+
+/// Keeps a [`PersistentAutomerge`] in sync with a peer on the other end of a WebSocket.
+#[derive(Debug)]
+pub struct WebSocketReplicator<P, S> {
+    document: PersistentAutomerge<P>,
+    socket: WebSocket<S>,
+    peer_id: Vec<u8>,
+}
+
+impl<P, S> WebSocketReplicator<P, S>
+where
+    P: Persister + 'static,
+    S: Read + Write,
+{
+    /// Replicate the document with the peer on the other end of the socket.
+    ///
+    /// The peer id identifies the sync state to resume from so should be stable across
+    /// connections to the same peer.
+    pub fn new(
+        mut document: PersistentAutomerge<P>,
+        socket: WebSocket<S>,
+        peer_id: Vec<u8>,
+    ) -> Self {
+        document.reload_sync_state(&peer_id);
+        Self {
+            document,
+            socket,
+            peer_id,
+        }
+    }
+
+    /// Get a reference to the document.
+    pub const fn document(&self) -> &PersistentAutomerge<P> {
+        &self.document
+    }
+
+    /// Get a mutable reference to the document, for making local changes between syncs.
+    pub const fn document_mut(&mut self) -> &mut PersistentAutomerge<P> {
+        &mut self.document
+    }
+
+    /// Take back the document, dropping the socket.
+    pub fn into_document(self) -> PersistentAutomerge<P> {
+        self.document
+    }
+
+    fn send(&mut self, message: Option<sync::Message>) -> Result<(), ReplicationError<P::Error>> {
+        let bytes = message.map(sync::Message::encode).unwrap_or_default();
+        self.socket.send(Message::Binary(bytes))?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Frame, ReplicationError<P::Error>> {
+        loop {
+            match self.socket.read() {
+                Ok(Message::Binary(bytes)) if bytes.is_empty() => return Ok(Frame::Empty),
+                Ok(Message::Binary(bytes)) => {
+                    return Ok(Frame::Message(sync::Message::decode(&bytes)?))
+                }
+                // keep reading after a close so that the closing handshake completes
+                Ok(Message::Ping(_) | Message::Pong(_) | Message::Close(_)) => {}
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                    return Ok(Frame::Closed)
+                }
+                Ok(Message::Text(_) | Message::Frame(_)) => {
+                    return Err(ReplicationError::UnexpectedMessage)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Receive a message from the peer, persisting it, and work out the reply.
+    fn receive(
+        &mut self,
+        message: sync::Message,
+    ) -> Result<Option<sync::Message>, ReplicationError<P::Error>> {
+        self.document
+            .receive_sync_message(self.peer_id.clone(), message)?;
+        Ok(self.document.generate_sync_message(self.peer_id.clone())?)
+    }
+
+    /// Exchange messages with the peer until neither side has anything more to send.
+    ///
+    /// The peer should be running [`Self::serve`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket fails, the peer misbehaves or persisting fails.
+    pub fn sync(&mut self) -> Result<(), ReplicationError<P::Error>> {
+        let mut outgoing = self.document.generate_sync_message(self.peer_id.clone())?;
+        while let Some(message) = outgoing {
+            self.send(Some(message))?;
+            outgoing = match self.recv()? {
+                Frame::Message(message) => {
+                    let reply = self.receive(message)?;
+                    if reply.is_none() {
+                        self.send(None)?;
+                    }
+                    reply
+                }
+                Frame::Empty => None,
+                Frame::Closed => return Err(ReplicationError::ConnectionClosed),
+            };
+        }
+        Ok(())
+    }
+
+    /// Respond to sync messages from the peer until it closes the connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket fails, the peer misbehaves or persisting fails.
+    pub fn serve(&mut self) -> Result<(), ReplicationError<P::Error>> {
+        loop {
+            match self.recv()? {
+                Frame::Message(message) => {
+                    let reply = self.receive(message)?;
+                    self.send(reply)?;
+                }
+                Frame::Empty => {}
+                Frame::Closed => return Ok(()),
+            }
+        }
+    }
+
+    /// Close the connection and flush the document.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket could not be closed cleanly or flushing fails.
+    pub fn close(&mut self) -> Result<(), ReplicationError<P::Error>> {
+        self.socket.close(None)?;
+        while !matches!(self.recv()?, Frame::Closed) {}
+        self.document
+            .flush()
+            .map_err(|e| ReplicationError::PersistentError(Error::PersisterError(e)))?;
+        Ok(())
+    }
+}
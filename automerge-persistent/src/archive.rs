@@ -0,0 +1,526 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    error::Error,
+    io::{self, Read, Write},
+};
+
+use automerge::{AutomergeError, Change, ChangeHash};
+
+use crate::{metadata, persister, Backend, Cipher, PersistentAutomerge, Persister};
+
+/// The first entry of an archive, to tell them apart from other tar files.
+const FORMAT_ENTRY: &str = "format";
+const FORMAT: &[u8] = b"automerge-persistent archive 1\n";
+
+const BLOCK: usize = 512;
+
+/// The start of an encrypted archive, followed by the length of the salt, the salt and the
+/// encrypted archive.
+const ENCRYPTED_MAGIC: &[u8] = b"AMPEA1";
+
+/// Errors from exporting or importing an archive.
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError<E>
+where
+    E: Error + 'static,
+{
+    /// The persister failed.
+    #[error(transparent)]
+    PersisterError(E),
+    /// Reading or writing the archive failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The archive is not one written by [`export_archive`], or is corrupt.
+    #[error("malformed archive: {0}")]
+    Malformed(String),
+    /// A change in the archive could not be decoded.
+    #[error(transparent)]
+    AutomergeError(#[from] AutomergeError),
+}
+
+/// Write everything the persister stores to a tar archive, to move a document to another
+/// backend with [`import_archive`] or to send along with a bug report.
+///
+/// The archive holds the saved document, the individually stored changes, sync states and
+/// metadata, each as their own file so it can be inspected with `tar`. Records are written as
+/// the persister returns them, so for persisters wrapped with codecs or encryption they are
+/// decoded first.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{
+/// #     export_archive, import_archive, MemoryPersister, PersistentAutomerge,
+/// # };
+/// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+/// let actor = doc.actor_id().clone();
+///
+/// let mut archive = Vec::new();
+/// export_archive(doc.persister(), &mut archive).unwrap();
+///
+/// let mut moved = MemoryPersister::default();
+/// import_archive(&mut moved, archive.as_slice()).unwrap();
+/// let doc = PersistentAutomerge::load(moved).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 1);
+/// assert_eq!(doc.actor_id(), &actor);
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if the persister could not be read or the archive could not be written.
+pub fn export_archive<P, W>(persister: &P, mut writer: W) -> Result<(), ArchiveError<P::Error>>
+where
+    P: Persister + ?Sized,
+    W: Write,
+{
+    write_entry(&mut writer, FORMAT_ENTRY, FORMAT)?;
+    if let Some(document) = persister
+        .get_document()
+        .map_err(ArchiveError::PersisterError)?
+    {
+        write_entry(&mut writer, "document", &document)?;
+    }
+    for (index, change) in persister
+        .get_changes()
+        .map_err(ArchiveError::PersisterError)?
+        .iter()
+        .enumerate()
+    {
+        write_entry(&mut writer, &format!("changes/{index}"), change)?;
+    }
+    let peer_ids = persister
+        .get_peer_ids()
+        .map_err(ArchiveError::PersisterError)?;
+    for (index, peer_id) in peer_ids.iter().enumerate() {
+        if let Some(sync_state) = persister
+            .get_sync_state(peer_id)
+            .map_err(ArchiveError::PersisterError)?
+        {
+            write_entry(&mut writer, &format!("sync_states/{index}/key"), peer_id)?;
+            write_entry(
+                &mut writer,
+                &format!("sync_states/{index}/value"),
+                &sync_state,
+            )?;
+        }
+    }
+    let keys = persister
+        .get_metadata_keys()
+        .map_err(ArchiveError::PersisterError)?;
+    for (index, key) in keys.iter().enumerate() {
+        if let Some(value) = persister
+            .get_metadata(key)
+            .map_err(ArchiveError::PersisterError)?
+        {
+            write_entry(&mut writer, &format!("metadata/{index}/key"), key)?;
+            write_entry(&mut writer, &format!("metadata/{index}/value"), &value)?;
+        }
+    }
+    // the end of a tar archive is marked by two empty blocks
+    writer.write_all(&[0; 2 * BLOCK])?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Store the contents of an archive written by [`export_archive`] into the persister.
+///
+/// The persister should be empty, any document already stored is replaced and records with the
+/// same keys are overwritten. Changes are stored by hash if the persister is content-addressed.
+/// The archive is read in full before anything is stored.
+///
+/// Incremental backups from [`PersistentAutomerge::backup_since`] add their changes to what is
+/// stored, so a document is restored by importing its full backup then each later one.
+///
+/// # Errors
+///
+/// Returns an error if the archive could not be read or is malformed, or the persister fails.
+pub fn import_archive<P, R>(persister: &mut P, mut reader: R) -> Result<(), ArchiveError<P::Error>>
+where
+    P: Persister + ?Sized,
+    R: Read,
+{
+    let mut format = None;
+    let mut document = None;
+    let mut changes = Vec::new();
+    let mut sync_states = BTreeMap::new();
+    let mut metadata = BTreeMap::new();
+    while let Some((name, data)) = read_entry(&mut reader)? {
+        let mut parts = name.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(FORMAT_ENTRY), None, _, _) => format = Some(data),
+            (Some("document"), None, _, _) => document = Some(data),
+            // incremental backups record what they cover, the changes are all that is stored
+            (Some("since" | "heads"), None, _, _) => {}
+            (Some("changes"), Some(_), None, _) => {
+                changes.push(Change::from_bytes(data).map_err(AutomergeError::from)?)
+            }
+            (Some("sync_states"), Some(index), Some(part), None) => {
+                record_part(&mut sync_states, &name, index, part, data)?
+            }
+            (Some("metadata"), Some(index), Some(part), None) => {
+                record_part(&mut metadata, &name, index, part, data)?
+            }
+            _ => return Err(ArchiveError::Malformed(format!("unexpected entry {name}"))),
+        }
+    }
+    if format.as_deref() != Some(FORMAT) {
+        return Err(ArchiveError::Malformed(
+            "not an automerge-persistent archive".to_owned(),
+        ));
+    }
+
+    if let Some(document) = document {
+        persister
+            .set_document(document)
+            .map_err(ArchiveError::PersisterError)?;
+    }
+    persister::insert_raw_changes(
+        persister,
+        changes.iter().map(|c| (c, c.raw_bytes().to_vec())),
+    )
+    .map_err(ArchiveError::PersisterError)?;
+    for (key, value) in complete_records(sync_states)? {
+        persister
+            .set_sync_state(key, value)
+            .map_err(ArchiveError::PersisterError)?;
+    }
+    for (key, value) in complete_records(metadata)? {
+        persister
+            .set_metadata(key, value)
+            .map_err(ArchiveError::PersisterError)?;
+    }
+    Ok(())
+}
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Write an incremental backup of the changes made since a previous backup, returning the
+    /// heads it brings the backup up to.
+    ///
+    /// The backup is an archive like [`export_archive`] holding just the changes not covered by
+    /// `previous_heads`, along with those heads and the new ones. It is restored with
+    /// [`import_archive`] after the backups before it. The first backup can be made from no heads,
+    /// to hold every change. Pending changes are not backed up until they can be applied.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{import_archive, MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// let mut put = |doc: &mut PersistentAutomerge<MemoryPersister>, key: &str| {
+    ///     doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, key, 1).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// };
+    ///
+    /// put(&mut doc, "a");
+    /// let mut full = Vec::new();
+    /// let heads = doc.backup_since(&[], &mut full).unwrap();
+    ///
+    /// put(&mut doc, "b");
+    /// let mut increment = Vec::new();
+    /// let heads = doc.backup_since(&heads, &mut increment).unwrap();
+    /// assert_eq!(heads, doc.document().get_heads());
+    ///
+    /// let mut restored = MemoryPersister::default();
+    /// import_archive(&mut restored, full.as_slice()).unwrap();
+    /// import_archive(&mut restored, increment.as_slice()).unwrap();
+    /// let restored = PersistentAutomerge::load(restored).unwrap();
+    /// assert_eq!(restored.document().get_heads(), heads);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the previous heads are not in the document or the backup could not be
+    /// written.
+    pub fn backup_since<W>(
+        &self,
+        previous_heads: &[ChangeHash],
+        mut writer: W,
+    ) -> Result<Vec<ChangeHash>, ArchiveError<P::Error>>
+    where
+        W: Write,
+    {
+        let changes = self.document.get_changes(previous_heads)?;
+        let heads = self.document.get_heads();
+        write_entry(&mut writer, FORMAT_ENTRY, FORMAT)?;
+        write_entry(
+            &mut writer,
+            "since",
+            &metadata::encode_hashes(previous_heads),
+        )?;
+        write_entry(&mut writer, "heads", &metadata::encode_hashes(&heads))?;
+        for (index, change) in changes.iter().enumerate() {
+            write_entry(&mut writer, &format!("changes/{index}"), change.raw_bytes())?;
+        }
+        writer.write_all(&[0; 2 * BLOCK])?;
+        writer.flush()?;
+        Ok(heads)
+    }
+}
+
+/// Derives the [`Cipher`] for an encrypted archive from a passphrase, see
+/// [`export_encrypted_archive`].
+///
+/// Passphrases are weak keys so this should be a memory-hard function such as argon2id, giving
+/// the key of an authenticated cipher such as XChaCha20-Poly1305, as `Argon2id` does with the
+/// `passphrase` feature. The salt is stored in the clear at the start of the archive.
+pub trait KeyDerivation {
+    /// The cipher the key is for.
+    type Cipher: Cipher<Error = Self::Error>;
+    /// The error type that deriving and the cipher can produce.
+    type Error: Error + 'static;
+
+    /// Generate a new random salt for an archive being exported, at most 255 bytes.
+    fn new_salt(&self) -> Result<Vec<u8>, Self::Error>;
+
+    /// Derive the cipher for the passphrase and salt.
+    fn derive(&self, passphrase: &[u8], salt: &[u8]) -> Result<Self::Cipher, Self::Error>;
+}
+
+/// Errors from exporting or importing an encrypted archive.
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptedArchiveError<P, C>
+where
+    P: Error + 'static,
+    C: Error + 'static,
+{
+    /// Writing or reading the archive inside failed.
+    #[error(transparent)]
+    ArchiveError(ArchiveError<P>),
+    /// Deriving the key, encrypting or decrypting failed, such as for a wrong passphrase.
+    #[error(transparent)]
+    CipherError(C),
+    /// The data is not an encrypted archive.
+    #[error("not an encrypted archive")]
+    NotEncrypted,
+    /// The salt was longer than 255 bytes.
+    #[error("salt of {0} bytes is too long")]
+    SaltTooLong(usize),
+}
+
+/// Write an archive like [`export_archive`], encrypted with a key derived from the passphrase, to
+/// back up documents to places that aren't trusted such as email or cloud drives.
+///
+/// `Argon2id`, with the `passphrase` feature, derives the key with argon2id for
+/// XChaCha20-Poly1305 so the archive can't be read or changed without the passphrase, see it for
+/// an example.
+///
+/// # Errors
+///
+/// Returns an error if the persister could not be read, the key could not be derived or the
+/// archive could not be encrypted or written.
+pub fn export_encrypted_archive<P, K, W>(
+    persister: &P,
+    kdf: &K,
+    passphrase: &[u8],
+    mut writer: W,
+) -> Result<(), EncryptedArchiveError<P::Error, K::Error>>
+where
+    P: Persister + ?Sized,
+    K: KeyDerivation,
+    W: Write,
+{
+    let salt = kdf.new_salt().map_err(EncryptedArchiveError::CipherError)?;
+    let salt_len =
+        u8::try_from(salt.len()).map_err(|_| EncryptedArchiveError::SaltTooLong(salt.len()))?;
+    let cipher = kdf
+        .derive(passphrase, &salt)
+        .map_err(EncryptedArchiveError::CipherError)?;
+
+    let mut archive = Vec::new();
+    export_archive(persister, &mut archive).map_err(EncryptedArchiveError::ArchiveError)?;
+    let ciphertext = cipher
+        .encrypt(&archive)
+        .map_err(EncryptedArchiveError::CipherError)?;
+
+    let write = |writer: &mut W| {
+        writer.write_all(ENCRYPTED_MAGIC)?;
+        writer.write_all(&[salt_len])?;
+        writer.write_all(&salt)?;
+        writer.write_all(&ciphertext)?;
+        writer.flush()
+    };
+    write(&mut writer).map_err(|e| EncryptedArchiveError::ArchiveError(ArchiveError::Io(e)))
+}
+
+/// Store the contents of an archive written by [`export_encrypted_archive`] into the persister,
+/// as with [`import_archive`].
+///
+/// # Errors
+///
+/// Returns an error if the archive could not be read or decrypted, such as with the wrong
+/// passphrase, is malformed, or the persister fails.
+pub fn import_encrypted_archive<P, K, R>(
+    persister: &mut P,
+    kdf: &K,
+    passphrase: &[u8],
+    mut reader: R,
+) -> Result<(), EncryptedArchiveError<P::Error, K::Error>>
+where
+    P: Persister + ?Sized,
+    K: KeyDerivation,
+    R: Read,
+{
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .map_err(|e| EncryptedArchiveError::ArchiveError(ArchiveError::Io(e)))?;
+    let (salt_len, rest) = bytes
+        .strip_prefix(ENCRYPTED_MAGIC)
+        .and_then(|rest| rest.split_first())
+        .ok_or(EncryptedArchiveError::NotEncrypted)?;
+    let (salt, ciphertext) = rest
+        .split_at_checked(usize::from(*salt_len))
+        .ok_or(EncryptedArchiveError::NotEncrypted)?;
+
+    let cipher = kdf
+        .derive(passphrase, salt)
+        .map_err(EncryptedArchiveError::CipherError)?;
+    let archive = cipher
+        .decrypt(ciphertext)
+        .map_err(EncryptedArchiveError::CipherError)?;
+    import_archive(persister, archive.as_slice()).map_err(EncryptedArchiveError::ArchiveError)
+}
+
+/// A key and value, such as of a sync state or metadata.
+type Record = (Vec<u8>, Vec<u8>);
+
+/// The parts of the records read so far, by their index in the archive.
+type Records = BTreeMap<String, (Option<Vec<u8>>, Option<Vec<u8>>)>;
+
+fn record_part<E>(
+    records: &mut Records,
+    name: &str,
+    index: &str,
+    part: &str,
+    data: Vec<u8>,
+) -> Result<(), ArchiveError<E>>
+where
+    E: Error + 'static,
+{
+    let record = records.entry(index.to_owned()).or_default();
+    match part {
+        "key" => record.0 = Some(data),
+        "value" => record.1 = Some(data),
+        _ => return Err(ArchiveError::Malformed(format!("unexpected entry {name}"))),
+    }
+    Ok(())
+}
+
+fn complete_records<E>(records: Records) -> Result<Vec<Record>, ArchiveError<E>>
+where
+    E: Error + 'static,
+{
+    records
+        .into_iter()
+        .map(|(index, record)| match record {
+            (Some(key), Some(value)) => Ok((key, value)),
+            _ => Err(ArchiveError::Malformed(format!(
+                "record {index} is missing its key or value"
+            ))),
+        })
+        .collect()
+}
+
+/// Write a regular file in the ustar format.
+fn write_entry<W: Write>(writer: &mut W, name: &str, data: &[u8]) -> io::Result<()> {
+    let size = format!("{:011o}\0", data.len());
+    if size.len() != 12 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("entry {name} is too large for an archive"),
+        ));
+    }
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(size.as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // the checksum is calculated with its own field as spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum = header.iter().map(|b| u32::from(*b)).sum::<u32>();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+    writer.write_all(&header)?;
+    writer.write_all(data)?;
+    writer.write_all(&[0; BLOCK][..padding(data.len())])
+}
+
+/// Read the next regular file, skipping other kinds of entry, or `None` at the end of the
+/// archive.
+fn read_entry<R, E>(reader: &mut R) -> Result<Option<(String, Vec<u8>)>, ArchiveError<E>>
+where
+    R: Read,
+    E: Error + 'static,
+{
+    loop {
+        let mut header = [0; BLOCK];
+        reader.read_exact(&mut header)?;
+        if header.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+
+        let expected = parse_octal(&header[148..156])?;
+        let checksum = header[..148]
+            .iter()
+            .chain(&[b' '; 8])
+            .chain(&header[156..])
+            .map(|b| u64::from(*b))
+            .sum::<u64>();
+        if checksum != expected {
+            return Err(ArchiveError::Malformed("bad header checksum".to_owned()));
+        }
+
+        let name_len = header[..100].iter().position(|b| *b == 0).unwrap_or(100);
+        let name = String::from_utf8(header[..name_len].to_vec())
+            .map_err(|_| ArchiveError::Malformed("entry name is not utf-8".to_owned()))?;
+        let size = usize::try_from(parse_octal(&header[124..136])?)
+            .map_err(|_| ArchiveError::Malformed(format!("entry {name} is too large")))?;
+        let mut data = vec![0; size];
+        reader.read_exact(&mut data)?;
+        reader.read_exact(&mut [0; BLOCK][..padding(size)])?;
+
+        // directories and extended headers added by other tools carry nothing needed
+        if header[156] == b'0' || header[156] == 0 {
+            return Ok(Some((name, data)));
+        }
+    }
+}
+
+fn parse_octal<E>(field: &[u8]) -> Result<u64, ArchiveError<E>>
+where
+    E: Error + 'static,
+{
+    let digits = field
+        .iter()
+        .copied()
+        .skip_while(|b| *b == b' ')
+        .take_while(|b| (b'0'..=b'7').contains(b))
+        .collect::<Vec<_>>();
+    if digits.is_empty() {
+        return Err(ArchiveError::Malformed("bad number in header".to_owned()));
+    }
+    Ok(digits
+        .iter()
+        .fold(0, |n, digit| n * 8 + u64::from(digit - b'0')))
+}
+
+/// The bytes needed after data of the length to fill its last block.
+const fn padding(len: usize) -> usize {
+    (BLOCK - len % BLOCK) % BLOCK
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
-use crate::{Error, PeerId, Persister};
-use automerge::{sync, ApplyOptions, AutoCommit, Change, ChangeHash, OpObserver};
+use crate::{metadata::ACTOR_ID_KEY, persister, Error, PeerId, Persister};
+use automerge::{sync, ActorId, ApplyOptions, AutoCommit, Change, ChangeHash, OpObserver};
 
 /// A wrapper for a persister and an automerge document.
 #[derive(Debug)]
@@ -10,18 +10,20 @@ pub struct PersistentAutoCommit<P> {
     sync_states: HashMap<PeerId, sync::State>,
     persister: P,
     saved_heads: Vec<ChangeHash>,
+    /// Whether the actor id is stored, which it is once there is a local change made by it.
+    actor_persisted: bool,
 }
 
 impl<P> PersistentAutoCommit<P>
 where
     P: Persister + 'static,
 {
-    pub fn document(&self) -> &AutoCommit {
+    pub const fn document(&self) -> &AutoCommit {
         &self.document
     }
 
     /// UNSAFE: this may lead to changes not being immediately persisted
-    pub fn document_mut(&mut self) -> &mut AutoCommit {
+    pub const fn document_mut(&mut self) -> &mut AutoCommit {
         &mut self.document
     }
 
@@ -39,11 +41,15 @@ where
     /// Load the persisted changes (both individual changes and a document) from storage and
     /// rebuild the Backend.
     ///
+    /// The actor id is restored from the persister. Otherwise a new one is generated and persisted
+    /// along with the first local change, so that it is reused next time; loading without making
+    /// changes leaves nothing stored.
+    ///
     /// ```rust
     /// # use automerge_persistent::MemoryPersister;
-    /// # use automerge_persistent::PersistentBackend;
+    /// # use automerge_persistent::PersistentAutoCommit;
     /// let persister = MemoryPersister::default();
-    /// let backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+    /// let doc = PersistentAutoCommit::load(persister).unwrap();
     /// ```
     pub fn load(persister: P) -> Result<Self, Error<P::Error>> {
         let document = persister.get_document().map_err(Error::PersisterError)?;
@@ -66,12 +72,21 @@ where
             .apply_changes(changes)
             .map_err(Error::AutomergeError)?;
 
+        let actor_id = persister
+            .get_metadata(ACTOR_ID_KEY)
+            .map_err(Error::PersisterError)?;
+        let actor_persisted = actor_id.is_some();
+        if let Some(actor_id) = actor_id {
+            backend.set_actor(ActorId::from(actor_id));
+        }
+
         let saved_heads = backend.get_heads();
         Ok(Self {
             document: backend,
             sync_states: HashMap::new(),
             persister,
             saved_heads,
+            actor_persisted,
         })
     }
 
@@ -85,10 +100,10 @@ where
     ///
     /// ```rust
     /// # use automerge_persistent::MemoryPersister;
-    /// # use automerge_persistent::PersistentBackend;
+    /// # use automerge_persistent::PersistentAutoCommit;
     /// # let persister = MemoryPersister::default();
-    /// # let mut backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
-    /// backend.compact(&[]).unwrap();
+    /// # let mut doc = PersistentAutoCommit::load(persister).unwrap();
+    /// doc.compact(&[]).unwrap();
     /// ```
     pub fn compact(&mut self, old_peer_ids: &[&[u8]]) -> Result<(), Error<P::Error>> {
         let saved_backend = self.document.save();
@@ -97,9 +112,7 @@ where
         self.persister
             .set_document(saved_backend)
             .map_err(Error::PersisterError)?;
-        self.persister
-            .remove_changes(changes.into_iter().map(|c| (c.actor_id(), c.seq)).collect())
-            .map_err(Error::PersisterError)?;
+        persister::remove_changes(&mut self.persister, changes).map_err(Error::PersisterError)?;
         self.persister
             .remove_sync_states(old_peer_ids)
             .map_err(Error::PersisterError)?;
@@ -116,10 +129,10 @@ where
     ///
     /// ```rust
     /// # use automerge_persistent::MemoryPersister;
-    /// # use automerge_persistent::PersistentBackend;
+    /// # use automerge_persistent::PersistentAutoCommit;
     /// # let persister = MemoryPersister::default();
-    /// # let mut backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
-    /// let message = backend.generate_sync_message(vec![]).unwrap();
+    /// # let mut doc = PersistentAutoCommit::load(persister).unwrap();
+    /// let message = doc.generate_sync_message(vec![]).unwrap();
     /// ```
     pub fn generate_sync_message(
         &mut self,
@@ -190,24 +203,16 @@ where
         let sync_state = self.sync_states.entry(peer_id.clone()).or_default();
 
         let heads = self.document.get_heads();
-        let patch = self
-            .document
+        self.document
             .receive_sync_message_with(sync_state, message, options)
             .map_err(Error::AutomergeError)?;
         let changes = self.document.get_changes(&heads)?;
-        self.persister
-            .insert_changes(
-                changes
-                    .into_iter()
-                    .map(|c| (c.actor_id().clone(), c.seq, c.raw_bytes().to_vec()))
-                    .collect(),
-            )
-            .map_err(Error::PersisterError)?;
+        persister::insert_changes(&mut self.persister, changes).map_err(Error::PersisterError)?;
 
         self.persister
             .set_sync_state(peer_id, sync_state.encode())
             .map_err(Error::PersisterError)?;
-        Ok(patch)
+        Ok(())
     }
 
     /// Flush any data out to storage returning the number of bytes flushed.
@@ -223,14 +228,17 @@ where
 
     /// Close any current transaction and write out the changes to disk.
     pub fn close_transaction(&mut self) -> Result<(), Error<P::Error>> {
+        let actor = self.document.get_actor().clone();
         for change in self.document.get_changes(&self.saved_heads)? {
-            self.persister
-                .insert_changes(vec![(
-                    change.actor_id().clone(),
-                    change.seq,
-                    change.raw_bytes().to_vec(),
-                )])
-                .map_err(Error::PersisterError)?
+            // a document that is only read keeps no actor, so it isn't written on loading
+            if !self.actor_persisted && change.actor_id() == &actor {
+                self.persister
+                    .set_metadata(ACTOR_ID_KEY.to_vec(), actor.to_bytes().to_vec())
+                    .map_err(Error::PersisterError)?;
+                self.actor_persisted = true;
+            }
+            persister::insert_changes(&mut self.persister, Some(change))
+                .map_err(Error::PersisterError)?;
         }
         self.saved_heads = self.document.get_heads();
         Ok(())
@@ -248,8 +256,30 @@ where
         Ok(self.persister)
     }
 
+    /// The actor id used for local changes.
+    pub fn actor_id(&self) -> &ActorId {
+        self.document.get_actor()
+    }
+
+    /// Set the actor id used for local changes, persisting it so that it is used on future loads.
+    ///
+    /// Any pending changes are closed off and persisted under the previous actor first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pending changes or the actor id could not be persisted.
+    pub fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Error<P::Error>> {
+        self.close_transaction()?;
+        self.persister
+            .set_metadata(ACTOR_ID_KEY.to_vec(), actor_id.to_bytes().to_vec())
+            .map_err(Error::PersisterError)?;
+        self.document.set_actor(actor_id);
+        self.actor_persisted = true;
+        Ok(())
+    }
+
     /// Obtain a reference to the persister.
-    pub fn persister(&self) -> &P {
+    pub const fn persister(&self) -> &P {
         &self.persister
     }
 
@@ -0,0 +1,279 @@
+use automerge::{
+    sync, ActorId, ApplyOptions, Automerge, AutomergeError, Change, ChangeHash, Patch,
+    VecOpObserver,
+};
+
+/// The operations on a document that persistence needs.
+///
+/// [`crate::PersistentAutomerge`] is generic over this so that the persistence logic is not tied
+/// to [`Automerge`], other document types such as wrappers around it can be persisted by
+/// implementing this trait for them. Applying changes, syncing, compacting and reading the history
+/// work with any backend, loaded with [`crate::PersistentAutomerge::load_backend`].
+///
+/// Transactions, and the `_with` variants taking automerge's own commit or apply options, are
+/// only available with [`Automerge`] documents, as are [`crate::PersistentAutomerge::load`] and
+/// [`crate::PersistentAutomerge::load_with`] so that the document type is inferred for them.
+pub trait Backend: Default {
+    /// Load a document from its saved form.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data could not be decoded.
+    fn load(data: &[u8]) -> Result<Self, AutomergeError>;
+
+    /// Save the whole document.
+    fn save(&mut self) -> Vec<u8>;
+
+    /// Apply the changes, queueing those with missing dependencies.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the changes could not be applied.
+    fn apply_changes(&mut self, changes: Vec<Change>) -> Result<(), AutomergeError>;
+
+    /// Apply the changes like [`Self::apply_changes`], returning the patches they produce.
+    ///
+    /// By default no patches are produced.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the changes could not be applied.
+    fn apply_changes_with_patches(
+        &mut self,
+        changes: Vec<Change>,
+    ) -> Result<Vec<Patch>, AutomergeError> {
+        self.apply_changes(changes).map(|()| Vec::new())
+    }
+
+    /// The applied changes that are not ancestors of the given heads.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the heads are not in the document.
+    fn get_changes(&self, have_deps: &[ChangeHash]) -> Result<Vec<&Change>, AutomergeError>;
+
+    /// The applied change with the given hash.
+    fn get_change_by_hash(&self, hash: &ChangeHash) -> Option<&Change>;
+
+    /// The current heads of the document.
+    fn get_heads(&self) -> Vec<ChangeHash>;
+
+    /// The dependencies of queued changes, and of the given heads, that are not in the document.
+    fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash>;
+
+    /// Generate a sync message for the peer with the given state, if there is anything to send.
+    fn generate_sync_message(&self, sync_state: &mut sync::State) -> Option<sync::Message>;
+
+    /// Receive a sync message from the peer with the given state, applying the changes in it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the changes in the message could not be applied.
+    fn receive_sync_message(
+        &mut self,
+        sync_state: &mut sync::State,
+        message: sync::Message,
+    ) -> Result<(), AutomergeError>;
+
+    /// Receive a sync message like [`Self::receive_sync_message`], returning the patches the
+    /// changes in it produce.
+    ///
+    /// By default no patches are produced.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the changes in the message could not be applied.
+    fn receive_sync_message_with_patches(
+        &mut self,
+        sync_state: &mut sync::State,
+        message: sync::Message,
+    ) -> Result<Vec<Patch>, AutomergeError> {
+        self.receive_sync_message(sync_state, message)
+            .map(|()| Vec::new())
+    }
+
+    /// The actor used for local changes.
+    fn get_actor(&self) -> &ActorId;
+
+    /// Set the actor used for local changes.
+    fn set_actor(&mut self, actor: ActorId);
+}
+
+impl Backend for Automerge {
+    fn load(data: &[u8]) -> Result<Self, AutomergeError> {
+        Self::load(data)
+    }
+
+    fn save(&mut self) -> Vec<u8> {
+        self.save()
+    }
+
+    fn apply_changes(&mut self, changes: Vec<Change>) -> Result<(), AutomergeError> {
+        self.apply_changes(changes)
+    }
+
+    fn apply_changes_with_patches(
+        &mut self,
+        changes: Vec<Change>,
+    ) -> Result<Vec<Patch>, AutomergeError> {
+        let mut observer = VecOpObserver::default();
+        self.apply_changes_with(
+            changes,
+            ApplyOptions::default().with_op_observer(&mut observer),
+        )?;
+        Ok(observer.take_patches())
+    }
+
+    fn get_changes(&self, have_deps: &[ChangeHash]) -> Result<Vec<&Change>, AutomergeError> {
+        self.get_changes(have_deps)
+    }
+
+    fn get_change_by_hash(&self, hash: &ChangeHash) -> Option<&Change> {
+        self.get_change_by_hash(hash)
+    }
+
+    fn get_heads(&self) -> Vec<ChangeHash> {
+        self.get_heads()
+    }
+
+    fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
+        self.get_missing_deps(heads)
+    }
+
+    fn generate_sync_message(&self, sync_state: &mut sync::State) -> Option<sync::Message> {
+        self.generate_sync_message(sync_state)
+    }
+
+    fn receive_sync_message(
+        &mut self,
+        sync_state: &mut sync::State,
+        message: sync::Message,
+    ) -> Result<(), AutomergeError> {
+        self.receive_sync_message(sync_state, message)
+    }
+
+    fn receive_sync_message_with_patches(
+        &mut self,
+        sync_state: &mut sync::State,
+        message: sync::Message,
+    ) -> Result<Vec<Patch>, AutomergeError> {
+        let mut observer = VecOpObserver::default();
+        self.receive_sync_message_with(
+            sync_state,
+            message,
+            ApplyOptions::default().with_op_observer(&mut observer),
+        )?;
+        Ok(observer.take_patches())
+    }
+
+    fn get_actor(&self) -> &ActorId {
+        self.get_actor()
+    }
+
+    fn set_actor(&mut self, actor: ActorId) {
+        self.set_actor(actor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{
+        sync, transaction::Transactable, ActorId, Automerge, AutomergeError, Change, ChangeHash,
+        ROOT,
+    };
+
+    use super::Backend;
+    use crate::{LoadOptions, MemoryPersister, PersistentAutomerge, RemoveBefore};
+
+    /// A backend other than [`Automerge`], passing everything through to one.
+    #[derive(Debug, Default)]
+    struct Wrapped(Automerge);
+
+    impl Backend for Wrapped {
+        fn load(data: &[u8]) -> Result<Self, AutomergeError> {
+            Automerge::load(data).map(Self)
+        }
+
+        fn save(&mut self) -> Vec<u8> {
+            self.0.save()
+        }
+
+        fn apply_changes(&mut self, changes: Vec<Change>) -> Result<(), AutomergeError> {
+            self.0.apply_changes(changes)
+        }
+
+        fn get_changes(&self, have_deps: &[ChangeHash]) -> Result<Vec<&Change>, AutomergeError> {
+            self.0.get_changes(have_deps)
+        }
+
+        fn get_change_by_hash(&self, hash: &ChangeHash) -> Option<&Change> {
+            self.0.get_change_by_hash(hash)
+        }
+
+        fn get_heads(&self) -> Vec<ChangeHash> {
+            self.0.get_heads()
+        }
+
+        fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
+            self.0.get_missing_deps(heads)
+        }
+
+        fn generate_sync_message(&self, sync_state: &mut sync::State) -> Option<sync::Message> {
+            self.0.generate_sync_message(sync_state)
+        }
+
+        fn receive_sync_message(
+            &mut self,
+            sync_state: &mut sync::State,
+            message: sync::Message,
+        ) -> Result<(), AutomergeError> {
+            self.0.receive_sync_message(sync_state, message)
+        }
+
+        fn get_actor(&self) -> &ActorId {
+            self.0.get_actor()
+        }
+
+        fn set_actor(&mut self, actor: ActorId) {
+            self.0.set_actor(actor);
+        }
+    }
+
+    #[test]
+    fn other_backends_sync_and_remove_history() {
+        let mut source = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+        source
+            .transact::<_, _, std::convert::Infallible>(|tx| {
+                tx.put(ROOT, "a", 1).unwrap();
+                Ok(())
+            })
+            .unwrap();
+
+        let mut doc = PersistentAutomerge::<_, Wrapped>::load_backend(
+            MemoryPersister::default(),
+            LoadOptions::default(),
+        )
+        .unwrap();
+        let (source_peer, doc_peer) = (b"source".to_vec(), b"doc".to_vec());
+        for _ in 0..4 {
+            if let Some(message) = source.generate_sync_message(doc_peer.clone()).unwrap() {
+                doc.receive_sync_message(source_peer.clone(), message)
+                    .unwrap();
+            }
+            if let Some(message) = doc.generate_sync_message(source_peer.clone()).unwrap() {
+                source
+                    .receive_sync_message(doc_peer.clone(), message)
+                    .unwrap();
+            }
+        }
+        assert_eq!(doc.document().get_heads(), source.document().get_heads());
+        assert_eq!(doc.history().count(), 1);
+
+        let heads = doc.document().get_heads();
+        assert_eq!(
+            doc.remove_changes_before(RemoveBefore::Heads(heads))
+                .unwrap(),
+            1
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Standard workloads for comparing persisters, enabled with the `bench` feature.
+//!
+//! Each [`Workload`] is run against fresh storage from a function given by the caller, so the
+//! same numbers can be gathered for any backends for documents shaped like the ones they'll hold.
+//!
+//! ```rust
+//! # use automerge_persistent::{bench, MemoryPersister};
+//! let results = bench::run_all(|| Ok(MemoryPersister::default()), 3).unwrap();
+//! for result in &results {
+//!     println!("{result}");
+//! }
+//!
+//! let result = bench::run(
+//!     || Ok(MemoryPersister::default()),
+//!     bench::Workload::SmallChanges { count: 10 },
+//!     3,
+//! )
+//! .unwrap();
+//! assert_eq!(result.samples.len(), 3);
+//! assert!(result.sizes.changes > 0);
+//! ```
+
+use std::{
+    convert::TryFrom,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use automerge::{transaction::Transactable, ROOT};
+
+use crate::{Error, PersistentAutomerge, Persister, StoredSizes, TransactionError};
+
+/// A workload to time against a persister.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// Make `count` small local changes, one after another, then flush.
+    SmallChanges {
+        /// The number of changes to make.
+        count: usize,
+    },
+    /// Save a document with `keys` keys each holding a string of `value_len` bytes, by compacting
+    /// it.
+    LargeDocument {
+        /// The number of keys in the document.
+        keys: usize,
+        /// The length of each value.
+        value_len: usize,
+    },
+    /// Load a document made of `changes` small changes, optionally compacted beforehand.
+    Load {
+        /// The number of changes in the document.
+        changes: usize,
+        /// Whether the document is compacted before being loaded.
+        compacted: bool,
+    },
+}
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SmallChanges { count } => write!(f, "{count} small changes"),
+            Self::LargeDocument { keys, value_len } => {
+                write!(f, "save document of {keys} keys of {value_len} bytes")
+            }
+            Self::Load { changes, compacted } => write!(
+                f,
+                "load {changes} changes{}",
+                if *compacted { " compacted" } else { "" }
+            ),
+        }
+    }
+}
+
+/// The timings from running a [`Workload`].
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    /// The workload that was run.
+    pub workload: Workload,
+    /// How long each run took, excluding setting up the storage.
+    pub samples: Vec<Duration>,
+    /// What the persister reported storing at the end of the last run.
+    pub sizes: StoredSizes,
+}
+
+impl BenchResult {
+    /// The mean time of a run.
+    pub fn mean(&self) -> Duration {
+        let runs = u32::try_from(self.samples.len()).unwrap_or(u32::MAX).max(1);
+        self.samples.iter().sum::<Duration>() / runs
+    }
+
+    /// The fastest run.
+    pub fn min(&self) -> Duration {
+        self.samples.iter().min().copied().unwrap_or_default()
+    }
+
+    /// The slowest run.
+    pub fn max(&self) -> Duration {
+        self.samples.iter().max().copied().unwrap_or_default()
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: mean {:?}, min {:?}, max {:?} over {} runs, {} bytes stored",
+            self.workload,
+            self.mean(),
+            self.min(),
+            self.max(),
+            self.samples.len(),
+            self.sizes.changes + self.sizes.document + self.sizes.sync_states + self.sizes.metadata
+        )
+    }
+}
+
+/// The workloads run by [`run_all`].
+pub fn standard_workloads() -> Vec<Workload> {
+    vec![
+        Workload::SmallChanges { count: 100 },
+        Workload::LargeDocument {
+            keys: 1000,
+            value_len: 100,
+        },
+        Workload::Load {
+            changes: 1000,
+            compacted: false,
+        },
+        Workload::Load {
+            changes: 1000,
+            compacted: true,
+        },
+    ]
+}
+
+/// Run the workload `iterations` times, each against a new persister from `new_persister`.
+///
+/// The persister should start out empty, such as a temporary directory or a new table.
+///
+/// # Errors
+///
+/// Returns an error if creating the persister or running the workload fails.
+pub fn run<P, F>(
+    mut new_persister: F,
+    workload: Workload,
+    iterations: usize,
+) -> Result<BenchResult, Error<P::Error>>
+where
+    P: Persister + 'static,
+    F: FnMut() -> Result<P, P::Error>,
+{
+    let mut samples = Vec::with_capacity(iterations);
+    let mut sizes = StoredSizes::default();
+    for _ in 0..iterations {
+        let persister = new_persister().map_err(Error::PersisterError)?;
+        let (elapsed, persister) = run_once(persister, workload)?;
+        sizes = persister.sizes();
+        samples.push(elapsed);
+    }
+    Ok(BenchResult {
+        workload,
+        samples,
+        sizes,
+    })
+}
+
+/// Run each of the [`standard_workloads`] with [`run`].
+///
+/// # Errors
+///
+/// Returns an error if creating a persister or running a workload fails.
+pub fn run_all<P, F>(
+    mut new_persister: F,
+    iterations: usize,
+) -> Result<Vec<BenchResult>, Error<P::Error>>
+where
+    P: Persister + 'static,
+    F: FnMut() -> Result<P, P::Error>,
+{
+    standard_workloads()
+        .into_iter()
+        .map(|workload| run(&mut new_persister, workload, iterations))
+        .collect()
+}
+
+fn run_once<P>(persister: P, workload: Workload) -> Result<(Duration, P), Error<P::Error>>
+where
+    P: Persister + 'static,
+{
+    let mut doc = PersistentAutomerge::load(persister)?;
+    let elapsed = match workload {
+        Workload::SmallChanges { count } => {
+            let start = Instant::now();
+            small_changes(&mut doc, count)?;
+            doc.flush().map_err(Error::PersisterError)?;
+            start.elapsed()
+        }
+        Workload::LargeDocument { keys, value_len } => {
+            let value = "a".repeat(value_len);
+            put(&mut doc, |tx| {
+                for i in 0..keys {
+                    tx.put(ROOT, format!("key{i}"), value.as_str())?;
+                }
+                Ok(())
+            })?;
+            let start = Instant::now();
+            doc.compact(&[])?;
+            doc.flush().map_err(Error::PersisterError)?;
+            start.elapsed()
+        }
+        Workload::Load { changes, compacted } => {
+            small_changes(&mut doc, changes)?;
+            if compacted {
+                doc.compact(&[])?;
+            }
+            let persister = doc.close().map_err(Error::PersisterError)?;
+            let start = Instant::now();
+            doc = PersistentAutomerge::load(persister)?;
+            start.elapsed()
+        }
+    };
+    Ok((elapsed, doc.close().map_err(Error::PersisterError)?))
+}
+
+fn small_changes<P>(doc: &mut PersistentAutomerge<P>, count: usize) -> Result<(), Error<P::Error>>
+where
+    P: Persister + 'static,
+{
+    for i in 0..count {
+        put(doc, |tx| {
+            tx.put(ROOT, "counter", i64::try_from(i).unwrap_or(i64::MAX))?;
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+fn put<P, F>(doc: &mut PersistentAutomerge<P>, f: F) -> Result<(), Error<P::Error>>
+where
+    P: Persister + 'static,
+    F: FnOnce(&mut automerge::transaction::Transaction) -> Result<(), automerge::AutomergeError>,
+{
+    doc.transact(f).map_err(|e| match e {
+        TransactionError::PersisterError(e) => Error::PersisterError(e),
+        TransactionError::TransactionError(failure) => Error::AutomergeError(failure.error),
+    })?;
+    Ok(())
+}
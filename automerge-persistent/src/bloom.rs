@@ -0,0 +1,94 @@
+use std::collections::HashSet;
+
+use automerge::{sync::BloomFilter, Change};
+
+use crate::{Backend, Error, PersistentAutomerge, Persister};
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Summarise the hashes of the changes held locally, including pending ones, in a bloom
+    /// filter of around 10 bits per change.
+    ///
+    /// This is for negotiating what to send over links where exchanging the full lists of heads
+    /// and hashes costs too much, such as BLE or LoRa. The filter is sent with
+    /// [`BloomFilter::to_bytes`] and the peer answers with [`Self::changes_not_in`].
+    ///
+    /// ```rust
+    /// # use std::convert::TryFrom;
+    /// # use automerge::{sync::BloomFilter, transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut alice = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// alice
+    ///     .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "a", 1).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// let mut bob = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// bob.apply_changes(alice.document().get_changes(&[]).unwrap().into_iter().cloned())
+    ///     .unwrap();
+    /// alice
+    ///     .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "b", 2).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    ///
+    /// // bob sends his summary and alice replies with just what he doesn't have
+    /// let bytes = bob.change_filter().unwrap().to_bytes();
+    /// let filter = BloomFilter::try_from(bytes.as_slice()).unwrap();
+    /// let missing = alice
+    ///     .changes_not_in(&filter)
+    ///     .unwrap()
+    ///     .into_iter()
+    ///     .cloned()
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(missing.len(), 1);
+    ///
+    /// bob.apply_changes(missing).unwrap();
+    /// assert_eq!(bob.document().get_heads(), alice.document().get_heads());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the changes could not be read from the document.
+    pub fn change_filter(&self) -> Result<BloomFilter, Error<P::Error>> {
+        let stored = self.document.get_changes(&[])?;
+        let hashes = stored
+            .iter()
+            .map(|c| &c.hash)
+            .chain(&self.pending_hashes)
+            .collect::<Vec<_>>();
+        // chained iterators don't know their exact length, which the filter sizes itself by
+        Ok(BloomFilter::from_hashes(hashes.iter().copied()))
+    }
+
+    /// The changes of the document that aren't in a peer's [`Self::change_filter`], in causal
+    /// order.
+    ///
+    /// As with the automerge sync protocol, a false positive in the filter could otherwise hide a
+    /// change the peer needs, so the changes that depend on any change being sent are sent too.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the changes could not be read from the document.
+    pub fn changes_not_in(&self, filter: &BloomFilter) -> Result<Vec<&Change>, Error<P::Error>> {
+        let mut sending = HashSet::new();
+        Ok(self
+            .document
+            .get_changes(&[])?
+            .into_iter()
+            .filter(|change| {
+                let send = !filter.contains_hash(&change.hash)
+                    || change.deps.iter().any(|dep| sending.contains(dep));
+                if send {
+                    sending.insert(change.hash);
+                }
+                send
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,430 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, MutexGuard},
+};
+
+use automerge::{ActorId, Change, ChangeHash};
+
+use crate::{
+    forward_persister, DocumentVersion, Forward, MappedDocument, Persister, VersionConflict,
+    VersionedDocument,
+};
+
+#[derive(Debug, Default)]
+struct Cache {
+    /// The document, if it has been fetched, along with whether one was stored.
+    document: Option<Option<Vec<u8>>>,
+    /// The version of the cached document, if it is known.
+    version: Option<Option<DocumentVersion>>,
+    /// Change bytes by actor and sequence number, along with when they were last used.
+    changes: HashMap<(ActorId, u64), (Vec<u8>, u64)>,
+    /// The keys of the cached changes by when they were last used, oldest first.
+    recency: BTreeMap<u64, (ActorId, u64)>,
+    tick: u64,
+    changes_size: u64,
+    /// Whether the cached changes are all of those stored, so they can be served without
+    /// fetching.
+    complete: bool,
+}
+
+impl Cache {
+    fn set_document(
+        &mut self,
+        document: Option<Vec<u8>>,
+        version: Option<Option<DocumentVersion>>,
+    ) {
+        self.document = Some(document);
+        self.version = version;
+    }
+
+    fn clear_document(&mut self) {
+        self.document = None;
+        self.version = None;
+    }
+
+    /// Cache the change as the most recently used, evicting the least recently used beyond
+    /// `max_bytes`.
+    fn insert_change(&mut self, key: (ActorId, u64), change: Vec<u8>, max_bytes: u64) {
+        self.tick += 1;
+        self.changes_size += change.len() as u64;
+        if let Some((old, used)) = self.changes.insert(key.clone(), (change, self.tick)) {
+            self.changes_size -= old.len() as u64;
+            self.recency.remove(&used);
+        }
+        self.recency.insert(self.tick, key);
+        while self.changes_size > max_bytes {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((old, _)) = self.changes.remove(&key) {
+                self.changes_size -= old.len() as u64;
+            }
+            self.complete = false;
+        }
+    }
+
+    fn remove_change(&mut self, key: &(ActorId, u64)) {
+        if let Some((old, used)) = self.changes.remove(key) {
+            self.changes_size -= old.len() as u64;
+            self.recency.remove(&used);
+        }
+    }
+
+    fn clear_changes(&mut self) {
+        self.changes.clear();
+        self.recency.clear();
+        self.changes_size = 0;
+        self.complete = false;
+    }
+}
+
+/// A persister that caches the document and changes read from an inner persister.
+///
+/// Once fetched the document, along with its version, and the changes are served from memory, so
+/// repeatedly loading a document, such as when it is evicted and reopened, does not go back to a
+/// slow backend. Writes go straight through to the inner persister and keep the cache up to date.
+///
+/// The changes are kept in a least recently used cache of the configured number of bytes. Loads
+/// are served from it while it holds every stored change, once some have been evicted the next
+/// load fetches them from the inner persister again. Changes need to be decodable to be cached,
+/// so this should be the outermost layer when used with codecs. Sync states and metadata are not
+/// cached.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{CachedPersister, MemoryPersister, PersistentAutomerge};
+/// let persister = CachedPersister::new(MemoryPersister::default(), 1024 * 1024);
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// // the second load is served from the cache
+/// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 1);
+/// ```
+#[derive(Debug)]
+pub struct CachedPersister<P> {
+    inner: P,
+    max_cached_bytes: u64,
+    cache: Mutex<Cache>,
+}
+
+impl<P> CachedPersister<P>
+where
+    P: Persister,
+{
+    /// Wrap the persister, caching up to `max_cached_bytes` of changes.
+    pub fn new(inner: P, max_cached_bytes: u64) -> Self {
+        Self {
+            inner,
+            max_cached_bytes,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    /// Drop everything cached, the next reads will go to the inner persister.
+    pub fn clear(&mut self) {
+        *self.cache_mut() = Cache::default();
+    }
+
+    /// Get a reference to the inner persister.
+    pub const fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Take the inner persister back out.
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().expect("cache lock poisoned")
+    }
+
+    fn cache_mut(&mut self) -> &mut Cache {
+        self.cache.get_mut().expect("cache lock poisoned")
+    }
+}
+
+impl<P> Forward for CachedPersister<P>
+where
+    P: Persister,
+{
+    type Inner = P;
+    type Error = P::Error;
+
+    fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn map_error(error: P::Error) -> Self::Error {
+        error
+    }
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        {
+            let cache = self.cache();
+            if cache.complete {
+                return Ok(cache.changes.values().map(|(c, _)| c.clone()).collect());
+            }
+        }
+        let changes = self.inner.get_changes()?;
+        let mut cache = self.cache();
+        cache.clear_changes();
+        cache.complete = true;
+        for c in &changes {
+            match Change::from_bytes(c.clone()) {
+                Ok(change) => cache.insert_change(
+                    (change.actor_id().clone(), change.seq),
+                    c.clone(),
+                    self.max_cached_bytes,
+                ),
+                Err(_) => cache.complete = false,
+            }
+        }
+        Ok(changes)
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        if let Err(e) = self.inner.insert_changes(changes.clone()) {
+            // some of the changes may have been stored
+            self.cache_mut().clear_changes();
+            return Err(e);
+        }
+        let max_cached_bytes = self.max_cached_bytes;
+        let cache = self.cache_mut();
+        for (a, s, c) in changes {
+            cache.insert_change((a, s), c, max_cached_bytes);
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        if let Err(e) = self.inner.remove_changes(changes.clone()) {
+            self.cache_mut().clear_changes();
+            return Err(e);
+        }
+        let cache = self.cache_mut();
+        for (a, s) in changes {
+            cache.remove_change(&(a.clone(), s));
+        }
+        Ok(())
+    }
+
+    /// The cache is keyed by `actor_id` so is dropped when changes are written by hash.
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        self.cache_mut().clear_changes();
+        self.inner.insert_changes_by_hash(changes)
+    }
+
+    /// The cache is keyed by `actor_id` so is dropped when changes are removed by hash.
+    fn remove_changes_by_hash(&mut self, hashes: &[ChangeHash]) -> Result<(), Self::Error> {
+        self.cache_mut().clear_changes();
+        self.inner.remove_changes_by_hash(hashes)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(document) = &self.cache().document {
+            return Ok(document.clone());
+        }
+        let document = self.inner.get_document()?;
+        self.cache().set_document(document.clone(), None);
+        Ok(document)
+    }
+
+    /// The version of the new document isn't known, so the next versioned read goes to the inner
+    /// persister.
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        // drop the cached copy first so a failed write cannot leave it stale
+        self.cache_mut().clear_document();
+        self.inner.set_document(data.clone())?;
+        self.cache_mut().set_document(Some(data), None);
+        Ok(())
+    }
+
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        {
+            let cache = self.cache();
+            if let (Some(document), Some(version)) = (&cache.document, &cache.version) {
+                return Ok((document.clone(), version.clone()));
+            }
+        }
+        let (document, version) = self.inner.get_document_versioned()?;
+        self.cache()
+            .set_document(document.clone(), Some(version.clone()));
+        Ok((document, version))
+    }
+
+    /// A cached document is served from memory, otherwise it is read mapped from the inner
+    /// persister and copied into the cache.
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        {
+            let cache = self.cache();
+            if let (Some(document), Some(version)) = (&cache.document, &cache.version) {
+                return Ok((
+                    document
+                        .clone()
+                        .map(|d| Box::new(d) as Box<dyn AsRef<[u8]>>),
+                    version.clone(),
+                ));
+            }
+        }
+        let (document, version) = self.inner.get_document_mapped()?;
+        let copy = document.as_ref().map(|d| (**d).as_ref().to_vec());
+        self.cache().set_document(copy, Some(version.clone()));
+        Ok((document, version))
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        // a conflict means another writer has replaced the document, so it stays dropped
+        self.cache_mut().clear_document();
+        let version = self.inner.set_document_if(data.clone(), expected)?;
+        if let Ok(version) = &version {
+            self.cache_mut()
+                .set_document(Some(data), Some(version.clone()));
+        }
+        Ok(version)
+    }
+
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.cache_mut().clear_document();
+        let version = match self
+            .inner
+            .compact_if(data.clone(), expected, changes.clone())
+        {
+            Ok(version) => version,
+            Err(e) => {
+                self.cache_mut().clear_changes();
+                return Err(e);
+            }
+        };
+        if let Ok(version) = &version {
+            let cache = self.cache_mut();
+            cache.set_document(Some(data), Some(version.clone()));
+            for (a, s) in changes {
+                cache.remove_change(&(a.clone(), s));
+            }
+        }
+        Ok(version)
+    }
+}
+
+forward_persister!(impl<P> for CachedPersister<P> where P: Persister);
+
+#[cfg(test)]
+mod tests {
+    use automerge::{transaction::Transactable, Automerge, ROOT};
+    use automerge_persistent_core::test_support::Probe;
+
+    use super::CachedPersister;
+    use crate::Persister;
+
+    fn change(actor: u8) -> (automerge::ActorId, u64, Vec<u8>) {
+        let mut doc = Automerge::new();
+        doc.set_actor(automerge::ActorId::from(vec![actor]));
+        doc.transact::<_, _, std::convert::Infallible>(|tx| {
+            tx.put(ROOT, "a", 1).unwrap();
+            Ok(())
+        })
+        .unwrap();
+        let change = doc.get_last_local_change().unwrap();
+        (
+            change.actor_id().clone(),
+            change.seq,
+            change.raw_bytes().to_vec(),
+        )
+    }
+
+    #[test]
+    fn document_is_served_from_the_cache() {
+        let mut persister = CachedPersister::new(Probe::default(), 1024);
+        let (_, version) = persister.get_document_versioned().unwrap();
+        let version = persister
+            .set_document_if(vec![1, 2, 3], version.as_ref())
+            .unwrap()
+            .unwrap();
+
+        for _ in 0..2 {
+            let (document, cached_version) = persister.get_document_mapped().unwrap();
+            assert_eq!((*document.unwrap()).as_ref(), &[1, 2, 3]);
+            assert_eq!(cached_version, version);
+        }
+        assert_eq!(persister.inner().mapped_reads(), 0);
+    }
+
+    #[test]
+    fn conflicting_set_document_if_drops_the_cached_document() {
+        let mut persister = CachedPersister::new(Probe::default(), 1024);
+        let (_, stale) = persister.get_document_versioned().unwrap();
+        persister
+            .set_document_if(vec![1], stale.as_ref())
+            .unwrap()
+            .unwrap();
+        assert!(persister
+            .set_document_if(vec![2], stale.as_ref())
+            .unwrap()
+            .is_err());
+
+        let (document, _) = persister.get_document_mapped().unwrap();
+        assert_eq!((*document.unwrap()).as_ref(), &[1]);
+        assert_eq!(persister.inner().mapped_reads(), 1);
+    }
+
+    #[test]
+    fn least_recently_used_changes_are_evicted() {
+        let changes = (0..3).map(change).collect::<Vec<_>>();
+        let size = changes[0].2.len() as u64;
+        let mut persister = CachedPersister::new(Probe::default(), size * 2);
+        assert!(persister.get_changes().unwrap().is_empty());
+        persister.insert_changes(changes[..2].to_vec()).unwrap();
+        assert!(persister.cache().complete);
+        assert_eq!(persister.get_changes().unwrap().len(), 2);
+
+        // the first change is evicted, so the next load reads them all again
+        persister.insert_changes(changes[2..].to_vec()).unwrap();
+        let cache = persister.cache_mut();
+        assert!(!cache.complete);
+        assert!(!cache.changes.contains_key(&(changes[0].0.clone(), 1)));
+        assert_eq!(cache.changes_size, size * 2);
+        assert_eq!(persister.get_changes().unwrap().len(), 3);
+
+        persister.remove_changes(vec![(&changes[0].0, 1)]).unwrap();
+        assert_eq!(persister.get_changes().unwrap().len(), 2);
+        assert!(persister.cache().complete);
+    }
+
+    #[test]
+    fn compaction_is_passed_through_whole() {
+        let change = change(0);
+        let mut persister = CachedPersister::new(Probe::default(), 1024);
+        persister.insert_changes(vec![change.clone()]).unwrap();
+        assert_eq!(persister.get_changes().unwrap().len(), 1);
+
+        persister
+            .compact_if(vec![1], None, vec![(&change.0, change.1)])
+            .unwrap()
+            .unwrap();
+        assert_eq!(persister.inner().compactions(), 1);
+        assert!(persister.get_changes().unwrap().is_empty());
+        assert_eq!(persister.get_document().unwrap(), Some(vec![1]));
+    }
+}
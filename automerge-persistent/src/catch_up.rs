@@ -0,0 +1,111 @@
+use std::error::Error as StdError;
+
+use automerge::{AutomergeError, Change};
+
+use crate::{frontier, Backend, Error, PersistentAutomerge, Persister};
+
+/// Errors from [`PersistentAutomerge::sync_from`].
+#[derive(Debug, thiserror::Error)]
+pub enum SyncFromError<S, E>
+where
+    S: StdError + 'static,
+    E: StdError + 'static,
+{
+    /// Reading from the source failed.
+    #[error("reading from the source: {0}")]
+    Source(S),
+    /// Applying or persisting the changes failed.
+    #[error(transparent)]
+    Document(#[from] Error<E>),
+}
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Pull the changes this document doesn't have from another persister, returning how many
+    /// were pulled.
+    ///
+    /// This lets a warm standby keep up with a primary that writes to storage it can read. The
+    /// primary's actor [`frontier`] is compared first so that nothing more is read when this
+    /// document is up to date. Otherwise the stored changes are pulled, and the stored document
+    /// too if they don't bring this one up to the frontier, such as after the primary compacted,
+    /// or the source has no frontier stored. The pulled changes are persisted to this document's own persister as with
+    /// [`Self::apply_changes`].
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut primary = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// let mut standby = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// primary
+    ///     .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "a", 1).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    ///
+    /// assert_eq!(standby.sync_from(primary.persister()).unwrap(), 1);
+    /// assert_eq!(standby.sync_from(primary.persister()).unwrap(), 0);
+    ///
+    /// // changes only in the compacted document are found too
+    /// primary
+    ///     .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "b", 2).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// primary.compact(&[]).unwrap();
+    /// assert_eq!(standby.sync_from(primary.persister()).unwrap(), 1);
+    /// assert_eq!(standby.document().get_heads(), primary.document().get_heads());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source could not be read, its changes could not be decoded, or
+    /// they could not be applied or persisted.
+    pub fn sync_from<S>(&mut self, src: &S) -> Result<usize, SyncFromError<S::Error, P::Error>>
+    where
+        S: Persister + ?Sized,
+    {
+        let src_frontier = frontier(src).map_err(SyncFromError::Source)?;
+        let behind = |doc: &Self| {
+            src_frontier.as_ref().is_none_or(|src_frontier| {
+                src_frontier
+                    .iter()
+                    .any(|(actor, seq)| doc.actor_seqs.get(actor).is_none_or(|ours| ours < seq))
+            })
+        };
+        if !behind(self) {
+            return Ok(0);
+        }
+
+        let mut unknown = Vec::new();
+        for bytes in src.get_changes().map_err(SyncFromError::Source)? {
+            let change =
+                Change::from_bytes(bytes).map_err(|e| Error::from(AutomergeError::from(e)))?;
+            if !self.has_change(&change.hash) {
+                unknown.push(change);
+            }
+        }
+        let mut pulled = unknown.len();
+        self.apply_changes(unknown)?;
+
+        if behind(self) {
+            if let Some(document) = src.get_document().map_err(SyncFromError::Source)? {
+                let stored = B::load(&document).map_err(Error::from)?;
+                let unknown = stored
+                    .get_changes(&[])
+                    .map_err(Error::from)?
+                    .into_iter()
+                    .filter(|c| !self.has_change(&c.hash))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                pulled += unknown.len();
+                self.apply_changes(unknown)?;
+            }
+        }
+        Ok(pulled)
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::mpsc;
+
+use automerge::Change;
+
+use crate::{Backend, PersistentAutomerge, Persister};
+
+/// Where a change given to [`PersistentAutomerge::subscribe_changes`] came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOrigin {
+    /// Made to this document, such as by a transaction.
+    Local,
+    /// Received from elsewhere, such as applied or through a sync message.
+    Remote,
+}
+
+/// A change that has just been persisted, as given to
+/// [`PersistentAutomerge::subscribe_changes`].
+#[derive(Debug, Clone)]
+pub struct PersistedChange {
+    /// The change itself.
+    pub change: Change,
+    /// Where the change came from.
+    pub origin: ChangeOrigin,
+}
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Subscribe to the changes persisted to this document, such as for a replication layer to
+    /// tail it without polling for changes since some heads.
+    ///
+    /// Each change is sent once it has been persisted, in the order they were persisted, flagged
+    /// with whether it was made locally. Changes waiting on their dependencies are sent when they
+    /// are persisted rather than when they are later applied. Subscribers whose receiver has been
+    /// dropped are removed the next time changes are sent.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Automerge, ROOT};
+    /// # use automerge_persistent::{ChangeOrigin, MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// let changes = doc.subscribe_changes();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    ///
+    /// let mut other = Automerge::new();
+    /// other
+    ///     .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "b", 2).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// doc.apply_changes(other.get_changes(&[]).unwrap().into_iter().cloned())
+    ///     .unwrap();
+    ///
+    /// let origins = changes.try_iter().map(|c| c.origin).collect::<Vec<_>>();
+    /// assert_eq!(origins, vec![ChangeOrigin::Local, ChangeOrigin::Remote]);
+    /// ```
+    pub fn subscribe_changes(&mut self) -> mpsc::Receiver<PersistedChange> {
+        let (sender, receiver) = mpsc::channel();
+        self.change_subscribers.push(sender);
+        receiver
+    }
+
+    /// Copy the changes borrowed from the document so they can be published once the document
+    /// has been updated, or nothing if there are no subscribers.
+    pub(crate) fn changes_to_publish(&self, changes: &[&Change]) -> Vec<Change> {
+        if self.change_subscribers.is_empty() {
+            Vec::new()
+        } else {
+            changes.iter().map(|&c| c.clone()).collect()
+        }
+    }
+
+    /// Send the persisted changes to every subscriber, dropping those that have gone away.
+    pub(crate) fn publish_changes<'c>(
+        &mut self,
+        changes: impl IntoIterator<Item = &'c Change>,
+        origin: ChangeOrigin,
+    ) {
+        if self.change_subscribers.is_empty() {
+            return;
+        }
+        for change in changes {
+            let persisted = PersistedChange {
+                change: change.clone(),
+                origin,
+            };
+            self.change_subscribers
+                .retain(|sender| sender.send(persisted.clone()).is_ok());
+        }
+    }
+}
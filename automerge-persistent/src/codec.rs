@@ -0,0 +1,67 @@
+/// The first byte of every automerge change and document.
+const AUTOMERGE_MAGIC: u8 = 0x85;
+const ZSTD_HEADER: u8 = 1;
+const ENCRYPTED_HEADER: u8 = 2;
+const ZSTD_DICTIONARY_HEADER: u8 = 3;
+
+/// The codec a persisted change or document was written with.
+///
+/// Records written by a codec layer, such as [`crate::EncryptedPersister`], start with a header
+/// byte identifying the codec. Plain automerge changes and documents start with the automerge
+/// magic bytes instead, so records that were stored before a layer was enabled are recognised as
+/// [`Codec::Raw`]. Compression reads them without migrating the store, while encryption rejects
+/// them unless told otherwise.
+///
+/// ```rust
+/// # use automerge_persistent::Codec;
+/// let record = Codec::Zstd.encode(b"compressed");
+/// assert_eq!(Codec::decode(&record).unwrap(), (Codec::Zstd, &b"compressed"[..]));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// A plain automerge change or document, without a header.
+    Raw,
+    /// Compressed with zstd.
+    Zstd,
+    /// Encrypted by an [`crate::EncryptedPersister`].
+    Encrypted,
+    /// Compressed with zstd using a trained dictionary.
+    ZstdDictionary,
+}
+
+/// A record started with a header byte that is not a known [`Codec`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("unknown codec header {0:#x}")]
+pub struct UnknownCodec(pub u8);
+
+impl Codec {
+    /// Work out which codec a record was written with, returning it along with the encoded body.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the record has a header for an unknown codec.
+    pub const fn decode(record: &[u8]) -> Result<(Self, &[u8]), UnknownCodec> {
+        match record.split_first() {
+            None => Ok((Self::Raw, record)),
+            Some((&AUTOMERGE_MAGIC, _)) => Ok((Self::Raw, record)),
+            Some((&ZSTD_HEADER, body)) => Ok((Self::Zstd, body)),
+            Some((&ENCRYPTED_HEADER, body)) => Ok((Self::Encrypted, body)),
+            Some((&ZSTD_DICTIONARY_HEADER, body)) => Ok((Self::ZstdDictionary, body)),
+            Some((&header, _)) => Err(UnknownCodec(header)),
+        }
+    }
+
+    /// Make a record from a body encoded with this codec, adding the header.
+    pub fn encode(self, body: &[u8]) -> Vec<u8> {
+        let header = match self {
+            Self::Raw => return body.to_vec(),
+            Self::Zstd => ZSTD_HEADER,
+            Self::Encrypted => ENCRYPTED_HEADER,
+            Self::ZstdDictionary => ZSTD_DICTIONARY_HEADER,
+        };
+        let mut record = Vec::with_capacity(body.len() + 1);
+        record.push(header);
+        record.extend(body);
+        record
+    }
+}
@@ -0,0 +1,365 @@
+use std::{collections::HashMap, convert::TryInto, fmt, io::Read, sync::Arc};
+
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+use crate::{
+    metadata::{self, ZSTD_DICTIONARY_KEY, ZSTD_DICTIONARY_PREFIX},
+    Codec, Persister, RecordCodec, UnknownCodec,
+};
+
+/// Errors from the [`Zstd`] codec.
+#[derive(Debug, thiserror::Error)]
+pub enum CompressionError {
+    /// Compressing or decompressing a record failed.
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// A record had a header for an unknown codec.
+    #[error(transparent)]
+    UnknownCodec(#[from] UnknownCodec),
+    /// A record was compressed with a dictionary that hasn't been loaded.
+    #[error("no zstd dictionary with id {0:#x}")]
+    UnknownDictionary(u32),
+    /// A record was too short to contain the id of its dictionary.
+    #[error("record too short to be compressed with a dictionary")]
+    Truncated,
+}
+
+/// A [`RecordCodec`] compressing changes and documents with zstd.
+///
+/// Records are tagged with [`Codec::Zstd`] and records written with any other codec are returned
+/// as they are, so compression can be enabled on an existing store without migrating it.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, RecordCodec, Stack, Zstd};
+/// // start with an uncompressed store
+/// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// // and turn compression on, the old change is still readable
+/// let persister = Stack::new(doc.close().unwrap())
+///     .layer(Zstd::default().into_layer())
+///     .into_persister();
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "b", 2).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 2);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Zstd {
+    level: i32,
+}
+
+impl Default for Zstd {
+    fn default() -> Self {
+        Self::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+impl Zstd {
+    /// Compress new records with the given zstd level.
+    pub const fn new(level: i32) -> Self {
+        Self { level }
+    }
+}
+
+impl RecordCodec for Zstd {
+    type Error = CompressionError;
+
+    fn encode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        Ok(Codec::Zstd.encode(&zstd::encode_all(record, self.level)?))
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        match Codec::decode(record)? {
+            (Codec::Zstd, body) => Ok(zstd::decode_all(body)?),
+            _ => Ok(record.to_vec()),
+        }
+    }
+}
+
+/// A [`RecordCodec`] compressing changes and documents with zstd using a dictionary.
+///
+/// Individual changes are small so compress poorly on their own, a dictionary trained on a sample
+/// of the stored changes with [`Self::train`] gives zstd the patterns they share. Records are
+/// tagged with [`Codec::ZstdDictionary`] and the id of the dictionary, derived from its contents,
+/// and need that dictionary to be read back, so save it with [`Self::save`]. Records compressed by
+/// [`Zstd`] are still read, and those written with any other codec are returned as they are.
+///
+/// Every saved dictionary is kept, so the dictionary can be retrained as the changes evolve:
+/// [`Self::load`] compresses with the one saved last and reads records compressed with any of
+/// them.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{
+/// #     MemoryPersister, PersistentAutomerge, Persister, RecordCodec, Stack, ZstdDictionary,
+/// # };
+/// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+/// for i in 0..500 {
+///     doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///         tx.put(ROOT, format!("key{}", i % 10), i).unwrap();
+///         Ok(())
+///     })
+///     .unwrap();
+/// }
+///
+/// // train on the stored changes and save the dictionary alongside them
+/// let samples = doc.persister().get_changes().unwrap();
+/// let dictionary = ZstdDictionary::train(&samples, 4096).unwrap();
+/// let mut persister = doc.close().unwrap();
+/// dictionary.save(&mut persister).unwrap();
+///
+/// // later on, compress new changes with the saved dictionary
+/// let dictionary = ZstdDictionary::load(&persister).unwrap().unwrap();
+/// let persister = Stack::new(persister)
+///     .layer(dictionary.into_layer())
+///     .into_persister();
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "key0", 500).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 10);
+/// ```
+#[derive(Clone)]
+pub struct ZstdDictionary {
+    id: u32,
+    level: i32,
+    dictionary: Arc<[u8]>,
+    encoder: Arc<EncoderDictionary<'static>>,
+    /// The decoders of this and any previous dictionaries, by id.
+    decoders: HashMap<u32, Arc<DecoderDictionary<'static>>>,
+}
+
+impl fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdDictionary")
+            .field("id", &self.id)
+            .field("level", &self.level)
+            .field("dictionary_len", &self.dictionary.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ZstdDictionary {
+    /// Compress with a dictionary previously trained by [`Self::train`], at the default level.
+    pub fn new(dictionary: Vec<u8>) -> Self {
+        let dictionary: Arc<[u8]> = dictionary.into();
+        let id = dictionary_id(&dictionary);
+        let mut decoders = HashMap::new();
+        decoders.insert(id, Arc::new(DecoderDictionary::copy(&dictionary)));
+        Self {
+            id,
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            encoder: Arc::new(EncoderDictionary::copy(
+                &dictionary,
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )),
+            dictionary,
+            decoders,
+        }
+    }
+
+    /// Train a dictionary of at most `max_size` bytes on the sample records, such as the changes
+    /// returned by [`Persister::get_changes`].
+    ///
+    /// A hundred times as many bytes of samples as the size of the dictionary is a good start.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if training failed, such as if there were too few samples.
+    pub fn train(samples: &[Vec<u8>], max_size: usize) -> Result<Self, CompressionError> {
+        Ok(Self::new(zstd::dict::from_samples(samples, max_size)?))
+    }
+
+    /// Load the dictionary saved last by [`Self::save`], if there is one, along with every other
+    /// saved dictionary for reading the records compressed with them.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister.
+    pub fn load<P>(persister: &P) -> Result<Option<Self>, P::Error>
+    where
+        P: Persister + ?Sized,
+    {
+        let current = persister
+            .get_metadata(ZSTD_DICTIONARY_KEY)?
+            .and_then(|id| Some(u32::from_be_bytes(id.as_slice().try_into().ok()?)));
+        let Some(current) = current else {
+            return Ok(None);
+        };
+        let Some(dictionary) = persister.get_metadata(&metadata::zstd_dictionary_key(current))?
+        else {
+            return Ok(None);
+        };
+        let mut dictionary = Self::new(dictionary);
+        for key in persister.get_metadata_keys()? {
+            let id = key
+                .strip_prefix(ZSTD_DICTIONARY_PREFIX)
+                .and_then(|id| id.try_into().ok())
+                .map(u32::from_be_bytes);
+            match id {
+                Some(id) if id != current => {
+                    if let Some(previous) = persister.get_metadata(&key)? {
+                        dictionary
+                            .decoders
+                            .insert(id, Arc::new(DecoderDictionary::copy(&previous)));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Some(dictionary))
+    }
+
+    /// Save the dictionary in the persister's metadata, for [`Self::load`].
+    ///
+    /// Records compressed with a dictionary can't be read without it, so save it before using it.
+    /// Previously saved dictionaries are kept, as records may still be compressed with them.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister.
+    pub fn save<P>(&self, persister: &mut P) -> Result<(), P::Error>
+    where
+        P: Persister + ?Sized,
+    {
+        persister.set_metadata(
+            metadata::zstd_dictionary_key(self.id),
+            self.dictionary.to_vec(),
+        )?;
+        persister.set_metadata(ZSTD_DICTIONARY_KEY.to_vec(), self.id.to_be_bytes().to_vec())
+    }
+
+    /// Compress new records with the given zstd level.
+    #[must_use]
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self.encoder = Arc::new(EncoderDictionary::copy(&self.dictionary, level));
+        self
+    }
+
+    /// The raw dictionary.
+    pub fn dictionary(&self) -> &[u8] {
+        &self.dictionary
+    }
+
+    /// The id of the dictionary, stored in each record compressed with it.
+    pub const fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// The id of a dictionary, a 32 bit FNV-1a hash of its contents.
+fn dictionary_id(dictionary: &[u8]) -> u32 {
+    dictionary.iter().fold(0x811c_9dc5, |hash, b| {
+        (hash ^ u32::from(*b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+impl RecordCodec for ZstdDictionary {
+    type Error = CompressionError;
+
+    fn encode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        let mut body = Vec::new();
+        zstd::stream::read::Encoder::with_prepared_dictionary(record, &self.encoder)?
+            .read_to_end(&mut body)?;
+        let mut framed = self.id.to_be_bytes().to_vec();
+        framed.extend(body);
+        Ok(Codec::ZstdDictionary.encode(&framed))
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        match Codec::decode(record)? {
+            (Codec::ZstdDictionary, body) => {
+                if body.len() < 4 {
+                    return Err(CompressionError::Truncated);
+                }
+                let (id, body) = body.split_at(4);
+                let id = u32::from_be_bytes(id.try_into().expect("split at 4 bytes"));
+                let decoder = self
+                    .decoders
+                    .get(&id)
+                    .ok_or(CompressionError::UnknownDictionary(id))?;
+                let mut decoded = Vec::new();
+                zstd::stream::read::Decoder::with_prepared_dictionary(body, decoder)?
+                    .read_to_end(&mut decoded)?;
+                Ok(decoded)
+            }
+            (Codec::Zstd, body) => Ok(zstd::decode_all(body)?),
+            _ => Ok(record.to_vec()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{transaction::Transactable, ROOT};
+
+    use super::ZstdDictionary;
+    use crate::{MemoryPersister, PersistentAutomerge, Persister, RecordCodec, Stack};
+
+    fn edit<P: Persister + 'static>(doc: &mut PersistentAutomerge<P>, from: i64, to: i64) {
+        for i in from..to {
+            doc.transact::<_, _, std::convert::Infallible>(|tx| {
+                tx.put(ROOT, format!("key{}", i % 10), i).unwrap();
+                Ok(())
+            })
+            .unwrap();
+        }
+    }
+
+    fn train(persister: &MemoryPersister) -> ZstdDictionary {
+        ZstdDictionary::train(&persister.get_changes().unwrap(), 4096).unwrap()
+    }
+
+    #[test]
+    fn records_are_read_after_retraining() {
+        let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+        edit(&mut doc, 0, 500);
+        let mut persister = doc.close().unwrap();
+        let first = train(&persister);
+        first.save(&mut persister).unwrap();
+
+        let layered = |persister: MemoryPersister| {
+            let dictionary = ZstdDictionary::load(&persister).unwrap().unwrap();
+            Stack::new(persister)
+                .layer(dictionary.into_layer())
+                .into_persister()
+        };
+        let mut doc = PersistentAutomerge::load(layered(persister)).unwrap();
+        edit(&mut doc, 500, 510);
+
+        // retrain on what is stored now and compress new changes with that
+        let mut persister = doc.close().unwrap().into_inner();
+        let second = train(&persister);
+        assert_ne!(first.id(), second.id());
+        second.save(&mut persister).unwrap();
+        let mut doc = PersistentAutomerge::load(layered(persister)).unwrap();
+        edit(&mut doc, 510, 520);
+        let heads = doc.document().get_heads();
+
+        let doc = PersistentAutomerge::load(layered(doc.close().unwrap().into_inner())).unwrap();
+        assert_eq!(doc.document().get_heads(), heads);
+        assert_eq!(
+            ZstdDictionary::load(doc.persister().inner())
+                .unwrap()
+                .unwrap()
+                .id(),
+            second.id()
+        );
+    }
+}
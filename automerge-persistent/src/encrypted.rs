@@ -0,0 +1,352 @@
+use std::{collections::HashMap, convert::TryInto, error::Error};
+
+use automerge::Change;
+
+use crate::{persister, Codec, CodecError, CodecPersister, Persister, RecordCodec, UnknownCodec};
+
+/// A symmetric cipher for use by an [`EncryptedPersister`].
+///
+/// Implementations are expected to be authenticated, failing to decrypt tampered data.
+pub trait Cipher {
+    /// The error type that the operations can produce
+    type Error: Error + 'static;
+
+    /// Encrypt the data.
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Self::Error>;
+
+    /// Decrypt data previously encrypted by [`Self::encrypt`].
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Self::Error>;
+}
+
+/// Errors from the [`Keyring`] of an [`EncryptedPersister`].
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptionError<C>
+where
+    C: Error + 'static,
+{
+    /// The cipher failed.
+    #[error(transparent)]
+    CipherError(C),
+    /// A record was encrypted with a key that has not been added.
+    #[error("no key with id {0}")]
+    UnknownKey(u32),
+    /// A record was too short to contain the key id.
+    #[error("record too short to be encrypted")]
+    Truncated,
+    /// A record was not encrypted, so may have been planted by anyone able to write to the store.
+    ///
+    /// Plaintext records from before encryption was enabled can be read with
+    /// [`Keyring::allow_plaintext_reads`].
+    #[error("record is not encrypted, it was written with the {0:?} codec")]
+    NotEncrypted(Codec),
+    /// A record had a header for an unknown codec.
+    #[error(transparent)]
+    UnknownCodec(#[from] UnknownCodec),
+    /// A decrypted change could not be decoded while rewrapping.
+    #[error(transparent)]
+    InvalidChange(#[from] automerge::DecodingError),
+}
+
+/// The keys an [`EncryptedPersister`] encrypts and decrypts records with, as a [`RecordCodec`].
+///
+/// Each record is prefixed with the id of the key it was encrypted with, so multiple key versions
+/// can be in use at once. New records are encrypted with the current key.
+#[derive(Debug)]
+pub struct Keyring<C> {
+    keys: HashMap<u32, C>,
+    current: u32,
+    allow_plaintext: bool,
+}
+
+impl<C> Keyring<C>
+where
+    C: Cipher,
+{
+    /// Encrypt new records with the given key.
+    pub fn new(key_id: u32, cipher: C) -> Self {
+        let mut keys = HashMap::new();
+        keys.insert(key_id, cipher);
+        Self {
+            keys,
+            current: key_id,
+            allow_plaintext: false,
+        }
+    }
+
+    /// Return records that aren't encrypted as they are, rather than failing with
+    /// [`EncryptionError::NotEncrypted`].
+    ///
+    /// This is for migrating a store written before encryption was enabled, with
+    /// [`EncryptedPersister::rewrap`], and should be turned off again afterwards: while it is on,
+    /// anyone able to write to the store can plant changes or a document that will be accepted.
+    #[must_use]
+    pub const fn allow_plaintext_reads(mut self) -> Self {
+        self.allow_plaintext = true;
+        self
+    }
+
+    /// Add a key that is only used for decrypting existing records.
+    #[must_use]
+    pub fn with_key(mut self, key_id: u32, cipher: C) -> Self {
+        self.keys.insert(key_id, cipher);
+        self
+    }
+
+    /// Add a key that is only used for decrypting existing records.
+    pub fn add_key(&mut self, key_id: u32, cipher: C) -> &mut Self {
+        self.keys.insert(key_id, cipher);
+        self
+    }
+
+    /// Remove a key, records encrypted with it will no longer be readable.
+    ///
+    /// The current key cannot be removed.
+    pub fn remove_key(&mut self, key_id: u32) -> Option<C> {
+        if key_id == self.current {
+            None
+        } else {
+            self.keys.remove(&key_id)
+        }
+    }
+
+    /// The id of the key new records are encrypted with.
+    pub const fn current_key(&self) -> u32 {
+        self.current
+    }
+
+    /// Make the given key the current one, keeping the others for decrypting existing records.
+    ///
+    /// Use [`EncryptedPersister::rewrap`] to also re-encrypt those records.
+    pub fn rotate(&mut self, key_id: u32, cipher: C) -> &mut Self {
+        self.keys.insert(key_id, cipher);
+        self.current = key_id;
+        self
+    }
+}
+
+impl<C> RecordCodec for Keyring<C>
+where
+    C: Cipher,
+{
+    type Error = EncryptionError<C::Error>;
+
+    fn encode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        let cipher = self
+            .keys
+            .get(&self.current)
+            .ok_or(EncryptionError::UnknownKey(self.current))?;
+        let mut body = self.current.to_be_bytes().to_vec();
+        body.extend(
+            cipher
+                .encrypt(record)
+                .map_err(EncryptionError::CipherError)?,
+        );
+        Ok(Codec::Encrypted.encode(&body))
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        let body = match Codec::decode(record)? {
+            (Codec::Encrypted, body) => body,
+            _ if self.allow_plaintext => return Ok(record.to_vec()),
+            (codec, _) => return Err(EncryptionError::NotEncrypted(codec)),
+        };
+        if body.len() < 4 {
+            return Err(EncryptionError::Truncated);
+        }
+        let (key_id, ciphertext) = body.split_at(4);
+        let key_id = u32::from_be_bytes(key_id.try_into().expect("split at 4 bytes"));
+        let cipher = self
+            .keys
+            .get(&key_id)
+            .ok_or(EncryptionError::UnknownKey(key_id))?;
+        cipher
+            .decrypt(ciphertext)
+            .map_err(EncryptionError::CipherError)
+    }
+}
+
+/// A persister that encrypts changes and documents with a [`Keyring`] before passing them to an
+/// inner persister.
+///
+/// Keys can be rotated with [`Self::rewrap`]. Quarantined records are encrypted too, while sync
+/// states and metadata are passed through unencrypted.
+///
+/// Records are tagged with [`Codec::Encrypted`] and reading a record written with any other codec
+/// fails, so plaintext planted in the store isn't accepted. To enable encryption on an existing
+/// store, read it with [`Keyring::allow_plaintext_reads`] and rewrap it once.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{
+/// #     Cipher, EncryptedPersister, Keyring, MemoryPersister, PersistentAutomerge,
+/// # };
+/// /// An example cipher, this is not secure!
+/// struct Xor(u8);
+///
+/// impl Cipher for Xor {
+///     type Error = std::convert::Infallible;
+///
+///     fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Self::Error> {
+///         Ok(plaintext.iter().map(|b| b ^ self.0).collect())
+///     }
+///
+///     fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Self::Error> {
+///         self.encrypt(ciphertext)
+///     }
+/// }
+///
+/// let persister = EncryptedPersister::new(MemoryPersister::default(), Keyring::new(1, Xor(42)));
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// // rotate to a new key, re-encrypting everything stored
+/// doc.persister_mut().rewrap(2, Xor(7)).unwrap();
+/// doc.persister_mut().codec_mut().remove_key(1);
+///
+/// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 1);
+/// ```
+pub type EncryptedPersister<P, C> = CodecPersister<P, Keyring<C>>;
+
+impl<P, C> EncryptedPersister<P, C>
+where
+    P: Persister,
+    C: Cipher,
+{
+    /// Make the given key the current one and re-encrypt all stored changes, the document and
+    /// quarantined records with it, including plaintext ones when they are allowed by
+    /// [`Keyring::allow_plaintext_reads`].
+    ///
+    /// Once this returns the old keys are no longer needed and can be removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a record could not be decrypted or the inner persister fails.
+    pub fn rewrap(
+        &mut self,
+        key_id: u32,
+        cipher: C,
+    ) -> Result<(), CodecError<P::Error, EncryptionError<C::Error>>> {
+        let changes = Persister::get_changes(self)?;
+        let document = Persister::get_document(self)?;
+        let quarantined = Persister::get_quarantined(self)?;
+
+        self.codec_mut().rotate(key_id, cipher);
+
+        let changes = changes
+            .into_iter()
+            .map(Change::from_bytes)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| CodecError::CodecError(e.into()))?;
+        persister::insert_changes(self, &changes)?;
+        if let Some(document) = document {
+            Persister::set_document(self, document)?;
+        }
+        for (key, record) in quarantined {
+            Persister::quarantine(self, key, record)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge_persistent_core::test_support::{self, Probe};
+
+    use super::{Cipher, EncryptedPersister, EncryptionError, Keyring};
+    use crate::{Codec, CodecError, MemoryPersister, Persister};
+
+    struct Xor(u8);
+
+    impl Cipher for Xor {
+        type Error = std::convert::Infallible;
+
+        fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            Ok(plaintext.iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            self.encrypt(ciphertext)
+        }
+    }
+
+    #[test]
+    fn conforms_as_a_layer() {
+        test_support::check_layer(
+            |probe| EncryptedPersister::new(probe, Keyring::new(1, Xor(42))),
+            |p| p.inner(),
+        );
+    }
+
+    #[test]
+    fn quarantined_records_are_encrypted() {
+        let mut persister = EncryptedPersister::new(Probe::default(), Keyring::new(1, Xor(42)));
+        persister
+            .quarantine(b"key".to_vec(), vec![1, 2, 3])
+            .unwrap();
+
+        let stored = persister.inner().get_quarantined().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_ne!(stored[0].1, vec![1, 2, 3]);
+        assert_eq!(
+            persister.get_quarantined().unwrap(),
+            vec![(b"key".to_vec(), vec![1, 2, 3])]
+        );
+
+        persister.remove_quarantined(b"key").unwrap();
+        assert!(persister.inner().get_quarantined().unwrap().is_empty());
+    }
+
+    #[test]
+    fn report_counts_the_framing_as_overhead() {
+        let mut persister = EncryptedPersister::new(Probe::default(), Keyring::new(1, Xor(42)));
+        persister.set_document(vec![1, 2, 3]).unwrap();
+
+        let report = persister.report().unwrap();
+        assert_eq!(report.document_size, 3);
+        // the codec tag and key id
+        assert_eq!(report.sizes.document, 8);
+        assert_eq!(report.overhead, 5);
+    }
+
+    #[test]
+    fn plaintext_is_rejected() {
+        let mut persister =
+            EncryptedPersister::new(MemoryPersister::default(), Keyring::new(1, Xor(42)));
+        persister.set_document(vec![1, 2, 3]).unwrap();
+        // planted by someone with access to the store
+        let mut inner = persister.into_inner();
+        inner.set_document(vec![0x85, 1, 2, 3]).unwrap();
+
+        let persister = EncryptedPersister::new(inner, Keyring::new(1, Xor(42)));
+        assert!(matches!(
+            persister.get_document(),
+            Err(CodecError::CodecError(EncryptionError::NotEncrypted(
+                Codec::Raw
+            )))
+        ));
+
+        let persister = EncryptedPersister::new(
+            persister.into_inner(),
+            Keyring::new(1, Xor(42)).allow_plaintext_reads(),
+        );
+        assert_eq!(persister.get_document().unwrap(), Some(vec![0x85, 1, 2, 3]));
+    }
+
+    #[test]
+    fn plaintext_store_is_migrated_by_rewrapping() {
+        let mut inner = MemoryPersister::default();
+        inner.set_document(vec![0x85, 1, 2, 3]).unwrap();
+
+        let mut persister =
+            EncryptedPersister::new(inner, Keyring::new(1, Xor(42)).allow_plaintext_reads());
+        persister.rewrap(2, Xor(7)).unwrap();
+
+        let persister = EncryptedPersister::new(persister.into_inner(), Keyring::new(2, Xor(7)));
+        assert_eq!(persister.get_document().unwrap(), Some(vec![0x85, 1, 2, 3]));
+    }
+}
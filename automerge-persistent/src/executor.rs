@@ -0,0 +1,114 @@
+use std::{future::Future, pin::Pin, thread, time::Duration};
+
+use crate::CompactionScheduler;
+
+/// A boxed future that can be sent between threads, as taken and returned by an [`Executor`].
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+
+/// The parts of an async runtime needed by background work on documents, so that it can run on
+/// any of them rather than only on tokio.
+///
+/// Implementations are cheap handles to the runtime, cloned in to the tasks they spawn.
+pub trait Executor: Clone + Send + Sync + 'static {
+    /// Run the future in the background.
+    fn spawn(&self, future: BoxFuture<()>);
+
+    /// A future that completes once the duration has passed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<()>;
+}
+
+/// An [`Executor`] needing no runtime, running each spawned future on a thread of its own and
+/// sleeping on another.
+///
+/// This suits a handful of long running background tasks in applications without a runtime, or
+/// with one that isn't supported directly.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadExecutor;
+
+impl Executor for ThreadExecutor {
+    fn spawn(&self, future: BoxFuture<()>) {
+        thread::spawn(move || futures_executor::block_on(future));
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<()> {
+        let (sender, receiver) = futures_channel::oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(duration);
+            let _ = sender.send(());
+        });
+        Box::pin(async move {
+            let _ = receiver.await;
+        })
+    }
+}
+
+/// An [`Executor`] spawning on to the current tokio runtime.
+///
+/// # Panics
+///
+/// Spawning and sleeping panic if called outside of a tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioExecutor;
+
+#[cfg(feature = "tokio")]
+impl Executor for TokioExecutor {
+    fn spawn(&self, future: BoxFuture<()>) {
+        tokio::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+impl CompactionScheduler {
+    /// Call `tick` every `interval` until it returns `false`, sleeping on the executor in
+    /// between, for running compaction in the background.
+    ///
+    /// `tick` is given the scheduler to run [`Self::tick`] over the documents it has access to,
+    /// typically behind a mutex shared with the rest of the application. The returned future is
+    /// `Send` when `tick` is, so it can be given to [`Executor::spawn`].
+    ///
+    /// ```rust
+    /// # use std::{
+    /// #     sync::{Arc, Mutex},
+    /// #     time::Duration,
+    /// # };
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{
+    /// #     CompactionScheduler, MemoryPersister, PersistentAutomerge, Persister, ThreadExecutor,
+    /// # };
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// let documents = Arc::new(Mutex::new(vec![("a", doc)]));
+    ///
+    /// let mut ticks = 0;
+    /// let shared = Arc::clone(&documents);
+    /// let compaction = CompactionScheduler::default().run(
+    ///     ThreadExecutor,
+    ///     Duration::from_millis(10),
+    ///     move |scheduler| {
+    ///         let mut documents = shared.lock().unwrap();
+    ///         scheduler.tick(documents.iter_mut().map(|(key, doc)| (*key, doc)));
+    ///         ticks += 1;
+    ///         ticks < 2
+    ///     },
+    /// );
+    /// futures_executor::block_on(compaction);
+    /// assert_eq!(documents.lock().unwrap()[0].1.persister().sizes().changes, 0);
+    /// ```
+    pub async fn run<X, F>(self, executor: X, interval: Duration, mut tick: F)
+    where
+        X: Executor,
+        F: FnMut(&Self) -> bool,
+    {
+        while tick(&self) {
+            executor.sleep(interval).await;
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::{HashMap, HashSet};
+
+use automerge::{ActorId, Automerge, AutomergeError, Change, ChangeHash};
+
+use crate::{Error, Persister};
+
+/// A document built from only some actors' changes, see [`load_filtered`].
+#[derive(Debug)]
+pub struct FilteredDocument {
+    /// The document holding the selected changes.
+    pub document: Automerge,
+    /// The changes from the selected actors that were left out as they depend on a change from
+    /// another actor, directly or through other left out changes.
+    pub skipped: Vec<ChangeHash>,
+}
+
+/// Build a document from only the changes the given actors made, for inspecting what a single
+/// device contributed.
+///
+/// Both the saved document and the individual changes are read. A change is only applied when
+/// all of its dependencies are, so a change made after seeing another actor's edits is skipped
+/// along with those after it. The persister is only read from, the filtered document is not
+/// persisted so it can't be saved over the full one.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, Automerge, ROOT};
+/// # use automerge_persistent::{load_filtered, MemoryPersister, PersistentAutomerge};
+/// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// let mut other = Automerge::new();
+/// other
+///     .transact::<_, _, std::convert::Infallible>(|tx| {
+///         tx.put(ROOT, "b", 2).unwrap();
+///         Ok(())
+///     })
+///     .unwrap();
+/// doc.apply_changes(other.get_changes(&[]).unwrap().into_iter().cloned())
+///     .unwrap();
+/// // depends on the other actor's change
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "c", 3).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// let actor = doc.actor_id().clone();
+/// let filtered = load_filtered(doc.persister(), &[actor]).unwrap();
+/// assert!(filtered.document.get(ROOT, "a").unwrap().is_some());
+/// assert!(filtered.document.get(ROOT, "b").unwrap().is_none());
+/// assert!(filtered.document.get(ROOT, "c").unwrap().is_none());
+/// assert_eq!(filtered.skipped.len(), 1);
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if the storage could not be read or the changes could not be decoded.
+pub fn load_filtered<P>(
+    persister: &P,
+    actors: &[ActorId],
+) -> Result<FilteredDocument, Error<P::Error>>
+where
+    P: Persister + ?Sized,
+{
+    let actors = actors.iter().collect::<HashSet<_>>();
+    let mut changes = Vec::new();
+    if let Some(document) = persister.get_document().map_err(Error::PersisterError)? {
+        let document = Automerge::load(&document)?;
+        changes.extend(document.get_changes(&[])?.into_iter().cloned());
+    }
+    for bytes in persister.get_changes().map_err(Error::PersisterError)? {
+        changes.push(Change::from_bytes(bytes).map_err(AutomergeError::from)?);
+    }
+    let mut seen = HashSet::new();
+    changes.retain(|c| actors.contains(c.actor_id()) && seen.insert(c.hash));
+
+    // apply in dependency order, only once every dependency has been
+    let mut unmet = Vec::with_capacity(changes.len());
+    let mut waiting: HashMap<ChangeHash, Vec<usize>> = HashMap::new();
+    let mut ready = Vec::new();
+    for (i, change) in changes.iter().enumerate() {
+        unmet.push(change.deps.len());
+        for dep in &change.deps {
+            waiting.entry(*dep).or_default().push(i);
+        }
+        if change.deps.is_empty() {
+            ready.push(i);
+        }
+    }
+    let mut order = Vec::with_capacity(changes.len());
+    while let Some(i) = ready.pop() {
+        order.push(i);
+        for j in waiting.remove(&changes[i].hash).unwrap_or_default() {
+            unmet[j] -= 1;
+            if unmet[j] == 0 {
+                ready.push(j);
+            }
+        }
+    }
+
+    let mut changes = changes.into_iter().map(Some).collect::<Vec<_>>();
+    let mut document = Automerge::new();
+    document.apply_changes(order.iter().filter_map(|i| changes[*i].take()))?;
+    Ok(FilteredDocument {
+        document,
+        skipped: changes.into_iter().flatten().map(|c| c.hash).collect(),
+    })
+}
@@ -0,0 +1,145 @@
+//! Entry points for fuzzing the loading of corrupted storage, enabled with the `fuzz` feature.
+//!
+//! These are wired up as `cargo fuzz` targets in the `fuzz` directory of the repository, but can
+//! be called from any fuzzer. They panic only when the library misbehaves; any input that can't be
+//! decoded must surface as an [`Error`](crate::Error).
+//!
+//! ```rust
+//! # use automerge::{transaction::Transactable, ROOT};
+//! # use automerge_persistent::{fuzz, MemoryPersister, PersistentAutomerge};
+//! let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+//! doc.transact::<_, _, std::convert::Infallible>(|tx| {
+//!     tx.put(ROOT, "a", 1).unwrap();
+//!     Ok(())
+//! })
+//! .unwrap();
+//! let change = doc.document().get_last_local_change().unwrap().raw_bytes().to_vec();
+//!
+//! // valid input, then the same truncated and with garbage
+//! fuzz::fuzz_load(&doc.document_mut().save(), &fuzz::encode_changes(&[&change]));
+//! fuzz::fuzz_load(b"not a document", &fuzz::encode_changes(&[&change[..change.len() / 2]]));
+//! fuzz::fuzz_changes(&[0xff; 64]);
+//! ```
+
+use std::convert::TryFrom;
+
+use automerge::{ActorId, Change};
+
+use crate::{LoadMode, LoadOptions, MemoryPersister, PersistentAutomerge, Persister};
+
+/// Split the bytes into records, each prefixed by its length as a little endian `u16`.
+///
+/// A record whose length runs past the end of the bytes takes the rest of them, so every input is
+/// valid.
+pub fn decode_changes(mut bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut records = Vec::new();
+    while bytes.len() >= 2 {
+        let len = usize::from(u16::from_le_bytes([bytes[0], bytes[1]]));
+        let rest = &bytes[2..];
+        let len = len.min(rest.len());
+        records.push(rest[..len].to_vec());
+        bytes = &rest[len..];
+    }
+    records
+}
+
+/// Join records into the format read by [`decode_changes`], for building seed inputs.
+///
+/// # Panics
+///
+/// Panics if a record is longer than [`u16::MAX`] bytes.
+pub fn encode_changes(records: &[&[u8]]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for record in records {
+        let len = u16::try_from(record.len()).expect("record too long to encode");
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend_from_slice(record);
+    }
+    bytes
+}
+
+/// Load a document from storage holding the given document and changes, in every
+/// [`LoadMode`] and with and without verifying consistency.
+///
+/// The changes are split with [`decode_changes`] and stored under a made up actor, as corrupted
+/// storage wouldn't have them under the right one either. Any document that loads is then
+/// compacted and loaded again with the same options, which must succeed and give the same heads.
+///
+/// # Panics
+///
+/// Panics if the library misbehaves, such as if a document that loaded can't be loaded again
+/// after compacting it.
+pub fn fuzz_load(document_bytes: &[u8], change_bytes: &[u8]) {
+    let changes = decode_changes(change_bytes)
+        .into_iter()
+        .zip(1..)
+        .map(|(bytes, seq)| (ActorId::from(&[0; 16][..]), seq, bytes))
+        .collect::<Vec<_>>();
+
+    for mode in [
+        LoadMode::Combined,
+        LoadMode::DocumentOnly,
+        LoadMode::ChangesOnly,
+    ] {
+        for verify_consistency in [false, true] {
+            let mut persister = MemoryPersister::default();
+            if !document_bytes.is_empty() {
+                persister
+                    .set_document(document_bytes.to_vec())
+                    .expect("memory persister failed");
+            }
+            persister
+                .insert_changes(changes.clone())
+                .expect("memory persister failed");
+
+            let options = LoadOptions::default()
+                .with_mode(mode)
+                .with_verify_consistency(verify_consistency);
+            let mut doc = match PersistentAutomerge::load_with(persister, options.clone()) {
+                Ok(doc) => doc,
+                Err(_) => continue,
+            };
+            let heads = doc.document_mut().get_heads();
+            doc.compact(&[])
+                .expect("failed to compact a loaded document");
+            let persister = doc.close().expect("memory persister failed");
+            let mut doc = PersistentAutomerge::load_with(persister, options)
+                .expect("failed to load a document after compacting it");
+            assert_eq!(
+                doc.document_mut().get_heads(),
+                heads,
+                "compacting changed the heads"
+            );
+        }
+    }
+}
+
+/// Decode the bytes as changes with [`decode_changes`] and apply them to an empty document.
+///
+/// Any changes that decode and apply are then checked to load again from storage.
+///
+/// # Panics
+///
+/// Panics if the library misbehaves, such as if changes that were applied can't be loaded again.
+pub fn fuzz_changes(change_bytes: &[u8]) {
+    let records = decode_changes(change_bytes);
+    for bytes in &records {
+        // decoding alone mustn't panic, whether or not the change applies
+        let _ = Change::from_bytes(bytes.clone());
+    }
+
+    let mut doc = PersistentAutomerge::load(MemoryPersister::default())
+        .expect("failed to load an empty document");
+    if doc.apply_raw_changes(records).is_err() {
+        return;
+    }
+    let heads = doc.document_mut().get_heads();
+    let persister = doc.close().expect("memory persister failed");
+    let mut doc =
+        PersistentAutomerge::load(persister).expect("failed to load changes that were applied");
+    assert_eq!(
+        doc.document_mut().get_heads(),
+        heads,
+        "reloading changed the heads"
+    );
+}
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+
+use automerge::{AutomergeError, Change, ChangeHash};
+
+use crate::{Backend, Error, PersistentAutomerge, Persister};
+
+/// What [`PersistentAutomerge::gc_orphans`] removed from storage.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OrphanCollection {
+    /// Stored changes that were already included in the stored document.
+    pub covered: usize,
+    /// Extra stored copies of changes that were kept under another key.
+    pub duplicates: usize,
+    /// The bytes of stored changes freed, as reported by [`Persister::sizes`].
+    pub bytes_freed: u64,
+}
+
+impl OrphanCollection {
+    /// The number of stored changes removed.
+    pub const fn removed(&self) -> usize {
+        self.covered + self.duplicates
+    }
+}
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Remove the individually stored changes that are no longer needed, such as after a crash
+    /// part way through [`Self::compact`] left the changes in storage after the document that
+    /// includes them was saved.
+    ///
+    /// This removes:
+    ///
+    /// - changes already included in the stored document
+    /// - duplicate copies of a change, such as one stored under its actor and sequence number
+    ///   before the persister became content-addressed, keeping the copy under the key currently
+    ///   used
+    ///
+    /// Only the stored document is trusted to cover changes, so changes just in memory are kept.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    ///
+    /// // as left by a compaction interrupted after saving the document
+    /// doc.save_snapshot().unwrap();
+    /// assert_eq!(doc.persister().get_changes().unwrap().len(), 1);
+    ///
+    /// let collected = doc.gc_orphans().unwrap();
+    /// assert_eq!(collected.covered, 1);
+    /// assert!(doc.persister().get_changes().unwrap().is_empty());
+    ///
+    /// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+    /// assert_eq!(doc.document().length(ROOT), 1);
+    /// ```
+    ///
+    /// Duplicates are found across keys:
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::new_content_addressed()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// let change = doc.persister().get_changes().unwrap().remove(0);
+    /// let actor = doc.actor_id().clone();
+    /// doc.persister_mut()
+    ///     .insert_changes(vec![(actor, 1, change)])
+    ///     .unwrap();
+    /// assert_eq!(doc.persister().get_changes().unwrap().len(), 2);
+    ///
+    /// assert_eq!(doc.gc_orphans().unwrap().duplicates, 1);
+    /// assert_eq!(doc.persister().get_changes().unwrap().len(), 1);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage could not be read or written, or the stored document or
+    /// changes could not be decoded.
+    pub fn gc_orphans(&mut self) -> Result<OrphanCollection, Error<P::Error>> {
+        let before = self.persister.sizes().changes;
+        let in_document = self
+            .persister
+            .get_document()
+            .map_err(Error::PersisterError)?
+            .as_deref()
+            .map(B::load)
+            .transpose()?
+            .map(|stored| {
+                stored
+                    .get_changes(&[])
+                    .map(|changes| changes.into_iter().map(|c| c.hash).collect())
+            })
+            .transpose()?
+            .unwrap_or_else(HashSet::<ChangeHash>::new);
+
+        let mut copies: HashMap<ChangeHash, (Change, usize)> = HashMap::new();
+        for bytes in self
+            .persister
+            .get_changes()
+            .map_err(Error::PersisterError)?
+        {
+            let change = Change::from_bytes(bytes).map_err(AutomergeError::from)?;
+            copies.entry(change.hash).or_insert((change, 0)).1 += 1;
+        }
+        let (covered, duplicated): (Vec<_>, Vec<_>) = copies
+            .into_values()
+            .filter(|(change, count)| *count > 1 || in_document.contains(&change.hash))
+            .partition(|(change, _)| in_document.contains(&change.hash));
+
+        // covered changes go from under either key, of duplicates only the copy under the key that
+        // isn't currently used is removed
+        let covered = covered.into_iter().map(|(c, _)| c).collect::<Vec<_>>();
+        self.persister
+            .remove_changes_by_hash(&covered.iter().map(|c| c.hash).collect::<Vec<_>>())
+            .map_err(Error::PersisterError)?;
+        self.persister
+            .remove_changes(covered.iter().map(|c| (c.actor_id(), c.seq)).collect())
+            .map_err(Error::PersisterError)?;
+        if self.persister.content_addressed() {
+            self.persister
+                .remove_changes(
+                    duplicated
+                        .iter()
+                        .map(|(c, _)| (c.actor_id(), c.seq))
+                        .collect(),
+                )
+                .map_err(Error::PersisterError)?;
+        } else {
+            self.persister
+                .remove_changes_by_hash(&duplicated.iter().map(|(c, _)| c.hash).collect::<Vec<_>>())
+                .map_err(Error::PersisterError)?;
+        }
+
+        let collection = OrphanCollection {
+            covered: covered.len(),
+            duplicates: duplicated.iter().map(|(_, count)| count - 1).sum(),
+            bytes_freed: before.saturating_sub(self.persister.sizes().changes),
+        };
+        if collection.removed() > 0 {
+            log_info!("collected orphaned changes: {collection:?}");
+        }
+        Ok(collection)
+    }
+}
@@ -0,0 +1,74 @@
+use automerge::{ActorId, Change, ChangeHash};
+
+use crate::{Backend, Error, PersistentAutomerge, Persister};
+
+/// Information about a change in the history of a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeMetadata {
+    /// The hash of the change.
+    pub hash: ChangeHash,
+    /// The actor that made the change.
+    pub actor: ActorId,
+    /// The sequence number of the change for its actor.
+    pub seq: u64,
+    /// The time the change was committed, in milliseconds since the unix epoch.
+    pub timestamp: i64,
+    /// The message attached to the change, if any.
+    pub message: Option<String>,
+    /// The size of the encoded change in bytes.
+    pub size: usize,
+}
+
+impl From<&Change> for ChangeMetadata {
+    fn from(change: &Change) -> Self {
+        Self {
+            hash: change.hash,
+            actor: change.actor_id().clone(),
+            seq: change.seq,
+            timestamp: change.time,
+            message: change.message(),
+            size: change.raw_bytes().len(),
+        }
+    }
+}
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Iterate over information about every change in the document, in topological order.
+    ///
+    /// Changes that are persisted but still waiting on their dependencies are not included. If the
+    /// changes can't be read out of the document the only item is the error.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::{CommitOptions, Transactable}, ROOT};
+    /// # use automerge_persistent::MemoryPersister;
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// # let persister = MemoryPersister::default();
+    /// # let mut doc = PersistentAutomerge::load(persister).unwrap();
+    /// doc.transact_with::<_, _, std::convert::Infallible, _, ()>(
+    ///     |_| CommitOptions::default().with_message("first".to_owned()),
+    ///     |tx| {
+    ///         tx.put(ROOT, "a", 1).unwrap();
+    ///         Ok(())
+    ///     },
+    /// )
+    /// .unwrap();
+    ///
+    /// let history = doc.history().collect::<Result<Vec<_>, _>>().unwrap();
+    /// assert_eq!(history.len(), 1);
+    /// assert_eq!(history[0].message.as_deref(), Some("first"));
+    /// ```
+    pub fn history(&self) -> impl Iterator<Item = Result<ChangeMetadata, Error<P::Error>>> + '_ {
+        let (changes, error) = match self.document.get_changes(&[]) {
+            Ok(changes) => (changes, None),
+            Err(e) => (Vec::new(), Some(Error::from(e))),
+        };
+        error
+            .into_iter()
+            .map(Err)
+            .chain(changes.into_iter().map(|c| Ok(ChangeMetadata::from(c))))
+    }
+}
@@ -0,0 +1,430 @@
+use std::error::Error;
+
+use automerge::ActorId;
+
+use crate::{MappedDocument, Persister, QuarantinedRecord, StorageReport, StoredSizes};
+
+const CHANGES_PREFIX: &[u8] = b"changes/";
+const DOCUMENT_KEY: &[u8] = b"document";
+const SYNC_STATES_PREFIX: &[u8] = b"sync_states/";
+const METADATA_PREFIX: &[u8] = b"metadata/";
+const QUARANTINE_PREFIX: &[u8] = b"quarantine/";
+
+/// A key and its value.
+pub type KvPair = (Vec<u8>, Vec<u8>);
+
+/// A value as returned by [`KvStore::get_mapped`], possibly borrowed from the store.
+pub type MappedValue = Box<dyn AsRef<[u8]>>;
+
+/// A minimal ordered key-value store that a [`KvPersister`] can be built on.
+///
+/// Implementing this for a new backend is enough to get a full [`Persister`] without
+/// reimplementing the key layout.
+///
+/// ```rust
+/// # use std::collections::BTreeMap;
+/// # use automerge_persistent::{KvPair, KvPersister, KvStore, PersistentAutomerge};
+/// #[derive(Debug, Default)]
+/// struct BTreeStore(BTreeMap<Vec<u8>, Vec<u8>>);
+///
+/// impl KvStore for BTreeStore {
+///     type Error = std::convert::Infallible;
+///
+///     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+///         Ok(self.0.get(key).cloned())
+///     }
+///
+///     fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+///         self.0.insert(key, value);
+///         Ok(())
+///     }
+///
+///     fn delete(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+///         self.0.remove(key);
+///         Ok(())
+///     }
+///
+///     fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KvPair>, Self::Error> {
+///         Ok(self
+///             .0
+///             .range(prefix.to_vec()..)
+///             .take_while(|(k, _)| k.starts_with(prefix))
+///             .map(|(k, v)| (k.clone(), v.clone()))
+///             .collect())
+///     }
+/// }
+///
+/// let persister = KvPersister::new(BTreeStore::default(), "doc1").unwrap();
+/// let doc = PersistentAutomerge::load(persister).unwrap();
+/// ```
+pub trait KvStore {
+    /// The error type that the operations can produce
+    type Error: Error + 'static;
+
+    /// Returns the value stored under the key, if any.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Returns the value stored under the key like [`Self::get`], but borrowed from the store
+    /// where it can be, see [`Persister::get_document_mapped`].
+    ///
+    /// The default copies it out of [`Self::get`].
+    fn get_mapped(&self, key: &[u8]) -> Result<Option<MappedValue>, Self::Error> {
+        Ok(self.get(key)?.map(|value| Box::new(value) as MappedValue))
+    }
+
+    /// Stores the value under the key, replacing any existing value.
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Removes the value stored under the key.
+    ///
+    /// If the key does not exist this should not return an error.
+    fn delete(&mut self, key: &[u8]) -> Result<(), Self::Error>;
+
+    /// Returns all of the key-value pairs whose key starts with the prefix.
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KvPair>, Self::Error>;
+
+    /// Flush the data out to disk, returning the number of bytes flushed if known.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+
+    /// Reclaim the space used by removed entries, see [`Persister::vacuum`].
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// The largest value the store can hold, see [`Persister::max_value_size`].
+    fn max_value_size(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// A [`Persister`] for any [`KvStore`].
+///
+/// All keys are namespaced under the given prefix so multiple documents can share one store.
+/// Quarantined records are kept apart from the metadata and count towards its size.
+#[derive(Debug)]
+pub struct KvPersister<S> {
+    store: S,
+    prefix: Vec<u8>,
+    sizes: StoredSizes,
+}
+
+impl<S> KvPersister<S>
+where
+    S: KvStore,
+{
+    /// Construct a new persister, namespacing keys under the prefix.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the existing contents of the store could not be read to calculate the
+    /// stored sizes.
+    pub fn new<P>(store: S, prefix: P) -> Result<Self, S::Error>
+    where
+        P: Into<Vec<u8>>,
+    {
+        let mut s = Self {
+            store,
+            prefix: prefix.into(),
+            sizes: StoredSizes::default(),
+        };
+        s.sizes.changes = s.prefix_size(CHANGES_PREFIX)?;
+        s.sizes.document = s.get_document()?.unwrap_or_default().len() as u64;
+        s.sizes.sync_states = s.prefix_size(SYNC_STATES_PREFIX)?;
+        s.sizes.metadata = s.prefix_size(METADATA_PREFIX)? + s.prefix_size(QUARANTINE_PREFIX)?;
+        Ok(s)
+    }
+
+    /// Get a reference to the underlying store.
+    pub const fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Take the underlying store back out.
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    fn make_key(&self, kind: &[u8], key: &[u8]) -> Vec<u8> {
+        let mut k = self.prefix.clone();
+        k.extend(kind);
+        k.extend(key);
+        k
+    }
+
+    /// Make a key from the `actor_id` and `sequence_number`, with the `sequence_number` in big
+    /// endian form.
+    fn make_change_key(&self, actor_id: &ActorId, seq: u64) -> Vec<u8> {
+        let mut key = self.make_key(CHANGES_PREFIX, actor_id.to_bytes());
+        key.extend(&seq.to_be_bytes());
+        key
+    }
+
+    fn scan(&self, kind: &[u8]) -> Result<Vec<KvPair>, S::Error> {
+        self.store.scan_prefix(&self.make_key(kind, &[]))
+    }
+
+    fn prefix_size(&self, kind: &[u8]) -> Result<u64, S::Error> {
+        Ok(self.scan(kind)?.iter().map(|(_, v)| v.len() as u64).sum())
+    }
+
+    fn scan_keys(&self, kind: &[u8]) -> Result<Vec<Vec<u8>>, S::Error> {
+        let strip = self.prefix.len() + kind.len();
+        Ok(self
+            .scan(kind)?
+            .into_iter()
+            .map(|(k, _)| k[strip..].to_vec())
+            .collect())
+    }
+
+    /// Put a value, returning the size change.
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(u64, u64), S::Error> {
+        let old = self.store.get(&key)?.map_or(0, |v| v.len() as u64);
+        let new = value.len() as u64;
+        self.store.put(key, value)?;
+        Ok((new, old))
+    }
+
+    /// Delete a value, returning the size removed.
+    fn delete(&mut self, key: &[u8]) -> Result<u64, S::Error> {
+        match self.store.get(key)? {
+            Some(old) => {
+                self.store.delete(key)?;
+                Ok(old.len() as u64)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+impl<S> Persister for KvPersister<S>
+where
+    S: KvStore,
+{
+    type Error = S::Error;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .scan(CHANGES_PREFIX)?
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        for (a, s, c) in changes {
+            let (new, old) = self.put(self.make_change_key(&a, s), c)?;
+            self.sizes.changes += new;
+            self.sizes.changes -= old;
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        for (a, s) in changes {
+            self.sizes.changes -= self.delete(&self.make_change_key(a, s))?;
+        }
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.store.get(&self.make_key(DOCUMENT_KEY, &[]))
+    }
+
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        Ok((
+            self.store.get_mapped(&self.make_key(DOCUMENT_KEY, &[]))?,
+            None,
+        ))
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.sizes.document = data.len() as u64;
+        self.store.put(self.make_key(DOCUMENT_KEY, &[]), data)
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.store.get(&self.make_key(SYNC_STATES_PREFIX, peer_id))
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let (new, old) = self.put(self.make_key(SYNC_STATES_PREFIX, &peer_id), sync_state)?;
+        self.sizes.sync_states += new;
+        self.sizes.sync_states -= old;
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for id in peer_ids {
+            self.sizes.sync_states -= self.delete(&self.make_key(SYNC_STATES_PREFIX, id))?;
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.scan_keys(SYNC_STATES_PREFIX)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.store.get(&self.make_key(METADATA_PREFIX, key))
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let (new, old) = self.put(self.make_key(METADATA_PREFIX, &key), value)?;
+        self.sizes.metadata += new;
+        self.sizes.metadata -= old;
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.sizes.metadata -= self.delete(&self.make_key(METADATA_PREFIX, key))?;
+        Ok(())
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.scan_keys(METADATA_PREFIX)
+    }
+
+    fn quarantine(&mut self, key: Vec<u8>, record: Vec<u8>) -> Result<(), Self::Error> {
+        let (new, old) = self.put(self.make_key(QUARANTINE_PREFIX, &key), record)?;
+        self.sizes.metadata += new;
+        self.sizes.metadata -= old;
+        Ok(())
+    }
+
+    fn get_quarantined(&self) -> Result<Vec<QuarantinedRecord>, Self::Error> {
+        let strip = self.prefix.len() + QUARANTINE_PREFIX.len();
+        Ok(self
+            .scan(QUARANTINE_PREFIX)?
+            .into_iter()
+            .map(|(k, v)| (k[strip..].to_vec(), v))
+            .collect())
+    }
+
+    fn remove_quarantined(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.sizes.metadata -= self.delete(&self.make_key(QUARANTINE_PREFIX, key))?;
+        Ok(())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.store.flush()
+    }
+
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        self.store.vacuum()
+    }
+
+    fn max_value_size(&self) -> Option<usize> {
+        self.store.max_value_size()
+    }
+
+    /// The overhead is the bytes of the keys in the store, which the sizes leave out.
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        let mut report = StorageReport::read(self)?;
+        report.overhead = self
+            .store
+            .scan_prefix(&self.prefix)?
+            .iter()
+            .map(|(k, _)| k.len() as u64)
+            .sum();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, collections::BTreeMap};
+
+    use super::{KvPair, KvPersister, KvStore, MappedValue};
+    use crate::Persister;
+
+    #[derive(Debug, Default)]
+    struct Store {
+        values: BTreeMap<Vec<u8>, Vec<u8>>,
+        mapped_reads: Cell<usize>,
+    }
+
+    impl KvStore for Store {
+        type Error = std::convert::Infallible;
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+            Ok(self.values.get(key).cloned())
+        }
+
+        fn get_mapped(&self, key: &[u8]) -> Result<Option<MappedValue>, Self::Error> {
+            self.mapped_reads.set(self.mapped_reads.get() + 1);
+            Ok(self.get(key)?.map(|value| Box::new(value) as MappedValue))
+        }
+
+        fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+            self.values.insert(key, value);
+            Ok(())
+        }
+
+        fn delete(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+            self.values.remove(key);
+            Ok(())
+        }
+
+        fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KvPair>, Self::Error> {
+            Ok(self
+                .values
+                .range(prefix.to_vec()..)
+                .take_while(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn mapped_document_is_read_from_the_store() {
+        let mut persister = KvPersister::new(Store::default(), "doc").unwrap();
+        persister.set_document(vec![1, 2, 3]).unwrap();
+
+        let (document, _) = persister.get_document_mapped().unwrap();
+        assert_eq!((*document.unwrap()).as_ref(), &[1, 2, 3]);
+        assert_eq!(persister.store().mapped_reads.get(), 1);
+    }
+
+    #[test]
+    fn quarantined_records_are_kept_apart_from_metadata() {
+        let mut persister = KvPersister::new(Store::default(), "doc").unwrap();
+        persister
+            .quarantine(b"key".to_vec(), vec![1, 2, 3])
+            .unwrap();
+        assert!(persister.get_metadata_keys().unwrap().is_empty());
+        assert_eq!(persister.sizes().metadata, 3);
+
+        // still there after reopening the store
+        let mut persister = KvPersister::new(persister.into_store(), "doc").unwrap();
+        assert_eq!(
+            persister.get_quarantined().unwrap(),
+            vec![(b"key".to_vec(), vec![1, 2, 3])]
+        );
+        assert_eq!(persister.sizes().metadata, 3);
+
+        persister.remove_quarantined(b"key").unwrap();
+        assert!(persister.get_quarantined().unwrap().is_empty());
+        assert_eq!(persister.sizes().metadata, 0);
+    }
+
+    #[test]
+    fn report_counts_keys_as_overhead() {
+        let mut persister = KvPersister::new(Store::default(), "doc").unwrap();
+        persister.set_document(vec![1, 2, 3]).unwrap();
+        persister.quarantine(b"key".to_vec(), vec![4, 5]).unwrap();
+
+        let report = persister.report().unwrap();
+        assert_eq!(report.document_size, 3);
+        assert_eq!(report.sizes.metadata, 2);
+        assert_eq!(
+            report.overhead,
+            ("docdocument".len() + "docquarantine/key".len()) as u64
+        );
+    }
+}
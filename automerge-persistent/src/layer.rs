@@ -0,0 +1,389 @@
+use std::error::Error;
+
+use automerge::{ActorId, ChangeHash};
+
+use crate::{
+    forward_persister, persister::decode_actors, DocumentVersion, Forward, MappedDocument,
+    Persister, QuarantinedRecord, StorageReport, VersionConflict, VersionedDocument,
+};
+
+/// Wraps a persister in another, adding some behaviour such as compression, encryption or
+/// metrics.
+///
+/// Layers are applied with [`Stack::layer`]. Any function taking the inner persister and returning
+/// the wrapped one is a layer, so existing wrappers can be used directly, for example
+/// `.layer(|p| CachedPersister::new(p, 100))`.
+///
+/// A new wrapper should implement [`Forward`], only writing the operations it changes, with
+/// [`forward_persister!`] implementing [`Persister`] from that.
+pub trait PersisterLayer<P> {
+    /// The persister this layer produces.
+    type Persister: Persister;
+
+    /// Wrap the inner persister.
+    fn layer(self, inner: P) -> Self::Persister;
+}
+
+impl<P, F, Q> PersisterLayer<P> for F
+where
+    F: FnOnce(P) -> Q,
+    Q: Persister,
+{
+    type Persister = Q;
+
+    fn layer(self, inner: P) -> Self::Persister {
+        self(inner)
+    }
+}
+
+/// Builds up a persister from a base persister and layers applied on top of it.
+///
+/// The last layer added is the outermost, seeing data first on writes and last on reads.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, RecordCodec, Stack};
+/// /// A codec that reverses the bytes of each record.
+/// struct Reverse;
+///
+/// impl RecordCodec for Reverse {
+///     type Error = std::convert::Infallible;
+///
+///     fn encode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error> {
+///         Ok(record.iter().rev().copied().collect())
+///     }
+///
+///     fn decode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error> {
+///         self.encode(record)
+///     }
+/// }
+///
+/// let persister = Stack::new(MemoryPersister::default())
+///     .layer(Reverse.into_layer())
+///     .into_persister();
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 1);
+/// ```
+#[derive(Debug)]
+pub struct Stack<P>(P);
+
+impl<P> Stack<P>
+where
+    P: Persister,
+{
+    /// Start a stack from the base persister that actually stores the data.
+    pub const fn new(base: P) -> Self {
+        Self(base)
+    }
+
+    /// Wrap the stack so far in another layer.
+    pub fn layer<L>(self, layer: L) -> Stack<L::Persister>
+    where
+        L: PersisterLayer<P>,
+    {
+        Stack(layer.layer(self.0))
+    }
+
+    /// Get a reference to the outermost persister.
+    pub const fn persister(&self) -> &P {
+        &self.0
+    }
+
+    /// Finish the stack, returning the outermost persister.
+    pub fn into_persister(self) -> P {
+        self.0
+    }
+}
+
+/// Transforms the bytes of stored changes and documents, without needing to implement the rest of
+/// [`Persister`].
+///
+/// Use [`CodecPersister`] to apply the codec to a persister. Sync states and metadata are passed
+/// through untouched.
+pub trait RecordCodec {
+    /// The error type that the operations can produce
+    type Error: Error + 'static;
+
+    /// Encode a change or document before it is stored.
+    fn encode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error>;
+
+    /// Decode a change or document after it is loaded.
+    fn decode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error>;
+
+    /// Turn the codec into a layer for use with [`Stack::layer`].
+    fn into_layer(self) -> CodecLayer<Self>
+    where
+        Self: Sized,
+    {
+        CodecLayer(self)
+    }
+}
+
+/// A [`PersisterLayer`] applying a [`RecordCodec`].
+#[derive(Debug, Clone)]
+pub struct CodecLayer<C>(C);
+
+impl<P, C> PersisterLayer<P> for CodecLayer<C>
+where
+    P: Persister,
+    C: RecordCodec,
+{
+    type Persister = CodecPersister<P, C>;
+
+    fn layer(self, inner: P) -> Self::Persister {
+        CodecPersister::new(inner, self.0)
+    }
+}
+
+/// Errors from a [`CodecPersister`].
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError<P, C>
+where
+    P: Error + 'static,
+    C: Error + 'static,
+{
+    /// The inner persister failed.
+    #[error(transparent)]
+    PersisterError(P),
+    /// The codec failed.
+    #[error(transparent)]
+    CodecError(C),
+}
+
+/// A persister that applies a [`RecordCodec`] to changes and documents before passing them to an
+/// inner persister.
+#[derive(Debug)]
+pub struct CodecPersister<P, C> {
+    inner: P,
+    codec: C,
+}
+
+impl<P, C> CodecPersister<P, C>
+where
+    P: Persister,
+    C: RecordCodec,
+{
+    /// Wrap the persister with the codec.
+    pub const fn new(inner: P, codec: C) -> Self {
+        Self { inner, codec }
+    }
+
+    /// Get a reference to the codec.
+    pub const fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Get a mutable reference to the codec, such as to add keys to a [`crate::Keyring`].
+    pub const fn codec_mut(&mut self) -> &mut C {
+        &mut self.codec
+    }
+
+    /// Get a reference to the inner persister.
+    pub const fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Take the inner persister back out.
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<Vec<u8>, CodecError<P::Error, C::Error>> {
+        self.codec.decode(record).map_err(CodecError::CodecError)
+    }
+
+    fn encode(&self, record: &[u8]) -> Result<Vec<u8>, CodecError<P::Error, C::Error>> {
+        self.codec.encode(record).map_err(CodecError::CodecError)
+    }
+}
+
+impl<P, C> Forward for CodecPersister<P, C>
+where
+    P: Persister,
+    C: RecordCodec,
+{
+    type Inner = P;
+    type Error = CodecError<P::Error, C::Error>;
+
+    fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn map_error(error: P::Error) -> Self::Error {
+        CodecError::PersisterError(error)
+    }
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.inner
+            .get_changes()
+            .map_err(CodecError::PersisterError)?
+            .iter()
+            .map(|c| self.decode(c))
+            .collect()
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let changes = changes
+            .into_iter()
+            .map(|(a, s, c)| Ok((a, s, self.encode(&c)?)))
+            .collect::<Result<Vec<_>, Self::Error>>()?;
+        self.inner
+            .insert_changes(changes)
+            .map_err(CodecError::PersisterError)
+    }
+
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        let changes = changes
+            .into_iter()
+            .map(|(h, c)| Ok((h, self.encode(&c)?)))
+            .collect::<Result<Vec<_>, Self::Error>>()?;
+        self.inner
+            .insert_changes_by_hash(changes)
+            .map_err(CodecError::PersisterError)
+    }
+
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        // the inner persister only sees encoded changes
+        Ok(decode_actors(Forward::get_changes(self)?))
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner
+            .get_document()
+            .map_err(CodecError::PersisterError)?
+            .map(|d| self.decode(&d))
+            .transpose()
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let data = self.encode(&data)?;
+        self.inner
+            .set_document(data)
+            .map_err(CodecError::PersisterError)
+    }
+
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        let (document, version) = self
+            .inner
+            .get_document_versioned()
+            .map_err(CodecError::PersisterError)?;
+        Ok((document.map(|d| self.decode(&d)).transpose()?, version))
+    }
+
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        let (document, version) = self
+            .inner
+            .get_document_mapped()
+            .map_err(CodecError::PersisterError)?;
+        let document = document
+            .map(|d| self.decode((*d).as_ref()))
+            .transpose()?
+            .map(|d| Box::new(d) as Box<dyn AsRef<[u8]>>);
+        Ok((document, version))
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let data = self.encode(&data)?;
+        self.inner
+            .set_document_if(data, expected)
+            .map_err(CodecError::PersisterError)
+    }
+
+    /// The document is encoded and the compaction passed through whole, the changes are removed
+    /// by their keys which the codec doesn't change.
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let data = self.encode(&data)?;
+        self.inner
+            .compact_if(data, expected, changes)
+            .map_err(CodecError::PersisterError)
+    }
+
+    fn quarantine(&mut self, key: Vec<u8>, record: Vec<u8>) -> Result<(), Self::Error> {
+        let record = self.encode(&record)?;
+        self.inner
+            .quarantine(key, record)
+            .map_err(CodecError::PersisterError)
+    }
+
+    fn get_quarantined(&self) -> Result<Vec<QuarantinedRecord>, Self::Error> {
+        self.inner
+            .get_quarantined()
+            .map_err(CodecError::PersisterError)?
+            .into_iter()
+            .map(|(key, record)| Ok((key, self.decode(&record)?)))
+            .collect()
+    }
+
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        // read back decoded, so what the codec adds is counted as overhead
+        StorageReport::read(self)
+    }
+}
+
+forward_persister!(impl<P, C> for CodecPersister<P, C> where P: Persister, C: RecordCodec);
+
+#[cfg(test)]
+mod tests {
+    use super::{CodecPersister, RecordCodec};
+    use crate::{MemoryPersister, Persister};
+
+    struct Reverse;
+
+    impl RecordCodec for Reverse {
+        type Error = std::convert::Infallible;
+
+        fn encode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            Ok(record.iter().rev().copied().collect())
+        }
+
+        fn decode(&self, record: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            self.encode(record)
+        }
+    }
+
+    #[test]
+    fn codec_applies_to_defaulted_methods() {
+        let mut persister = CodecPersister::new(MemoryPersister::default(), Reverse);
+        persister.set_document(vec![1, 2, 3]).unwrap();
+        persister.quarantine(b"key".to_vec(), vec![4, 5]).unwrap();
+
+        let (document, _) = persister.get_document_mapped().unwrap();
+        assert_eq!((*document.unwrap()).as_ref(), &[1, 2, 3]);
+        assert_eq!(
+            persister.get_quarantined().unwrap(),
+            vec![(b"key".to_vec(), vec![4, 5])]
+        );
+        // stored encoded
+        assert_eq!(
+            persister.inner().get_quarantined().unwrap()[0].1,
+            vec![5, 4]
+        );
+        assert_eq!(persister.report().unwrap().document_size, 3);
+
+        persister.remove_quarantined(b"key").unwrap();
+        assert!(persister.get_quarantined().unwrap().is_empty());
+    }
+}
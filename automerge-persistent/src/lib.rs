@@ -6,7 +6,7 @@
 
 //! A library for constructing efficient persistent automerge documents.
 //!
-//! A [`PersistentBackend`] wraps an [`automerge::Backend`] and handles making the changes applied
+//! A [`PersistentAutomerge`] wraps an [`automerge::Automerge`] and handles making the changes applied
 //! to it durable. This works by persisting every change before it is applied to the backend. Then
 //! occasionally the user should call `compact` to save the backend in a more compact format and
 //! cleanup the included changes. This strategy aims to be fast while also being space efficient
@@ -14,40 +14,151 @@
 //!
 //! ```rust
 //! # use automerge_persistent::MemoryPersister;
-//! # use automerge_persistent::PersistentBackend;
-//! # fn main() -> Result<(), automerge_persistent::Error<std::convert::Infallible,
-//! automerge_backend::AutomergeError>> {
+//! # use automerge_persistent::PersistentAutomerge;
+//! # fn main() -> Result<(), automerge_persistent::Error<std::convert::Infallible>> {
 //! let persister = MemoryPersister::default();
-//! let backend = PersistentBackend::<_, automerge::Backend>::load(persister)?;
+//! let doc = PersistentAutomerge::load(persister)?;
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! # Features
+//!
+//! - `async`: the `AsyncPersister` trait and the `BlockingPersisterAdapter` for running a
+//!   [`Persister`] on a thread of its own from async code, and the `Executor` trait abstracting
+//!   over runtimes for background work such as `CompactionScheduler::run`, with a
+//!   `TokioExecutor` when the `tokio` feature is enabled too.
+//! - `log`: emit records through the [`log`](https://docs.rs/log) crate for load timings,
+//!   persisted changes, compactions and recovery, such as loading changes with missing
+//!   dependencies or retrying transient errors.
+//! - `tokio`: [`PersistentAutomerge::watch_heads`] for async tasks to follow the document's
+//!   heads.
+//! - `zstd`: the `Zstd` and `ZstdDictionary` codecs for compressing stored records.
+//! - `proptest`: the `testing` module of model-based tests for persisters.
+//! - `fuzz`: the `fuzz` module of entry points for fuzzing the loading of corrupted storage.
+//! - `bench`: the `bench` module of standard workloads for comparing persisters.
+//! - `passphrase`: the `Argon2id` key derivation and `XChaCha20` cipher for passphrase encrypted
+//!   archives.
 
+#[macro_use]
+mod logging;
+
+mod archive;
 mod autocommit;
-mod mem;
+mod backend;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod bloom;
+mod cached;
+mod catch_up;
+mod change_stream;
+mod codec;
+#[cfg(feature = "zstd")]
+mod compressed;
+mod encrypted;
+#[cfg(feature = "async")]
+mod executor;
+mod filter;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+mod gc;
+mod history;
+mod kv;
+mod layer;
+mod metadata;
+mod observer;
+mod options;
+mod overview;
+#[cfg(feature = "passphrase")]
+mod passphrase;
 mod persister;
+mod quarantine;
+mod rate_limit;
+mod reconcile;
+mod remove_before;
+mod repair;
+mod replica;
+mod replicate;
+mod retry;
+mod scheduler;
+mod sharded;
+mod shared;
+mod sync_manager;
+#[cfg(feature = "proptest")]
+pub mod testing;
+mod time_travel;
+mod wal;
+#[cfg(feature = "tokio")]
+mod watch;
 
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
+};
 
+pub use archive::{
+    export_archive, export_encrypted_archive, import_archive, import_encrypted_archive,
+    ArchiveError, EncryptedArchiveError, KeyDerivation,
+};
 pub use autocommit::PersistentAutoCommit;
 use automerge::{
     sync,
     transaction::{CommitOptions, Failure, Success, Transaction},
-    ApplyOptions, Automerge, AutomergeError, Change, OpObserver,
+    ActorId, ApplyOptions, Automerge, AutomergeError, Change, ChangeHash, OpObserver, Patch,
+    VecOpObserver,
 };
-pub use mem::MemoryPersister;
-pub use persister::Persister;
-
-/// Bytes stored for each of the stored types.
-#[derive(Debug, Default, Clone)]
-pub struct StoredSizes {
-    /// Total bytes stored for all changes.
-    pub changes: u64,
-    /// Total bytes stored in the document.
-    pub document: u64,
-    /// Total bytes stored for all sync states.
-    pub sync_states: u64,
-}
+pub use automerge_persistent_core::forward_persister;
+pub use automerge_persistent_core::{
+    compact_in_steps, ChangeKey, ChecksumError, ChecksummedPersister, ChunkedError,
+    ChunkedPersister, DocumentId, DocumentPersister, DocumentVersion, DurabilityPolicy,
+    DurabilityTracker, Forward, MappedDocument, MemoryPersister, MultiDocPersister, Persister,
+    QuarantinedRecord, SharedPersister, StorageReport, StoredSizes, VersionConflict,
+    VersionedDocument,
+};
+#[cfg(feature = "async")]
+pub use automerge_persistent_core::{AsyncPersister, BlockingPersisterAdapter};
+pub use backend::Backend;
+pub use cached::CachedPersister;
+pub use catch_up::SyncFromError;
+pub use change_stream::{ChangeOrigin, PersistedChange};
+pub use codec::{Codec, UnknownCodec};
+#[cfg(feature = "zstd")]
+pub use compressed::{CompressionError, Zstd, ZstdDictionary};
+pub use encrypted::{Cipher, EncryptedPersister, EncryptionError, Keyring};
+#[cfg(all(feature = "async", feature = "tokio"))]
+pub use executor::TokioExecutor;
+#[cfg(feature = "async")]
+pub use executor::{BoxFuture, Executor, ThreadExecutor};
+pub use filter::{load_filtered, FilteredDocument};
+pub use gc::OrphanCollection;
+pub use history::ChangeMetadata;
+pub use kv::{KvPair, KvPersister, KvStore, MappedValue};
+pub use layer::{CodecError, CodecLayer, CodecPersister, PersisterLayer, RecordCodec, Stack};
+use metadata::{
+    ACTOR_ID_KEY, ACTOR_SEQS_KEY, COMPACTED_CHANGES_KEY, LAST_COMPACTION_KEY, MISSING_DEPS_KEY,
+    TAG_PREFIX, TOMBSTONES_KEY,
+};
+use observer::ObserverSlot;
+pub use observer::{CompactionResult, Observer};
+pub use options::{LoadMode, LoadOptions, MigrateDocument, RetentionPolicy};
+pub use overview::{frontier, storage_overview, ActorStorage, StorageOverview};
+#[cfg(feature = "passphrase")]
+pub use passphrase::{Argon2id, PassphraseError, XChaCha20};
+pub use rate_limit::{RateLimit, RateLimitError, RateLimitedPersister};
+pub use reconcile::Reconciliation;
+pub use remove_before::RemoveBefore;
+pub use repair::RepairReport;
+pub use replica::ReplicaBackend;
+pub use replicate::{replicate, ReplicationError, ReplicationProgress, ReplicationStage};
+pub use retry::{RetryPersister, RetryPolicy};
+pub use scheduler::{CompactionScheduler, CompactionTick};
+pub use sharded::ShardedPersister;
+pub use shared::SharedPersistentAutomerge;
+pub use sync_manager::SyncManager;
+pub use wal::WalPersister;
 
 /// Errors that persistent backends can return.
 #[derive(Debug, thiserror::Error)]
@@ -58,6 +169,53 @@ pub enum Error<E> {
     /// A persister error.
     #[error(transparent)]
     PersisterError(E),
+    /// The saved document and individual changes in storage disagree.
+    #[error("inconsistent storage: {0}")]
+    InconsistentStorage(Inconsistency),
+    /// Another writer set the document since this one last read it, so writing it would have
+    /// lost their update.
+    ///
+    /// The document should be reloaded from the persister before trying again.
+    #[error(transparent)]
+    VersionConflict(VersionConflict),
+    /// The document read back after writing it didn't match what was written, so the changes it
+    /// holds were kept.
+    #[error("wrote a document of {written} bytes but read back {read:?} bytes that don't match")]
+    UnverifiedWrite {
+        /// The length of the document written.
+        written: usize,
+        /// The length of the document read back, if there was one.
+        read: Option<usize>,
+    },
+    /// The heads to diff from are not in the history of those to diff to, see
+    /// [`PersistentAutomerge::diff`].
+    #[error("heads {0:?} are not in the history being diffed to")]
+    NotAncestor(Vec<ChangeHash>),
+}
+
+/// Ways in which the saved document and the individual changes in storage can disagree.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Inconsistency {
+    /// Changes depend on others that are in neither the saved document nor the individual
+    /// changes.
+    #[error("missing dependencies {0:?}")]
+    MissingDependencies(Vec<ChangeHash>),
+    /// Two different changes were stored for the same actor and sequence number.
+    #[error("conflicting changes {first:?} and {second:?} for actor {actor:?} seq {seq}")]
+    ConflictingChanges {
+        /// The actor of the changes.
+        actor: ActorId,
+        /// The sequence number of the changes.
+        seq: u64,
+        /// The hash of the change seen first.
+        first: ChangeHash,
+        /// The hash of the change seen second.
+        second: ChangeHash,
+    },
+    /// Changes marked for removal by a two-phase compaction are not in the saved document, so
+    /// were kept.
+    #[error("saved document is missing tombstoned changes {0:?}")]
+    UnverifiedSnapshot(Vec<ChangeHash>),
 }
 
 /// Errors that persistent backends can return after a transaction.
@@ -76,298 +234,1910 @@ pub type TransactionResult<O, E, PE> = Result<Success<O>, TransactionError<PE, E
 type PeerId = Vec<u8>;
 
 /// A wrapper for a persister and an automerge document.
+///
+/// The document is an [`Automerge`] by default, other [`Backend`]s can be persisted too through
+/// [`Self::from_backend`] and [`Self::load_backend`].
 #[derive(Debug)]
-pub struct PersistentAutomerge<P> {
-    document: Automerge,
+pub struct PersistentAutomerge<P, B = Automerge> {
+    document: B,
     sync_states: HashMap<PeerId, sync::State>,
     persister: P,
+    /// Hashes of persisted changes that are waiting on dependencies before they can be applied.
+    pending_hashes: HashSet<ChangeHash>,
+    /// The dependencies of pending changes that are not known, with the changes waiting on each.
+    missing_deps: HashMap<ChangeHash, HashSet<ChangeHash>>,
+    /// The highest sequence number applied for each actor.
+    actor_seqs: HashMap<ActorId, u64>,
+    /// Whether `actor_seqs` has changed since it was last persisted.
+    actor_seqs_dirty: bool,
+    /// Whether local changes are kept in the outbox until marked as sent.
+    track_outbox: bool,
+    /// Which changes compacting keeps stored individually.
+    retention: RetentionPolicy,
+    /// Whether compacting verifies the saved document before removing changes.
+    two_phase_compaction: bool,
+    /// Whether the document is read back after writing it before removing changes.
+    verify_writes: bool,
+    observer: ObserverSlot,
+    /// Senders for the receivers returned by `subscribe_patches`.
+    patch_subscribers: Vec<mpsc::Sender<Vec<Patch>>>,
+    /// Senders for the receivers returned by `subscribe_changes`.
+    change_subscribers: Vec<mpsc::Sender<PersistedChange>>,
+    /// The sender for the receivers returned by `watch_heads`, once one has been asked for.
+    #[cfg(feature = "tokio")]
+    heads_sender: Option<tokio::sync::watch::Sender<Vec<ChangeHash>>>,
+    /// The version of the stored document last read or written, checked when writing it again.
+    document_version: Option<DocumentVersion>,
+    /// Whether the actor id is stored, which it is once there is a local change made by it.
+    actor_persisted: bool,
 }
 
-impl<P> PersistentAutomerge<P>
+/// The saved document loaded into a backend along with its version and size, or why it failed to
+/// load along with its bytes.
+type LoadedDocument<B> = Result<(B, Option<DocumentVersion>, usize), (AutomergeError, Vec<u8>)>;
+
+impl<P, B> PersistentAutomerge<P, B>
 where
     P: Persister + 'static,
+    B: Backend,
 {
-    pub fn document(&self) -> &Automerge {
+    pub const fn document(&self) -> &B {
         &self.document
     }
 
-    pub fn document_mut(&mut self) -> &mut Automerge {
+    /// Get mutable access to the document without persisting anything, prefer
+    /// [`Self::with_document_mut`] which persists the changes made.
+    pub const fn document_mut(&mut self) -> &mut B {
         &mut self.document
     }
 
-    pub fn transact<F, O, E>(&mut self, f: F) -> TransactionResult<O, E, P::Error>
-    where
-        F: FnOnce(&mut Transaction) -> Result<O, E>,
-    {
-        let result = self.document.transact(f)?;
-        if let Err(e) = self.after_transaction() {
-            return Err(TransactionError::PersisterError(e));
-        }
-        Ok(result)
-    }
-
-    fn after_transaction(&mut self) -> Result<(), P::Error> {
-        if let Some(change) = self.document.get_last_local_change() {
-            self.persister.insert_changes(vec![(
-                change.actor_id().clone(),
-                change.seq,
-                change.raw_bytes().to_vec(),
-            )])?;
-        }
-        Ok(())
-    }
-
-    pub fn transact_with<'a, F, O, E, C, Obs>(
-        &mut self,
-        c: C,
-        f: F,
-    ) -> TransactionResult<O, E, P::Error>
+    /// Run a function with mutable access to the document, persisting any changes it makes.
+    ///
+    /// This is an escape hatch for automerge APIs that this wrapper doesn't expose. Once the
+    /// function returns, every change that is now in the document's history but wasn't before is
+    /// persisted. Changes applied that are still waiting on their dependencies are not visible in
+    /// the history so are not persisted, use [`Self::apply_changes`] for those. The actor should
+    /// be changed with [`Self::set_actor_id`] so that it is persisted too.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.with_document_mut(|document| {
+    ///     let mut tx = document.transaction();
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     tx.commit();
+    /// })
+    /// .unwrap();
+    ///
+    /// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+    /// assert_eq!(doc.document().length(ROOT), 1);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the new changes could not be persisted.
+    pub fn with_document_mut<F, O>(&mut self, f: F) -> Result<O, Error<P::Error>>
     where
-        F: FnOnce(&mut Transaction) -> Result<O, E>,
-        C: FnOnce(&O) -> CommitOptions<'a, Obs>,
-        Obs: 'a + OpObserver,
+        F: FnOnce(&mut B) -> O,
     {
-        let result = self.document.transact_with(c, f)?;
-        if let Err(e) = self.after_transaction() {
-            return Err(TransactionError::PersisterError(e));
+        let heads = self.document.get_heads();
+        let result = f(&mut self.document);
+        let changes = self.document.get_changes(&heads)?;
+        persister::insert_changes(&mut self.persister, changes.iter().copied())
+            .map_err(Error::PersisterError)?;
+        let applied = changes
+            .iter()
+            .map(|c| (c.actor_id().clone(), c.seq))
+            .collect::<Vec<_>>();
+        let hashes = changes.iter().map(|c| c.hash).collect::<Vec<_>>();
+        let local = changes
+            .iter()
+            .filter(|c| c.actor_id() == self.document.get_actor())
+            .map(|c| c.hash)
+            .collect::<Vec<_>>();
+        let published = self.changes_to_publish(&changes);
+        self.add_to_outbox(&local).map_err(Error::PersisterError)?;
+        for (actor, seq) in applied {
+            self.note_applied(actor, seq);
         }
+        self.save_actor_seqs().map_err(Error::PersisterError)?;
+        self.observer.changes_persisted(&hashes);
+        let (local, remote): (Vec<_>, Vec<_>) = published
+            .iter()
+            .partition(|c| c.actor_id() == self.document.get_actor());
+        self.publish_changes(local, ChangeOrigin::Local);
+        self.publish_changes(remote, ChangeOrigin::Remote);
+        self.publish_heads();
         Ok(result)
     }
 
     /// Apply changes to this document.
+    ///
+    /// Changes that are already known, either applied or waiting on their dependencies, are
+    /// skipped so redelivered changes are not persisted again.
+    ///
+    /// Changes whose dependencies are missing are persisted straight away and held as pending,
+    /// including across restarts, then applied automatically once the missing dependencies
+    /// arrive. See [`Self::pending_changes`].
     pub fn apply_changes(
         &mut self,
         changes: impl IntoIterator<Item = Change>,
     ) -> Result<(), Error<P::Error>> {
-        self.apply_changes_with::<_, ()>(changes, ApplyOptions::default())
-    }
+        let (changes, seen) = self.unknown_changes(changes);
+        if changes.is_empty() {
+            return Ok(());
+        }
 
-    pub fn apply_changes_with<I: IntoIterator<Item = Change>, Obs: OpObserver>(
-        &mut self,
-        changes: I,
-        options: ApplyOptions<Obs>,
-    ) -> Result<(), Error<P::Error>> {
-        let mut to_persist = vec![];
-        let result = self.document.apply_changes_with(
-            changes.into_iter().map(|change| {
-                to_persist.push((
-                    change.actor_id().clone(),
-                    change.seq,
-                    change.raw_bytes().to_vec(),
-                ));
-                change
-            }),
-            options,
-        )?;
-        self.persister
-            .insert_changes(to_persist)
+        let to_persist = changes.clone();
+        if self.patch_subscribers.is_empty() {
+            self.document.apply_changes(changes)?;
+        } else {
+            let patches = self.document.apply_changes_with_patches(changes)?;
+            self.publish_patches(patches);
+        }
+        persister::insert_changes(&mut self.persister, &to_persist)
             .map_err(Error::PersisterError)?;
-        Ok(result)
+
+        self.after_apply(seen).map_err(Error::PersisterError)?;
+        self.publish_changes(&to_persist, ChangeOrigin::Remote);
+        self.publish_heads();
+        self.track_missing_deps(to_persist.iter().map(|c| (&c.hash, c.deps.as_slice())))
+            .map_err(Error::PersisterError)
     }
 
-    /// Load the persisted changes (both individual changes and a document) from storage and
-    /// rebuild the Backend.
+    /// Filter out changes that are already known or repeated, returning the rest along with
+    /// their hashes.
+    fn unknown_changes(
+        &self,
+        changes: impl IntoIterator<Item = Change>,
+    ) -> (Vec<Change>, HashSet<ChangeHash>) {
+        let mut seen = HashSet::new();
+        let changes = changes
+            .into_iter()
+            .filter(|change| !self.has_change(&change.hash) && seen.insert(change.hash))
+            .collect::<Vec<_>>();
+        (changes, seen)
+    }
+
+    /// Apply changes to this document from their encoded bytes.
+    ///
+    /// The bytes are persisted exactly as they were received rather than re-encoded from the
+    /// decoded change, so storage holds byte-identical copies of what the peer sent. As with
+    /// [`Self::apply_changes`] changes that are already known are skipped.
     ///
     /// ```rust
-    /// # use automerge_persistent::MemoryPersister;
-    /// # use automerge_persistent::PersistentBackend;
-    /// let persister = MemoryPersister::default();
-    /// let backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+    /// # use automerge::{transaction::Transactable, Automerge, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut other = Automerge::new();
+    /// other
+    ///     .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "a", 1).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// let bytes = other.get_last_local_change().unwrap().raw_bytes().to_vec();
+    ///
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.apply_raw_changes(vec![bytes.clone()]).unwrap();
+    /// assert_eq!(doc.document().length(ROOT), 1);
+    /// assert_eq!(doc.persister().get_changes().unwrap(), vec![bytes]);
     /// ```
-    pub fn load(persister: P) -> Result<Self, Error<P::Error>> {
-        let document = persister.get_document().map_err(Error::PersisterError)?;
-        let mut backend = if let Some(document) = document {
-            Automerge::load(&document).map_err(Error::AutomergeError)?
-        } else {
-            Automerge::default()
-        };
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the changes could not be decoded, in which case none are
+    /// applied.
+    pub fn apply_raw_changes(&mut self, changes: Vec<Vec<u8>>) -> Result<(), Error<P::Error>> {
+        let mut seen = HashSet::new();
+        let mut decoded = Vec::new();
+        for bytes in changes {
+            let change = Change::from_bytes(bytes.clone()).map_err(AutomergeError::from)?;
+            if !self.has_change(&change.hash) && seen.insert(change.hash) {
+                decoded.push((change, bytes));
+            }
+        }
+        if decoded.is_empty() {
+            return Ok(());
+        }
 
-        let change_bytes = persister.get_changes().map_err(Error::PersisterError)?;
+        self.document
+            .apply_changes(decoded.iter().map(|(change, _)| change.clone()).collect())?;
+        persister::insert_raw_changes(
+            &mut self.persister,
+            decoded
+                .iter()
+                .map(|(change, bytes)| (change, bytes.clone())),
+        )
+        .map_err(Error::PersisterError)?;
 
-        let mut changes = Vec::new();
-        for change_bytes in change_bytes {
-            changes.push(
-                Change::from_bytes(change_bytes).map_err(|e| Error::AutomergeError(e.into()))?,
-            )
+        self.after_apply(seen).map_err(Error::PersisterError)?;
+        self.publish_changes(
+            decoded.iter().map(|(change, _)| change),
+            ChangeOrigin::Remote,
+        );
+        self.publish_heads();
+        self.track_missing_deps(
+            decoded
+                .iter()
+                .map(|(change, _)| (&change.hash, change.deps.as_slice())),
+        )
+        .map_err(Error::PersisterError)
+    }
+
+    /// Track the newly persisted changes, noting those that could be applied.
+    fn after_apply(&mut self, hashes: HashSet<ChangeHash>) -> Result<(), P::Error> {
+        self.observer
+            .changes_persisted(&hashes.iter().copied().collect::<Vec<_>>());
+        self.pending_hashes.extend(hashes);
+        let document = &self.document;
+        let applied = self
+            .pending_hashes
+            .iter()
+            .filter_map(|hash| document.get_change_by_hash(hash))
+            .map(|c| (c.actor_id().clone(), c.seq))
+            .collect::<Vec<_>>();
+        self.pending_hashes
+            .retain(|hash| document.get_change_by_hash(hash).is_none());
+        for (actor, seq) in applied {
+            self.note_applied(actor, seq);
         }
+        self.save_actor_seqs()
+    }
 
-        backend
-            .apply_changes(changes)
-            .map_err(Error::AutomergeError)?;
-        Ok(Self {
-            document: backend,
-            sync_states: HashMap::new(),
-            persister,
-        })
+    /// Record the dependencies of the given changes that are not known, and forget those that
+    /// have since arrived, persisting them so they are known straight after a restart.
+    fn track_missing_deps<'c>(
+        &mut self,
+        changes: impl IntoIterator<Item = (&'c ChangeHash, &'c [ChangeHash])>,
+    ) -> Result<(), P::Error> {
+        let mut changed = false;
+        for (hash, deps) in changes {
+            if self.document.get_change_by_hash(hash).is_some() {
+                continue;
+            }
+            for dep in deps {
+                if !self.has_change(dep) {
+                    changed |= self.missing_deps.entry(*dep).or_default().insert(*hash);
+                }
+            }
+        }
+        let document = &self.document;
+        let pending = &self.pending_hashes;
+        self.missing_deps.retain(|dep, dependents| {
+            let before = dependents.len();
+            dependents.retain(|hash| document.get_change_by_hash(hash).is_none());
+            let keep = !dependents.is_empty()
+                && !pending.contains(dep)
+                && document.get_change_by_hash(dep).is_none();
+            changed |= !keep || dependents.len() != before;
+            keep
+        });
+
+        if !changed {
+            Ok(())
+        } else if self.missing_deps.is_empty() {
+            self.persister.remove_metadata(MISSING_DEPS_KEY)
+        } else {
+            self.persister.set_metadata(
+                MISSING_DEPS_KEY.to_vec(),
+                metadata::encode_missing_deps(&self.missing_deps),
+            )
+        }
     }
 
-    /// Compact the storage.
-    ///
-    /// This first obtains the changes currently in the backend, saves the backend and persists the
-    /// saved document. We then can remove the previously obtained changes one by one.
+    /// Whether this document knows of the change with the given hash.
     ///
-    /// It also clears out the storage used up by old sync states for peers by removing those given
-    /// in `old_peers`.
+    /// This includes changes that have been persisted but are still waiting on their dependencies
+    /// to be applied.
     ///
     /// ```rust
-    /// # use automerge_persistent::MemoryPersister;
-    /// # use automerge_persistent::PersistentBackend;
-    /// # let persister = MemoryPersister::default();
-    /// # let mut backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
-    /// backend.compact(&[]).unwrap();
+    /// # use automerge::{transaction::Transactable, Automerge, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut other = Automerge::new();
+    /// other
+    ///     .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "a", 1).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// let change = other.get_last_local_change().unwrap().clone();
+    ///
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.apply_changes(vec![change.clone()]).unwrap();
+    /// assert!(doc.has_change(&change.hash));
+    ///
+    /// // applying it again doesn't persist anything new
+    /// let sizes = doc.persister().sizes();
+    /// doc.apply_changes(vec![change]).unwrap();
+    /// assert_eq!(doc.persister().sizes().changes, sizes.changes);
     /// ```
-    pub fn compact(&mut self, old_peer_ids: &[&[u8]]) -> Result<(), Error<P::Error>> {
-        let saved_backend = self.document.save();
-        let changes = self.document.get_changes(&[])?;
-        self.persister
-            .set_document(saved_backend)
-            .map_err(Error::PersisterError)?;
-        self.persister
-            .remove_changes(changes.into_iter().map(|c| (c.actor_id(), c.seq)).collect())
-            .map_err(Error::PersisterError)?;
-        self.persister
-            .remove_sync_states(old_peer_ids)
-            .map_err(Error::PersisterError)?;
-        Ok(())
+    pub fn has_change(&self, hash: &ChangeHash) -> bool {
+        self.pending_hashes.contains(hash) || self.document.get_change_by_hash(hash).is_some()
     }
 
-    /// Generate a sync message to be sent to a peer backend.
+    /// The hashes of persisted changes that are waiting on their dependencies before they can be
+    /// applied, in no particular order.
     ///
-    /// Peer id is intentionally low level and up to the user as it can be a DNS name, IP address or
-    /// something else.
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Automerge, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut other = Automerge::new();
+    /// for i in 0..2 {
+    ///     other
+    ///         .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///             tx.put(ROOT, "a", i).unwrap();
+    ///             Ok(())
+    ///         })
+    ///         .unwrap();
+    /// }
+    /// let changes = other.get_changes(&[]).unwrap();
+    /// let (first, second) = (changes[0].clone(), changes[1].clone());
     ///
-    /// This internally retrieves the previous sync state from storage and saves the new one
-    /// afterwards.
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.apply_changes(vec![second.clone()]).unwrap();
+    /// assert_eq!(doc.pending_changes(), vec![second.hash]);
+    /// assert_eq!(doc.missing_dependencies(), vec![first.hash]);
     ///
-    /// ```rust
-    /// # use automerge_persistent::MemoryPersister;
-    /// # use automerge_persistent::PersistentBackend;
-    /// # let persister = MemoryPersister::default();
-    /// # let mut backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
-    /// let message = backend.generate_sync_message(vec![]).unwrap();
+    /// // pending changes survive a restart and are applied once their dependencies arrive
+    /// let mut doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+    /// assert_eq!(doc.pending_changes(), vec![second.hash]);
+    /// doc.apply_changes(vec![first]).unwrap();
+    /// assert!(doc.pending_changes().is_empty());
+    /// assert_eq!(doc.document().get_heads(), vec![second.hash]);
     /// ```
-    pub fn generate_sync_message(
-        &mut self,
-        peer_id: PeerId,
-    ) -> Result<Option<sync::Message>, Error<P::Error>> {
-        if !self.sync_states.contains_key(&peer_id) {
-            if let Some(sync_state) = self
-                .persister
-                .get_sync_state(&peer_id)
-                .map_err(Error::PersisterError)?
-            {
-                let s = sync::State::decode(&sync_state)
-                    .map_err(|e| Error::AutomergeError(e.into()))?;
-                self.sync_states.insert(peer_id.clone(), s);
-            }
-        }
-        let sync_state = self.sync_states.entry(peer_id.clone()).or_default();
-        let message = self.document.generate_sync_message(sync_state);
-        self.persister
-            .set_sync_state(peer_id, sync_state.encode())
-            .map_err(Error::PersisterError)?;
-        Ok(message)
+    pub fn pending_changes(&self) -> Vec<ChangeHash> {
+        self.pending_hashes.iter().copied().collect()
     }
 
-    /// Receive a sync message from a peer backend.
+    /// The hashes of changes that pending changes depend on but that are not in the document.
     ///
-    /// Peer id is intentionally low level and up to the user as it can be a DNS name, IP address or
-    /// something else.
+    /// These are persisted along with the changes waiting on them, so they are known straight
+    /// after a restart, even when loading with [`LoadMode::DocumentOnly`] which doesn't load the
+    /// pending changes.
     ///
-    /// This internally retrieves the previous sync state from storage and saves the new one
-    /// afterwards.
-    pub fn receive_sync_message(
-        &mut self,
-        peer_id: PeerId,
-        message: sync::Message,
-    ) -> Result<(), Error<P::Error>> {
-        self.receive_sync_message_with(peer_id, message, ApplyOptions::<()>::default())
-    }
-
-    /// Receive a sync message from a peer backend.
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Automerge, ROOT};
+    /// # use automerge_persistent::{LoadMode, LoadOptions, MemoryPersister, PersistentAutomerge};
+    /// let mut other = Automerge::new();
+    /// for i in 0..2 {
+    ///     other
+    ///         .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///             tx.put(ROOT, "a", i).unwrap();
+    ///             Ok(())
+    ///         })
+    ///         .unwrap();
+    /// }
+    /// let changes = other.get_changes(&[]).unwrap();
+    /// let (first, second) = (changes[0].clone(), changes[1].clone());
     ///
-    /// Peer id is intentionally low level and up to the user as it can be a DNS name, IP address or
-    /// something else.
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.apply_changes(vec![second.clone()]).unwrap();
     ///
-    /// This internally retrieves the previous sync state from storage and saves the new one
-    /// afterwards.
-    pub fn receive_sync_message_with<Obs: OpObserver>(
-        &mut self,
-        peer_id: PeerId,
-        message: sync::Message,
-        options: ApplyOptions<Obs>,
-    ) -> Result<(), Error<P::Error>> {
-        if !self.sync_states.contains_key(&peer_id) {
-            if let Some(sync_state) = self
-                .persister
-                .get_sync_state(&peer_id)
-                .map_err(Error::PersisterError)?
-            {
-                let s = sync::State::decode(&sync_state)
-                    .map_err(|e| Error::AutomergeError(e.into()))?;
-                self.sync_states.insert(peer_id.clone(), s);
+    /// let options = LoadOptions::default().with_mode(LoadMode::DocumentOnly);
+    /// let mut doc = PersistentAutomerge::load_with(doc.close().unwrap(), options).unwrap();
+    /// assert_eq!(doc.missing_dependencies(), vec![first.hash]);
+    /// assert_eq!(doc.waiting_on(&first.hash), vec![second.hash]);
+    ///
+    /// doc.apply_changes(vec![first, second]).unwrap();
+    /// assert!(doc.missing_dependencies().is_empty());
+    /// ```
+    pub fn missing_dependencies(&self) -> Vec<ChangeHash> {
+        let mut missing = self.document.get_missing_deps(&[]);
+        for dep in self.missing_deps.keys() {
+            if !missing.contains(dep) {
+                missing.push(*dep);
             }
         }
-        let sync_state = self.sync_states.entry(peer_id.clone()).or_default();
-
-        let heads = self.document.get_heads();
-        let patch = self
-            .document
-            .receive_sync_message_with(sync_state, message, options)
-            .map_err(Error::AutomergeError)?;
-        let changes = self.document.get_changes(&heads)?;
-        self.persister
-            .insert_changes(
-                changes
-                    .into_iter()
-                    .map(|c| (c.actor_id().clone(), c.seq, c.raw_bytes().to_vec()))
-                    .collect(),
-            )
-            .map_err(Error::PersisterError)?;
+        missing
+    }
 
-        self.persister
-            .set_sync_state(peer_id, sync_state.encode())
-            .map_err(Error::PersisterError)?;
-        Ok(patch)
+    /// The hashes of the pending changes waiting on the missing dependency, in no particular
+    /// order.
+    pub fn waiting_on(&self, dependency: &ChangeHash) -> Vec<ChangeHash> {
+        self.missing_deps
+            .get(dependency)
+            .map(|dependents| dependents.iter().copied().collect())
+            .unwrap_or_default()
     }
 
-    /// Flush any data out to storage returning the number of bytes flushed.
+    /// Look up a change by its hash.
+    ///
+    /// Changes that have been applied are borrowed from the document, while those that are
+    /// persisted but still waiting on their dependencies are read back from the persister.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Automerge, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut other = Automerge::new();
+    /// for i in 0..2 {
+    ///     other
+    ///         .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///             tx.put(ROOT, "a", i).unwrap();
+    ///             Ok(())
+    ///         })
+    ///         .unwrap();
+    /// }
+    /// // the second change can't be applied without the first
+    /// let change = other.get_last_local_change().unwrap().clone();
+    ///
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.apply_changes(vec![change.clone()]).unwrap();
+    /// let found = doc.get_change_by_hash(&change.hash).unwrap().unwrap();
+    /// assert_eq!(found.raw_bytes(), change.raw_bytes());
+    /// ```
     ///
     /// # Errors
     ///
-    /// Returns the error returned by the persister during flushing.
-    pub fn flush(&mut self) -> Result<usize, P::Error> {
-        self.persister.flush()
+    /// Returns an error if the persister fails or a persisted change cannot be decoded.
+    pub fn get_change_by_hash(
+        &self,
+        hash: &ChangeHash,
+    ) -> Result<Option<Cow<'_, Change>>, Error<P::Error>> {
+        if let Some(change) = self.document.get_change_by_hash(hash) {
+            return Ok(Some(Cow::Borrowed(change)));
+        }
+        if !self.pending_hashes.contains(hash) {
+            return Ok(None);
+        }
+        for bytes in self
+            .persister
+            .get_changes()
+            .map_err(Error::PersisterError)?
+        {
+            let change = Change::from_bytes(bytes).map_err(|e| Error::AutomergeError(e.into()))?;
+            if change.hash == *hash {
+                return Ok(Some(Cow::Owned(change)));
+            }
+        }
+        Ok(None)
     }
 
-    /// Close the document.
+    /// Load the document from storage into the backend type, using the given options.
     ///
-    /// This calls flush on the persister and returns it for potential use in other documents.
+    /// This is [`PersistentAutomerge::load_with`] for documents other than [`Automerge`].
+    ///
+    /// ```rust
+    /// # use automerge::Automerge;
+    /// # use automerge_persistent::{LoadOptions, MemoryPersister, PersistentAutomerge};
+    /// let doc = PersistentAutomerge::<_, Automerge>::load_backend(
+    ///     MemoryPersister::default(),
+    ///     LoadOptions::default(),
+    /// )
+    /// .unwrap();
+    /// ```
     ///
     /// # Errors
     ///
-    /// Returns the error from flushing.
-    pub fn close(mut self) -> Result<P, P::Error> {
-        self.flush()?;
-        Ok(self.persister)
+    /// Returns an error if the storage could not be read or the document could not be rebuilt
+    /// from it.
+    pub fn load_backend(mut persister: P, options: LoadOptions) -> Result<Self, Error<P::Error>> {
+        let start = Instant::now();
+        let document = Self::load_document(&mut persister, &options)?
+            .map_err(|(error, _)| Error::AutomergeError(error))?;
+        Self::load_changes(persister, options, document, start)
     }
 
-    /// Obtain a reference to the persister.
-    pub fn persister(&self) -> &P {
-        &self.persister
+    /// Load the saved document into a backend as [`Self::load_backend`] does, returning it along
+    /// with its version and size, or why it failed to load along with its bytes.
+    fn load_document(
+        persister: &mut P,
+        options: &LoadOptions,
+    ) -> Result<LoadedDocument<B>, Error<P::Error>> {
+        let (document, mut document_version) = persister
+            .get_document_mapped()
+            .map_err(Error::PersisterError)?;
+        // the version is still needed to write the document later on
+        if document.is_some() && options.mode == LoadMode::ChangesOnly {
+            log_info!("ignoring the saved document, rebuilding from the individual changes");
+        }
+        let document = document.filter(|_| options.mode != LoadMode::ChangesOnly);
+        let document = document.as_deref().map(|document| document.as_ref());
+        let document_size = document.map_or(0, <[u8]>::len);
+        let migrated = document
+            .zip(options.migrate_document)
+            .and_then(|(document, migrate)| migrate(document));
+        let backend = match (migrated, document) {
+            (Some(migrated), Some(document)) => match B::load(&migrated) {
+                Ok(backend) => {
+                    log_info!("migrated the saved document to the current format");
+                    persister::set_document_if_unchanged(
+                        persister,
+                        &mut document_version,
+                        migrated,
+                    )?;
+                    backend
+                }
+                Err(error) => return Ok(Err((error, document.to_vec()))),
+            },
+            (_, Some(document)) => match B::load(document) {
+                Ok(backend) => backend,
+                Err(error) => return Ok(Err((error, document.to_vec()))),
+            },
+            (_, None) => B::default(),
+        };
+        Ok(Ok((backend, document_version, document_size)))
     }
 
-    /// Obtain a mut reference to the persister.
-    pub fn persister_mut(&mut self) -> &mut P {
-        &mut self.persister
-    }
+    /// Finish loading from the persister by applying the stored changes to the loaded document.
+    fn load_changes(
+        mut persister: P,
+        options: LoadOptions,
+        (mut backend, document_version, document_size): (B, Option<DocumentVersion>, usize),
+        start: Instant,
+    ) -> Result<Self, Error<P::Error>> {
+        let change_bytes = if options.mode == LoadMode::DocumentOnly {
+            Vec::new()
+        } else {
+            persister.get_changes().map_err(Error::PersisterError)?
+        };
 
-    /// Reset the sync state for a peer.
-    ///
-    /// This is typically used when a peer disconnects, we need to reset the sync state for them as
-    /// they may come back up with different state.
-    pub fn reset_sync_state(&mut self, peer_id: &[u8]) -> Result<(), P::Error> {
-        self.sync_states.remove(peer_id);
-        self.persister.remove_sync_states(&[peer_id])
+        let mut changes = Vec::new();
+        for change_bytes in change_bytes {
+            if !options.quarantine_invalid_changes {
+                changes.push(
+                    Change::from_bytes(change_bytes)
+                        .map_err(|e| Error::AutomergeError(e.into()))?,
+                );
+                continue;
+            }
+            match Change::from_bytes(change_bytes.clone()) {
+                Ok(change) => changes.push(change),
+                Err(e) => {
+                    let key = quarantine::quarantine_change(&mut persister, change_bytes)
+                        .map_err(Error::PersisterError)?;
+                    log_warn!(
+                        "quarantined a change that could not be decoded under {}: {}",
+                        String::from_utf8_lossy(&key),
+                        e
+                    );
+                }
+            }
+        }
+
+        if options.verify_consistency {
+            check_conflicting_changes(&backend, &changes)?;
+        }
+
+        let change_count = changes.len();
+        let mut pending_hashes = changes.iter().map(|c| c.hash).collect::<HashSet<_>>();
+        let loaded = changes
+            .iter()
+            .map(|c| (c.actor_id().clone(), c.seq, c.hash, c.deps.clone()))
+            .collect::<Vec<_>>();
+        backend
+            .apply_changes(changes)
+            .map_err(Error::AutomergeError)?;
+        pending_hashes.retain(|hash| backend.get_change_by_hash(hash).is_none());
+        if !pending_hashes.is_empty() {
+            log_warn!(
+                "{} loaded changes are missing dependencies and wait as pending",
+                pending_hashes.len()
+            );
+        }
+
+        let stored_actor_seqs = if options.mode == LoadMode::Combined {
+            persister
+                .get_metadata(ACTOR_SEQS_KEY)
+                .map_err(Error::PersisterError)?
+                .and_then(|bytes| metadata::decode_change_keys(&bytes))
+        } else {
+            None
+        };
+        // the stored summary covers the saved document so only the loaded changes need adding,
+        // without one the whole history is walked
+        let mut actor_seqs: HashMap<ActorId, u64> = HashMap::new();
+        let actor_seqs_dirty = if let Some(stored) = stored_actor_seqs {
+            actor_seqs.extend(stored);
+            for (actor, seq, hash, _) in &loaded {
+                if !pending_hashes.contains(hash) {
+                    let max = actor_seqs.entry(actor.clone()).or_default();
+                    *max = (*max).max(*seq);
+                }
+            }
+            false
+        } else {
+            for change in backend.get_changes(&[]).map_err(Error::AutomergeError)? {
+                let max = actor_seqs.entry(change.actor_id().clone()).or_default();
+                *max = (*max).max(change.seq);
+            }
+            log_debug!("rebuilt the actor sequence summary from the document history");
+            true
+        };
+
+        if options.verify_consistency {
+            let missing_deps = backend.get_missing_deps(&[]);
+            if !missing_deps.is_empty() {
+                return Err(Error::InconsistentStorage(
+                    Inconsistency::MissingDependencies(missing_deps),
+                ));
+            }
+        }
+
+        let actor_id = persister
+            .get_metadata(ACTOR_ID_KEY)
+            .map_err(Error::PersisterError)?;
+        let actor_persisted = actor_id.is_some();
+        if let Some(actor_id) = actor_id {
+            backend.set_actor(ActorId::from(actor_id));
+        }
+
+        log_debug!(
+            "loaded a document of {} bytes and {} changes in {:?}",
+            document_size,
+            change_count,
+            start.elapsed()
+        );
+        let missing_deps = persister
+            .get_metadata(MISSING_DEPS_KEY)
+            .map_err(Error::PersisterError)?
+            .and_then(|bytes| metadata::decode_missing_deps(&bytes))
+            .unwrap_or_default();
+        let mut doc = Self {
+            document: backend,
+            sync_states: HashMap::new(),
+            persister,
+            pending_hashes,
+            missing_deps,
+            actor_seqs,
+            actor_seqs_dirty,
+            track_outbox: options.track_outbox,
+            retention: options.retention,
+            two_phase_compaction: options.two_phase_compaction,
+            verify_writes: options.verify_writes,
+            observer: ObserverSlot::default(),
+            patch_subscribers: Vec::new(),
+            change_subscribers: Vec::new(),
+            #[cfg(feature = "tokio")]
+            heads_sender: None,
+            document_version,
+            actor_persisted,
+        };
+        doc.track_missing_deps(
+            loaded
+                .iter()
+                .map(|(_, _, hash, deps)| (hash, deps.as_slice())),
+        )
+        .map_err(Error::PersisterError)?;
+        if options.mode == LoadMode::Combined {
+            // the changes are still stored so a compaction that can't be finished yet is no
+            // reason to fail loading
+            match doc.finalize_compaction() {
+                Ok(_) => {}
+                Err(Error::PersisterError(e)) => return Err(Error::PersisterError(e)),
+                Err(e) => log_warn!("keeping the changes of an unfinished compaction: {}", e),
+            }
+        }
+        Ok(doc)
+    }
+
+    /// Start persisting an existing in-memory document.
+    ///
+    /// The document is saved into the persister as a whole, along with its actor, so its history
+    /// does not need replaying through [`Self::apply_changes`]. The persister should be empty as
+    /// any document already stored is replaced, while stored changes would still be loaded.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Automerge, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut backend = Automerge::new();
+    /// backend
+    ///     .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "a", 1).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    ///
+    /// let doc = PersistentAutomerge::from_backend(backend, MemoryPersister::default()).unwrap();
+    /// let actor = doc.actor_id().clone();
+    ///
+    /// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+    /// assert_eq!(doc.document().length(ROOT), 1);
+    /// assert_eq!(doc.actor_id(), &actor);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the document could not be persisted.
+    pub fn from_backend(mut backend: B, mut persister: P) -> Result<Self, Error<P::Error>> {
+        let (_, mut document_version) = persister
+            .get_document_versioned()
+            .map_err(Error::PersisterError)?;
+        persister::set_document_if_unchanged(
+            &mut persister,
+            &mut document_version,
+            backend.save(),
+        )?;
+        persister
+            .set_metadata(
+                ACTOR_ID_KEY.to_vec(),
+                backend.get_actor().to_bytes().to_vec(),
+            )
+            .map_err(Error::PersisterError)?;
+
+        let mut actor_seqs: HashMap<ActorId, u64> = HashMap::new();
+        for change in backend.get_changes(&[])? {
+            let max = actor_seqs.entry(change.actor_id().clone()).or_default();
+            *max = (*max).max(change.seq);
+        }
+        let mut doc = Self {
+            document: backend,
+            sync_states: HashMap::new(),
+            persister,
+            pending_hashes: HashSet::new(),
+            missing_deps: HashMap::new(),
+            actor_seqs,
+            actor_seqs_dirty: true,
+            track_outbox: false,
+            retention: RetentionPolicy::default(),
+            two_phase_compaction: false,
+            verify_writes: false,
+            observer: ObserverSlot::default(),
+            patch_subscribers: Vec::new(),
+            change_subscribers: Vec::new(),
+            #[cfg(feature = "tokio")]
+            heads_sender: None,
+            document_version,
+            actor_persisted: true,
+        };
+        doc.save_actor_seqs().map_err(Error::PersisterError)?;
+        Ok(doc)
+    }
+
+    /// The highest sequence number applied for each actor, like a vector clock of the document.
+    ///
+    /// This is kept up to date as changes are applied and persisted in the metadata along with
+    /// them, so sync layers can advertise what they have without walking the history. Use
+    /// [`frontier`] to read it from storage without loading the document.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// for i in 0..3 {
+    ///     doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "a", i).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// }
+    /// let actor = doc.actor_id().clone();
+    /// assert_eq!(doc.actor_seq_summary().get(&actor), Some(&3));
+    ///
+    /// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+    /// assert_eq!(doc.actor_seq_summary().get(&actor), Some(&3));
+    /// ```
+    pub const fn actor_seq_summary(&self) -> &HashMap<ActorId, u64> {
+        &self.actor_seqs
+    }
+
+    fn note_applied(&mut self, actor: ActorId, seq: u64) {
+        let max = self.actor_seqs.entry(actor).or_default();
+        if *max < seq {
+            *max = seq;
+            self.actor_seqs_dirty = true;
+        }
+    }
+
+    /// Persist the summary if it has changed.
+    fn save_actor_seqs(&mut self) -> Result<(), P::Error> {
+        if self.actor_seqs_dirty {
+            let seqs = self
+                .actor_seqs
+                .iter()
+                .map(|(a, s)| (a, *s))
+                .collect::<Vec<_>>();
+            self.persister
+                .set_metadata(ACTOR_SEQS_KEY.to_vec(), metadata::encode_change_keys(&seqs))?;
+            self.actor_seqs_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// The hashes of local changes that have not yet been marked as sent, in no particular order.
+    ///
+    /// When loaded with [`LoadOptions::track_outbox`] every local change is put in the outbox as
+    /// it is persisted, and stays there across restarts until [`Self::mark_sent`] is called with
+    /// its hash. This lets offline-first clients know exactly which changes still need pushing
+    /// upstream.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{LoadOptions, MemoryPersister, PersistentAutomerge};
+    /// let options = LoadOptions::default().with_track_outbox(true);
+    /// let mut doc = PersistentAutomerge::load_with(MemoryPersister::default(), options.clone())
+    ///     .unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    ///
+    /// let mut doc = PersistentAutomerge::load_with(doc.close().unwrap(), options).unwrap();
+    /// let unsent = doc.outbox().unwrap();
+    /// assert_eq!(unsent, doc.document().get_heads());
+    ///
+    /// doc.mark_sent(&unsent).unwrap();
+    /// assert!(doc.outbox().unwrap().is_empty());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when listing the outbox.
+    pub fn outbox(&self) -> Result<Vec<ChangeHash>, P::Error> {
+        Ok(self
+            .persister
+            .get_metadata_keys()?
+            .into_iter()
+            .filter_map(|key| metadata::decode_outbox_key(&key))
+            .collect())
+    }
+
+    /// Remove the changes with the given hashes from the outbox, once peers have acknowledged
+    /// them.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when removing the outbox entries.
+    pub fn mark_sent(&mut self, hashes: &[ChangeHash]) -> Result<(), P::Error> {
+        for hash in hashes {
+            self.persister
+                .remove_metadata(&metadata::outbox_key(hash))?;
+        }
+        Ok(())
+    }
+
+    fn add_to_outbox(&mut self, hashes: &[ChangeHash]) -> Result<(), P::Error> {
+        if self.track_outbox {
+            for hash in hashes {
+                self.persister
+                    .set_metadata(metadata::outbox_key(hash), Vec::new())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compact the storage.
+    ///
+    /// This first obtains the changes currently in the backend, saves the backend and persists the
+    /// saved document. We then can remove the previously obtained changes one by one.
+    ///
+    /// It also clears out the storage used up by old sync states for peers by removing those given
+    /// in `old_peers`.
+    ///
+    /// ```rust
+    /// # use automerge_persistent::MemoryPersister;
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// # let persister = MemoryPersister::default();
+    /// # let mut doc = PersistentAutomerge::load(persister).unwrap();
+    /// doc.compact(&[]).unwrap();
+    /// ```
+    ///
+    /// Changes kept by the [`LoadOptions::retention`] policy are left stored individually.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{
+    /// #     LoadOptions, MemoryPersister, PersistentAutomerge, Persister, RetentionPolicy,
+    /// # };
+    /// let options = LoadOptions::default().with_retention(RetentionPolicy::KeepAll);
+    /// let mut doc = PersistentAutomerge::load_with(MemoryPersister::default(), options).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// doc.compact(&[]).unwrap();
+    ///
+    /// assert!(doc.persister().get_document().unwrap().is_some());
+    /// assert_eq!(doc.persister().get_changes().unwrap().len(), 1);
+    /// ```
+    pub fn compact(&mut self, old_peer_ids: &[&[u8]]) -> Result<(), Error<P::Error>> {
+        let saved_backend = self.document.save();
+        let now = SystemTime::now();
+        let changes = self
+            .document
+            .get_changes(&[])?
+            .into_iter()
+            .filter(|c| self.retention.removes(c, now))
+            .collect::<Vec<_>>();
+        let result = CompactionResult {
+            changes_removed: changes.len(),
+            document_size: saved_backend.len(),
+            sync_states_removed: old_peer_ids.len(),
+        };
+        let tombstones = self
+            .two_phase_compaction
+            .then(|| changes.iter().map(|c| c.hash).collect::<Vec<_>>());
+        // if another writer compacted since this one last read the document then their snapshot
+        // may hold changes that are no longer stored individually, so it must not be replaced
+        if let Some(mut tombstones) = tombstones {
+            persister::set_document_verified(
+                &mut self.persister,
+                &mut self.document_version,
+                saved_backend,
+                self.verify_writes,
+            )?;
+            if let Some(bytes) = self
+                .persister
+                .get_metadata(TOMBSTONES_KEY)
+                .map_err(Error::PersisterError)?
+            {
+                tombstones.extend(metadata::decode_hashes(&bytes).unwrap_or_default());
+                tombstones.sort_unstable();
+                tombstones.dedup();
+            }
+            self.persister
+                .set_metadata(
+                    TOMBSTONES_KEY.to_vec(),
+                    metadata::encode_hashes(&tombstones),
+                )
+                .map_err(Error::PersisterError)?;
+            self.finalize_compaction()?;
+        } else if self.verify_writes {
+            persister::set_document_verified(
+                &mut self.persister,
+                &mut self.document_version,
+                saved_backend,
+                true,
+            )?;
+            persister::remove_changes(&mut self.persister, changes)
+                .map_err(Error::PersisterError)?;
+        } else {
+            // together, so storage with transactions is never seen part way through
+            persister::compact_if_unchanged(
+                &mut self.persister,
+                &mut self.document_version,
+                saved_backend,
+                changes,
+            )?;
+        }
+        self.persister
+            .remove_sync_states(old_peer_ids)
+            .map_err(Error::PersisterError)?;
+        let kept = self.persister.sizes().changes;
+        self.persister
+            .set_metadata(COMPACTED_CHANGES_KEY.to_vec(), kept.to_be_bytes().to_vec())
+            .map_err(Error::PersisterError)?;
+        self.save_actor_seqs().map_err(Error::PersisterError)?;
+        self.observer.compacted(result);
+        Ok(())
+    }
+
+    /// Remove the changes marked as tombstones by a two-phase compaction, once the saved document
+    /// has been read back and found to hold them, returning how many were removed.
+    ///
+    /// With [`LoadOptions::two_phase_compaction`] a compaction marks the changes it would remove
+    /// as tombstones alongside the document it writes and only removes them once this succeeds,
+    /// so a bad snapshot never loses the changes it should have held. It is called by
+    /// [`Self::compact`] and again on loading, finishing a compaction interrupted before its
+    /// changes were removed.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{LoadOptions, MemoryPersister, PersistentAutomerge, Persister};
+    /// let options = LoadOptions::default().with_two_phase_compaction(true);
+    /// let mut doc = PersistentAutomerge::load_with(MemoryPersister::default(), options).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// doc.compact(&[]).unwrap();
+    ///
+    /// assert!(doc.persister().get_changes().unwrap().is_empty());
+    /// // nothing is left to finalize
+    /// assert_eq!(doc.finalize_compaction().unwrap(), 0);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Inconsistency::UnverifiedSnapshot`] with the changes the saved document is
+    /// missing, or an automerge error if it could not be loaded, in which case the changes are
+    /// all kept. Otherwise returns the error from the persister.
+    pub fn finalize_compaction(&mut self) -> Result<usize, Error<P::Error>> {
+        let tombstones = match self
+            .persister
+            .get_metadata(TOMBSTONES_KEY)
+            .map_err(Error::PersisterError)?
+        {
+            Some(bytes) => metadata::decode_hashes(&bytes).unwrap_or_default(),
+            None => return Ok(0),
+        };
+        let saved = match self
+            .persister
+            .get_document()
+            .map_err(Error::PersisterError)?
+        {
+            Some(document) => B::load(&document)?,
+            None => B::default(),
+        };
+        let missing = tombstones
+            .iter()
+            .filter(|hash| saved.get_change_by_hash(hash).is_none())
+            .copied()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(Error::InconsistentStorage(
+                Inconsistency::UnverifiedSnapshot(missing),
+            ));
+        }
+        let changes = tombstones
+            .iter()
+            .filter_map(|hash| saved.get_change_by_hash(hash))
+            .collect::<Vec<_>>();
+        persister::remove_changes(&mut self.persister, changes.iter().copied())
+            .map_err(Error::PersisterError)?;
+        self.persister
+            .remove_metadata(TOMBSTONES_KEY)
+            .map_err(Error::PersisterError)?;
+        Ok(changes.len())
+    }
+
+    /// Persist the saved document without removing any stored changes.
+    ///
+    /// This speeds up loading, as the changes already included in the document are skipped,
+    /// while keeping the full raw history of changes in storage. Changes stay stored until
+    /// [`Self::compact`] is called.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// doc.save_snapshot().unwrap();
+    ///
+    /// assert!(doc.persister().get_document().unwrap().is_some());
+    /// assert_eq!(doc.persister().get_changes().unwrap().len(), 1);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the document could not be stored, or another writer has set it since
+    /// this one last read it.
+    pub fn save_snapshot(&mut self) -> Result<(), Error<P::Error>> {
+        let saved_backend = self.document.save();
+        persister::set_document_if_unchanged(
+            &mut self.persister,
+            &mut self.document_version,
+            saved_backend,
+        )?;
+        self.save_actor_seqs().map_err(Error::PersisterError)
+    }
+
+    /// Compact the storage if the last compaction done through this method was at least `max_age`
+    /// ago, or there hasn't been one yet.
+    ///
+    /// This is intended to be called periodically by maintenance jobs. The time of the compaction
+    /// is persisted so the schedule carries on across restarts. Returns whether a compaction was
+    /// performed.
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # use automerge_persistent::MemoryPersister;
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// # let persister = MemoryPersister::default();
+    /// # let mut doc = PersistentAutomerge::load(persister).unwrap();
+    /// let day = Duration::from_secs(60 * 60 * 24);
+    /// assert!(doc.compact_if_older_than(day, &[]).unwrap());
+    /// assert!(!doc.compact_if_older_than(day, &[]).unwrap());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading the last compaction time or compacting fails.
+    pub fn compact_if_older_than(
+        &mut self,
+        max_age: Duration,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<bool, Error<P::Error>> {
+        let now = SystemTime::now();
+        if let Some(last) = self.last_compaction().map_err(Error::PersisterError)? {
+            // a last compaction in the future is treated as due so a bad clock can't block it
+            if now.duration_since(last).is_ok_and(|age| age < max_age) {
+                return Ok(false);
+            }
+        }
+        self.compact(old_peer_ids)?;
+        self.persister
+            .set_metadata(LAST_COMPACTION_KEY.to_vec(), metadata::encode_time(now))
+            .map_err(Error::PersisterError)?;
+        Ok(true)
+    }
+
+    /// Compact the storage if the bytes of changes stored since the last compaction exceed
+    /// `ratio` times the bytes of the saved document.
+    ///
+    /// The changes stored since the last compaction are already in the document once it is saved
+    /// again, so comparing their size to the document tracks the space wasted better than a fixed
+    /// number of changes. A `ratio` of `0.5` compacts when they are more than half the size of the
+    /// document. Changes kept by the [`LoadOptions::retention`] policy aren't counted, as
+    /// compacting again wouldn't remove them. Returns whether a compaction was performed.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// // there is no saved document yet so any changes are too many
+    /// assert!(doc.compact_if_changes_exceed(0.5, &[]).unwrap());
+    /// assert!(!doc.compact_if_changes_exceed(0.5, &[]).unwrap());
+    /// ```
+    ///
+    /// Changes kept by the retention policy don't make the next call compact again.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{
+    /// #     LoadOptions, MemoryPersister, PersistentAutomerge, RetentionPolicy,
+    /// # };
+    /// let options = LoadOptions::default().with_retention(RetentionPolicy::KeepAll);
+    /// let mut doc = PersistentAutomerge::load_with(MemoryPersister::default(), options).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// assert!(doc.compact_if_changes_exceed(0.5, &[]).unwrap());
+    /// assert!(!doc.compact_if_changes_exceed(0.5, &[]).unwrap());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if compacting fails.
+    pub fn compact_if_changes_exceed(
+        &mut self,
+        ratio: f64,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<bool, Error<P::Error>> {
+        let written = self
+            .changes_since_compaction()
+            .map_err(Error::PersisterError)?;
+        if written as f64 <= ratio * self.persister.sizes().document as f64 {
+            return Ok(false);
+        }
+        self.compact(old_peer_ids)?;
+        Ok(true)
+    }
+
+    /// The bytes of changes stored since the last compaction, not counting those it left stored
+    /// such as by the [`LoadOptions::retention`] policy.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when reading what the last compaction left.
+    pub fn changes_since_compaction(&self) -> Result<u64, P::Error> {
+        let kept = self
+            .persister
+            .get_metadata(COMPACTED_CHANGES_KEY)?
+            .and_then(|bytes| metadata::decode_compacted_changes(&bytes))
+            .unwrap_or(0);
+        Ok(self.persister.sizes().changes.saturating_sub(kept))
+    }
+
+    /// The time of the last compaction done through [`Self::compact_if_older_than`], if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when reading the time.
+    pub fn last_compaction(&self) -> Result<Option<SystemTime>, P::Error> {
+        Ok(self
+            .persister
+            .get_metadata(LAST_COMPACTION_KEY)?
+            .and_then(|bytes| metadata::decode_time(&bytes)))
+    }
+
+    /// Tag the current heads of the document with a name, replacing any previous tag of that
+    /// name.
+    ///
+    /// Tags are stored in the persister so can be used for application level versioning of the
+    /// history.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::MemoryPersister;
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// # let persister = MemoryPersister::default();
+    /// # let mut doc = PersistentAutomerge::load(persister).unwrap();
+    /// doc.tag("v1").unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    ///
+    /// assert_eq!(doc.list_tags().unwrap(), vec!["v1".to_owned()]);
+    /// assert_eq!(doc.changes_since_tag("v1").unwrap().unwrap().len(), 1);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when storing the tag.
+    pub fn tag(&mut self, name: &str) -> Result<(), P::Error> {
+        let heads = self.document.get_heads();
+        self.persister
+            .set_metadata(metadata::tag_key(name), metadata::encode_hashes(&heads))
+    }
+
+    /// Remove the tag with the given name.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when removing the tag.
+    pub fn remove_tag(&mut self, name: &str) -> Result<(), P::Error> {
+        self.persister.remove_metadata(&metadata::tag_key(name))
+    }
+
+    /// List the names of the stored tags, in sorted order.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when listing the tags.
+    pub fn list_tags(&self) -> Result<Vec<String>, P::Error> {
+        let mut tags = self
+            .persister
+            .get_metadata_keys()?
+            .into_iter()
+            .filter_map(|key| {
+                key.strip_prefix(TAG_PREFIX)
+                    .and_then(|name| String::from_utf8(name.to_vec()).ok())
+            })
+            .collect::<Vec<_>>();
+        tags.sort();
+        Ok(tags)
+    }
+
+    /// The heads that the tag with the given name refers to, if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when reading the tag.
+    pub fn tag_heads(&self, name: &str) -> Result<Option<Vec<ChangeHash>>, P::Error> {
+        Ok(self
+            .persister
+            .get_metadata(&metadata::tag_key(name))?
+            .and_then(|bytes| metadata::decode_hashes(&bytes)))
+    }
+
+    /// The changes made since the tag with the given name, or `None` if there is no such tag.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tag could not be read or refers to changes not in this document.
+    pub fn changes_since_tag(&self, name: &str) -> Result<Option<Vec<&Change>>, Error<P::Error>> {
+        if let Some(heads) = self.tag_heads(name).map_err(Error::PersisterError)? {
+            Ok(Some(self.document.get_changes(&heads)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Flush any data out to storage returning the number of bytes flushed.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by the persister during flushing.
+    pub fn flush(&mut self) -> Result<usize, P::Error> {
+        self.save_actor_seqs()?;
+        self.persister.flush()
+    }
+
+    /// Run storage maintenance, reclaiming the space of data removed by compactions.
+    ///
+    /// This can be slow so is intended to be called occasionally, after [`Self::compact`]. See
+    /// [`Persister::vacuum`].
+    ///
+    /// ```rust
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.compact(&[]).unwrap();
+    /// doc.maintain().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister.
+    pub fn maintain(&mut self) -> Result<(), P::Error> {
+        self.save_actor_seqs()?;
+        self.persister.vacuum()
+    }
+
+    /// Close the document.
+    ///
+    /// This calls flush on the persister and returns it for potential use in other documents.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from flushing.
+    pub fn close(mut self) -> Result<P, P::Error> {
+        self.flush()?;
+        Ok(self.persister)
+    }
+
+    /// Split the wrapper into the document and the persister.
+    ///
+    /// Unlike [`Self::close`] this does not flush, so call [`Self::flush`] first if the persister
+    /// buffers writes. Changes made to the document afterwards are not persisted.
+    ///
+    /// ```rust
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// let (document, persister) = doc.into_inner();
+    /// ```
+    pub fn into_inner(self) -> (B, P) {
+        (self.document, self.persister)
+    }
+
+    /// The actor id used for local changes.
+    ///
+    /// It is persisted along with the first local change, so documents that are only read don't
+    /// write to the persister when loaded.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// assert!(doc.persister().get_metadata_keys().unwrap().is_empty());
+    ///
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// let actor = doc.actor_id().clone();
+    /// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+    /// assert_eq!(doc.actor_id(), &actor);
+    /// ```
+    pub fn actor_id(&self) -> &ActorId {
+        self.document.get_actor()
+    }
+
+    /// Set the actor id used for local changes, persisting it so that it is used on future loads.
+    ///
+    /// ```rust
+    /// # use automerge::ActorId;
+    /// # use automerge_persistent::MemoryPersister;
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// let actor_id = ActorId::random();
+    /// doc.set_actor_id(actor_id.clone()).unwrap();
+    ///
+    /// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+    /// assert_eq!(doc.actor_id(), &actor_id);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when saving the actor id.
+    pub fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), P::Error> {
+        self.persister
+            .set_metadata(ACTOR_ID_KEY.to_vec(), actor_id.to_bytes().to_vec())?;
+        self.document.set_actor(actor_id);
+        self.actor_persisted = true;
+        Ok(())
+    }
+
+    /// Subscribe to the patches produced by changes to the document.
+    ///
+    /// Patches are sent for changes applied through [`Self::apply_changes`],
+    /// [`Self::receive_sync_message`] and those made by [`Self::transact`]. The `_with` variants
+    /// of these take their own observer so don't send patches. Subscriptions end when the
+    /// receiver is dropped.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Patch, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// let patches = doc.subscribe_patches();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    ///
+    /// let received = patches.try_recv().unwrap();
+    /// assert!(matches!(received[..], [Patch::Put { .. }]));
+    /// ```
+    pub fn subscribe_patches(&mut self) -> mpsc::Receiver<Vec<Patch>> {
+        let (sender, receiver) = mpsc::channel();
+        self.patch_subscribers.push(sender);
+        receiver
+    }
+
+    /// Send the patches to every subscriber, dropping those that have gone away.
+    fn publish_patches(&mut self, patches: Vec<Patch>) {
+        if !patches.is_empty() {
+            self.patch_subscribers
+                .retain(|sender| sender.send(patches.clone()).is_ok());
+        }
+    }
+
+    /// Update the heads given to the receivers from `watch_heads`, waking them only if the heads
+    /// have moved.
+    #[cfg_attr(not(feature = "tokio"), allow(clippy::missing_const_for_fn))]
+    pub(crate) fn publish_heads(&self) {
+        #[cfg(feature = "tokio")]
+        if let Some(sender) = &self.heads_sender {
+            let current = self.document.get_heads();
+            sender.send_if_modified(|heads| {
+                if *heads == current {
+                    false
+                } else {
+                    *heads = current;
+                    true
+                }
+            });
+        }
+    }
+
+    /// Set the observer to notify of persistence events, replacing any previous one.
+    ///
+    /// See [`Observer`].
+    pub fn set_observer(&mut self, observer: impl Observer + 'static) {
+        self.observer.set(Box::new(observer));
+    }
+
+    /// Remove the observer, returning it if one was set.
+    pub fn remove_observer(&mut self) -> Option<Box<dyn Observer>> {
+        self.observer.take()
+    }
+
+    /// Obtain a reference to the persister.
+    pub const fn persister(&self) -> &P {
+        &self.persister
+    }
+
+    /// Obtain a mut reference to the persister.
+    pub const fn persister_mut(&mut self) -> &mut P {
+        &mut self.persister
+    }
+
+    /// Generate a sync message to be sent to a peer backend.
+    ///
+    /// Peer id is intentionally low level and up to the user as it can be a DNS name, IP address or
+    /// something else.
+    ///
+    /// This internally retrieves the previous sync state from storage and saves the new one
+    /// afterwards.
+    ///
+    /// ```rust
+    /// # use automerge_persistent::MemoryPersister;
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// # let persister = MemoryPersister::default();
+    /// # let mut doc = PersistentAutomerge::load(persister).unwrap();
+    /// let message = doc.generate_sync_message(vec![]).unwrap();
+    /// ```
+    pub fn generate_sync_message(
+        &mut self,
+        peer_id: PeerId,
+    ) -> Result<Option<sync::Message>, Error<P::Error>> {
+        self.load_sync_state(&peer_id)?;
+        let sync_state = self.sync_states.entry(peer_id.clone()).or_default();
+        let message = self.document.generate_sync_message(sync_state);
+        self.persister
+            .set_sync_state(peer_id, sync_state.encode())
+            .map_err(Error::PersisterError)?;
+        Ok(message)
+    }
+
+    /// Receive a sync message from a peer backend.
+    ///
+    /// Peer id is intentionally low level and up to the user as it can be a DNS name, IP address or
+    /// something else.
+    ///
+    /// This internally retrieves the previous sync state from storage and saves the new one
+    /// afterwards.
+    pub fn receive_sync_message(
+        &mut self,
+        peer_id: PeerId,
+        message: sync::Message,
+    ) -> Result<(), Error<P::Error>> {
+        let patches = !self.patch_subscribers.is_empty();
+        self.receive_sync_message_by(peer_id, |document, sync_state| {
+            if patches {
+                document.receive_sync_message_with_patches(sync_state, message)
+            } else {
+                document
+                    .receive_sync_message(sync_state, message)
+                    .map(|()| Vec::new())
+            }
+        })
+    }
+
+    /// Read the sync state for the peer from the persister if it isn't in memory.
+    fn load_sync_state(&mut self, peer_id: &PeerId) -> Result<(), Error<P::Error>> {
+        if !self.sync_states.contains_key(peer_id) {
+            if let Some(sync_state) = self
+                .persister
+                .get_sync_state(peer_id)
+                .map_err(Error::PersisterError)?
+            {
+                let s = sync::State::decode(&sync_state)
+                    .map_err(|e| Error::AutomergeError(e.into()))?;
+                self.sync_states.insert(peer_id.clone(), s);
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive a sync message through `receive`, then persist the changes it applied and the new
+    /// sync state, publishing the patches it returns.
+    fn receive_sync_message_by(
+        &mut self,
+        peer_id: PeerId,
+        receive: impl FnOnce(&mut B, &mut sync::State) -> Result<Vec<Patch>, AutomergeError>,
+    ) -> Result<(), Error<P::Error>> {
+        self.load_sync_state(&peer_id)?;
+        let sync_state = self.sync_states.entry(peer_id.clone()).or_default();
+
+        let heads = self.document.get_heads();
+        let patches = receive(&mut self.document, sync_state).map_err(Error::AutomergeError)?;
+        let sync_state = sync_state.encode();
+        self.publish_patches(patches);
+        let changes = self.document.get_changes(&heads)?;
+        persister::insert_changes(&mut self.persister, changes.iter().copied())
+            .map_err(Error::PersisterError)?;
+        let applied = changes
+            .iter()
+            .map(|c| (c.actor_id().clone(), c.seq))
+            .collect::<Vec<_>>();
+        let hashes = changes.iter().map(|c| c.hash).collect::<Vec<_>>();
+
+        self.persister
+            .set_sync_state(peer_id, sync_state)
+            .map_err(Error::PersisterError)?;
+        let published = self.changes_to_publish(&changes);
+        for (actor, seq) in applied {
+            self.note_applied(actor, seq);
+        }
+        self.save_actor_seqs().map_err(Error::PersisterError)?;
+        self.observer.changes_persisted(&hashes);
+        self.publish_changes(&published, ChangeOrigin::Remote);
+        self.publish_heads();
+        self.track_missing_deps(std::iter::empty())
+            .map_err(Error::PersisterError)
+    }
+
+    /// Reset the sync state for a peer.
+    ///
+    /// This is typically used when a peer disconnects, we need to reset the sync state for them as
+    /// they may come back up with different state.
+    pub fn reset_sync_state(&mut self, peer_id: &[u8]) -> Result<(), P::Error> {
+        self.sync_states.remove(peer_id);
+        self.persister.remove_sync_states(&[peer_id])
+    }
+
+    /// Forget the in-memory sync state for a peer, keeping the persisted one.
+    ///
+    /// This is typically used when a peer reconnects, the persisted state only keeps what was
+    /// known to be shared with the peer so syncing resumes from there rather than from scratch.
+    pub fn reload_sync_state(&mut self, peer_id: &[u8]) {
+        self.sync_states.remove(peer_id);
+    }
+}
+
+/// Loading with the document type inferred, and transactions and the `_with` variants taking
+/// automerge's own options, which need an [`Automerge`] document, see [`Backend`].
+impl<P> PersistentAutomerge<P>
+where
+    P: Persister + 'static,
+{
+    /// Load the persisted changes (both individual changes and a document) from storage and
+    /// rebuild the Backend.
+    ///
+    /// The actor id is restored from the persister. Otherwise a new one is generated and persisted
+    /// along with the first local change, so that it is reused next time; loading without making
+    /// changes leaves nothing stored.
+    ///
+    /// ```rust
+    /// # use automerge_persistent::MemoryPersister;
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// let persister = MemoryPersister::default();
+    /// let doc = PersistentAutomerge::load(persister).unwrap();
+    /// ```
+    pub fn load(persister: P) -> Result<Self, Error<P::Error>> {
+        Self::load_with(persister, LoadOptions::default())
+    }
+
+    /// Load the document from storage using the given options.
+    ///
+    /// This allows recovering from a corrupt saved document by rebuilding from the individual
+    /// changes alone.
+    ///
+    /// ```rust
+    /// # use automerge_persistent::{LoadMode, LoadOptions, MemoryPersister};
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// let persister = MemoryPersister::default();
+    /// let options = LoadOptions::default().with_mode(LoadMode::ChangesOnly);
+    /// let doc = PersistentAutomerge::load_with(persister, options).unwrap();
+    /// ```
+    ///
+    /// With [`LoadOptions::verify_consistency`] set, changes that depend on missing history or
+    /// conflict with each other are reported as [`Error::InconsistentStorage`] rather than being
+    /// silently left unapplied or failing inside automerge.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Automerge, ROOT};
+    /// # use automerge_persistent::{Error, LoadOptions, MemoryPersister, Persister};
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// let mut other = Automerge::new();
+    /// for i in 0..2 {
+    ///     other
+    ///         .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///             tx.put(ROOT, "a", i).unwrap();
+    ///             Ok(())
+    ///         })
+    ///         .unwrap();
+    /// }
+    /// // only store the second change, which depends on the first
+    /// let change = other.get_last_local_change().unwrap();
+    /// let mut persister = MemoryPersister::default();
+    /// persister
+    ///     .insert_changes(vec![(
+    ///         change.actor_id().clone(),
+    ///         change.seq,
+    ///         change.raw_bytes().to_vec(),
+    ///     )])
+    ///     .unwrap();
+    ///
+    /// let options = LoadOptions::default().with_verify_consistency(true);
+    /// let result = PersistentAutomerge::load_with(persister, options);
+    /// assert!(matches!(result, Err(Error::InconsistentStorage(_))));
+    /// ```
+    ///
+    /// With [`LoadOptions::migrate_document`] set, saved documents in older formats are converted
+    /// and rewritten in storage rather than failing to load.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Automerge, ROOT};
+    /// # use automerge_persistent::{LoadOptions, MemoryPersister, Persister};
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// let mut other = Automerge::new();
+    /// other
+    ///     .transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "a", 1).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// // pretend an older version wrote a header before the document
+    /// let mut persister = MemoryPersister::default();
+    /// persister
+    ///     .set_document([&b"v0"[..], &other.save()].concat())
+    ///     .unwrap();
+    ///
+    /// let options = LoadOptions::default()
+    ///     .with_migrate_document(|stored| stored.strip_prefix(b"v0").map(<[u8]>::to_vec));
+    /// let doc = PersistentAutomerge::load_with(persister, options).unwrap();
+    /// assert_eq!(doc.document().length(ROOT), 1);
+    ///
+    /// // the migrated document was written back so loads without the hook
+    /// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+    /// assert_eq!(doc.document().length(ROOT), 1);
+    /// ```
+    pub fn load_with(persister: P, options: LoadOptions) -> Result<Self, Error<P::Error>> {
+        Self::load_backend(persister, options)
+    }
+
+    /// Load the document using the given options, notifying the observer once loaded.
+    ///
+    /// ```rust
+    /// # use automerge::ChangeHash;
+    /// # use automerge_persistent::{LoadOptions, MemoryPersister, Observer, PersistentAutomerge};
+    /// struct Loaded;
+    ///
+    /// impl Observer for Loaded {
+    ///     fn on_load_complete(&mut self, heads: &[ChangeHash]) {
+    ///         println!("loaded at {:?}", heads);
+    ///     }
+    /// }
+    ///
+    /// let doc =
+    ///     PersistentAutomerge::load_observed(MemoryPersister::default(), LoadOptions::default(), Loaded)
+    ///         .unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if loading fails, in which case the observer is not notified.
+    pub fn load_observed(
+        persister: P,
+        options: LoadOptions,
+        observer: impl Observer + 'static,
+    ) -> Result<Self, Error<P::Error>> {
+        let mut doc = Self::load_with(persister, options)?;
+        doc.set_observer(observer);
+        let heads = doc.document.get_heads();
+        doc.observer.load_complete(&heads);
+        Ok(doc)
+    }
+
+    pub fn transact<F, O, E>(&mut self, f: F) -> TransactionResult<O, E, P::Error>
+    where
+        F: FnOnce(&mut Transaction) -> Result<O, E>,
+    {
+        let result = self.transact_in_memory(f)?;
+        if let Err(e) = self.after_transaction() {
+            return Err(TransactionError::PersisterError(e));
+        }
+        Ok(result)
+    }
+
+    /// Run the transaction like [`Self::transact`], then flush so that the change is durable
+    /// before this returns whatever the persister's [`DurabilityPolicy`] is.
+    ///
+    /// This is for the operations that must not be lost, such as submitting an order, while other
+    /// changes stay buffered.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "draft", "typing").unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// doc.transact_durable::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "submitted", true).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the transaction, or from the persister when persisting or flushing
+    /// the change, in which case it may not be durable.
+    pub fn transact_durable<F, O, E>(&mut self, f: F) -> TransactionResult<O, E, P::Error>
+    where
+        F: FnOnce(&mut Transaction) -> Result<O, E>,
+    {
+        let result = self.transact(f)?;
+        self.flush().map_err(TransactionError::PersisterError)?;
+        Ok(result)
+    }
+
+    /// Run the transaction on the document without persisting the change it makes, which is left
+    /// to the caller.
+    pub(crate) fn transact_in_memory<F, O, E>(&mut self, f: F) -> Result<Success<O>, Failure<E>>
+    where
+        F: FnOnce(&mut Transaction) -> Result<O, E>,
+    {
+        if self.patch_subscribers.is_empty() {
+            self.document.transact(f)
+        } else {
+            let mut observer = VecOpObserver::default();
+            let result = self.document.transact_with(
+                |_| CommitOptions::default().with_op_observer(&mut observer),
+                f,
+            )?;
+            self.publish_patches(observer.take_patches());
+            Ok(result)
+        }
+    }
+
+    fn after_transaction(&mut self) -> Result<(), P::Error> {
+        if let Some(change) = self.document.get_last_local_change().cloned() {
+            self.persist_local_changes(&[change])?;
+        }
+        Ok(())
+    }
+
+    /// Persist local changes already in the document.
+    pub(crate) fn persist_local_changes(&mut self, changes: &[Change]) -> Result<(), P::Error> {
+        // a document that is only read keeps no actor, so it isn't written on loading
+        if !self.actor_persisted {
+            self.persister.set_metadata(
+                ACTOR_ID_KEY.to_vec(),
+                self.document.get_actor().to_bytes().to_vec(),
+            )?;
+            self.actor_persisted = true;
+        }
+        persister::insert_changes(&mut self.persister, changes)?;
+        let hashes = changes.iter().map(|c| c.hash).collect::<Vec<_>>();
+        self.add_to_outbox(&hashes)?;
+        for change in changes {
+            self.note_applied(change.actor_id().clone(), change.seq);
+        }
+        self.save_actor_seqs()?;
+        self.observer.changes_persisted(&hashes);
+        self.publish_changes(changes, ChangeOrigin::Local);
+        self.publish_heads();
+        Ok(())
+    }
+
+    pub fn transact_with<'a, F, O, E, C, Obs>(
+        &mut self,
+        c: C,
+        f: F,
+    ) -> TransactionResult<O, E, P::Error>
+    where
+        F: FnOnce(&mut Transaction) -> Result<O, E>,
+        C: FnOnce(&O) -> CommitOptions<'a, Obs>,
+        Obs: 'a + OpObserver,
+    {
+        let result = self.document.transact_with(c, f)?;
+        if let Err(e) = self.after_transaction() {
+            return Err(TransactionError::PersisterError(e));
+        }
+        Ok(result)
+    }
+
+    pub fn apply_changes_with<I: IntoIterator<Item = Change>, Obs: OpObserver>(
+        &mut self,
+        changes: I,
+        options: ApplyOptions<Obs>,
+    ) -> Result<(), Error<P::Error>> {
+        let (changes, seen) = self.unknown_changes(changes);
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let to_persist = changes.clone();
+        self.document.apply_changes_with(changes, options)?;
+        persister::insert_changes(&mut self.persister, &to_persist)
+            .map_err(Error::PersisterError)?;
+
+        self.after_apply(seen).map_err(Error::PersisterError)?;
+        self.publish_changes(&to_persist, ChangeOrigin::Remote);
+        self.publish_heads();
+        self.track_missing_deps(to_persist.iter().map(|c| (&c.hash, c.deps.as_slice())))
+            .map_err(Error::PersisterError)
+    }
+
+    /// Receive a sync message from a peer backend.
+    ///
+    /// Peer id is intentionally low level and up to the user as it can be a DNS name, IP address or
+    /// something else.
+    ///
+    /// This internally retrieves the previous sync state from storage and saves the new one
+    /// afterwards.
+    pub fn receive_sync_message_with<Obs: OpObserver>(
+        &mut self,
+        peer_id: PeerId,
+        message: sync::Message,
+        options: ApplyOptions<Obs>,
+    ) -> Result<(), Error<P::Error>> {
+        self.receive_sync_message_by(peer_id, |document, sync_state| {
+            document
+                .receive_sync_message_with(sync_state, message, options)
+                .map(|()| Vec::new())
+        })
+    }
+}
+
+/// Check that no two changes, either in the document or those about to be applied to it, share
+/// an actor and sequence number.
+fn check_conflicting_changes<B: Backend, E>(
+    document: &B,
+    changes: &[Change],
+) -> Result<(), Error<E>>
+where
+    E: std::error::Error + 'static,
+{
+    let mut seen = HashMap::new();
+    let existing = document.get_changes(&[])?;
+    for change in existing.into_iter().chain(changes) {
+        if let Some(first) = seen.insert((change.actor_id(), change.seq), change.hash) {
+            if first != change.hash {
+                return Err(Error::InconsistentStorage(
+                    Inconsistency::ConflictingChanges {
+                        actor: change.actor_id().clone(),
+                        seq: change.seq,
+                        first,
+                        second: change.hash,
+                    },
+                ));
+            }
+        }
     }
+    Ok(())
 }
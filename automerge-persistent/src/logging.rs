@@ -0,0 +1,27 @@
+//! Records for the `log` crate, emitted only with the `log` feature.
+//!
+//! Without the feature the arguments are still type checked, so values only computed for logging
+//! don't trigger unused warnings, but nothing is formatted.
+
+macro_rules! log_event {
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        log::$level!(target: "automerge_persistent", $($arg)+);
+        #[cfg(not(feature = "log"))]
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }};
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)+) => { log_event!(debug, $($arg)+) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)+) => { log_event!(info, $($arg)+) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => { log_event!(warn, $($arg)+) };
+}
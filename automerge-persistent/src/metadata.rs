@@ -0,0 +1,285 @@
+//! Keys and encodings for the state this library keeps in the persister's metadata.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::{TryFrom, TryInto},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use automerge::{ActorId, ChangeHash};
+
+use crate::DocumentVersion;
+
+/// Metadata key for the actor id used for local changes.
+pub const ACTOR_ID_KEY: &[u8] = b"actor_id";
+
+/// Metadata key for the time of the last scheduled compaction.
+pub const LAST_COMPACTION_KEY: &[u8] = b"last_compaction";
+
+/// Metadata key for the bytes of changes left stored by the last compaction.
+pub const COMPACTED_CHANGES_KEY: &[u8] = b"compacted_changes";
+
+/// Metadata key for the highest sequence number applied for each actor.
+pub const ACTOR_SEQS_KEY: &[u8] = b"actor_seqs";
+
+/// Metadata key for the missing dependencies of pending changes.
+pub const MISSING_DEPS_KEY: &[u8] = b"missing_deps";
+
+/// Metadata key for the hashes of changes that a two-phase compaction will remove once the saved
+/// document is verified to hold them.
+pub const TOMBSTONES_KEY: &[u8] = b"compaction_tombstones";
+
+/// Metadata key prefix for named tags, followed by the tag name.
+pub const TAG_PREFIX: &[u8] = b"tag/";
+
+/// Metadata key prefix for local changes not yet sent to peers, followed by the change hash.
+pub const OUTBOX_PREFIX: &[u8] = b"outbox/";
+
+/// Metadata key prefix for the journal kept by [`crate::WalPersister`].
+pub const WAL_PREFIX: &[u8] = b"wal/";
+
+/// Metadata key for a journalled document write.
+pub const WAL_DOCUMENT_KEY: &[u8] = b"wal/document";
+
+/// Metadata key for a journalled document write conditional on its version.
+pub const WAL_DOCUMENT_IF_KEY: &[u8] = b"wal/document_if";
+
+/// Metadata key for a journalled compaction, setting the document and removing the changes it
+/// includes.
+pub const WAL_COMPACT_KEY: &[u8] = b"wal/compact";
+
+/// Metadata key for a journalled removal of changes.
+pub const WAL_REMOVE_CHANGES_KEY: &[u8] = b"wal/remove_changes";
+
+/// Metadata key for a journalled removal of changes by hash.
+pub const WAL_REMOVE_HASHES_KEY: &[u8] = b"wal/remove_hashes";
+
+/// Metadata key for the id of the dictionary last saved by [`crate::ZstdDictionary::save`].
+#[cfg(feature = "zstd")]
+pub const ZSTD_DICTIONARY_KEY: &[u8] = b"zstd_dictionary";
+
+/// Prefix of the metadata keys of every dictionary saved by [`crate::ZstdDictionary::save`],
+/// followed by its id.
+#[cfg(feature = "zstd")]
+pub const ZSTD_DICTIONARY_PREFIX: &[u8] = b"zstd_dictionary/";
+
+/// Make the metadata key for the tag with the given name.
+pub fn tag_key(name: &str) -> Vec<u8> {
+    let mut key = TAG_PREFIX.to_vec();
+    key.extend(name.as_bytes());
+    key
+}
+
+/// Make the metadata key for the saved zstd dictionary with the given id.
+#[cfg(feature = "zstd")]
+pub fn zstd_dictionary_key(id: u32) -> Vec<u8> {
+    let mut key = ZSTD_DICTIONARY_PREFIX.to_vec();
+    key.extend(id.to_be_bytes());
+    key
+}
+
+/// Make the metadata key for the outbox entry of the change with the given hash.
+pub fn outbox_key(hash: &ChangeHash) -> Vec<u8> {
+    let mut key = OUTBOX_PREFIX.to_vec();
+    key.extend(hash.0);
+    key
+}
+
+/// The hash of the change an outbox metadata key is for, or `None` if it is not an outbox key.
+pub fn decode_outbox_key(key: &[u8]) -> Option<ChangeHash> {
+    key.strip_prefix(OUTBOX_PREFIX)
+        .and_then(|hash| ChangeHash::try_from(hash).ok())
+}
+
+/// Encode a list of hashes by concatenating them.
+pub fn encode_hashes(hashes: &[ChangeHash]) -> Vec<u8> {
+    hashes.iter().flat_map(|hash| hash.0).collect()
+}
+
+/// Decode hashes encoded by [`encode_hashes`], returning `None` if the bytes are malformed.
+pub fn decode_hashes(bytes: &[u8]) -> Option<Vec<ChangeHash>> {
+    if !bytes.len().is_multiple_of(32) {
+        return None;
+    }
+    bytes
+        .chunks_exact(32)
+        .map(|chunk| ChangeHash::try_from(chunk).ok())
+        .collect()
+}
+
+/// Encode a time as big endian milliseconds since the unix epoch.
+pub fn encode_time(time: SystemTime) -> Vec<u8> {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    millis.to_be_bytes().to_vec()
+}
+
+/// Decode a time encoded by [`encode_time`], returning `None` if the bytes are malformed.
+pub fn decode_time(bytes: &[u8]) -> Option<SystemTime> {
+    let millis = u64::from_be_bytes(bytes.try_into().ok()?);
+    UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+}
+
+/// Decode the bytes stored under [`COMPACTED_CHANGES_KEY`].
+pub fn decode_compacted_changes(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Encode a journal entry as its big endian length followed by the data, so a torn write can be
+/// detected.
+pub fn encode_journal(data: &[u8]) -> Vec<u8> {
+    let mut bytes = (data.len() as u64).to_be_bytes().to_vec();
+    bytes.extend(data);
+    bytes
+}
+
+/// Decode a journal entry encoded by [`encode_journal`], returning `None` if it is incomplete.
+pub fn decode_journal(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.len() < 8 {
+        return None;
+    }
+    let (len, data) = bytes.split_at(8);
+    let len = u64::from_be_bytes(len.try_into().ok()?);
+    (data.len() as u64 == len).then_some(data)
+}
+
+/// Encode a conditional document write as a flag for whether a version is expected, the big
+/// endian length of the version and the version, followed by the document.
+pub fn encode_conditional_document(expected: Option<&DocumentVersion>, document: &[u8]) -> Vec<u8> {
+    let version = expected.map_or(&[][..], |version| &version.0);
+    let mut bytes = vec![u8::from(expected.is_some())];
+    bytes.extend(&(version.len() as u32).to_be_bytes());
+    bytes.extend(version);
+    bytes.extend(document);
+    bytes
+}
+
+/// Decode a conditional document write encoded by [`encode_conditional_document`], returning
+/// `None` if the bytes are malformed.
+pub fn decode_conditional_document(bytes: &[u8]) -> Option<(Option<DocumentVersion>, &[u8])> {
+    let (&flag, rest) = bytes.split_first()?;
+    if rest.len() < 4 {
+        return None;
+    }
+    let (len, rest) = rest.split_at(4);
+    let len = u32::from_be_bytes(len.try_into().ok()?) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (version, document) = rest.split_at(len);
+    let expected = match flag {
+        0 => None,
+        1 => Some(DocumentVersion(version.to_vec())),
+        _ => return None,
+    };
+    Some((expected, document))
+}
+
+/// Encode a compaction as a conditional document write, see [`encode_conditional_document`],
+/// whose document is its big endian length and the document followed by the encoded change keys.
+pub fn encode_compaction(
+    expected: Option<&DocumentVersion>,
+    document: &[u8],
+    changes: &[(&ActorId, u64)],
+) -> Vec<u8> {
+    let mut body = (document.len() as u64).to_be_bytes().to_vec();
+    body.extend(document);
+    body.extend(encode_change_keys(changes));
+    encode_conditional_document(expected, &body)
+}
+
+/// A decoded compaction, the expected version, the document and the keys of the changes to
+/// remove.
+pub type Compaction<'a> = (Option<DocumentVersion>, &'a [u8], Vec<(ActorId, u64)>);
+
+/// Decode a compaction encoded by [`encode_compaction`], returning `None` if the bytes are
+/// malformed.
+pub fn decode_compaction(bytes: &[u8]) -> Option<Compaction<'_>> {
+    let (expected, body) = decode_conditional_document(bytes)?;
+    if body.len() < 8 {
+        return None;
+    }
+    let (len, rest) = body.split_at(8);
+    let len = usize::try_from(u64::from_be_bytes(len.try_into().ok()?)).ok()?;
+    if rest.len() < len {
+        return None;
+    }
+    let (document, changes) = rest.split_at(len);
+    Some((expected, document, decode_change_keys(changes)?))
+}
+
+/// Encode change keys as a length prefixed actor id followed by the big endian sequence number.
+pub fn encode_change_keys(changes: &[(&ActorId, u64)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (actor, seq) in changes {
+        let actor = actor.to_bytes();
+        bytes.extend(&(actor.len() as u32).to_be_bytes());
+        bytes.extend(actor);
+        bytes.extend(&seq.to_be_bytes());
+    }
+    bytes
+}
+
+/// Decode change keys encoded by [`encode_change_keys`], returning `None` if the bytes are
+/// malformed.
+pub fn decode_change_keys(mut bytes: &[u8]) -> Option<Vec<(ActorId, u64)>> {
+    let mut changes = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let (len, rest) = bytes.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().ok()?) as usize;
+        if rest.len() < len + 8 {
+            return None;
+        }
+        let (actor, rest) = rest.split_at(len);
+        let (seq, rest) = rest.split_at(8);
+        changes.push((
+            ActorId::from(actor),
+            u64::from_be_bytes(seq.try_into().ok()?),
+        ));
+        bytes = rest;
+    }
+    Some(changes)
+}
+
+/// Encode each missing dependency as its hash, the big endian number of changes waiting on it and
+/// their hashes.
+pub fn encode_missing_deps(missing: &HashMap<ChangeHash, HashSet<ChangeHash>>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (dependency, dependents) in missing {
+        bytes.extend(dependency.0);
+        bytes.extend(&(dependents.len() as u32).to_be_bytes());
+        for dependent in dependents {
+            bytes.extend(dependent.0);
+        }
+    }
+    bytes
+}
+
+/// Decode missing dependencies encoded by [`encode_missing_deps`], returning `None` if the bytes
+/// are malformed.
+pub fn decode_missing_deps(mut bytes: &[u8]) -> Option<HashMap<ChangeHash, HashSet<ChangeHash>>> {
+    let mut missing = HashMap::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 36 {
+            return None;
+        }
+        let (dependency, rest) = bytes.split_at(32);
+        let (count, rest) = rest.split_at(4);
+        let len = u32::from_be_bytes(count.try_into().ok()?) as usize * 32;
+        if rest.len() < len {
+            return None;
+        }
+        let (dependents, rest) = rest.split_at(len);
+        missing.insert(
+            ChangeHash::try_from(dependency).ok()?,
+            decode_hashes(dependents)?.into_iter().collect(),
+        );
+        bytes = rest;
+    }
+    Some(missing)
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+use automerge::ChangeHash;
+
+/// What a compaction did, passed to [`Observer::on_compacted`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionResult {
+    /// The number of changes removed now that they are in the saved document.
+    pub changes_removed: usize,
+    /// The size of the saved document in bytes.
+    pub document_size: usize,
+    /// The number of sync states removed.
+    pub sync_states_removed: usize,
+}
+
+/// Callbacks for events in the persistence lifecycle of a document.
+///
+/// This lets applications drive indicators like "saved" or trigger replication without polling.
+/// Every method does nothing by default so only the events of interest need implementing. The
+/// callbacks run synchronously in the operation that triggered them, after it has succeeded.
+///
+/// ```rust
+/// # use std::sync::{Arc, Mutex};
+/// # use automerge::{transaction::Transactable, ChangeHash, ROOT};
+/// # use automerge_persistent::{MemoryPersister, Observer, PersistentAutomerge};
+/// #[derive(Default)]
+/// struct Saved(Arc<Mutex<Vec<ChangeHash>>>);
+///
+/// impl Observer for Saved {
+///     fn on_changes_persisted(&mut self, hashes: &[ChangeHash]) {
+///         self.0.lock().unwrap().extend(hashes);
+///     }
+/// }
+///
+/// let saved = Saved::default();
+/// let persisted = Arc::clone(&saved.0);
+/// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+/// doc.set_observer(saved);
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+/// assert_eq!(*persisted.lock().unwrap(), doc.document().get_heads());
+/// ```
+pub trait Observer: Send + Sync {
+    /// Called once changes have been persisted, whether local or received from peers.
+    fn on_changes_persisted(&mut self, _hashes: &[ChangeHash]) {}
+
+    /// Called once a compaction has completed.
+    fn on_compacted(&mut self, _result: CompactionResult) {}
+
+    /// Called once the document has been loaded, with its heads, see
+    /// [`crate::PersistentAutomerge::load_observed`].
+    fn on_load_complete(&mut self, _heads: &[ChangeHash]) {}
+}
+
+/// The observer of a document, if one is set.
+#[derive(Default)]
+pub struct ObserverSlot(Option<Box<dyn Observer>>);
+
+impl ObserverSlot {
+    pub fn set(&mut self, observer: Box<dyn Observer>) {
+        self.0 = Some(observer);
+    }
+
+    pub fn take(&mut self) -> Option<Box<dyn Observer>> {
+        self.0.take()
+    }
+
+    pub fn changes_persisted(&mut self, hashes: &[ChangeHash]) {
+        if hashes.is_empty() {
+            return;
+        }
+        log_debug!("persisted {} changes", hashes.len());
+        if let Some(observer) = &mut self.0 {
+            observer.on_changes_persisted(hashes);
+        }
+    }
+
+    pub fn compacted(&mut self, result: CompactionResult) {
+        log_info!(
+            "compacted into a document of {} bytes, removing {} changes and {} sync states",
+            result.document_size,
+            result.changes_removed,
+            result.sync_states_removed
+        );
+        if let Some(observer) = &mut self.0 {
+            observer.on_compacted(result);
+        }
+    }
+
+    pub fn load_complete(&mut self, heads: &[ChangeHash]) {
+        if let Some(observer) = &mut self.0 {
+            observer.on_load_complete(heads);
+        }
+    }
+}
+
+impl fmt::Debug for ObserverSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ObserverSlot")
+            .field(&self.0.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    convert::TryFrom,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use automerge::Change;
+
+/// Which of the persisted data to rebuild the document from when loading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadMode {
+    /// Load the saved document and apply the individual changes on top.
+    #[default]
+    Combined,
+    /// Trust the saved document and skip applying the individual changes.
+    DocumentOnly,
+    /// Ignore the saved document and rebuild purely from the individual changes.
+    ///
+    /// This is intended for recovering when the saved document is corrupt. Changes that were
+    /// removed by a compaction are only in the saved document so will be missing.
+    ChangesOnly,
+}
+
+/// Which of the changes included in the saved document [`crate::PersistentAutomerge::compact`]
+/// keeps stored individually, for keeping raw history around such as for audits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RetentionPolicy {
+    /// Remove every change included in the saved document, keeping only those since the last
+    /// snapshot.
+    #[default]
+    SinceLastSnapshot,
+    /// Keep every change forever, compacting only saves the document.
+    KeepAll,
+    /// Keep the changes with a commit time within this long before compacting.
+    ///
+    /// The time of a change is set by whoever made it, so a change from a peer with a bad clock
+    /// may be kept for longer or shorter. Changes committed without a time, which is the default
+    /// in automerge, have a time of `0` and are always kept as their age is unknown.
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{
+    /// #     LoadOptions, MemoryPersister, PersistentAutomerge, Persister, RetentionPolicy,
+    /// # };
+    /// let retention = RetentionPolicy::KeepFor(Duration::from_secs(60));
+    /// let options = LoadOptions::default().with_retention(retention);
+    /// let mut doc = PersistentAutomerge::load_with(MemoryPersister::default(), options).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// doc.compact(&[]).unwrap();
+    ///
+    /// // the change has no time, so it is kept
+    /// assert_eq!(doc.persister().get_changes().unwrap().len(), 1);
+    /// ```
+    KeepFor(Duration),
+}
+
+impl RetentionPolicy {
+    /// Whether a change included in the saved document may be removed at the time given.
+    pub(crate) fn removes(self, change: &Change, now: SystemTime) -> bool {
+        match self {
+            Self::SinceLastSnapshot => true,
+            Self::KeepAll => false,
+            Self::KeepFor(duration) => {
+                let cutoff = now
+                    .checked_sub(duration)
+                    .and_then(|cutoff| cutoff.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |cutoff| {
+                        i64::try_from(cutoff.as_millis()).unwrap_or(i64::MAX)
+                    });
+                change.time != 0 && change.time < cutoff
+            }
+        }
+    }
+}
+
+/// A function converting a saved document in an older format, see
+/// [`LoadOptions::migrate_document`].
+pub type MigrateDocument = fn(&[u8]) -> Option<Vec<u8>>;
+
+/// Options for loading a document from a persister.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Which of the persisted data to load from.
+    pub mode: LoadMode,
+    /// Whether to check that the saved document and the individual changes are consistent with
+    /// each other, returning [`crate::Error::InconsistentStorage`] if not.
+    pub verify_consistency: bool,
+    /// Convert a saved document in an older format, such as from before an automerge upgrade,
+    /// into one that can be loaded.
+    ///
+    /// This is called with the stored bytes before loading them, detecting their version and
+    /// returning the converted document or `None` if it is already current. A converted document
+    /// is written back to storage once loaded.
+    pub migrate_document: Option<MigrateDocument>,
+    /// Whether to keep an outbox of local changes until they are marked as sent, see
+    /// [`crate::PersistentAutomerge::outbox`].
+    pub track_outbox: bool,
+    /// Which changes compacting keeps stored individually.
+    pub retention: RetentionPolicy,
+    /// Whether compacting marks the changes to remove as tombstones and verifies the saved
+    /// document holds them before removing them, see
+    /// [`crate::PersistentAutomerge::finalize_compaction`].
+    pub two_phase_compaction: bool,
+    /// Whether to read back the document after writing it and check its checksum before removing
+    /// the changes it holds, returning [`crate::Error::UnverifiedWrite`] if it doesn't match.
+    pub verify_writes: bool,
+    /// Whether to move stored changes that can't be decoded into quarantine and load without
+    /// them, rather than failing, see [`crate::Persister::quarantine`].
+    pub quarantine_invalid_changes: bool,
+}
+
+impl LoadOptions {
+    /// Set the mode to load with.
+    #[must_use]
+    pub const fn with_mode(mut self, mode: LoadMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Set the mode to load with.
+    pub const fn set_mode(&mut self, mode: LoadMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Set whether to verify the consistency of the stored data.
+    #[must_use]
+    pub const fn with_verify_consistency(mut self, verify_consistency: bool) -> Self {
+        self.verify_consistency = verify_consistency;
+        self
+    }
+
+    /// Set whether to verify the consistency of the stored data.
+    pub const fn set_verify_consistency(&mut self, verify_consistency: bool) -> &mut Self {
+        self.verify_consistency = verify_consistency;
+        self
+    }
+
+    /// Set the function to convert saved documents in older formats.
+    #[must_use]
+    pub const fn with_migrate_document(mut self, migrate: MigrateDocument) -> Self {
+        self.migrate_document = Some(migrate);
+        self
+    }
+
+    /// Set the function to convert saved documents in older formats.
+    pub const fn set_migrate_document(&mut self, migrate: MigrateDocument) -> &mut Self {
+        self.migrate_document = Some(migrate);
+        self
+    }
+
+    /// Set whether to keep an outbox of local changes.
+    #[must_use]
+    pub const fn with_track_outbox(mut self, track_outbox: bool) -> Self {
+        self.track_outbox = track_outbox;
+        self
+    }
+
+    /// Set whether to keep an outbox of local changes.
+    pub const fn set_track_outbox(&mut self, track_outbox: bool) -> &mut Self {
+        self.track_outbox = track_outbox;
+        self
+    }
+
+    /// Set which changes compacting keeps stored individually.
+    #[must_use]
+    pub const fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Set which changes compacting keeps stored individually.
+    pub const fn set_retention(&mut self, retention: RetentionPolicy) -> &mut Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Set whether to verify the saved document before removing the changes it holds.
+    #[must_use]
+    pub const fn with_two_phase_compaction(mut self, two_phase_compaction: bool) -> Self {
+        self.two_phase_compaction = two_phase_compaction;
+        self
+    }
+
+    /// Set whether to verify the saved document before removing the changes it holds.
+    pub const fn set_two_phase_compaction(&mut self, two_phase_compaction: bool) -> &mut Self {
+        self.two_phase_compaction = two_phase_compaction;
+        self
+    }
+
+    /// Set whether to read back the document after writing it.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{LoadOptions, MemoryPersister, PersistentAutomerge, Persister};
+    /// let options = LoadOptions::default().with_verify_writes(true);
+    /// let mut doc = PersistentAutomerge::load_with(MemoryPersister::default(), options).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// // the document read back matches so the changes are removed
+    /// doc.compact(&[]).unwrap();
+    /// assert!(doc.persister().get_changes().unwrap().is_empty());
+    /// ```
+    #[must_use]
+    pub const fn with_verify_writes(mut self, verify_writes: bool) -> Self {
+        self.verify_writes = verify_writes;
+        self
+    }
+
+    /// Set whether to read back the document after writing it.
+    pub const fn set_verify_writes(&mut self, verify_writes: bool) -> &mut Self {
+        self.verify_writes = verify_writes;
+        self
+    }
+
+    /// Set whether to quarantine stored changes that can't be decoded.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{LoadOptions, MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// let (document, mut persister) = doc.into_inner();
+    ///
+    /// // corrupt the end of the stored change
+    /// let mut bytes = persister.get_changes().unwrap().remove(0);
+    /// *bytes.last_mut().unwrap() ^= 1;
+    /// let actor = document.get_actor().clone();
+    /// persister.insert_changes(vec![(actor, 1, bytes)]).unwrap();
+    ///
+    /// let options = LoadOptions::default().with_quarantine_invalid_changes(true);
+    /// let doc = PersistentAutomerge::load_with(persister, options).unwrap();
+    /// assert!(doc.document().get(ROOT, "a").unwrap().is_none());
+    /// assert!(doc.persister().get_changes().unwrap().is_empty());
+    /// assert_eq!(doc.persister().get_quarantined().unwrap().len(), 1);
+    /// ```
+    #[must_use]
+    pub const fn with_quarantine_invalid_changes(mut self, quarantine: bool) -> Self {
+        self.quarantine_invalid_changes = quarantine;
+        self
+    }
+
+    /// Set whether to quarantine stored changes that can't be decoded.
+    pub const fn set_quarantine_invalid_changes(&mut self, quarantine: bool) -> &mut Self {
+        self.quarantine_invalid_changes = quarantine;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use automerge::{transaction::Transactable, Automerge, Change, ROOT};
+
+    use super::RetentionPolicy;
+
+    fn change_at(time: i64) -> Change {
+        let mut doc = Automerge::new();
+        let mut tx = doc.transaction();
+        tx.put(ROOT, "a", 1).unwrap();
+        tx.commit_with::<()>(automerge::transaction::CommitOptions::default().with_time(time));
+        doc.get_last_local_change().unwrap().clone()
+    }
+
+    #[test]
+    fn keep_for_keeps_recent_and_untimed_changes() {
+        let now = SystemTime::now();
+        let millis = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        let policy = RetentionPolicy::KeepFor(Duration::from_secs(60));
+
+        assert!(!policy.removes(&change_at(0), now));
+        assert!(!policy.removes(&change_at(millis(now)), now));
+        assert!(policy.removes(&change_at(millis(now - Duration::from_secs(120))), now));
+    }
+}
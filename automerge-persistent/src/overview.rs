@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use automerge::{ActorId, AutomergeError, Change};
+
+use crate::{
+    metadata::{self, ACTOR_ID_KEY, ACTOR_SEQS_KEY},
+    Backend, Error, PersistentAutomerge, Persister, StoredSizes,
+};
+
+/// A summary of what a persister holds, see [`storage_overview`].
+#[derive(Debug, Clone)]
+pub struct StorageOverview {
+    /// The actors that have changes stored.
+    pub actors: Vec<ActorId>,
+    /// The actor id used for local changes, if one has been stored.
+    pub local_actor: Option<ActorId>,
+    /// The peers that have sync states stored.
+    pub peer_ids: Vec<Vec<u8>>,
+    /// The bytes stored for each of the stored types.
+    pub sizes: StoredSizes,
+}
+
+/// The individually stored changes of one actor, see
+/// [`PersistentAutomerge::storage_breakdown`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ActorStorage {
+    /// The number of changes stored.
+    pub changes: usize,
+    /// The bytes of the changes as stored.
+    pub bytes: u64,
+}
+
+/// Summarise what the persister holds without loading the document.
+///
+/// This is intended for tooling that wants to inspect storage, such as who has written to a
+/// document, without paying for a full [`crate::PersistentAutomerge::load`].
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{storage_overview, MemoryPersister, PersistentAutomerge};
+/// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+/// let actor = doc.actor_id().clone();
+///
+/// let overview = storage_overview(&doc.close().unwrap()).unwrap();
+/// assert_eq!(overview.actors, vec![actor.clone()]);
+/// assert_eq!(overview.local_actor, Some(actor));
+/// ```
+///
+/// # Errors
+///
+/// Returns the error from the persister when reading from it.
+pub fn storage_overview<P>(persister: &P) -> Result<StorageOverview, P::Error>
+where
+    P: Persister + ?Sized,
+{
+    Ok(StorageOverview {
+        actors: persister.list_actors()?,
+        local_actor: persister.get_metadata(ACTOR_ID_KEY)?.map(ActorId::from),
+        peer_ids: persister.get_peer_ids()?,
+        sizes: persister.sizes(),
+    })
+}
+
+/// Read the highest sequence number stored for each actor without loading the document, see
+/// [`crate::PersistentAutomerge::actor_seq_summary`].
+///
+/// This is written along with every change persisted, so gossip protocols can advertise what is
+/// stored in a few bytes per actor straight after starting, while the document is still loading.
+/// Changes waiting on missing dependencies are not included. This is `None` if the storage was
+/// written before the summary was kept, or holds no changes.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{frontier, MemoryPersister, PersistentAutomerge};
+/// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+/// for i in 0..2 {
+///     doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///         tx.put(ROOT, "a", i).unwrap();
+///         Ok(())
+///     })
+///     .unwrap();
+/// }
+/// let actor = doc.actor_id().clone();
+///
+/// let frontier = frontier(doc.persister()).unwrap().unwrap();
+/// assert_eq!(frontier.get(&actor), Some(&2));
+/// ```
+///
+/// # Errors
+///
+/// Returns the error from the persister when reading from it.
+pub fn frontier<P>(persister: &P) -> Result<Option<HashMap<ActorId, u64>>, P::Error>
+where
+    P: Persister + ?Sized,
+{
+    Ok(persister
+        .get_metadata(ACTOR_SEQS_KEY)?
+        .and_then(|bytes| metadata::decode_change_keys(&bytes))
+        .map(|seqs| seqs.into_iter().collect()))
+}
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Break down the individually stored changes by the actor that made them, to find which
+    /// collaborator or device is behind a growing history.
+    ///
+    /// This reads and decodes every stored change. Changes already compacted into the stored
+    /// document are not counted.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// for i in 0..3 {
+    ///     doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "a", i).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// }
+    ///
+    /// let breakdown = doc.storage_breakdown().unwrap();
+    /// let local = &breakdown[doc.actor_id()];
+    /// assert_eq!(local.changes, 3);
+    /// let stored = doc.persister().get_changes().unwrap();
+    /// assert_eq!(local.bytes, stored.iter().map(|c| c.len() as u64).sum::<u64>());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the changes could not be read or decoded.
+    pub fn storage_breakdown(&self) -> Result<HashMap<ActorId, ActorStorage>, Error<P::Error>> {
+        let mut breakdown: HashMap<ActorId, ActorStorage> = HashMap::new();
+        for bytes in self
+            .persister
+            .get_changes()
+            .map_err(Error::PersisterError)?
+        {
+            let len = bytes.len() as u64;
+            let change = Change::from_bytes(bytes).map_err(AutomergeError::from)?;
+            let actor = breakdown.entry(change.actor_id().clone()).or_default();
+            actor.changes += 1;
+            actor.bytes += len;
+        }
+        Ok(breakdown)
+    }
+}
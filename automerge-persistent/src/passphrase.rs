@@ -0,0 +1,204 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::{Cipher, KeyDerivation};
+
+/// The length of the salts generated for new archives.
+const SALT_LEN: usize = 16;
+/// The length of the nonce at the start of each ciphertext.
+const NONCE_LEN: usize = 24;
+
+/// Errors from [`Argon2id`] and the [`XChaCha20`] cipher it derives.
+#[derive(Debug, thiserror::Error)]
+pub enum PassphraseError {
+    /// Deriving the key from the passphrase failed, such as for a salt that is too short.
+    #[error("deriving the key failed: {0}")]
+    KeyDerivation(argon2::Error),
+    /// The operating system couldn't provide random bytes for a salt or nonce.
+    #[error("generating random bytes failed: {0}")]
+    Random(chacha20poly1305::aead::rand_core::Error),
+    /// Encrypting failed.
+    #[error("encryption failed")]
+    Encryption,
+    /// The ciphertext failed authentication, either the passphrase is wrong or the data has been
+    /// changed.
+    #[error("decryption failed, the passphrase is wrong or the data has been changed")]
+    Decryption,
+}
+
+/// A [`KeyDerivation`] stretching the passphrase with argon2id in to the key of an
+/// [`XChaCha20`] cipher, for [`crate::export_encrypted_archive`].
+///
+/// The default parameters are those recommended by OWASP, 19 MiB of memory and two passes,
+/// taking a fraction of a second. They are needed again to import the archive so should only be
+/// changed along with every importer.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{
+/// #     export_encrypted_archive, import_encrypted_archive, Argon2id, MemoryPersister,
+/// #     PersistentAutomerge,
+/// # };
+/// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// let kdf = Argon2id::default();
+/// let mut backup = Vec::new();
+/// export_encrypted_archive(doc.persister(), &kdf, b"correct horse", &mut backup).unwrap();
+///
+/// let mut restored = MemoryPersister::default();
+/// assert!(
+///     import_encrypted_archive(&mut restored, &kdf, b"battery staple", backup.as_slice())
+///         .is_err()
+/// );
+/// import_encrypted_archive(&mut restored, &kdf, b"correct horse", backup.as_slice()).unwrap();
+/// let doc = PersistentAutomerge::load(restored).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 1);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Argon2id {
+    params: Params,
+}
+
+impl Argon2id {
+    /// Derive keys with the given argon2 parameters rather than the defaults.
+    #[must_use]
+    pub const fn new(params: Params) -> Self {
+        Self { params }
+    }
+}
+
+impl KeyDerivation for Argon2id {
+    type Cipher = XChaCha20;
+    type Error = PassphraseError;
+
+    fn new_salt(&self) -> Result<Vec<u8>, Self::Error> {
+        let mut salt = vec![0; SALT_LEN];
+        OsRng
+            .try_fill_bytes(&mut salt)
+            .map_err(PassphraseError::Random)?;
+        Ok(salt)
+    }
+
+    fn derive(&self, passphrase: &[u8], salt: &[u8]) -> Result<Self::Cipher, Self::Error> {
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+            .hash_password_into(passphrase, salt, &mut key)
+            .map_err(PassphraseError::KeyDerivation)?;
+        Ok(XChaCha20(XChaCha20Poly1305::new(&key.into())))
+    }
+}
+
+/// A [`Cipher`] authenticating and encrypting with XChaCha20-Poly1305, derived by [`Argon2id`].
+///
+/// Each ciphertext starts with the random nonce it was encrypted with, the extended nonce being
+/// long enough that random ones don't repeat.
+pub struct XChaCha20(XChaCha20Poly1305);
+
+impl std::fmt::Debug for XChaCha20 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("XChaCha20").finish_non_exhaustive()
+    }
+}
+
+impl Cipher for XChaCha20 {
+    type Error = PassphraseError;
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        let mut nonce = XNonce::default();
+        OsRng
+            .try_fill_bytes(&mut nonce)
+            .map_err(PassphraseError::Random)?;
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| PassphraseError::Encryption)?;
+        Ok(nonce.into_iter().chain(ciphertext).collect())
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        if ciphertext.len() < NONCE_LEN {
+            return Err(PassphraseError::Decryption);
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(NONCE_LEN);
+        self.0
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| PassphraseError::Decryption)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::Params;
+    use automerge::{transaction::Transactable, ROOT};
+
+    use super::{Argon2id, PassphraseError};
+    use crate::{
+        export_encrypted_archive, import_encrypted_archive, EncryptedArchiveError, MemoryPersister,
+        PersistentAutomerge, Persister,
+    };
+
+    /// Cheap parameters, the defaults are slow without optimisations.
+    fn kdf() -> Argon2id {
+        Argon2id::new(Params::new(8, 1, 1, None).unwrap())
+    }
+
+    fn backup(passphrase: &[u8]) -> Vec<u8> {
+        let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+        doc.transact::<_, _, std::convert::Infallible>(|tx| {
+            tx.put(ROOT, "a", 1).unwrap();
+            Ok(())
+        })
+        .unwrap();
+        let mut backup = Vec::new();
+        export_encrypted_archive(doc.persister(), &kdf(), passphrase, &mut backup).unwrap();
+        backup
+    }
+
+    #[test]
+    fn round_trips() {
+        let backup = backup(b"passphrase");
+        let mut restored = MemoryPersister::default();
+        import_encrypted_archive(&mut restored, &kdf(), b"passphrase", backup.as_slice()).unwrap();
+        let doc = PersistentAutomerge::load(restored).unwrap();
+        assert_eq!(doc.document().length(ROOT), 1);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let backup = backup(b"passphrase");
+        let mut restored = MemoryPersister::default();
+        let result = import_encrypted_archive(&mut restored, &kdf(), b"guess", backup.as_slice());
+        assert!(matches!(
+            result,
+            Err(EncryptedArchiveError::CipherError(
+                PassphraseError::Decryption
+            ))
+        ));
+        assert!(restored.get_changes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn tampered_archive_is_rejected() {
+        let mut backup = backup(b"passphrase");
+        let last = backup.len() - 1;
+        backup[last] ^= 1;
+        let mut restored = MemoryPersister::default();
+        let result =
+            import_encrypted_archive(&mut restored, &kdf(), b"passphrase", backup.as_slice());
+        assert!(matches!(
+            result,
+            Err(EncryptedArchiveError::CipherError(
+                PassphraseError::Decryption
+            ))
+        ));
+        assert!(restored.get_changes().unwrap().is_empty());
+    }
+}
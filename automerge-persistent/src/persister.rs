@@ -1,66 +1,149 @@
-use std::error::Error;
+use std::collections::HashSet;
 
-use automerge::ActorId;
+use automerge::{ActorId, Change};
 
-use crate::StoredSizes;
+use crate::{sharded::fnv1a, DocumentVersion, Error, Persister};
 
-/// A Persister persists both changes and documents to durable storage.
-///
-/// In the event of a power loss changes should still be around for loading after. It is up to the
-/// implementation to decide on trade-offs regarding how often to fsync for example.
-///
-/// Changes are identified by a pair of `actor_id` and `sequence_number`. This uniquely identifies a
-/// change and so is suitable for use as a key in the implementation.
-///
-/// Documents are saved automerge Backends so are more compact than the raw changes they represent.
-pub trait Persister {
-    /// The error type that the operations can produce
-    type Error: Error + 'static;
-
-    /// Returns all of the changes that have been persisted through this persister.
-    /// Ordering is not specified as the automerge Backend should handle that.
-    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
-
-    /// Inserts the given change at the unique address specified by the `actor_id` and `sequence_number`.
-    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error>;
-
-    /// Removes the change at the unique address specified by the `actor_id` and `sequence_number`.
-    ///
-    /// If the change does not exist this should not return an error.
-    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error>;
-
-    /// Returns the document, if one has been persisted previously.
-    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error>;
-
-    /// Sets the document to the given data.
-    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error>;
+/// Store the changes, addressed by hash if the persister is content-addressed.
+pub fn insert_changes<'a, P>(
+    persister: &mut P,
+    changes: impl IntoIterator<Item = &'a Change>,
+) -> Result<(), P::Error>
+where
+    P: Persister + ?Sized,
+{
+    insert_raw_changes(
+        persister,
+        changes.into_iter().map(|c| (c, c.raw_bytes().to_vec())),
+    )
+}
 
-    /// Returns the sync state for the given peer if one exists.
-    ///
-    /// A peer id corresponds to an instance of a backend and may be serving multiple frontends so
-    /// we cannot have it work on `ActorIds`.
-    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+/// Store the given bytes for each change, addressed by hash if the persister is
+/// content-addressed.
+pub fn insert_raw_changes<'a, P>(
+    persister: &mut P,
+    changes: impl IntoIterator<Item = (&'a Change, Vec<u8>)>,
+) -> Result<(), P::Error>
+where
+    P: Persister + ?Sized,
+{
+    if persister.content_addressed() {
+        persister.insert_changes_by_hash(
+            changes
+                .into_iter()
+                .map(|(c, bytes)| (c.hash, bytes))
+                .collect(),
+        )
+    } else {
+        persister.insert_changes(
+            changes
+                .into_iter()
+                .map(|(c, bytes)| (c.actor_id().clone(), c.seq, bytes))
+                .collect(),
+        )
+    }
+}
 
-    /// Sets the sync state for the given peer.
-    ///
-    /// A peer id corresponds to an instance of a backend and may be serving multiple frontends so
-    /// we cannot have it work on `ActorIds`.
-    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error>;
+/// Set the document if the stored one is still at `version`, so that a newer one set by another
+/// writer isn't lost, updating `version` to the new one.
+pub fn set_document_if_unchanged<P>(
+    persister: &mut P,
+    version: &mut Option<DocumentVersion>,
+    data: Vec<u8>,
+) -> Result<(), Error<P::Error>>
+where
+    P: Persister + ?Sized,
+{
+    *version = persister
+        .set_document_if(data, version.as_ref())
+        .map_err(Error::PersisterError)?
+        .map_err(Error::VersionConflict)?;
+    Ok(())
+}
 
-    /// Removes the sync states associated with the given `peer_ids`.
-    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error>;
+/// Set the document like [`set_document_if_unchanged`] and remove the changes it includes with
+/// [`Persister::compact_if`], so that persisters with transactions do both at once.
+///
+/// Content-addressed persisters have the changes removed afterwards by hash as well, as with
+/// [`remove_changes`].
+pub fn compact_if_unchanged<'a, P>(
+    persister: &mut P,
+    version: &mut Option<DocumentVersion>,
+    data: Vec<u8>,
+    changes: impl IntoIterator<Item = &'a Change>,
+) -> Result<(), Error<P::Error>>
+where
+    P: Persister + ?Sized,
+{
+    let changes = changes.into_iter().collect::<Vec<_>>();
+    *version = persister
+        .compact_if(
+            data,
+            version.as_ref(),
+            changes.iter().map(|c| (c.actor_id(), c.seq)).collect(),
+        )
+        .map_err(Error::PersisterError)?
+        .map_err(Error::VersionConflict)?;
+    if persister.content_addressed() {
+        persister
+            .remove_changes_by_hash(&changes.iter().map(|c| c.hash).collect::<Vec<_>>())
+            .map_err(Error::PersisterError)?;
+    }
+    Ok(())
+}
 
-    /// Returns the list of peer ids with stored `SyncStates`.
-    ///
-    /// This is intended for use by users to see what `peer_ids` are taking space so that they can be
-    /// removed during a compaction.
-    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+/// Set the document like [`set_document_if_unchanged`], then if `verify` is set read it back and
+/// check it has the length and checksum of what was written, for storage that can silently
+/// truncate writes.
+pub fn set_document_verified<P>(
+    persister: &mut P,
+    version: &mut Option<DocumentVersion>,
+    data: Vec<u8>,
+    verify: bool,
+) -> Result<(), Error<P::Error>>
+where
+    P: Persister + ?Sized,
+{
+    let check = verify.then(|| (data.len(), fnv1a(&data)));
+    set_document_if_unchanged(persister, version, data)?;
+    let Some((len, checksum)) = check else {
+        return Ok(());
+    };
+    match persister.get_document().map_err(Error::PersisterError)? {
+        Some(read) if read.len() == len && fnv1a(&read) == checksum => Ok(()),
+        read => Err(Error::UnverifiedWrite {
+            written: len,
+            read: read.map(|read| read.len()),
+        }),
+    }
+}
 
-    /// Returns the sizes components being stored consume.
-    ///
-    /// This can be used as an indicator of when to compact the storage.
-    fn sizes(&self) -> StoredSizes;
+/// Remove the changes, by hash if the persister is content-addressed.
+///
+/// Content-addressed persisters also have the changes removed by `actor_id` so that any stored
+/// before switching modes are cleaned up.
+pub fn remove_changes<'a, P>(
+    persister: &mut P,
+    changes: impl IntoIterator<Item = &'a Change>,
+) -> Result<(), P::Error>
+where
+    P: Persister + ?Sized,
+{
+    let changes = changes.into_iter().collect::<Vec<_>>();
+    if persister.content_addressed() {
+        persister.remove_changes_by_hash(&changes.iter().map(|c| c.hash).collect::<Vec<_>>())?;
+    }
+    persister.remove_changes(changes.iter().map(|c| (c.actor_id(), c.seq)).collect())
+}
 
-    /// Flush the data out to disk.
-    fn flush(&mut self) -> Result<usize, Self::Error>;
+/// The distinct actors of the changes that can be decoded, for wrappers that store changes the
+/// inner persister can't decode itself.
+pub fn decode_actors(changes: Vec<Vec<u8>>) -> Vec<ActorId> {
+    changes
+        .into_iter()
+        .filter_map(|bytes| Change::from_bytes(bytes).ok())
+        .map(|change| change.actor_id().clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect()
 }
@@ -0,0 +1,62 @@
+use std::convert::TryFrom;
+
+use automerge::ActorId;
+
+use crate::{sharded::fnv1a, Persister};
+
+const MAGIC_BYTES: [u8; 4] = [0x85, 0x6f, 0x4a, 0x83];
+const BLOCK_TYPE_CHANGE: u8 = 1;
+
+/// Move change bytes that could not be decoded out of the changes and into quarantine.
+///
+/// The change is quarantined under its actor and sequence number when its header can still be
+/// read, such as when only its body is corrupt, and removed from the changes. Otherwise it can't
+/// be told where it is stored so it is quarantined under a hash of its bytes and left in place,
+/// quarantining it again on later loads overwrites the same record.
+pub fn quarantine_change<P>(persister: &mut P, bytes: Vec<u8>) -> Result<Vec<u8>, P::Error>
+where
+    P: Persister,
+{
+    let address = change_address(&bytes);
+    let key = match &address {
+        Some((actor, seq)) => format!("change/{}/{}", actor.to_hex_string(), seq),
+        None => format!("change/{:016x}", fnv1a(&bytes)),
+    }
+    .into_bytes();
+    persister.quarantine(key.clone(), bytes)?;
+    if let Some((actor, seq)) = address.filter(|_| !persister.content_addressed()) {
+        persister.remove_changes(vec![(&actor, seq)])?;
+    }
+    Ok(key)
+}
+
+/// The actor and sequence number from the header of an uncompressed change, without checking
+/// the rest of it.
+fn change_address(bytes: &[u8]) -> Option<(ActorId, u64)> {
+    let rest = bytes.strip_prefix(&MAGIC_BYTES)?.get(4..)?;
+    let (&chunk_type, mut rest) = rest.split_first()?;
+    if chunk_type != BLOCK_TYPE_CHANGE {
+        return None;
+    }
+    // the chunk length, then the dependencies
+    read_uleb(&mut rest)?;
+    let deps = usize::try_from(read_uleb(&mut rest)?).ok()?;
+    rest = rest.get(deps.checked_mul(32)?..)?;
+    let actor_len = usize::try_from(read_uleb(&mut rest)?).ok()?;
+    let actor = rest.get(..actor_len)?;
+    rest = &rest[actor_len..];
+    let seq = read_uleb(&mut rest)?;
+    Some((ActorId::from(actor), seq))
+}
+
+fn read_uleb(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
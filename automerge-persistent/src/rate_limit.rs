@@ -0,0 +1,282 @@
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use automerge::{ActorId, ChangeHash};
+
+use crate::{
+    forward_persister, DocumentVersion, Forward, Persister, PersisterLayer, VersionConflict,
+};
+
+/// Limits on the write rate of a [`RateLimitedPersister`].
+///
+/// This is also a [`PersisterLayer`] so can be used directly with [`crate::Stack::layer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The sustained number of write operations allowed each second, zero is treated as one.
+    pub writes_per_second: u32,
+    /// The number of write operations that can be made in a burst after being idle.
+    pub burst: u32,
+    /// Whether to wait for the limit to allow a write rather than returning
+    /// [`RateLimitError::RateLimited`].
+    pub wait: bool,
+}
+
+impl RateLimit {
+    /// Allow the given number of writes each second, with bursts of the same size, waiting when
+    /// the limit is exceeded.
+    pub const fn per_second(writes_per_second: u32) -> Self {
+        Self {
+            writes_per_second,
+            burst: writes_per_second,
+            wait: true,
+        }
+    }
+
+    /// Set the burst size.
+    #[must_use]
+    pub const fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    /// Set the burst size.
+    pub const fn set_burst(&mut self, burst: u32) -> &mut Self {
+        self.burst = burst;
+        self
+    }
+
+    /// Set whether to wait when the limit is exceeded.
+    #[must_use]
+    pub const fn with_wait(mut self, wait: bool) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    /// Set whether to wait when the limit is exceeded.
+    pub const fn set_wait(&mut self, wait: bool) -> &mut Self {
+        self.wait = wait;
+        self
+    }
+}
+
+impl<P> PersisterLayer<P> for RateLimit
+where
+    P: Persister,
+{
+    type Persister = RateLimitedPersister<P>;
+
+    fn layer(self, inner: P) -> Self::Persister {
+        RateLimitedPersister::new(inner, self)
+    }
+}
+
+/// Errors from a [`RateLimitedPersister`].
+#[derive(Debug, thiserror::Error)]
+pub enum RateLimitError<E>
+where
+    E: Error + 'static,
+{
+    /// The inner persister failed.
+    #[error(transparent)]
+    PersisterError(E),
+    /// The write was rejected as the limit has been reached.
+    #[error("write rate limit exceeded")]
+    RateLimited,
+}
+
+/// A persister that bounds the rate of write operations made to an inner persister.
+///
+/// Each call that modifies the store counts as one operation, so a batch of changes from
+/// `apply_changes` costs the same as a single change. Reads are never limited.
+///
+/// This protects shared remote backends from storms of writes, such as during an initial sync.
+/// By default writers wait for the limit to allow them, with [`RateLimit::wait`] unset they get a
+/// [`RateLimitError::RateLimited`] instead.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, RateLimit, Stack};
+/// let persister = Stack::new(MemoryPersister::default())
+///     .layer(RateLimit::per_second(100).with_burst(10))
+///     .into_persister();
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// for i in 0..20 {
+///     doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///         tx.put(ROOT, "a", i).unwrap();
+///         Ok(())
+///     })
+///     .unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RateLimitedPersister<P> {
+    inner: P,
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl<P> RateLimitedPersister<P>
+where
+    P: Persister,
+{
+    /// Wrap the persister, limiting writes to it.
+    pub fn new(inner: P, limit: RateLimit) -> Self {
+        Self {
+            inner,
+            limit,
+            tokens: f64::from(limit.burst),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Get the current limit.
+    pub const fn limit(&self) -> &RateLimit {
+        &self.limit
+    }
+
+    /// Get a reference to the inner persister.
+    pub const fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Take the inner persister back out.
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    /// Take a token for a write, waiting for one if needed.
+    fn acquire(&mut self) -> Result<(), RateLimitError<P::Error>> {
+        let rate = f64::from(self.limit.writes_per_second.max(1));
+        let burst = f64::from(self.limit.burst.max(1));
+        let now = Instant::now();
+        self.tokens = (now - self.refilled)
+            .as_secs_f64()
+            .mul_add(rate, self.tokens)
+            .min(burst);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            if !self.limit.wait {
+                return Err(RateLimitError::RateLimited);
+            }
+            std::thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / rate));
+            self.tokens = 1.0;
+            self.refilled = Instant::now();
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+impl<P> Forward for RateLimitedPersister<P>
+where
+    P: Persister,
+{
+    type Inner = P;
+    type Error = RateLimitError<P::Error>;
+
+    fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn map_error(error: P::Error) -> Self::Error {
+        RateLimitError::PersisterError(error)
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        self.acquire()?;
+        self.inner
+            .insert_changes(changes)
+            .map_err(RateLimitError::PersisterError)
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.acquire()?;
+        self.inner
+            .remove_changes(changes)
+            .map_err(RateLimitError::PersisterError)
+    }
+
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        self.acquire()?;
+        self.inner
+            .insert_changes_by_hash(changes)
+            .map_err(RateLimitError::PersisterError)
+    }
+
+    fn remove_changes_by_hash(&mut self, hashes: &[ChangeHash]) -> Result<(), Self::Error> {
+        self.acquire()?;
+        self.inner
+            .remove_changes_by_hash(hashes)
+            .map_err(RateLimitError::PersisterError)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.acquire()?;
+        self.inner
+            .set_document(data)
+            .map_err(RateLimitError::PersisterError)
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.acquire()?;
+        self.inner
+            .set_document_if(data, expected)
+            .map_err(RateLimitError::PersisterError)
+    }
+
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.acquire()?;
+        self.inner
+            .compact_if(data, expected, changes)
+            .map_err(RateLimitError::PersisterError)
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.acquire()?;
+        self.inner
+            .set_sync_state(peer_id, sync_state)
+            .map_err(RateLimitError::PersisterError)
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.acquire()?;
+        self.inner
+            .remove_sync_states(peer_ids)
+            .map_err(RateLimitError::PersisterError)
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.acquire()?;
+        self.inner
+            .set_metadata(key, value)
+            .map_err(RateLimitError::PersisterError)
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.acquire()?;
+        self.inner
+            .remove_metadata(key)
+            .map_err(RateLimitError::PersisterError)
+    }
+}
+
+forward_persister!(impl<P> for RateLimitedPersister<P> where P: Persister);
@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+
+use automerge::{AutomergeError, Change, ChangeHash};
+
+use crate::{persister, Backend, Error, PersistentAutomerge, Persister};
+
+/// What [`PersistentAutomerge::reconcile_with_storage`] found and repaired.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// Whether the stored document had been replaced since this one last read or wrote it.
+    ///
+    /// This is only detected for persisters that track document versions.
+    pub document_replaced: bool,
+    /// Changes in storage that this document didn't have, which have now been applied.
+    pub changes_applied: usize,
+    /// Changes in this document that were missing from storage, which have been stored again.
+    pub changes_restored: usize,
+    /// Pending changes that are no longer in storage, which are no longer tracked.
+    pub pending_removed: usize,
+}
+
+impl Reconciliation {
+    /// Whether this document and the storage had diverged.
+    pub const fn diverged(&self) -> bool {
+        self.document_replaced
+            || self.changes_applied > 0
+            || self.changes_restored > 0
+            || self.pending_removed > 0
+    }
+}
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Re-read the storage and bring this document back in line with it, such as after another
+    /// process has compacted the same storage.
+    ///
+    /// A compaction elsewhere replaces the stored document and removes the individual changes it
+    /// covers, so this document may be missing changes that are now only in the stored document,
+    /// still track pending changes that are no longer stored individually, and have a stale
+    /// document version that would fail the next compaction. This:
+    ///
+    /// - applies the changes from the stored document and the individually stored changes that
+    ///   this document doesn't have, without persisting them again
+    /// - stores again any changes of this document that are in neither
+    /// - forgets pending changes that are no longer stored
+    /// - takes on the version of the stored document
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    ///
+    /// // storage in line with the document has nothing to repair
+    /// assert!(!doc.reconcile_with_storage().unwrap().diverged());
+    ///
+    /// // the change going missing from storage is noticed and it is stored again
+    /// let actor = doc.actor_id().clone();
+    /// doc.persister_mut().remove_changes(vec![(&actor, 1)]).unwrap();
+    /// assert_eq!(doc.reconcile_with_storage().unwrap().changes_restored, 1);
+    /// assert_eq!(doc.persister().get_changes().unwrap().len(), 1);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage could not be read or written, or the stored document or
+    /// changes could not be decoded or applied.
+    pub fn reconcile_with_storage(&mut self) -> Result<Reconciliation, Error<P::Error>> {
+        let (document, version) = self
+            .persister
+            .get_document_versioned()
+            .map_err(Error::PersisterError)?;
+        let document_replaced = version != self.document_version;
+        self.document_version = version;
+
+        let stored = document
+            .as_deref()
+            .map(B::load)
+            .transpose()?
+            .unwrap_or_default();
+        let in_document = stored
+            .get_changes(&[])?
+            .into_iter()
+            .map(|c| c.hash)
+            .collect::<HashSet<_>>();
+        let individual = self
+            .persister
+            .get_changes()
+            .map_err(Error::PersisterError)?
+            .into_iter()
+            .map(|bytes| Change::from_bytes(bytes).map_err(AutomergeError::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        let individual_hashes = individual
+            .iter()
+            .map(|c| c.hash)
+            .collect::<HashSet<ChangeHash>>();
+
+        let missing = self
+            .document
+            .get_changes(&[])?
+            .into_iter()
+            .filter(|c| !in_document.contains(&c.hash) && !individual_hashes.contains(&c.hash))
+            .cloned()
+            .collect::<Vec<_>>();
+        persister::insert_changes(&mut self.persister, &missing).map_err(Error::PersisterError)?;
+
+        let mut seen = HashSet::new();
+        let unknown = stored
+            .get_changes(&[])?
+            .into_iter()
+            .cloned()
+            .chain(individual)
+            .filter(|c| !self.has_change(&c.hash) && seen.insert(c.hash))
+            .collect::<Vec<_>>();
+        let changes_applied = unknown.len();
+        let unknown_deps = unknown
+            .iter()
+            .map(|c| (c.hash, c.deps.clone()))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            if self.patch_subscribers.is_empty() {
+                self.document.apply_changes(unknown)?;
+            } else {
+                let patches = self.document.apply_changes_with_patches(unknown)?;
+                self.publish_patches(patches);
+            }
+            self.publish_heads();
+        }
+
+        // the new changes are tracked like persisted ones, with any still waiting on dependencies
+        // kept as pending
+        self.pending_hashes.extend(seen);
+        let document = &self.document;
+        let applied = self
+            .pending_hashes
+            .iter()
+            .filter_map(|hash| document.get_change_by_hash(hash))
+            .map(|c| (c.actor_id().clone(), c.seq))
+            .collect::<Vec<_>>();
+        self.pending_hashes
+            .retain(|hash| document.get_change_by_hash(hash).is_none());
+        for (actor, seq) in applied {
+            self.note_applied(actor, seq);
+        }
+        // pending changes are stored individually so any that aren't can't be loaded again
+        let pending_before = self.pending_hashes.len();
+        self.pending_hashes
+            .retain(|hash| individual_hashes.contains(hash));
+        let pending_removed = pending_before - self.pending_hashes.len();
+        self.track_missing_deps(
+            unknown_deps
+                .iter()
+                .map(|(hash, deps)| (hash, deps.as_slice())),
+        )
+        .map_err(Error::PersisterError)?;
+        self.save_actor_seqs().map_err(Error::PersisterError)?;
+
+        let reconciliation = Reconciliation {
+            document_replaced,
+            changes_applied,
+            changes_restored: missing.len(),
+            pending_removed,
+        };
+        if reconciliation.diverged() {
+            log_info!("reconciled with storage: {reconciliation:?}");
+        }
+        Ok(reconciliation)
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashSet;
+
+use automerge::{Change, ChangeHash};
+
+use crate::{persister, Backend, Error, PersistentAutomerge, Persister};
+
+/// The cut-off point for [`PersistentAutomerge::remove_changes_before`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoveBefore {
+    /// The changes that these heads depend on, including the heads themselves.
+    Heads(Vec<ChangeHash>),
+    /// The changes committed before this time, in milliseconds since the unix epoch.
+    Time(i64),
+}
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Save a snapshot of the document and remove the individually stored records of the changes
+    /// from before the cut-off, returning how many records were removed.
+    ///
+    /// **This is not erasure.** It frees storage but doesn't drop any history: the automerge
+    /// document format retains every change, so the content of the removed changes remains
+    /// within the saved snapshot and the in-memory document, and is sent to peers when syncing.
+    /// The document can't be rewritten from a snapshot at the cut-off either, as later changes
+    /// depend on the earlier ones by hash. To really remove content start a new document from
+    /// the current state and discard the old one along with its storage.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, RemoveBefore};
+    /// # use automerge_persistent::PersistentAutomerge;
+    /// # let persister = MemoryPersister::default();
+    /// # let mut doc = PersistentAutomerge::load(persister).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// let heads = doc.document().get_heads();
+    /// assert_eq!(doc.remove_changes_before(RemoveBefore::Heads(heads)).unwrap(), 1);
+    /// // already removed
+    /// assert_eq!(doc.remove_changes_before(RemoveBefore::Time(i64::MAX)).unwrap(), 0);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the heads are not in the document or the persister fails.
+    pub fn remove_changes_before(
+        &mut self,
+        cutoff: RemoveBefore,
+    ) -> Result<usize, Error<P::Error>> {
+        let all_changes = self.document.get_changes(&[])?;
+        let to_remove = match &cutoff {
+            RemoveBefore::Heads(heads) => {
+                let after = self
+                    .document
+                    .get_changes(heads)?
+                    .into_iter()
+                    .map(|c| c.hash)
+                    .collect::<HashSet<_>>();
+                all_changes
+                    .into_iter()
+                    .filter(|c| !after.contains(&c.hash))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            }
+            RemoveBefore::Time(time) => all_changes
+                .into_iter()
+                .filter(|c| c.time < *time)
+                .cloned()
+                .collect::<Vec<_>>(),
+        };
+
+        let saved_backend = self.document.save();
+        persister::set_document_verified(
+            &mut self.persister,
+            &mut self.document_version,
+            saved_backend,
+            self.verify_writes,
+        )?;
+        let stored = self
+            .persister
+            .get_changes()
+            .map_err(Error::PersisterError)?
+            .into_iter()
+            .filter_map(|bytes| Change::from_bytes(bytes).ok())
+            .map(|c| c.hash)
+            .collect::<HashSet<_>>();
+        let removed = to_remove
+            .iter()
+            .filter(|c| stored.contains(&c.hash))
+            .count();
+        persister::remove_changes(&mut self.persister, &to_remove)
+            .map_err(Error::PersisterError)?;
+        self.save_actor_seqs().map_err(Error::PersisterError)?;
+        Ok(removed)
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::Instant;
+
+use automerge::{AutomergeError, ChangeHash};
+
+use crate::{persister, Backend, Error, LoadMode, LoadOptions, PersistentAutomerge, Persister};
+
+/// What [`PersistentAutomerge::repair`] recovered of a document whose saved snapshot could not
+/// be loaded.
+#[derive(Debug)]
+pub struct RepairReport {
+    /// Why the saved document failed to load.
+    pub error: AutomergeError,
+    /// The number of stored changes the document was rebuilt from.
+    pub changes_recovered: usize,
+    /// Dependencies of stored changes that are missing, such as those only in the snapshot after
+    /// a compaction. Their edits are lost unless they are synced from a peer, the changes
+    /// depending on them stay stored and wait as pending until then.
+    pub missing_deps: Vec<ChangeHash>,
+    /// The size of the fresh snapshot saved in bytes.
+    pub document_size: usize,
+}
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Load the document, rebuilding it from the stored changes alone if the saved document
+    /// fails to load, so that a corrupt snapshot isn't a fatal error on startup.
+    ///
+    /// When the saved document loads this is the same as [`Self::load_backend`] and no report is
+    /// returned. Otherwise the corrupt document is moved into quarantine under `document`, see
+    /// [`Persister::quarantine`], the changes are loaded as with [`LoadMode::ChangesOnly`] and a
+    /// fresh snapshot of them is saved.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Automerge, ROOT};
+    /// # use automerge_persistent::{LoadOptions, MemoryPersister, PersistentAutomerge, Persister};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// let (mut document, mut persister) = doc.into_inner();
+    ///
+    /// // corrupt the end of the saved document
+    /// let mut bytes = document.save();
+    /// *bytes.last_mut().unwrap() ^= 1;
+    /// persister.set_document(bytes.clone()).unwrap();
+    ///
+    /// let (doc, report) =
+    ///     PersistentAutomerge::<_, Automerge>::repair(persister, LoadOptions::default()).unwrap();
+    /// let report = report.unwrap();
+    /// assert_eq!(report.changes_recovered, 1);
+    /// assert!(report.missing_deps.is_empty());
+    /// assert!(doc.document().get(ROOT, "a").unwrap().is_some());
+    /// assert_eq!(doc.persister().get_quarantined().unwrap()[0].1, bytes);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage could not be read or written, or the changes could not be
+    /// loaded.
+    pub fn repair(
+        mut persister: P,
+        options: LoadOptions,
+    ) -> Result<(Self, Option<RepairReport>), Error<P::Error>> {
+        let start = Instant::now();
+        let (error, bytes) = match Self::load_document(&mut persister, &options)? {
+            Ok(document) => {
+                return Ok((
+                    Self::load_changes(persister, options, document, start)?,
+                    None,
+                ));
+            }
+            Err(failure) => failure,
+        };
+
+        log_warn!(
+            "the saved document failed to load, rebuilding it from the changes: {}",
+            error
+        );
+        persister
+            .quarantine(b"document".to_vec(), bytes)
+            .map_err(Error::PersisterError)?;
+        let mut doc = Self::load_backend(persister, options.with_mode(LoadMode::ChangesOnly))?;
+        let document = doc.document.save();
+        let report = RepairReport {
+            error,
+            changes_recovered: doc.document.get_changes(&[])?.len(),
+            missing_deps: doc.document.get_missing_deps(&[]),
+            document_size: document.len(),
+        };
+        persister::set_document_if_unchanged(
+            &mut doc.persister,
+            &mut doc.document_version,
+            document,
+        )?;
+        log_info!(
+            "repaired the saved document from {} changes",
+            report.changes_recovered
+        );
+        Ok((doc, Some(report)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::Automerge;
+    use automerge_persistent_core::test_support::Probe;
+
+    use crate::{LoadOptions, PersistentAutomerge, Persister};
+
+    #[test]
+    fn loadable_document_is_read_once() {
+        let mut persister = Probe::default();
+        persister.set_document(Automerge::new().save()).unwrap();
+
+        let (doc, report) =
+            PersistentAutomerge::<_, Automerge>::repair(persister, LoadOptions::default()).unwrap();
+        assert!(report.is_none());
+        assert_eq!(doc.persister().mapped_reads(), 1);
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use automerge::{Automerge, AutomergeError, Change, ChangeHash, Patch};
+
+use crate::{Backend, DocumentVersion, Error, Persister};
+
+/// A read-only view of a document that another process writes, for deployments with one writer
+/// and many readers over the same storage.
+///
+/// Nothing is ever written to the persister. Call [`Self::refresh`] to pick up what the writer
+/// has stored since the last refresh, such as on a timer or when notified of a write.
+///
+/// ```rust
+/// # use std::sync::{Arc, Mutex};
+/// # use automerge::{transaction::Transactable, ActorId, ROOT};
+/// # use automerge_persistent::{
+/// #     MemoryPersister, Persister, PersistentAutomerge, ReplicaBackend, SharedPersister,
+/// #     StoredSizes,
+/// # };
+/// # #[derive(Debug, Default)]
+/// # struct LockedMemory(Mutex<MemoryPersister>);
+/// #
+/// # impl SharedPersister for LockedMemory {
+/// #     type Error = std::convert::Infallible;
+/// #
+/// #     fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+/// #         self.0.lock().unwrap().get_changes()
+/// #     }
+/// #     fn insert_changes(&self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+/// #         self.0.lock().unwrap().insert_changes(changes)
+/// #     }
+/// #     fn remove_changes(&self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+/// #         self.0.lock().unwrap().remove_changes(changes)
+/// #     }
+/// #     fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+/// #         self.0.lock().unwrap().get_document()
+/// #     }
+/// #     fn set_document(&self, data: Vec<u8>) -> Result<(), Self::Error> {
+/// #         self.0.lock().unwrap().set_document(data)
+/// #     }
+/// #     fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+/// #         self.0.lock().unwrap().get_sync_state(peer_id)
+/// #     }
+/// #     fn set_sync_state(&self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+/// #         self.0.lock().unwrap().set_sync_state(peer_id, sync_state)
+/// #     }
+/// #     fn remove_sync_states(&self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+/// #         self.0.lock().unwrap().remove_sync_states(peer_ids)
+/// #     }
+/// #     fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+/// #         self.0.lock().unwrap().get_peer_ids()
+/// #     }
+/// #     fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+/// #         self.0.lock().unwrap().get_metadata(key)
+/// #     }
+/// #     fn set_metadata(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+/// #         self.0.lock().unwrap().set_metadata(key, value)
+/// #     }
+/// #     fn remove_metadata(&self, key: &[u8]) -> Result<(), Self::Error> {
+/// #         self.0.lock().unwrap().remove_metadata(key)
+/// #     }
+/// #     fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+/// #         self.0.lock().unwrap().get_metadata_keys()
+/// #     }
+/// #     fn sizes(&self) -> StoredSizes {
+/// #         self.0.lock().unwrap().sizes()
+/// #     }
+/// #     fn flush(&self) -> Result<usize, Self::Error> {
+/// #         self.0.lock().unwrap().flush()
+/// #     }
+/// # }
+/// // storage shared between the writer and reader, such as a database both processes open
+/// let storage = Arc::new(LockedMemory::default());
+/// let mut writer = PersistentAutomerge::load(Arc::clone(&storage)).unwrap();
+/// let mut reader = ReplicaBackend::<_>::open(Arc::clone(&storage)).unwrap();
+///
+/// writer
+///     .transact::<_, _, std::convert::Infallible>(|tx| {
+///         tx.put(ROOT, "a", 1).unwrap();
+///         Ok(())
+///     })
+///     .unwrap();
+/// assert_eq!(reader.document().length(ROOT), 0);
+///
+/// let patches = reader.refresh().unwrap();
+/// assert_eq!(patches.len(), 1);
+/// assert_eq!(reader.document().length(ROOT), 1);
+///
+/// // the writer compacting doesn't lose anything
+/// writer.compact(&[]).unwrap();
+/// assert!(reader.refresh().unwrap().is_empty());
+/// assert_eq!(reader.document().get_heads(), writer.document().get_heads());
+/// ```
+#[derive(Debug)]
+pub struct ReplicaBackend<P, B = Automerge> {
+    document: B,
+    persister: P,
+    /// Changes read from storage that are waiting on their dependencies.
+    pending: HashSet<ChangeHash>,
+    /// The version of the stored document last read, along with a hash of it for persisters
+    /// that don't track versions.
+    document_version: Option<DocumentVersion>,
+    document_hash: Option<u64>,
+}
+
+impl<P, B> ReplicaBackend<P, B>
+where
+    P: Persister,
+    B: Backend,
+{
+    /// Open the document in the storage.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage could not be read or the document could not be rebuilt
+    /// from it.
+    pub fn open(persister: P) -> Result<Self, Error<P::Error>> {
+        let mut replica = Self {
+            document: B::default(),
+            persister,
+            pending: HashSet::new(),
+            document_version: None,
+            document_hash: None,
+        };
+        replica.refresh()?;
+        Ok(replica)
+    }
+
+    /// Read what has been stored since the last refresh into the document, returning the patches
+    /// of the changes.
+    ///
+    /// The stored document is only loaded again when it has been replaced, such as by a
+    /// compaction, so a refresh with nothing new costs reading the stored changes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage could not be read, or the stored document or changes
+    /// could not be decoded or applied.
+    pub fn refresh(&mut self) -> Result<Vec<Patch>, Error<P::Error>> {
+        let (document, version) = self
+            .persister
+            .get_document_versioned()
+            .map_err(Error::PersisterError)?;
+        let mut unknown = Vec::new();
+        if let Some(document) = document {
+            let mut hasher = DefaultHasher::new();
+            document.hash(&mut hasher);
+            let hash = hasher.finish();
+            let replaced = if version.is_some() {
+                version != self.document_version
+            } else {
+                Some(hash) != self.document_hash
+            };
+            if replaced {
+                let stored = B::load(&document)?;
+                unknown.extend(
+                    stored
+                        .get_changes(&[])?
+                        .into_iter()
+                        .filter(|c| !self.has_change(&c.hash))
+                        .cloned(),
+                );
+            }
+            self.document_hash = Some(hash);
+        }
+        self.document_version = version;
+
+        for bytes in self
+            .persister
+            .get_changes()
+            .map_err(Error::PersisterError)?
+        {
+            let change = Change::from_bytes(bytes).map_err(AutomergeError::from)?;
+            if !self.has_change(&change.hash) && !unknown.iter().any(|c| c.hash == change.hash) {
+                unknown.push(change);
+            }
+        }
+        if unknown.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.pending.extend(unknown.iter().map(|c| c.hash));
+        let patches = self.document.apply_changes_with_patches(unknown)?;
+        let document = &self.document;
+        self.pending
+            .retain(|hash| document.get_change_by_hash(hash).is_none());
+        Ok(patches)
+    }
+
+    /// Whether the change has been read, including if it is waiting on its dependencies.
+    pub fn has_change(&self, hash: &ChangeHash) -> bool {
+        self.pending.contains(hash) || self.document.get_change_by_hash(hash).is_some()
+    }
+
+    /// The document as of the last refresh.
+    pub const fn document(&self) -> &B {
+        &self.document
+    }
+
+    /// The persister being read from.
+    pub const fn persister(&self) -> &P {
+        &self.persister
+    }
+
+    /// Take the document and persister back out.
+    pub fn into_inner(self) -> (B, P) {
+        (self.document, self.persister)
+    }
+}
@@ -0,0 +1,181 @@
+use std::error::Error;
+
+use automerge::Change;
+
+use crate::{persister, Persister};
+
+/// The number of changes written to the destination at a time by [`replicate`].
+const CHANGES_BATCH: usize = 256;
+
+/// The kind of data being copied by [`replicate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicationStage {
+    /// The saved document.
+    Document,
+    /// The individually stored changes.
+    Changes,
+    /// The sync states of peers.
+    SyncStates,
+    /// The metadata, such as the actor id.
+    Metadata,
+}
+
+/// How far a [`replicate`] has got, given to its callback as records are copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicationProgress {
+    /// The kind of data being copied.
+    pub stage: ReplicationStage,
+    /// The records of this stage copied so far.
+    pub copied: usize,
+    /// The records of this stage to copy.
+    pub total: usize,
+    /// The bytes copied so far across all stages.
+    pub bytes: u64,
+}
+
+/// Errors from [`replicate`].
+#[derive(Debug, thiserror::Error)]
+pub enum ReplicationError<S, D>
+where
+    S: Error + 'static,
+    D: Error + 'static,
+{
+    /// Reading from the source failed.
+    #[error("reading from the source: {0}")]
+    Source(S),
+    /// Writing to the destination failed.
+    #[error("writing to the destination: {0}")]
+    Destination(D),
+    /// A change in the source could not be decoded to find its key.
+    #[error(transparent)]
+    InvalidChange(#[from] automerge::DecodingError),
+}
+
+/// Copy everything stored in one persister into another, such as to migrate a document between
+/// backends or mirror it to cold storage, returning the bytes copied.
+///
+/// The document, changes, sync states and metadata are copied in turn, with `progress` called
+/// after each record, or batch of changes, is written. Changes are stored under the keys the
+/// destination uses, by hash if it is content-addressed, and the destination is flushed at the
+/// end. The destination should be empty, as its document is replaced and records with the same
+/// keys are overwritten.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{
+/// #     replicate, MemoryPersister, PersistentAutomerge, ReplicationStage,
+/// # };
+/// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// let mut mirror = MemoryPersister::new_content_addressed();
+/// let mut changes_copied = 0;
+/// replicate(doc.persister(), &mut mirror, |progress| {
+///     if progress.stage == ReplicationStage::Changes {
+///         changes_copied = progress.copied;
+///     }
+/// })
+/// .unwrap();
+/// assert_eq!(changes_copied, 1);
+///
+/// let mirrored = PersistentAutomerge::load(mirror).unwrap();
+/// assert_eq!(mirrored.document().length(ROOT), 1);
+/// assert_eq!(mirrored.actor_id(), doc.actor_id());
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if reading from the source or writing to the destination fails, or a stored
+/// change can't be decoded. Records copied before the error are left in the destination.
+pub fn replicate<S, D, F>(
+    src: &S,
+    dst: &mut D,
+    mut progress: F,
+) -> Result<u64, ReplicationError<S::Error, D::Error>>
+where
+    S: Persister + ?Sized,
+    D: Persister + ?Sized,
+    F: FnMut(ReplicationProgress),
+{
+    let mut bytes = 0;
+
+    if let Some(document) = src.get_document().map_err(ReplicationError::Source)? {
+        bytes += document.len() as u64;
+        dst.set_document(document)
+            .map_err(ReplicationError::Destination)?;
+        progress(ReplicationProgress {
+            stage: ReplicationStage::Document,
+            copied: 1,
+            total: 1,
+            bytes,
+        });
+    }
+
+    let changes = src.get_changes().map_err(ReplicationError::Source)?;
+    let total = changes.len();
+    let mut copied = 0;
+    for batch in changes.chunks(CHANGES_BATCH) {
+        let decoded = batch
+            .iter()
+            .map(|bytes| Change::from_bytes(bytes.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        persister::insert_raw_changes(
+            dst,
+            decoded
+                .iter()
+                .zip(batch)
+                .map(|(c, bytes)| (c, bytes.clone())),
+        )
+        .map_err(ReplicationError::Destination)?;
+        copied += batch.len();
+        bytes += batch.iter().map(|c| c.len() as u64).sum::<u64>();
+        progress(ReplicationProgress {
+            stage: ReplicationStage::Changes,
+            copied,
+            total,
+            bytes,
+        });
+    }
+
+    let peer_ids = src.get_peer_ids().map_err(ReplicationError::Source)?;
+    let total = peer_ids.len();
+    for (index, peer_id) in peer_ids.into_iter().enumerate() {
+        if let Some(sync_state) = src
+            .get_sync_state(&peer_id)
+            .map_err(ReplicationError::Source)?
+        {
+            bytes += sync_state.len() as u64;
+            dst.set_sync_state(peer_id, sync_state)
+                .map_err(ReplicationError::Destination)?;
+        }
+        progress(ReplicationProgress {
+            stage: ReplicationStage::SyncStates,
+            copied: index + 1,
+            total,
+            bytes,
+        });
+    }
+
+    let keys = src.get_metadata_keys().map_err(ReplicationError::Source)?;
+    let total = keys.len();
+    for (index, key) in keys.into_iter().enumerate() {
+        if let Some(value) = src.get_metadata(&key).map_err(ReplicationError::Source)? {
+            bytes += value.len() as u64;
+            dst.set_metadata(key, value)
+                .map_err(ReplicationError::Destination)?;
+        }
+        progress(ReplicationProgress {
+            stage: ReplicationStage::Metadata,
+            copied: index + 1,
+            total,
+            bytes,
+        });
+    }
+
+    dst.flush().map_err(ReplicationError::Destination)?;
+    Ok(bytes)
+}
@@ -0,0 +1,411 @@
+use std::{fmt, time::Duration};
+
+use automerge::{ActorId, ChangeHash};
+
+use crate::{
+    forward_persister, DocumentVersion, Forward, MappedDocument, Persister, QuarantinedRecord,
+    StorageReport, VersionConflict, VersionedDocument,
+};
+
+/// How a [`RetryPersister`] retries failed operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The total number of attempts to make, including the first.
+    pub max_attempts: u32,
+    /// The time to wait before the first retry.
+    pub initial_backoff: Duration,
+    /// The factor the wait grows by after each retry.
+    pub multiplier: u32,
+    /// The longest time to wait between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(50),
+            multiplier: 2,
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Set the total number of attempts.
+    #[must_use]
+    pub const fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set the total number of attempts.
+    pub const fn set_max_attempts(&mut self, max_attempts: u32) -> &mut Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set the time to wait before the first retry.
+    #[must_use]
+    pub const fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Set the time to wait before the first retry.
+    pub const fn set_initial_backoff(&mut self, initial_backoff: Duration) -> &mut Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Set the factor the wait grows by after each retry.
+    #[must_use]
+    pub const fn with_multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Set the factor the wait grows by after each retry.
+    pub const fn set_multiplier(&mut self, multiplier: u32) -> &mut Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Set the longest time to wait between retries.
+    #[must_use]
+    pub const fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Set the longest time to wait between retries.
+    pub const fn set_max_backoff(&mut self, max_backoff: Duration) -> &mut Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Run the operation, retrying it while it fails with transient errors.
+    fn run<T, E>(
+        &self,
+        is_transient: impl Fn(&E) -> bool,
+        mut op: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(e) if attempt < self.max_attempts && is_transient(&e) => {
+                    log_warn!(
+                        "attempt {attempt} failed with a transient error, retrying in {backoff:?}"
+                    );
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * self.multiplier).min(self.max_backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A persister that retries operations on an inner persister when they fail with a transient
+/// error, backing off between attempts.
+///
+/// Which errors are transient is decided by the given function, other errors are returned
+/// straight away. Once the attempts run out the last error is returned, unchanged.
+///
+/// This keeps brief network failures in remote persisters from surfacing as a
+/// [`crate::Error::PersisterError`] from operations like `apply_changes`.
+///
+/// ```rust
+/// # use std::time::Duration;
+/// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, RetryPersister, RetryPolicy};
+/// let policy = RetryPolicy::default()
+///     .with_max_attempts(3)
+///     .with_initial_backoff(Duration::from_millis(10));
+/// let persister = RetryPersister::new(MemoryPersister::default(), policy, |_| true);
+/// let doc = PersistentAutomerge::load(persister).unwrap();
+/// ```
+pub struct RetryPersister<P, F> {
+    inner: P,
+    policy: RetryPolicy,
+    is_transient: F,
+}
+
+impl<P, F> fmt::Debug for RetryPersister<P, F>
+where
+    P: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPersister")
+            .field("inner", &self.inner)
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P, F> RetryPersister<P, F>
+where
+    P: Persister,
+    F: Fn(&P::Error) -> bool,
+{
+    /// Wrap the persister, retrying errors that `is_transient` returns true for.
+    pub const fn new(inner: P, policy: RetryPolicy, is_transient: F) -> Self {
+        Self {
+            inner,
+            policy,
+            is_transient,
+        }
+    }
+
+    /// Get the retry policy.
+    pub const fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Get a reference to the inner persister.
+    pub const fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Take the inner persister back out.
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P, F> Forward for RetryPersister<P, F>
+where
+    P: Persister,
+    F: Fn(&P::Error) -> bool,
+{
+    type Inner = P;
+    type Error = P::Error;
+
+    fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn map_error(error: P::Error) -> Self::Error {
+        error
+    }
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.policy
+            .run(&self.is_transient, || self.inner.get_changes())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || inner.insert_changes(changes.clone()))
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || inner.remove_changes(changes.clone()))
+    }
+
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || {
+            inner.insert_changes_by_hash(changes.clone())
+        })
+    }
+
+    fn remove_changes_by_hash(&mut self, hashes: &[ChangeHash]) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || inner.remove_changes_by_hash(hashes))
+    }
+
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        self.policy
+            .run(&self.is_transient, || self.inner.list_actors())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.policy
+            .run(&self.is_transient, || self.inner.get_document())
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || inner.set_document(data.clone()))
+    }
+
+    fn get_document_versioned(&self) -> Result<VersionedDocument, Self::Error> {
+        self.policy
+            .run(&self.is_transient, || self.inner.get_document_versioned())
+    }
+
+    fn get_document_mapped(&self) -> Result<MappedDocument, Self::Error> {
+        self.policy
+            .run(&self.is_transient, || self.inner.get_document_mapped())
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || {
+            inner.set_document_if(data.clone(), expected)
+        })
+    }
+
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || {
+            inner.compact_if(data.clone(), expected, changes.clone())
+        })
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.policy
+            .run(&self.is_transient, || self.inner.get_sync_state(peer_id))
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || {
+            inner.set_sync_state(peer_id.clone(), sync_state.clone())
+        })
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || inner.remove_sync_states(peer_ids))
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.policy
+            .run(&self.is_transient, || self.inner.get_peer_ids())
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.policy
+            .run(&self.is_transient, || self.inner.get_metadata(key))
+    }
+
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || {
+            inner.set_metadata(key.clone(), value.clone())
+        })
+    }
+
+    fn remove_metadata(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || inner.remove_metadata(key))
+    }
+
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.policy
+            .run(&self.is_transient, || self.inner.get_metadata_keys())
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || inner.flush())
+    }
+
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || inner.vacuum())
+    }
+
+    fn quarantine(&mut self, key: Vec<u8>, record: Vec<u8>) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || {
+            inner.quarantine(key.clone(), record.clone())
+        })
+    }
+
+    fn get_quarantined(&self) -> Result<Vec<QuarantinedRecord>, Self::Error> {
+        self.policy
+            .run(&self.is_transient, || self.inner.get_quarantined())
+    }
+
+    fn remove_quarantined(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        let Self {
+            inner,
+            policy,
+            is_transient,
+        } = self;
+        policy.run(&*is_transient, || inner.remove_quarantined(key))
+    }
+
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        self.policy.run(&self.is_transient, || self.inner.report())
+    }
+}
+
+forward_persister!(impl<P, F> for RetryPersister<P, F> where P: Persister, F: Fn(&P::Error) -> bool);
@@ -0,0 +1,149 @@
+use std::time::{Duration, Instant};
+
+use crate::{Backend, Error, PersistentAutomerge, Persister};
+
+/// Budgets compaction work across the documents hosted by a server, so that compacting them all
+/// doesn't stall it.
+///
+/// Each [`Self::tick`] looks at the documents given to it and compacts those whose changes stored
+/// since their last compaction exceed [`Self::with_ratio`] times the size of their saved document, most bloated first, until
+/// the budget of documents or time for the tick runs out. The rest are deferred to later ticks,
+/// which would typically be run from a timer.
+///
+/// ```rust
+/// # use std::{collections::HashMap, time::Duration};
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{CompactionScheduler, MemoryPersister, PersistentAutomerge};
+/// let mut documents = HashMap::new();
+/// for name in ["a", "b", "c"] {
+///     let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+///     doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///         tx.put(ROOT, "name", name).unwrap();
+///         Ok(())
+///     })
+///     .unwrap();
+///     documents.insert(name, doc);
+/// }
+///
+/// let scheduler = CompactionScheduler::default()
+///     .with_max_documents(2)
+///     .with_max_duration(Duration::from_millis(50));
+/// let tick = scheduler.tick(documents.iter_mut().map(|(name, doc)| (*name, doc)));
+/// assert_eq!(tick.compacted.len(), 2);
+/// assert_eq!(tick.deferred.len(), 1);
+///
+/// // the next tick picks up the rest
+/// let tick = scheduler.tick(documents.iter_mut().map(|(name, doc)| (*name, doc)));
+/// assert_eq!(tick.compacted.len(), 1);
+/// assert!(tick.deferred.is_empty());
+/// ```
+#[derive(Debug, Clone)]
+pub struct CompactionScheduler {
+    ratio: f64,
+    max_documents: Option<usize>,
+    max_duration: Option<Duration>,
+}
+
+impl Default for CompactionScheduler {
+    fn default() -> Self {
+        Self {
+            ratio: 0.5,
+            max_documents: None,
+            max_duration: None,
+        }
+    }
+}
+
+/// What was done in a [`CompactionScheduler::tick`], by the keys of the documents.
+#[derive(Debug)]
+pub struct CompactionTick<K, E>
+where
+    E: std::error::Error + 'static,
+{
+    /// The documents that were compacted, in the order they were.
+    pub compacted: Vec<K>,
+    /// The documents that needed compacting but were left for a later tick, most bloated first.
+    pub deferred: Vec<K>,
+    /// The documents whose compaction failed.
+    pub failed: Vec<(K, Error<E>)>,
+    /// How long the tick took.
+    pub elapsed: Duration,
+}
+
+impl CompactionScheduler {
+    /// Compact documents whose changes stored since their last compaction exceed `ratio` times the
+    /// size of their saved document, see [`PersistentAutomerge::compact_if_changes_exceed`]. The default is `0.5`.
+    #[must_use]
+    pub const fn with_ratio(mut self, ratio: f64) -> Self {
+        self.ratio = ratio;
+        self
+    }
+
+    /// Compact at most this many documents per tick.
+    #[must_use]
+    pub const fn with_max_documents(mut self, max_documents: usize) -> Self {
+        self.max_documents = Some(max_documents);
+        self
+    }
+
+    /// Stop starting compactions once a tick has run for this long.
+    ///
+    /// A compaction that is started is always finished, so a tick can run over by up to the
+    /// time taken by one. At least one document is compacted per tick so that progress is made.
+    #[must_use]
+    pub const fn with_max_duration(mut self, max_duration: Duration) -> Self {
+        self.max_duration = Some(max_duration);
+        self
+    }
+
+    /// Compact the most bloated of the documents within the budget.
+    ///
+    /// A failed compaction is reported and counts towards the budget, the other documents are
+    /// still compacted.
+    pub fn tick<'a, K, P, B, I>(&self, documents: I) -> CompactionTick<K, P::Error>
+    where
+        I: IntoIterator<Item = (K, &'a mut PersistentAutomerge<P, B>)>,
+        P: Persister + 'static,
+        B: Backend + 'a,
+    {
+        let start = Instant::now();
+        let mut tick = CompactionTick {
+            compacted: Vec::new(),
+            deferred: Vec::new(),
+            failed: Vec::new(),
+            elapsed: Duration::default(),
+        };
+        let mut due = Vec::new();
+        for (key, doc) in documents {
+            let written = match doc.changes_since_compaction() {
+                Ok(written) => written,
+                Err(e) => {
+                    tick.failed.push((key, Error::PersisterError(e)));
+                    continue;
+                }
+            };
+            let document = doc.persister().sizes().document;
+            if written as f64 > self.ratio * document as f64 {
+                due.push((written as f64 / document as f64, written, key, doc));
+            }
+        }
+        // a document that was never compacted has infinite bloat, ties go to the most changes
+        due.sort_by(|a, b| b.0.total_cmp(&a.0).then(b.1.cmp(&a.1)));
+
+        for (attempted, (_, _, key, doc)) in due.into_iter().enumerate() {
+            let out_of_documents = self.max_documents.is_some_and(|max| attempted >= max);
+            let out_of_time =
+                attempted > 0 && self.max_duration.is_some_and(|max| start.elapsed() >= max);
+            if out_of_documents || out_of_time {
+                tick.deferred.push(key);
+                continue;
+            }
+            match doc.compact(&[]) {
+                Ok(()) => tick.compacted.push(key),
+                Err(e) => tick.failed.push((key, e)),
+            }
+        }
+        tick.elapsed = start.elapsed();
+        tick
+    }
+}
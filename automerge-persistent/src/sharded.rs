@@ -0,0 +1,300 @@
+use std::collections::HashSet;
+
+use automerge::{ActorId, ChangeHash};
+
+use crate::{
+    forward_persister, DocumentVersion, Forward, Persister, StorageReport, StoredSizes,
+    VersionConflict,
+};
+
+/// A persister that spreads changes over several inner persisters by their actor.
+///
+/// Each change is routed to a shard chosen by a stable hash of its actor id, so documents with
+/// many contributors get more write parallelism and smaller individual trees or files. The
+/// document, sync states and metadata are kept in the first shard.
+///
+/// The number of shards must not change once changes have been stored, otherwise changes are
+/// looked for in the wrong shard when they are removed.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, ShardedPersister};
+/// let shards = (0..4).map(|_| MemoryPersister::default()).collect();
+/// let mut doc = PersistentAutomerge::load(ShardedPersister::new(shards)).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+///
+/// let doc = PersistentAutomerge::load(doc.close().unwrap()).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 1);
+/// ```
+#[derive(Debug)]
+pub struct ShardedPersister<P> {
+    shards: Vec<P>,
+}
+
+impl<P> ShardedPersister<P>
+where
+    P: Persister,
+{
+    /// Construct a new persister over the given shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no shards.
+    pub fn new(shards: Vec<P>) -> Self {
+        assert!(!shards.is_empty(), "at least one shard is needed");
+        Self { shards }
+    }
+
+    /// Get the shards.
+    pub fn shards(&self) -> &[P] {
+        &self.shards
+    }
+
+    /// Take the shards back out.
+    pub fn into_shards(self) -> Vec<P> {
+        self.shards
+    }
+
+    /// The shard that changes for the given key live in.
+    fn shard_index(&self, key: &[u8]) -> usize {
+        (fnv1a(key) % self.shards.len() as u64) as usize
+    }
+
+    /// Split the items into a list per shard by the key.
+    fn partition<T>(&self, items: Vec<T>, key: impl Fn(&T) -> &[u8]) -> Vec<Vec<T>> {
+        let mut partitioned = self.shards.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+        for item in items {
+            let index = self.shard_index(key(&item));
+            partitioned[index].push(item);
+        }
+        partitioned
+    }
+}
+
+/// The 64 bit FNV-1a hash, used as it is stable across platforms and releases.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Everything but the changes is passed through to the first shard.
+impl<P> Forward for ShardedPersister<P>
+where
+    P: Persister,
+{
+    type Inner = P;
+    type Error = P::Error;
+
+    fn inner(&self) -> &P {
+        &self.shards[0]
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.shards[0]
+    }
+
+    fn map_error(error: P::Error) -> Self::Error {
+        error
+    }
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let mut changes = Vec::new();
+        for shard in &self.shards {
+            changes.extend(shard.get_changes()?);
+        }
+        Ok(changes)
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let partitioned = self.partition(changes, |(a, _, _)| a.to_bytes());
+        for (shard, changes) in self.shards.iter_mut().zip(partitioned) {
+            if !changes.is_empty() {
+                shard.insert_changes(changes)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let partitioned = self.partition(changes, |(a, _)| a.to_bytes());
+        for (shard, changes) in self.shards.iter_mut().zip(partitioned) {
+            if !changes.is_empty() {
+                shard.remove_changes(changes)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes are routed by their hash when content-addressed.
+    fn insert_changes_by_hash(
+        &mut self,
+        changes: Vec<(ChangeHash, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        let partitioned = self.partition(changes, |(h, _)| &h.0[..]);
+        for (shard, changes) in self.shards.iter_mut().zip(partitioned) {
+            if !changes.is_empty() {
+                shard.insert_changes_by_hash(changes)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_changes_by_hash(&mut self, hashes: &[ChangeHash]) -> Result<(), Self::Error> {
+        let partitioned = self.partition(hashes.to_vec(), |h| &h.0[..]);
+        for (shard, hashes) in self.shards.iter_mut().zip(partitioned) {
+            if !hashes.is_empty() {
+                shard.remove_changes_by_hash(&hashes)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The document is compacted with the changes of the first shard, then the changes of the
+    /// others are removed.
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        let mut partitioned = self.partition(changes, |(a, _)| a.to_bytes()).into_iter();
+        let first = partitioned.next().unwrap_or_default();
+        let version = self.shards[0].compact_if(data, expected, first)?;
+        if version.is_ok() {
+            for (shard, changes) in self.shards.iter_mut().skip(1).zip(partitioned) {
+                if !changes.is_empty() {
+                    shard.remove_changes(changes)?;
+                }
+            }
+        }
+        Ok(version)
+    }
+
+    /// Each actor's changes are in a single shard unless content-addressed.
+    fn list_actors(&self) -> Result<Vec<ActorId>, Self::Error> {
+        let mut actors = HashSet::new();
+        for shard in &self.shards {
+            actors.extend(shard.list_actors()?);
+        }
+        Ok(actors.into_iter().collect())
+    }
+
+    /// The change sizes of all shards are summed.
+    fn sizes(&self) -> StoredSizes {
+        let mut sizes = self.shards[0].sizes();
+        sizes.changes = self.shards.iter().map(|s| s.sizes().changes).sum();
+        sizes
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        let mut flushed = 0;
+        for shard in &mut self.shards {
+            flushed += shard.flush()?;
+        }
+        Ok(flushed)
+    }
+
+    fn vacuum(&mut self) -> Result<(), Self::Error> {
+        for shard in &mut self.shards {
+            shard.vacuum()?;
+        }
+        Ok(())
+    }
+
+    /// The changes of all shards are reported.
+    fn report(&self) -> Result<StorageReport, Self::Error> {
+        StorageReport::read(self)
+    }
+}
+
+forward_persister!(impl<P> for ShardedPersister<P> where P: Persister);
+
+#[cfg(test)]
+mod tests {
+    use automerge::ActorId;
+    use automerge_persistent_core::test_support::Probe;
+
+    use super::ShardedPersister;
+    use crate::Persister;
+
+    #[test]
+    fn mapped_document_is_read_from_the_first_shard() {
+        let shards = (0..2).map(|_| Probe::default()).collect();
+        let mut persister = ShardedPersister::new(shards);
+        persister.set_document(vec![1, 2, 3]).unwrap();
+
+        let (document, _) = persister.get_document_mapped().unwrap();
+        assert_eq!((*document.unwrap()).as_ref(), &[1, 2, 3]);
+        assert_eq!(persister.shards()[0].mapped_reads(), 1);
+    }
+
+    #[test]
+    fn quarantined_records_are_kept_in_the_first_shard() {
+        let shards = (0..2).map(|_| Probe::default()).collect();
+        let mut persister = ShardedPersister::new(shards);
+        persister.quarantine(b"a".to_vec(), vec![1, 2, 3]).unwrap();
+        let record = vec![(b"a".to_vec(), vec![1, 2, 3])];
+        assert_eq!(persister.get_quarantined().unwrap(), record);
+        assert_eq!(persister.shards()[0].get_quarantined().unwrap(), record);
+
+        persister.remove_quarantined(b"a").unwrap();
+        assert!(persister.get_quarantined().unwrap().is_empty());
+    }
+
+    #[test]
+    fn report_covers_every_shard() {
+        let shards = (0..2).map(|_| Probe::default()).collect();
+        let mut persister = ShardedPersister::new(shards);
+        for actor in 0..4_u8 {
+            let mut doc = automerge::Automerge::new();
+            doc.set_actor(automerge::ActorId::from(vec![actor]));
+            doc.transact::<_, _, std::convert::Infallible>(|tx| {
+                automerge::transaction::Transactable::put(tx, automerge::ROOT, "a", 1).unwrap();
+                Ok(())
+            })
+            .unwrap();
+            let change = doc.get_last_local_change().unwrap();
+            persister
+                .insert_changes(vec![(
+                    change.actor_id().clone(),
+                    change.seq,
+                    change.raw_bytes().to_vec(),
+                )])
+                .unwrap();
+        }
+
+        let report = persister.report().unwrap();
+        assert_eq!(report.change_records, 4);
+        assert_eq!(report.overhead, 0);
+        assert!(persister
+            .shards()
+            .iter()
+            .all(|s| s.report().unwrap().change_records < 4));
+    }
+
+    #[test]
+    fn compaction_is_passed_through_to_the_first_shard() {
+        let shards = (0..2).map(|_| Probe::default()).collect();
+        let mut persister = ShardedPersister::new(shards);
+        let actors = (0..4_u8)
+            .map(|a| ActorId::from(vec![a]))
+            .collect::<Vec<_>>();
+        persister
+            .insert_changes(actors.iter().map(|a| (a.clone(), 1, vec![1])).collect())
+            .unwrap();
+
+        persister
+            .compact_if(vec![1], None, actors.iter().map(|a| (a, 1)).collect())
+            .unwrap()
+            .unwrap();
+        assert_eq!(persister.shards()[0].compactions(), 1);
+        assert_eq!(persister.shards()[1].compactions(), 0);
+        assert!(persister.get_changes().unwrap().is_empty());
+        assert_eq!(persister.get_document().unwrap(), Some(vec![1]));
+    }
+}
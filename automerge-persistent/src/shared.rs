@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
+};
+
+use automerge::{sync, transaction::Transaction, Change, ChangeHash};
+
+use crate::{Error, PeerId, PersistentAutomerge, Persister, TransactionError, TransactionResult};
+
+/// A handle to a [`PersistentAutomerge`] that can be cloned and shared between threads.
+///
+/// Reads take a shared lock so can happen concurrently, while writes are serialised through an
+/// exclusive lock.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, SharedPersistentAutomerge};
+/// let doc = SharedPersistentAutomerge::new(
+///     PersistentAutomerge::load(MemoryPersister::default()).unwrap(),
+/// );
+///
+/// let handles = (0..4)
+///     .map(|i| {
+///         let doc = doc.clone();
+///         std::thread::spawn(move || {
+///             doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///                 tx.put(ROOT, i.to_string(), i).unwrap();
+///                 Ok(())
+///             })
+///             .unwrap();
+///         })
+///     })
+///     .collect::<Vec<_>>();
+/// for handle in handles {
+///     handle.join().unwrap();
+/// }
+///
+/// assert_eq!(doc.read(|doc| doc.document().length(ROOT)), 4);
+/// ```
+#[derive(Debug)]
+pub struct SharedPersistentAutomerge<P> {
+    inner: Arc<RwLock<PersistentAutomerge<P>>>,
+    group: Option<Arc<GroupCommit>>,
+}
+
+impl<P> Clone for SharedPersistentAutomerge<P> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            group: self.group.clone(),
+        }
+    }
+}
+
+/// The changes of concurrent transactions waiting to be persisted together.
+#[derive(Debug)]
+struct GroupCommit {
+    window: Duration,
+    state: Mutex<GroupState>,
+    finished: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct GroupState {
+    /// The id of the batch that new changes join.
+    open: u64,
+    /// Whether a transaction has taken on persisting the open batch.
+    has_leader: bool,
+    queued: Vec<Change>,
+    /// Batches that have been persisted, with whether that succeeded and how many of their
+    /// transactions are yet to see it.
+    finished: HashMap<u64, (bool, usize)>,
+}
+
+impl GroupCommit {
+    fn lock(&self) -> MutexGuard<'_, GroupState> {
+        self.state.lock().expect("group commit lock poisoned")
+    }
+}
+
+impl<P> SharedPersistentAutomerge<P>
+where
+    P: Persister + 'static,
+{
+    /// Share the given document.
+    pub fn new(document: PersistentAutomerge<P>) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(document)),
+            group: None,
+        }
+    }
+
+    /// Share the given document, persisting the changes of concurrent transactions together.
+    ///
+    /// The first transaction to finish waits up to `window` for others to join it before
+    /// persisting all of their changes in one [`Persister::insert_changes`] call, so backends that
+    /// sync to disk on every write do so once per batch rather than once per change. Each
+    /// transaction still only returns once its change is persisted, at the cost of up to `window`
+    /// of extra latency. Other handles see changes once they are in the document, which can be
+    /// before they are persisted.
+    ///
+    /// If persisting a batch fails each of its changes is persisted alone so that every
+    /// transaction gets its own result.
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, Persister, SharedPersistentAutomerge};
+    /// let doc = SharedPersistentAutomerge::with_group_commit(
+    ///     PersistentAutomerge::load(MemoryPersister::default()).unwrap(),
+    ///     Duration::from_millis(5),
+    /// );
+    ///
+    /// let handles = (0..8)
+    ///     .map(|i| {
+    ///         let doc = doc.clone();
+    ///         std::thread::spawn(move || {
+    ///             doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///                 tx.put(ROOT, i.to_string(), i).unwrap();
+    ///                 Ok(())
+    ///             })
+    ///             .unwrap();
+    ///         })
+    ///     })
+    ///     .collect::<Vec<_>>();
+    /// for handle in handles {
+    ///     handle.join().unwrap();
+    /// }
+    ///
+    /// // every transaction returned once its change was stored
+    /// assert_eq!(doc.read(|doc| doc.persister().get_changes().unwrap().len()), 8);
+    /// ```
+    pub fn with_group_commit(document: PersistentAutomerge<P>, window: Duration) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(document)),
+            group: Some(Arc::new(GroupCommit {
+                window,
+                state: Mutex::new(GroupState::default()),
+                finished: Condvar::new(),
+            })),
+        }
+    }
+
+    fn read_lock(&self) -> RwLockReadGuard<'_, PersistentAutomerge<P>> {
+        self.inner.read().expect("shared document lock poisoned")
+    }
+
+    fn write_lock(&self) -> RwLockWriteGuard<'_, PersistentAutomerge<P>> {
+        self.inner.write().expect("shared document lock poisoned")
+    }
+
+    /// Run a function with shared access to the document.
+    pub fn read<F, O>(&self, f: F) -> O
+    where
+        F: FnOnce(&PersistentAutomerge<P>) -> O,
+    {
+        f(&self.read_lock())
+    }
+
+    /// Run a function with exclusive access to the document.
+    pub fn write<F, O>(&self, f: F) -> O
+    where
+        F: FnOnce(&mut PersistentAutomerge<P>) -> O,
+    {
+        f(&mut self.write_lock())
+    }
+
+    /// The current heads of the document.
+    pub fn get_heads(&self) -> Vec<ChangeHash> {
+        self.read_lock().document().get_heads()
+    }
+
+    /// Run a transaction on the document, persisting the resulting change.
+    ///
+    /// See [`PersistentAutomerge::transact`] and, for handles created with it,
+    /// [`Self::with_group_commit`].
+    pub fn transact<F, O, E>(&self, f: F) -> TransactionResult<O, E, P::Error>
+    where
+        F: FnOnce(&mut Transaction) -> Result<O, E>,
+    {
+        match &self.group {
+            Some(group) => self.transact_grouped(group, f),
+            None => self.write_lock().transact(f),
+        }
+    }
+
+    /// Run a transaction on the document, persisting the resulting change and flushing so that it
+    /// is durable before this returns.
+    ///
+    /// See [`PersistentAutomerge::transact_durable`].
+    pub fn transact_durable<F, O, E>(&self, f: F) -> TransactionResult<O, E, P::Error>
+    where
+        F: FnOnce(&mut Transaction) -> Result<O, E>,
+    {
+        let result = self.transact(f)?;
+        self.flush().map_err(TransactionError::PersisterError)?;
+        Ok(result)
+    }
+
+    // the document stays locked while queueing the change, see below
+    #[allow(clippy::significant_drop_tightening)]
+    fn transact_grouped<F, O, E>(
+        &self,
+        group: &GroupCommit,
+        f: F,
+    ) -> TransactionResult<O, E, P::Error>
+    where
+        F: FnOnce(&mut Transaction) -> Result<O, E>,
+    {
+        // the change is queued while the document is still locked so batches keep the order the
+        // changes were made in
+        let (result, change, batch, leader) = {
+            let mut doc = self.write_lock();
+            let result = doc.transact_in_memory(f)?;
+            let change = match doc.document().get_last_local_change() {
+                Some(change) => change.clone(),
+                None => return Ok(result),
+            };
+            let mut state = group.lock();
+            state.queued.push(change.clone());
+            let leader = !state.has_leader;
+            state.has_leader = true;
+            (result, change, state.open, leader)
+        };
+
+        if leader {
+            std::thread::sleep(group.window);
+            let changes = {
+                let mut state = group.lock();
+                state.open += 1;
+                state.has_leader = false;
+                std::mem::take(&mut state.queued)
+            };
+            let persisted = self.write_lock().persist_local_changes(&changes).is_ok();
+            group
+                .lock()
+                .finished
+                .insert(batch, (persisted, changes.len()));
+            group.finished.notify_all();
+        }
+
+        let persisted = {
+            let mut state = group.lock();
+            loop {
+                if let Some((persisted, waiting)) = state.finished.get_mut(&batch) {
+                    let persisted = *persisted;
+                    *waiting -= 1;
+                    if *waiting == 0 {
+                        state.finished.remove(&batch);
+                    }
+                    break persisted;
+                }
+                state = group
+                    .finished
+                    .wait(state)
+                    .expect("group commit lock poisoned");
+            }
+        };
+        if !persisted {
+            self.write_lock()
+                .persist_local_changes(&[change])
+                .map_err(TransactionError::PersisterError)?;
+        }
+        Ok(result)
+    }
+
+    /// Apply changes to the document.
+    ///
+    /// See [`PersistentAutomerge::apply_changes`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the changes could not be applied or persisted.
+    pub fn apply_changes(
+        &self,
+        changes: impl IntoIterator<Item = Change>,
+    ) -> Result<(), Error<P::Error>> {
+        self.write_lock().apply_changes(changes)
+    }
+
+    /// Generate a sync message for a peer.
+    ///
+    /// See [`PersistentAutomerge::generate_sync_message`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the sync state could not be loaded or saved.
+    pub fn generate_sync_message(
+        &self,
+        peer_id: PeerId,
+    ) -> Result<Option<sync::Message>, Error<P::Error>> {
+        self.write_lock().generate_sync_message(peer_id)
+    }
+
+    /// Receive a sync message from a peer.
+    ///
+    /// See [`PersistentAutomerge::receive_sync_message`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message could not be applied or the results persisted.
+    pub fn receive_sync_message(
+        &self,
+        peer_id: PeerId,
+        message: sync::Message,
+    ) -> Result<(), Error<P::Error>> {
+        self.write_lock().receive_sync_message(peer_id, message)
+    }
+
+    /// Compact the storage.
+    ///
+    /// See [`PersistentAutomerge::compact`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the compaction failed.
+    pub fn compact(&self, old_peer_ids: &[&[u8]]) -> Result<(), Error<P::Error>> {
+        self.write_lock().compact(old_peer_ids)
+    }
+
+    /// Flush any data out to storage returning the number of bytes flushed.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by the persister during flushing.
+    pub fn flush(&self) -> Result<usize, P::Error> {
+        self.write_lock().flush()
+    }
+
+    /// Take back the document if this is the only handle to it, otherwise returns the handle.
+    ///
+    /// # Errors
+    ///
+    /// Returns the handle back if other handles to the document still exist.
+    pub fn try_unwrap(self) -> Result<PersistentAutomerge<P>, Self> {
+        let Self { inner, group } = self;
+        Arc::try_unwrap(inner)
+            .map(|lock| lock.into_inner().expect("shared document lock poisoned"))
+            .map_err(|inner| Self { inner, group })
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::BTreeSet;
+
+use automerge::sync;
+
+use crate::{Error, PeerId, PersistentAutomerge, Persister};
+
+/// Manages syncing a [`PersistentAutomerge`] with multiple peers.
+///
+/// The set of peers is resumed from the sync states stored in the persister so a manager created
+/// after a restart carries on syncing with the same peers from where it left off.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, SyncManager};
+/// let mut alice = SyncManager::new(PersistentAutomerge::load(MemoryPersister::default()).unwrap())
+///     .unwrap();
+/// let mut bob = SyncManager::new(PersistentAutomerge::load(MemoryPersister::default()).unwrap())
+///     .unwrap();
+/// alice
+///     .document_mut()
+///     .transact::<_, _, std::convert::Infallible>(|tx| {
+///         tx.put(ROOT, "a", 1).unwrap();
+///         Ok(())
+///     })
+///     .unwrap();
+///
+/// alice.add_peer(b"bob".to_vec());
+/// bob.add_peer(b"alice".to_vec());
+/// loop {
+///     let to_bob = alice.generate_sync_messages().unwrap();
+///     let to_alice = bob.generate_sync_messages().unwrap();
+///     if to_bob.is_empty() && to_alice.is_empty() {
+///         break;
+///     }
+///     for (_, message) in to_bob {
+///         bob.receive_sync_message(b"alice".to_vec(), message).unwrap();
+///     }
+///     for (_, message) in to_alice {
+///         alice.receive_sync_message(b"bob".to_vec(), message).unwrap();
+///     }
+/// }
+/// assert_eq!(
+///     alice.document().document().get_heads(),
+///     bob.document().document().get_heads()
+/// );
+/// ```
+#[derive(Debug)]
+pub struct SyncManager<P> {
+    document: PersistentAutomerge<P>,
+    peers: BTreeSet<PeerId>,
+}
+
+impl<P> SyncManager<P>
+where
+    P: Persister + 'static,
+{
+    /// Create a new manager for the document, tracking all peers that have a stored sync state.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when listing the stored peers.
+    pub fn new(document: PersistentAutomerge<P>) -> Result<Self, P::Error> {
+        let peers = document.persister().get_peer_ids()?.into_iter().collect();
+        Ok(Self { document, peers })
+    }
+
+    /// Obtain a reference to the managed document.
+    pub const fn document(&self) -> &PersistentAutomerge<P> {
+        &self.document
+    }
+
+    /// Obtain a mut reference to the managed document.
+    pub const fn document_mut(&mut self) -> &mut PersistentAutomerge<P> {
+        &mut self.document
+    }
+
+    /// Stop managing syncing and return the document.
+    pub fn into_document(self) -> PersistentAutomerge<P> {
+        self.document
+    }
+
+    /// The peers currently being synced with.
+    pub fn peers(&self) -> impl Iterator<Item = &[u8]> {
+        self.peers.iter().map(Vec::as_slice)
+    }
+
+    /// Start syncing with a peer, returning whether it was newly added.
+    ///
+    /// The peer's sync state is persisted when the first message is generated for it.
+    pub fn add_peer(&mut self, peer_id: PeerId) -> bool {
+        self.peers.insert(peer_id)
+    }
+
+    /// Stop syncing with a peer, removing its stored sync state.
+    ///
+    /// Returns whether the peer was being synced with.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when removing the sync state.
+    pub fn remove_peer(&mut self, peer_id: &[u8]) -> Result<bool, P::Error> {
+        let removed = self.peers.remove(peer_id);
+        self.document.reset_sync_state(peer_id)?;
+        Ok(removed)
+    }
+
+    /// Reset the sync state for a peer that has disconnected while continuing to track it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when removing the sync state.
+    pub fn reset_peer(&mut self, peer_id: &[u8]) -> Result<(), P::Error> {
+        self.document.reset_sync_state(peer_id)
+    }
+
+    /// Generate the sync messages that need sending, paired with the peer to send them to.
+    ///
+    /// Peers that are up to date are not included.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a sync state could not be loaded or saved.
+    pub fn generate_sync_messages(
+        &mut self,
+    ) -> Result<Vec<(PeerId, sync::Message)>, Error<P::Error>> {
+        let mut messages = Vec::new();
+        for peer_id in &self.peers {
+            if let Some(message) = self.document.generate_sync_message(peer_id.clone())? {
+                messages.push((peer_id.clone(), message));
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Generate a sync message for a single peer, starting to track it if not already.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the sync state could not be loaded or saved.
+    pub fn generate_sync_message(
+        &mut self,
+        peer_id: PeerId,
+    ) -> Result<Option<sync::Message>, Error<P::Error>> {
+        self.peers.insert(peer_id.clone());
+        self.document.generate_sync_message(peer_id)
+    }
+
+    /// Receive a sync message from a peer, starting to track it if not already.
+    ///
+    /// Any changes received are persisted before returning.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message could not be applied or the results persisted.
+    pub fn receive_sync_message(
+        &mut self,
+        peer_id: PeerId,
+        message: sync::Message,
+    ) -> Result<(), Error<P::Error>> {
+        self.peers.insert(peer_id.clone());
+        self.document.receive_sync_message(peer_id, message)
+    }
+}
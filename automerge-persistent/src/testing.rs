@@ -0,0 +1,213 @@
+//! Model-based tests for persisters, enabled with the `proptest` feature.
+//!
+//! [`ops`] generates random interleavings of local changes, compactions, flushes, crashes and
+//! reloads, and [`check_model`] runs them against a [`PersistentAutomerge`] over the persister
+//! under test, checking after every step that the document matches a plain map kept alongside it.
+//! A crash drops the document without flushing, so the reloaded document may have lost changes
+//! made since the last flush but must match the model as it was at some point since then, with
+//! no change torn or duplicated. A reload closes the document first, so must lose nothing.
+//!
+//! Backend authors only need to say how to reopen their storage:
+//!
+//! ```rust
+//! # use automerge_persistent::{testing, MemoryPersister};
+//! # use proptest::test_runner::TestRunner;
+//! let mut runner = TestRunner::default();
+//! runner
+//!     .run(&testing::ops(20), |ops| {
+//!         // the memory persister keeps everything it was given, so reopening it is a no-op
+//!         testing::check_model(MemoryPersister::default(), Ok, &ops).unwrap();
+//!         Ok(())
+//!     })
+//!     .unwrap();
+//! ```
+
+use std::collections::BTreeMap;
+
+use automerge::{transaction::Transactable, Automerge, AutomergeError, ROOT};
+use proptest::{collection::vec, prelude::*, strategy::Strategy};
+
+use crate::{Error, PersistentAutomerge, Persister, TransactionError};
+
+/// The keys that operations put to and delete from, kept few so that operations overlap.
+const KEYS: &[&str] = &["a", "b", "c", "d"];
+
+/// The state of the root map of a document, as the model sees it.
+pub type Model = BTreeMap<String, i64>;
+
+/// A change to a single key of the root map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    /// Put the value at the key.
+    Put(String, i64),
+    /// Delete the key, if it is present.
+    Delete(String),
+}
+
+/// One step to run against the document and the model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Make the edits in one local transaction.
+    Apply(Vec<Edit>),
+    /// Compact the document.
+    Compact,
+    /// Flush the document.
+    Flush,
+    /// Drop the document without flushing, then reopen the storage and load it again.
+    Crash,
+    /// Close the document, then reopen the storage and load it again.
+    Reload,
+}
+
+/// Errors from running operations with [`check_model`].
+#[derive(Debug, thiserror::Error)]
+pub enum ModelError<E>
+where
+    E: std::error::Error + 'static,
+{
+    /// The document or persister failed.
+    #[error(transparent)]
+    Document(#[from] Error<E>),
+    /// Reopening the storage failed.
+    #[error("failed to reopen the storage: {0}")]
+    Reopen(E),
+    /// The document didn't match the model.
+    #[error("step {step}: expected {expected:?} but the document has {actual:?}")]
+    Diverged {
+        /// The index of the operation after which they differed.
+        step: usize,
+        /// What the model expected, for a crash the state as of the last flush.
+        expected: Model,
+        /// What the document contained.
+        actual: Model,
+    },
+}
+
+/// A strategy for a single edit.
+pub fn edit() -> impl Strategy<Value = Edit> {
+    let key = proptest::sample::select(KEYS).prop_map(str::to_owned);
+    prop_oneof![
+        3 => (key.clone(), any::<i64>()).prop_map(|(k, v)| Edit::Put(k, v)),
+        1 => key.prop_map(Edit::Delete),
+    ]
+}
+
+/// A strategy for a single operation, mostly local changes.
+pub fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        6 => vec(edit(), 1..4).prop_map(Op::Apply),
+        1 => Just(Op::Compact),
+        1 => Just(Op::Flush),
+        1 => Just(Op::Crash),
+        1 => Just(Op::Reload),
+    ]
+}
+
+/// A strategy for up to `max_len` operations.
+pub fn ops(max_len: usize) -> impl Strategy<Value = Vec<Op>> {
+    vec(op(), 0..=max_len)
+}
+
+/// Run the operations against a document over the persister, checking it against the model after
+/// each one.
+///
+/// `reopen` is given the persister after a crash or reload and returns it as a new process would
+/// find it, such as by dropping it and opening the same files again.
+///
+/// # Errors
+///
+/// Returns [`ModelError::Diverged`] if the document doesn't match the model, or an error if the
+/// document, persister or reopening fails.
+pub fn check_model<P, F>(
+    persister: P,
+    mut reopen: F,
+    ops: &[Op],
+) -> Result<(), ModelError<P::Error>>
+where
+    P: Persister + 'static,
+    F: FnMut(P) -> Result<P, P::Error>,
+{
+    let mut doc = PersistentAutomerge::load(persister)?;
+    // the model after each transaction, the first being the empty document
+    let mut history = vec![Model::new()];
+    let mut flushed = 0;
+
+    for (step, op) in ops.iter().enumerate() {
+        match op {
+            Op::Apply(edits) => {
+                let mut model = history.last().cloned().unwrap_or_default();
+                doc.transact::<_, _, AutomergeError>(|tx| {
+                    for edit in edits {
+                        match edit {
+                            Edit::Put(key, value) => {
+                                tx.put(ROOT, key.as_str(), *value)?;
+                                model.insert(key.clone(), *value);
+                            }
+                            Edit::Delete(key) => {
+                                if model.remove(key).is_some() {
+                                    tx.delete(ROOT, key.as_str())?;
+                                }
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+                .map_err(|e| match e {
+                    TransactionError::PersisterError(e) => Error::PersisterError(e),
+                    TransactionError::TransactionError(failure) => {
+                        Error::AutomergeError(failure.error)
+                    }
+                })?;
+                history.push(model);
+            }
+            Op::Compact => doc.compact(&[])?,
+            Op::Flush => {
+                doc.flush().map_err(Error::PersisterError)?;
+                flushed = history.len() - 1;
+            }
+            Op::Crash => {
+                let (_, persister) = doc.into_inner();
+                doc = PersistentAutomerge::load(reopen(persister).map_err(ModelError::Reopen)?)?;
+                let actual = read_model(doc.document());
+                // any state since the last flush is allowed, the rest of the history is lost
+                let kept = (flushed..history.len())
+                    .rev()
+                    .find(|&i| history[i] == actual)
+                    .ok_or_else(|| ModelError::Diverged {
+                        step,
+                        expected: history[flushed].clone(),
+                        actual,
+                    })?;
+                history.truncate(kept + 1);
+                continue;
+            }
+            Op::Reload => {
+                let persister = doc.close().map_err(Error::PersisterError)?;
+                doc = PersistentAutomerge::load(reopen(persister).map_err(ModelError::Reopen)?)?;
+                flushed = history.len() - 1;
+            }
+        }
+
+        let actual = read_model(doc.document());
+        let expected = history.last().cloned().unwrap_or_default();
+        if actual != expected {
+            return Err(ModelError::Diverged {
+                step,
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Read the integer values in the root map of the document.
+pub fn read_model(document: &Automerge) -> Model {
+    document
+        .keys(ROOT)
+        .filter_map(|key| {
+            let value = document.get(ROOT, key.as_str()).ok()??.0.to_i64()?;
+            Some((key, value))
+        })
+        .collect()
+}
@@ -0,0 +1,144 @@
+use std::collections::HashSet;
+
+use automerge::{AutomergeError, Change, ChangeHash, Patch};
+
+use crate::{Backend, Error, PersistentAutomerge, Persister};
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// The patches that build the document as it was at the given heads from an empty one, for
+    /// showing past versions without keeping another document around for each.
+    ///
+    /// The history is rebuilt from the changes the heads depend on, including those waiting on
+    /// missing dependencies that are only in the persister.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Patch, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// let heads = doc.document().get_heads();
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 2).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    ///
+    /// let patches = doc.patch_at(&heads).unwrap();
+    /// assert!(matches!(
+    ///     &patches[..],
+    ///     [Patch::Put { value: (value, _), .. }] if value.to_i64() == Some(1)
+    /// ));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a change the heads depend on isn't stored, or couldn't be read or
+    /// applied.
+    pub fn patch_at(&self, heads: &[ChangeHash]) -> Result<Vec<Patch>, Error<P::Error>> {
+        let mut historical = B::default();
+        Ok(historical.apply_changes_with_patches(self.history_at(heads)?)?)
+    }
+
+    /// The patches that go from the document as it was at `from` to as it was at `to`, for
+    /// showing what changed between two versions.
+    ///
+    /// Changes can only be applied, not undone, so `from` must be in the history of `to`, such
+    /// as an earlier version of the same document.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, Patch, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// let mut versions = Vec::new();
+    /// for i in 0..3 {
+    ///     doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///         tx.put(ROOT, "a", i).unwrap();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    ///     versions.push(doc.document().get_heads());
+    /// }
+    ///
+    /// let patches = doc.diff(&versions[0], &versions[2]).unwrap();
+    /// assert_eq!(patches.len(), 2);
+    /// assert!(matches!(
+    ///     patches.last(),
+    ///     Some(Patch::Put { value: (value, _), .. }) if value.to_i64() == Some(2)
+    /// ));
+    /// assert!(doc.diff(&versions[2], &versions[0]).is_err());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NotAncestor`] with the heads of `from` that `to` doesn't depend on, or
+    /// an error if a change either depends on isn't stored, or couldn't be read or applied.
+    pub fn diff(
+        &self,
+        from: &[ChangeHash],
+        to: &[ChangeHash],
+    ) -> Result<Vec<Patch>, Error<P::Error>> {
+        let after = self.history_at(to)?;
+        let after_hashes = after.iter().map(|c| c.hash).collect::<HashSet<_>>();
+        let diverged = from
+            .iter()
+            .filter(|hash| !after_hashes.contains(hash))
+            .copied()
+            .collect::<Vec<_>>();
+        if !diverged.is_empty() {
+            return Err(Error::NotAncestor(diverged));
+        }
+
+        let before = self.history_at(from)?;
+        let before_hashes = before.iter().map(|c| c.hash).collect::<HashSet<_>>();
+        let mut historical = B::default();
+        historical.apply_changes(before)?;
+        Ok(historical.apply_changes_with_patches(
+            after
+                .into_iter()
+                .filter(|c| !before_hashes.contains(&c.hash))
+                .collect(),
+        )?)
+    }
+
+    /// The changes the heads depend on, including the heads themselves, in an order they can be
+    /// applied in.
+    fn history_at(&self, heads: &[ChangeHash]) -> Result<Vec<Change>, Error<P::Error>> {
+        let mut seen = HashSet::new();
+        let mut history = Vec::new();
+        // a change is pushed back on once it is looked up, to be added after its dependencies
+        let mut stack = heads
+            .iter()
+            .rev()
+            .map(|hash| (*hash, None))
+            .collect::<Vec<_>>();
+        while let Some((hash, change)) = stack.pop() {
+            if let Some(change) = change {
+                history.push(change);
+                continue;
+            }
+            if !seen.insert(hash) {
+                continue;
+            }
+            let change = self
+                .get_change_by_hash(&hash)?
+                .ok_or(AutomergeError::MissingHash(hash))?
+                .into_owned();
+            let deps = change.deps.clone();
+            stack.push((hash, Some(change)));
+            stack.extend(
+                deps.into_iter()
+                    .filter(|dep| !seen.contains(dep))
+                    .map(|dep| (dep, None)),
+            );
+        }
+        Ok(history)
+    }
+}
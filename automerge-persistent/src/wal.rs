@@ -0,0 +1,375 @@
+use automerge::{ActorId, ChangeHash};
+
+use crate::{
+    forward_persister,
+    metadata::{
+        self, WAL_COMPACT_KEY, WAL_DOCUMENT_IF_KEY, WAL_DOCUMENT_KEY, WAL_PREFIX,
+        WAL_REMOVE_CHANGES_KEY, WAL_REMOVE_HASHES_KEY,
+    },
+    DocumentVersion, Forward, Persister, VersionConflict,
+};
+
+/// A persister that journals multi-step writes to the metadata of an inner persister, so they can
+/// be finished after a crash.
+///
+/// Before the document is replaced or changes are removed the operation is written to the
+/// journal, and once it has completed the journal entry is removed. Constructing the persister
+/// recovers from any interrupted operation: a complete journal entry is rolled forward, while a
+/// torn one means the operation never started so it is discarded. This gives crash-atomic
+/// compaction on backends that cannot write a document atomically, relying only on single
+/// metadata writes being atomic.
+///
+/// A compaction is journalled as one entry holding both the document and the changes it
+/// includes, so recovering finishes both steps, removing the changes even when the document was
+/// written before the crash.
+///
+/// A conditional write journals the version it expects and is replayed with it, so it is
+/// discarded if another writer has set the document since.
+///
+/// Replacing the document and removing changes separately, rather than compacting, leaves those
+/// changes stored after a crash in between, which is harmless as they are already in the document
+/// and they are removed by the next compaction.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ROOT};
+/// # use automerge_persistent::{MemoryPersister, PersistentAutomerge, WalPersister};
+/// let persister = WalPersister::new(MemoryPersister::default()).unwrap();
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+///     tx.put(ROOT, "a", 1).unwrap();
+///     Ok(())
+/// })
+/// .unwrap();
+/// doc.compact(&[]).unwrap();
+///
+/// let persister = WalPersister::new(doc.close().unwrap().into_inner()).unwrap();
+/// let doc = PersistentAutomerge::load(persister).unwrap();
+/// assert_eq!(doc.document().length(ROOT), 1);
+/// ```
+#[derive(Debug)]
+pub struct WalPersister<P> {
+    inner: P,
+}
+
+impl<P> WalPersister<P>
+where
+    P: Persister,
+{
+    /// Wrap the persister, recovering any operation interrupted by a crash.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the inner persister if recovery fails.
+    pub fn new(inner: P) -> Result<Self, P::Error> {
+        let mut s = Self { inner };
+        s.recover()?;
+        Ok(s)
+    }
+
+    /// Finish or discard any journalled operation.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the inner persister if recovery fails.
+    pub fn recover(&mut self) -> Result<(), P::Error> {
+        if let Some(entry) = self.inner.get_metadata(WAL_DOCUMENT_KEY)? {
+            if let Some(document) = metadata::decode_journal(&entry) {
+                self.inner.set_document(document.to_vec())?;
+            }
+            self.inner.remove_metadata(WAL_DOCUMENT_KEY)?;
+        }
+        if let Some(entry) = self.inner.get_metadata(WAL_DOCUMENT_IF_KEY)? {
+            if let Some((expected, document)) =
+                metadata::decode_journal(&entry).and_then(metadata::decode_conditional_document)
+            {
+                // once written the version has moved on, so it would conflict with itself
+                if self.inner.get_document()?.as_deref() != Some(document) {
+                    // a conflict means another writer has set the document, which is kept
+                    let _ = self
+                        .inner
+                        .set_document_if(document.to_vec(), expected.as_ref())?;
+                }
+            }
+            self.inner.remove_metadata(WAL_DOCUMENT_IF_KEY)?;
+        }
+        if let Some(entry) = self.inner.get_metadata(WAL_COMPACT_KEY)? {
+            if let Some((expected, document, changes)) =
+                metadata::decode_journal(&entry).and_then(metadata::decode_compaction)
+            {
+                let changes = changes.iter().map(|(a, s)| (a, *s)).collect();
+                if self.inner.get_document()?.as_deref() == Some(document) {
+                    // the document was written, leaving only the changes
+                    self.inner.remove_changes(changes)?;
+                } else {
+                    // on a conflict the changes are kept, another writer's document may need them
+                    let _ = self
+                        .inner
+                        .compact_if(document.to_vec(), expected.as_ref(), changes)?;
+                }
+            }
+            self.inner.remove_metadata(WAL_COMPACT_KEY)?;
+        }
+        if let Some(entry) = self.inner.get_metadata(WAL_REMOVE_CHANGES_KEY)? {
+            if let Some(changes) =
+                metadata::decode_journal(&entry).and_then(metadata::decode_change_keys)
+            {
+                self.inner
+                    .remove_changes(changes.iter().map(|(a, s)| (a, *s)).collect())?;
+            }
+            self.inner.remove_metadata(WAL_REMOVE_CHANGES_KEY)?;
+        }
+        if let Some(entry) = self.inner.get_metadata(WAL_REMOVE_HASHES_KEY)? {
+            if let Some(hashes) = metadata::decode_journal(&entry).and_then(metadata::decode_hashes)
+            {
+                self.inner.remove_changes_by_hash(&hashes)?;
+            }
+            self.inner.remove_metadata(WAL_REMOVE_HASHES_KEY)?;
+        }
+        Ok(())
+    }
+
+    /// Get a reference to the inner persister.
+    pub const fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Take the inner persister back out.
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    /// Write the journal entry and make sure it is durable before the operation starts.
+    fn journal(&mut self, key: &[u8], data: &[u8]) -> Result<(), P::Error> {
+        self.inner
+            .set_metadata(key.to_vec(), metadata::encode_journal(data))?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+impl<P> Forward for WalPersister<P>
+where
+    P: Persister,
+{
+    type Inner = P;
+    type Error = P::Error;
+
+    fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn map_error(error: P::Error) -> Self::Error {
+        error
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.journal(
+            WAL_REMOVE_CHANGES_KEY,
+            &metadata::encode_change_keys(&changes),
+        )?;
+        self.inner.remove_changes(changes)?;
+        self.inner.remove_metadata(WAL_REMOVE_CHANGES_KEY)
+    }
+
+    fn remove_changes_by_hash(&mut self, hashes: &[ChangeHash]) -> Result<(), Self::Error> {
+        self.journal(WAL_REMOVE_HASHES_KEY, &metadata::encode_hashes(hashes))?;
+        self.inner.remove_changes_by_hash(hashes)?;
+        self.inner.remove_metadata(WAL_REMOVE_HASHES_KEY)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.journal(WAL_DOCUMENT_KEY, &data)?;
+        self.inner.set_document(data)?;
+        self.inner.remove_metadata(WAL_DOCUMENT_KEY)
+    }
+
+    fn set_document_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.journal(
+            WAL_DOCUMENT_IF_KEY,
+            &metadata::encode_conditional_document(expected, &data),
+        )?;
+        let version = self.inner.set_document_if(data, expected)?;
+        self.inner.remove_metadata(WAL_DOCUMENT_IF_KEY)?;
+        Ok(version)
+    }
+
+    fn compact_if(
+        &mut self,
+        data: Vec<u8>,
+        expected: Option<&DocumentVersion>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Self::Error> {
+        self.journal(
+            WAL_COMPACT_KEY,
+            &metadata::encode_compaction(expected, &data, &changes),
+        )?;
+        let version = self.inner.compact_if(data, expected, changes)?;
+        self.inner.remove_metadata(WAL_COMPACT_KEY)?;
+        Ok(version)
+    }
+
+    /// The journal is hidden from the listed keys.
+    fn get_metadata_keys(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .inner
+            .get_metadata_keys()?
+            .into_iter()
+            .filter(|key| !key.starts_with(WAL_PREFIX))
+            .collect())
+    }
+}
+
+forward_persister!(impl<P> for WalPersister<P> where P: Persister);
+
+#[cfg(test)]
+mod tests {
+    use automerge_persistent_core::test_support;
+
+    use automerge::ActorId;
+
+    use super::WalPersister;
+    use crate::{forward_persister, DocumentVersion, MemoryPersister, Persister, VersionConflict};
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("crashed")]
+    struct Crashed;
+
+    /// A [`MemoryPersister`] that crashes before writing the document while `crash` is set, or
+    /// while compacting after writing it while `crash_after_document` is.
+    #[derive(Debug, Default)]
+    struct Crashing {
+        inner: MemoryPersister,
+        crash: bool,
+        crash_after_document: bool,
+    }
+
+    impl crate::Forward for Crashing {
+        type Inner = MemoryPersister;
+        type Error = Crashed;
+
+        fn inner(&self) -> &MemoryPersister {
+            &self.inner
+        }
+
+        fn inner_mut(&mut self) -> &mut MemoryPersister {
+            &mut self.inner
+        }
+
+        fn map_error(error: std::convert::Infallible) -> Crashed {
+            match error {}
+        }
+
+        fn set_document_if(
+            &mut self,
+            data: Vec<u8>,
+            expected: Option<&DocumentVersion>,
+        ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Crashed> {
+            if self.crash {
+                return Err(Crashed);
+            }
+            Ok(self.inner.set_document_if(data, expected).unwrap())
+        }
+
+        fn compact_if(
+            &mut self,
+            data: Vec<u8>,
+            expected: Option<&DocumentVersion>,
+            changes: Vec<(&ActorId, u64)>,
+        ) -> Result<Result<Option<DocumentVersion>, VersionConflict>, Crashed> {
+            let version = Persister::set_document_if(self, data, expected)?;
+            if self.crash_after_document {
+                return Err(Crashed);
+            }
+            if version.is_ok() {
+                self.inner.remove_changes(changes).unwrap();
+            }
+            Ok(version)
+        }
+    }
+
+    forward_persister!(impl<> for Crashing);
+
+    #[test]
+    fn interrupted_conditional_write_is_replayed() {
+        let mut persister = WalPersister::new(Crashing::default()).unwrap();
+        persister.inner.crash = true;
+        assert!(persister.set_document_if(vec![1], None).is_err());
+
+        let mut inner = persister.into_inner();
+        inner.crash = false;
+        let persister = WalPersister::new(inner).unwrap();
+        assert_eq!(persister.get_document().unwrap(), Some(vec![1]));
+        assert!(persister.inner().get_metadata_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn interrupted_conditional_write_is_discarded_on_conflict() {
+        let mut persister = WalPersister::new(Crashing::default()).unwrap();
+        persister.inner.crash = true;
+        assert!(persister.set_document_if(vec![1], None).is_err());
+
+        // another writer sets the document before this one recovers
+        let mut inner = persister.into_inner();
+        inner.crash = false;
+        inner.set_document_if(vec![2], None).unwrap().unwrap();
+
+        let persister = WalPersister::new(inner).unwrap();
+        assert_eq!(persister.get_document().unwrap(), Some(vec![2]));
+        assert!(persister.inner().get_metadata_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn conforms_as_a_layer() {
+        let persister =
+            test_support::check_layer(|probe| WalPersister::new(probe).unwrap(), |p| p.inner());
+        // nothing is left in the journal
+        assert!(persister.inner().get_metadata_keys().unwrap().is_empty());
+    }
+
+    /// A persister holding one change, with the crash to recover from set.
+    fn crashed_compaction(crash_after_document: bool) -> Crashing {
+        let actor = ActorId::random();
+        let mut persister = WalPersister::new(Crashing::default()).unwrap();
+        persister
+            .insert_changes(vec![(actor.clone(), 1, vec![1])])
+            .unwrap();
+        persister.inner.crash = !crash_after_document;
+        persister.inner.crash_after_document = crash_after_document;
+        assert!(persister
+            .compact_if(vec![2], None, vec![(&actor, 1)])
+            .is_err());
+
+        let mut inner = persister.into_inner();
+        inner.crash = false;
+        inner.crash_after_document = false;
+        inner
+    }
+
+    #[test]
+    fn interrupted_compaction_is_finished() {
+        for crash_after_document in [false, true] {
+            let persister = WalPersister::new(crashed_compaction(crash_after_document)).unwrap();
+            assert_eq!(persister.get_document().unwrap(), Some(vec![2]));
+            assert!(persister.get_changes().unwrap().is_empty());
+            assert!(persister.inner().get_metadata_keys().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn interrupted_compaction_keeps_the_changes_on_conflict() {
+        let mut inner = crashed_compaction(false);
+        inner.set_document_if(vec![3], None).unwrap().unwrap();
+
+        let persister = WalPersister::new(inner).unwrap();
+        assert_eq!(persister.get_document().unwrap(), Some(vec![3]));
+        assert_eq!(persister.get_changes().unwrap(), vec![vec![1]]);
+    }
+}
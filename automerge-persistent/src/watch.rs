@@ -0,0 +1,44 @@
+use automerge::ChangeHash;
+use tokio::sync::watch;
+
+use crate::{Backend, PersistentAutomerge, Persister};
+
+impl<P, B> PersistentAutomerge<P, B>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    /// Watch the heads of the document, for async tasks such as websocket pushers or indexers to
+    /// react to new state.
+    ///
+    /// The receiver starts with the current heads and is updated each time changes are
+    /// persisted and applied that move them, whether local or remote. Changes still waiting on
+    /// their dependencies don't move the heads so don't wake the receivers until they are
+    /// applied.
+    ///
+    /// ```rust
+    /// # use automerge::{transaction::Transactable, ROOT};
+    /// # use automerge_persistent::{MemoryPersister, PersistentAutomerge};
+    /// let mut doc = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    /// let mut heads = doc.watch_heads();
+    /// assert!(heads.borrow_and_update().is_empty());
+    ///
+    /// doc.transact::<_, _, std::convert::Infallible>(|tx| {
+    ///     tx.put(ROOT, "a", 1).unwrap();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// assert!(heads.has_changed().unwrap());
+    /// assert_eq!(*heads.borrow_and_update(), doc.document().get_heads());
+    /// ```
+    pub fn watch_heads(&mut self) -> watch::Receiver<Vec<ChangeHash>> {
+        match &self.heads_sender {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = watch::channel(self.document.get_heads());
+                self.heads_sender = Some(sender);
+                receiver
+            }
+        }
+    }
+}
@@ -0,0 +1,6 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|change_bytes: &[u8]| {
+    automerge_persistent::fuzz::fuzz_changes(change_bytes);
+});
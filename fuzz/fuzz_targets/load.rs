@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: (&[u8], &[u8])| {
+    let (document_bytes, change_bytes) = data;
+    automerge_persistent::fuzz::fuzz_load(document_bytes, change_bytes);
+});